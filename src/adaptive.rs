@@ -3,6 +3,29 @@ use super::*;
 pub const MAX_SUBDIVISIONS_PER_FRAME: usize = 1024000;
 pub const MAX_UNSUBDIVISIONS_PER_FRAME: usize = 1024000;
 
+/// Nodes subdivided and collapsed by the adaptive pass, for the last frame and smoothed
+/// over recent frames. Subdivisions staying well above collapses means the tree is growing.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct AdaptiveStats {
+    pub subdivided: usize,
+    pub unsubdivided: usize,
+    pub average_subdivided: f32,
+    pub average_unsubdivided: f32,
+}
+
+impl AdaptiveStats {
+    pub fn begin_frame(&mut self) {
+        self.subdivided = 0;
+        self.unsubdivided = 0;
+    }
+
+    pub fn end_frame(&mut self) {
+        let t = 0.05;
+        self.average_subdivided += (self.subdivided as f32 - self.average_subdivided) * t;
+        self.average_unsubdivided += (self.unsubdivided as f32 - self.average_unsubdivided) * t;
+    }
+}
+
 pub fn process_subdivision(
     compute: &mut Compute,
     gpu: &Gpu,
@@ -18,52 +41,78 @@ pub fn process_subdivision(
         let mut data = slice.get_mapped_range_mut();
         let result: &mut [u32] = unsafe { reinterpret::reinterpret_mut_slice(&mut data) };
 
-        // Reset atomic counter
-        let len = (result[0] as usize).min(MAX_SUBDIVISIONS_PER_FRAME - 1);
-        result[0] = 0;
+        subdivide_requested(result, &mut compute.stats, octree, world);
+
+        drop(data);
+        compute.subdivision_buffer.unmap();
+    } else {
+        panic!("Failed to run get subdivision buffer!")
+    }
+}
+
+/// Splits the nodes the gpu asked for in `result`, a count followed by node indices, and
+/// resets the count for the next frame
+fn subdivide_requested(
+    result: &mut [u32],
+    stats: &mut AdaptiveStats,
+    octree: &mut Octree,
+    world: &mut World,
+) {
+    // Reset atomic counter
+    let len = (result[0] as usize).min(MAX_SUBDIVISIONS_PER_FRAME - 1);
+    result[0] = 0;
 
-        // if len > 0 {
-        //     println!("Processing {} subdivisions", len);
-        // }
+    // if len > 0 {
+    //     println!("Processing {} subdivisions", len);
+    // }
 
-        for i in 1..=len {
-            let node_index = result[i] as usize;
+    for i in 1..=len {
+        let node_index = result[i] as usize;
 
-            if octree.get_node(node_index) < VOXEL_OFFSET {
-                println!("Doubleup!");
-                continue;
-            }
+        if octree.get_node(node_index) < VOXEL_OFFSET {
+            println!("Doubleup!");
+            continue;
+        }
 
-            let pos = octree.positions[node_index];
-            let (_, voxel_depth, _) = octree.find_voxel(pos, None);
-            let (cpu_chunk, cpu_index, _, _) = world.find_voxel(pos, Some(voxel_depth));
-
-            let tnipt = world.chunks.get(&cpu_chunk).unwrap().nodes[cpu_index];
-            if tnipt.pointer < CHUNK_OFFSET {
-                let mask = world
-                    .chunks
-                    .get(&cpu_chunk)
-                    .unwrap()
-                    .get_node_mask(tnipt.pointer as usize);
-                octree.subdivide(node_index, mask, voxel_depth + 1);
-            } else if tnipt.pointer > CHUNK_OFFSET {
-                let chunk_id = tnipt.pointer - CHUNK_OFFSET;
-                if world.chunks.contains_key(&chunk_id) {
-                    let mask = world.chunks.get(&chunk_id).unwrap().get_node_mask(0);
-                    octree.subdivide(node_index, mask, voxel_depth + 1);
-                } else {
-                    println!("Loading chunk {}", chunk_id);
-                    world.load_chunk(chunk_id);
+        let pos = octree.positions[node_index];
+        let (_, voxel_depth, _) = octree.find_voxel(pos, None);
+        let (cpu_chunk, cpu_index, _, _) = world.find_voxel(pos, Some(voxel_depth));
+
+        let tnipt = world.chunks.get(&cpu_chunk).unwrap().nodes[cpu_index];
+        // Nodes at the depth limit just stay leaves
+        if tnipt.pointer < CHUNK_OFFSET {
+            if octree
+                .refine_from(
+                    &world.chunks.get(&cpu_chunk).unwrap(),
+                    tnipt.pointer as usize,
+                    node_index,
+                    voxel_depth + 1,
+                )
+                .is_ok()
+            {
+                stats.subdivided += 1;
+            }
+        } else if tnipt.pointer > CHUNK_OFFSET {
+            let chunk_id = tnipt.pointer - CHUNK_OFFSET;
+            if world.chunks.contains_key(&chunk_id) {
+                if octree
+                    .refine_from(
+                        &world.chunks.get(&chunk_id).unwrap(),
+                        0,
+                        node_index,
+                        voxel_depth + 1,
+                    )
+                    .is_ok()
+                {
+                    stats.subdivided += 1;
                 }
+            } else {
+                println!("Loading chunk {}", chunk_id);
+                world.load_chunk(chunk_id);
             }
-
-            result[i] = 0;
         }
 
-        drop(data);
-        compute.subdivision_buffer.unmap();
-    } else {
-        panic!("Failed to run get subdivision buffer!")
+        result[i] = 0;
     }
 }
 
@@ -82,41 +131,7 @@ pub fn process_unsubdivision(
         let mut data = slice.get_mapped_range_mut();
         let result: &mut [u32] = unsafe { reinterpret::reinterpret_mut_slice(&mut data) };
 
-        // Reset atomic counter
-        let len = (result[0] as usize).min(MAX_UNSUBDIVISIONS_PER_FRAME - 1);
-        result[0] = 0;
-
-        // if len > 0 {
-        //     println!("Processing {} unsubdivisions", len);
-        // }
-
-        for i in 1..=len {
-            let node_index = result[i] as usize;
-            octree.unsubdivide(node_index);
-
-            let pos = octree.positions[node_index];
-            let (_, voxel_depth, _) = octree.find_voxel(pos, None);
-            let (cpu_chunk, cpu_index, _, _) = world.find_voxel(pos, Some(voxel_depth));
-
-            let tnipt = world.chunks.get(&cpu_chunk).unwrap().nodes[cpu_index];
-            let value = if tnipt.pointer < CHUNK_OFFSET {
-                tnipt.value
-            } else if tnipt.pointer > CHUNK_OFFSET {
-                let chunk = tnipt.pointer - CHUNK_OFFSET;
-                if chunk >= CHUNK_OFFSET / 2 {
-                    println!("Destroyed chunk {}", chunk);
-                    world.chunks.remove(&chunk);
-                }
-
-                tnipt.value
-            } else {
-                tnipt.value
-            };
-
-            octree.nodes[node_index] = value.to_value();
-
-            result[i] = 0;
-        }
+        unsubdivide_requested(result, &mut compute.stats, octree, world);
 
         drop(data);
         compute.unsubdivision_buffer.unmap();
@@ -124,3 +139,91 @@ pub fn process_unsubdivision(
         panic!("failed to run compute on gpu!")
     }
 }
+
+/// Collapses the nodes the gpu asked for in `result` back into leaves
+fn unsubdivide_requested(
+    result: &mut [u32],
+    stats: &mut AdaptiveStats,
+    octree: &mut Octree,
+    world: &mut World,
+) {
+    // Reset atomic counter
+    let len = (result[0] as usize).min(MAX_UNSUBDIVISIONS_PER_FRAME - 1);
+    result[0] = 0;
+
+    // if len > 0 {
+    //     println!("Processing {} unsubdivisions", len);
+    // }
+
+    for i in 1..=len {
+        let node_index = result[i] as usize;
+        result[i] = 0;
+
+        // Nodes that were already collapsed or still have subdivided children are skipped
+        if octree.unsubdivide(node_index).is_err() {
+            continue;
+        }
+        stats.unsubdivided += 1;
+
+        let pos = octree.positions[node_index];
+        let (_, voxel_depth, _) = octree.find_voxel(pos, None);
+        let (cpu_chunk, cpu_index, _, _) = world.find_voxel(pos, Some(voxel_depth));
+
+        let tnipt = world.chunks.get(&cpu_chunk).unwrap().nodes[cpu_index];
+        let value = if tnipt.pointer < CHUNK_OFFSET {
+            tnipt.value
+        } else if tnipt.pointer > CHUNK_OFFSET {
+            let chunk = tnipt.pointer - CHUNK_OFFSET;
+            if chunk >= CHUNK_OFFSET / 2 {
+                println!("Destroyed chunk {}", chunk);
+                world.chunks.remove(&chunk);
+            }
+
+            tnipt.value
+        } else {
+            tnipt.value
+        };
+
+        octree.set_leaf(node_index, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_follow_the_processed_requests() {
+        // Two cells filled so only the octants around them have children to split into
+        let mut grid = vec![false; 64];
+        grid[0] = true;
+        grid[63] = true;
+        let chunk = CpuOctree::from_grid(&grid, 4).unwrap();
+        let mut octree = Octree::new(chunk.get_node_mask(0));
+        let mut world = World::empty(String::new(), 1);
+        world.chunks.insert(0, chunk);
+
+        let mut stats = AdaptiveStats::default();
+        for frame in 1..=3 {
+            stats.begin_frame();
+            // 0 is asked for twice and 3 is empty all the way down, so only 0 and 7 split
+            let mut requests = [4, 0, 7, 0, 3];
+            subdivide_requested(&mut requests, &mut stats, &mut octree, &mut world);
+            assert_eq!(requests[0], 0);
+            assert_eq!(octree.occupied_leaf_count(), 2);
+
+            let mut requests = [3, 0, 7, 0];
+            unsubdivide_requested(&mut requests, &mut stats, &mut octree, &mut world);
+            assert_eq!(requests, [0; 4]);
+            assert_eq!((stats.subdivided, stats.unsubdivided), (2, 2));
+
+            stats.end_frame();
+            let expected = 2.0 * (1.0 - 0.95f32.powi(frame));
+            assert!((stats.average_subdivided - expected).abs() < 1e-5);
+            assert!((stats.average_unsubdivided - expected).abs() < 1e-5);
+        }
+
+        stats.begin_frame();
+        assert_eq!((stats.subdivided, stats.unsubdivided), (0, 0));
+    }
+}
@@ -13,17 +13,38 @@ pub struct App {
     pub character: Character,
     pub settings: Settings,
     ui: Ui,
+    animation: Animation,
+    model_cache: ModelCache,
+    loading: Option<FileLoad>,
+    pub metrics: SessionMetrics,
+    // Settings that are saved back to the config file when they change
+    config: WindowConfig,
 }
 
 impl App {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(window: &Window, metrics: SessionMetrics, config: WindowConfig) -> Self {
         let input = Input::new();
         let character = Character::new();
 
-        let settings = Settings {
+        let mut settings = Settings {
             octree_depth: 12,
+            max_octree_depth: 20,
             fov: 90.0,
             sensitivity: 0.00005,
+            precise_factor: 0.1,
+            model_size: 10.0,
+            show_scale_bar: false,
+            frame_rate: 10.0,
+            ui_scale: config.ui_scale,
+            show_crosshair: true,
+            crosshair_size: 8.0,
+            present_mode: config.present_mode,
+            show_gizmo: true,
+            grid_units: false,
+            min_speed: -12.0,
+            max_speed: 4.0,
+            preload_radius: 0.5,
+            solid_meshes: false,
         };
 
         let gpu = Gpu::new(window).await;
@@ -31,8 +52,8 @@ impl App {
 
         // let world = World::load_world("worlds/defualt").unwrap();
         let mut world = World::new(String::new());
-        let chunk = CpuOctree::load_file("files/statuette.rsvo".to_string(), 10).unwrap();
-        world.chunks.insert(0, chunk);
+        // The default mask shows until the statuette has loaded
+        world.chunks.insert(0, CpuOctree::new(0b01011011));
         world.generate_mip_tree(0);
 
         let gen_settings = GenSettings::default();
@@ -47,8 +68,19 @@ impl App {
         let mask = world.chunks.get(&0).unwrap().get_node_mask(0);
         let octree = Octree::new(mask);
 
-        let render = Render::new(&gpu, window, &octree).await;
-        let compute = Compute::new(&gpu, &render);
+        let mut render = Render::new(&gpu, window, &octree).await;
+        settings.present_mode = render.set_present_mode(&gpu, settings.present_mode);
+        let mut compute = Compute::new(&gpu, &render);
+        compute.set_max_depth(settings.octree_depth);
+
+        // Shallower than the slider so startup is quick, open_model keeps the slider where it is
+        let loading = FileLoad::spawn(
+            vec!["files/statuette.rsvo".to_string()],
+            vec![None],
+            settings.octree_depth.min(10),
+            false,
+            LoadKind::Open,
+        );
 
         let app = Self {
             octree,
@@ -61,29 +93,68 @@ impl App {
             input,
             character,
             settings,
-            ui: Default::default(),
+            ui: Ui {
+                supersample: 4,
+                ..Default::default()
+            },
+            animation: Default::default(),
+            model_cache: ModelCache::new(MODEL_CACHE_ENTRIES, MODEL_CACHE_BYTES),
+            loading: Some(loading),
+            metrics,
+            config,
         };
 
         app
     }
 
+    /// Writes settings that changed back to the config file, once slider drags are let go
+    fn save_config(&mut self) {
+        if self
+            .render
+            .egui_platform
+            .context()
+            .input()
+            .pointer
+            .any_down()
+        {
+            return;
+        }
+
+        if self.settings.ui_scale != self.config.ui_scale {
+            self.config.ui_scale = self.settings.ui_scale;
+            if let Err(e) = self
+                .config
+                .save_value("ui_scale", &self.settings.ui_scale.to_string())
+            {
+                self.ui.error_string = e;
+            }
+        }
+    }
+
     pub fn update(&mut self, time: f64) {
         self.gui(time);
+        self.poll_load();
+        self.save_config();
 
-        let input = Vector3::new(
-            self.input.right as u32 as f32 - self.input.left as u32 as f32,
-            self.input.up as u32 as f32 - self.input.down as u32 as f32,
-            self.input.forward as u32 as f32 - self.input.backward as u32 as f32,
-        ) * std::f32::consts::E.powf(self.character.speed);
+        self.metrics.record_frame(time, &self.octree);
+        self.metrics.files_loaded = self.model_cache.files_loaded;
 
-        let forward: Vector3<f32> = self.character.look.normalize();
-        let right = forward.cross(Vector3::unit_y()).normalize();
-        let up = right.cross(forward);
+        let precise_scale = if self.input.precise {
+            self.settings.precise_factor
+        } else {
+            1.0
+        };
 
-        self.character.pos += forward * input.z + right * input.x + up * input.y;
+        self.character.pos += self.character.movement(&self.input, precise_scale);
+        self.world
+            .preload_chunks(self.character.pos.to_vec(), self.settings.preload_radius);
 
         if self.character.cursour_grabbed {
-            let delta = self.settings.sensitivity * self.input.mouse_delta * self.settings.fov;
+            let right = self.character.look.cross(Vector3::unit_y()).normalize();
+            let delta = self.settings.sensitivity
+                * self.input.mouse_delta
+                * self.settings.fov
+                * precise_scale;
             let rotation = Quaternion::from_axis_angle(right, Rad(-delta.y))
                 * Quaternion::from_axis_angle(Vector3::unit_y(), Rad(-delta.x));
 
@@ -91,12 +162,43 @@ impl App {
             self.character.look = (rotation * self.character.look).normalize();
         }
 
+        // Pick the node under the cursor, or the screen centre while looking around
+        let pick_pos = if self.character.cursour_grabbed {
+            Vector2::new(
+                self.render.size.width as f32 / 2.0,
+                self.render.size.height as f32 / 2.0,
+            )
+        } else {
+            self.input.cursor_pos
+        };
+        self.ui.picked = self.render.pick(&self.gpu, pick_pos);
+
+        if self.animation.playing
+            && time - self.animation.last_frame_time >= 1.0 / self.settings.frame_rate as f64
+        {
+            self.animation.last_frame_time = time;
+            self.set_frame((self.animation.frame + 1) % self.animation.frames.len());
+        }
+
+        if self.input.clicked && self.ui.paint_bucket {
+            self.paint_bucket(pick_pos);
+        } else if self.input.clicked && self.ui.eraser {
+            self.erase(pick_pos);
+        }
+        self.input.clicked = false;
+
         self.render
             .update(&self.gpu, time, &mut self.settings, &self.character);
 
+        self.step_adaptive();
+    }
+
+    /// Runs one round of subdivision from the last frame's hits and uploads the octree
+    fn step_adaptive(&mut self) {
         if !self.render.uniforms.pause_adaptive {
             self.compute.update(&self.gpu, &self.octree);
 
+            self.compute.stats.begin_frame();
             process_subdivision(
                 &mut self.compute,
                 &self.gpu,
@@ -109,6 +211,21 @@ impl App {
                 &mut self.octree,
                 &mut self.world,
             );
+            self.compute.stats.end_frame();
+
+            // Refined nodes change the image so earlier samples no longer match
+            if self.compute.stats.subdivided + self.compute.stats.unsubdivided > 0 {
+                self.render.reset_accumulation();
+            }
+
+            let bytes = self.octree.gpu_bytes(0);
+            let capacity = NODE_BUFFER_LENGTH * std::mem::size_of::<u32>();
+            if near_buffer_limit(bytes, capacity) {
+                self.ui.warning_string = format!(
+                    "Octree is using {:.0}% of the node buffer. Try a lower octree depth",
+                    100.0 * bytes as f32 / capacity as f32
+                );
+            }
 
             // Write octree to gpu
             let nodes = self.octree.raw_data();
@@ -119,6 +236,296 @@ impl App {
         }
     }
 
+    /// Renders a supersampled screenshot offscreen and saves it as a png at window size.
+    /// Path traced and soft shadowed frames are saved with every sample accumulated so far.
+    fn capture(&mut self, path: &str, factor: u32) -> Result<(), String> {
+        let window_size = [self.render.size.width, self.render.size.height];
+        let format = self.render.config.format;
+        let keep_alpha = self.ui.screenshot_alpha;
+
+        if self.render.uniforms.accumulates() {
+            let texture = render_offscreen(&mut self.render, &self.gpu, window_size, true);
+            let pixels = read_texture(&self.gpu, &texture, window_size, format, keep_alpha);
+            return save_png(path, &pixels, window_size);
+        }
+
+        let max_dimension = self.gpu.device.limits().max_texture_dimension_2d;
+        let (factor, size) = supersample_size(window_size, factor, max_dimension);
+
+        // Let the adaptive octree refine for the higher resolution
+        for _ in 0..CAPTURE_SETTLE_FRAMES {
+            render_offscreen(&mut self.render, &self.gpu, size, false);
+            self.step_adaptive();
+        }
+
+        let texture = render_offscreen(&mut self.render, &self.gpu, size, false);
+        let pixels = read_texture(&self.gpu, &texture, size, format, keep_alpha);
+        let pixels = downsample(&pixels, size, factor);
+
+        save_png(path, &pixels, [size[0] / factor, size[1] / factor])
+    }
+
+    /// Screenshot to a timestamped file in the working directory
+    fn quick_capture(&mut self) {
+        let path = screenshot_path();
+        match self.capture(&path, self.ui.supersample) {
+            Ok(_) => self.ui.error_string = "".to_string(),
+            Err(e) => self.ui.error_string = format!("{}: {}", path, e),
+        }
+    }
+
+    /// Traces the current view on the cpu at a quarter of the window size and saves it
+    fn capture_cpu(&self, path: &str) -> Result<(), String> {
+        let size = [
+            (self.render.size.width / 4).max(1),
+            (self.render.size.height / 4).max(1),
+        ];
+        let image = trace_cpu(&self.octree, &self.render.uniforms, size, &DiffuseShader);
+
+        let pixels: Vec<u8> = image
+            .iter()
+            .flat_map(|c| {
+                let [r, g, b] = c.map(|c| (c.clamp(0.0, 1.0) * 255.0) as u8);
+                [r, g, b, 255]
+            })
+            .collect();
+
+        save_png(path, &pixels, size)
+    }
+
+    /// Loads `paths` at the current depth for `kind`, straight from the model cache when
+    /// every file is in it and otherwise on a worker. Starting a load replaces one in progress
+    fn load_models(&mut self, paths: Vec<String>, kind: LoadKind) {
+        let depth = self.settings.octree_depth;
+        let cached: Vec<Option<CpuOctree>> = paths
+            .iter()
+            .map(|path| self.model_cache.get(path, depth))
+            .collect();
+
+        // Opening still goes through the worker to read how deep the file goes
+        if cached.iter().all(Option::is_some) && !matches!(kind, LoadKind::Open) {
+            self.loading = None;
+            let models = cached.into_iter().flatten().collect();
+            let loaded = Loaded {
+                models,
+                file_depth: None,
+            };
+            self.finish_load(kind, &paths, depth, loaded);
+        } else {
+            let solid = self.settings.solid_meshes;
+            self.loading = Some(FileLoad::spawn(paths, cached, depth, solid, kind));
+        }
+    }
+
+    /// Uses the models from a finished background load
+    fn poll_load(&mut self) {
+        let result = match self.loading.as_ref().and_then(FileLoad::poll) {
+            Some(result) => result,
+            None => return,
+        };
+
+        let load = self.loading.take().unwrap();
+        match result {
+            Ok(loaded) => {
+                // Frames come from a directory rather than a file per model
+                if !matches!(load.kind, LoadKind::Frames) {
+                    let models = load.paths.iter().zip(&loaded.models).zip(&load.cached);
+                    for ((path, model), cached) in models {
+                        if !cached {
+                            self.model_cache
+                                .insert(path.clone(), load.depth, model.clone());
+                        }
+                    }
+                }
+                self.finish_load(load.kind, &load.paths, load.depth, loaded);
+            }
+            Err(e) => self.ui.error_string = e,
+        }
+    }
+
+    /// Uses models loaded at `depth` for `kind`
+    fn finish_load(&mut self, kind: LoadKind, paths: &[String], depth: u32, loaded: Loaded) {
+        let mut models = loaded.models;
+        match kind {
+            LoadKind::Open => self.open_model(models.pop().unwrap(), depth, loaded.file_depth),
+            LoadKind::Merge(composite) => {
+                let other = models.pop().unwrap();
+                let (report, merged) = {
+                    let base = self.world.chunks.get(&0).unwrap();
+                    (
+                        PaletteReport::new(base.value(), &other),
+                        base.merge(&other, composite),
+                    )
+                };
+
+                self.set_model(merged);
+                self.ui.warning_string = format!(
+                    "Merged {} + {} colours, {} shared, {} total",
+                    report.base, report.other, report.shared, report.combined
+                );
+                self.ui.error_string = "".to_string();
+            }
+            LoadKind::Csg(op) => {
+                let other = models.pop().unwrap();
+                let combined = self.world.chunks.get(&0).unwrap().csg(&other, op);
+
+                self.set_model(combined);
+                self.ui.error_string = "".to_string();
+            }
+            LoadKind::Gallery => {
+                let columns = if self.ui.gallery_columns == 0 {
+                    (models.len() as f32).sqrt().ceil() as u32
+                } else {
+                    self.ui.gallery_columns
+                };
+                let (octree, cells) = CpuOctree::gallery(&models, columns);
+
+                self.animation = Default::default();
+                self.set_model(octree);
+                self.ui.gallery_labels = paths
+                    .iter()
+                    .map(|path| {
+                        std::path::Path::new(path)
+                            .file_stem()
+                            .unwrap()
+                            .to_string_lossy()
+                            .to_string()
+                    })
+                    .zip(cells)
+                    .collect();
+                self.ui.error_string = "".to_string();
+            }
+            LoadKind::Frames => {
+                self.set_model(models[0].clone());
+                self.animation = Animation {
+                    frames: models,
+                    ..Default::default()
+                };
+                self.ui.error_string = "".to_string();
+            }
+        }
+    }
+
+    /// Shows a model opened from a file at `requested` depth in place of the current one.
+    /// `file_depth` is how deep the file goes if it was read
+    fn open_model(&mut self, chunk: CpuOctree, requested: u32, file_depth: Option<u32>) {
+        // A model shallower than asked for is all the file has, deeper ones can go further
+        let depth = chunk.depth();
+        let warning = shallow_model_warning(depth, requested);
+        if depth < requested {
+            self.settings.max_octree_depth = depth;
+            self.settings.octree_depth = depth;
+        } else {
+            let max = file_depth
+                .unwrap_or(self.settings.max_octree_depth)
+                .max(depth);
+            self.settings.max_octree_depth = max;
+            self.settings.octree_depth = self.settings.octree_depth.min(max);
+        }
+        self.compute.set_max_depth(self.settings.octree_depth);
+
+        self.animation = Default::default();
+        self.set_model(chunk);
+        self.ui.error_string = "".to_string();
+        if let Some(warning) = warning {
+            self.ui.warning_string = warning;
+        }
+    }
+
+    /// Replaces the model in chunk 0 and restarts the adaptive octree
+    fn set_model(&mut self, chunk: CpuOctree) {
+        self.ui.gallery_labels.clear();
+        self.world.chunks.remove(&0);
+        self.world.chunks.insert(0, chunk);
+        self.world.generate_mip_tree(0);
+
+        self.render
+            .set_materials(&self.gpu, &self.world.chunks.get(&0).unwrap().materials);
+
+        // Reset octree
+        let mask = self.world.chunks.get(&0).unwrap().get_node_mask(0);
+        self.octree = Octree::new(mask);
+        self.octree.palette = self.world.chunks.get(&0).unwrap().file_palette.clone();
+        self.render.set_palette(&self.gpu, &self.octree);
+
+        let nodes = self.octree.raw_data();
+        self.gpu
+            .queue
+            .write_buffer(&self.render.node_buffer, 0, bytemuck::cast_slice(&nodes));
+        self.render.reset_accumulation();
+
+        let bytes = self.world.chunks.get(&0).unwrap().gpu_bytes(0);
+        let limit = self.gpu.device.limits().max_storage_buffer_binding_size as usize;
+        self.ui.warning_string = if near_buffer_limit(bytes, limit) {
+            format!(
+                "Model needs {} MB at full detail, device allows {} MB. Try a lower octree depth",
+                bytes / 1000000,
+                limit / 1000000
+            )
+        } else {
+            "".to_string()
+        };
+    }
+
+    /// Leaf under the window pixel, traced exactly on the cpu so clicks don't wait for the
+    /// gpu pick to be read back
+    fn raycast_pixel(&self, pixel: Vector2<f32>) -> Option<usize> {
+        let clip_space = Vector2::new(
+            pixel.x / self.render.size.width.max(1) as f32 * 2.0 - 1.0,
+            -(pixel.y / self.render.size.height.max(1) as f32 * 2.0 - 1.0),
+        );
+        let (pos, dir) = camera_ray(&self.render.uniforms, clip_space);
+        self.octree
+            .raycast(pos, dir)
+            .map(|(index, _, _)| index)
+            .filter(|&index| index < self.octree.positions.len())
+    }
+
+    /// Recolours the region connected to the voxel under `pixel` in the model
+    fn paint_bucket(&mut self, pixel: Vector2<f32>) {
+        let index = match self.raycast_pixel(pixel) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let pos = self.octree.positions[index];
+        let (_, mut chunk) = self.world.chunks.remove(&0).unwrap();
+        // Black is an empty voxel so paint the closest colour instead
+        let [r, g, b] = self.ui.paint_colour;
+        let colour = if [r, g, b] == [0, 0, 0] {
+            Voxel::new(1, 1, 1)
+        } else {
+            Voxel::new(r, g, b)
+        };
+        let dirty = chunk.flood_fill(pos, colour, self.settings.octree_depth);
+
+        self.set_model(chunk);
+        self.ui.warning_string = format!("Recoloured {} voxels", dirty.len());
+    }
+
+    /// Empties the cell at the current depth under `pixel` in the model
+    fn erase(&mut self, pixel: Vector2<f32>) {
+        let index = match self.raycast_pixel(pixel) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let pos = self.octree.positions[index];
+        let (_, mut chunk) = self.world.chunks.remove(&0).unwrap();
+        chunk.remove_voxel(pos, self.settings.octree_depth);
+        self.set_model(chunk);
+    }
+
+    fn reset_view(&mut self) {
+        self.character.reset();
+        self.input.mouse_delta = Vector2::zero();
+    }
+
+    fn set_frame(&mut self, frame: usize) {
+        self.animation.frame = frame;
+        self.set_model(self.animation.frames[frame].clone());
+    }
+
     pub fn gui(&mut self, time: f64) {
         let fps = if let Some(previous_frame_time) = self.render.previous_frame_time {
             let fps = 1.0 / (time - previous_frame_time);
@@ -130,53 +537,101 @@ impl App {
         };
 
         let hole_percentage =
-            100.0 * (8.0 * self.octree.hole_stack.len() as f32) / self.octree.nodes.len() as f32;
+            100.0 * (8.0 * self.octree.hole_stack.len() as f32) / self.octree.node_count() as f32;
 
         egui::Window::new("Info").show(&self.render.egui_platform.context(), |ui| {
             ui.label(format!("FPS: {:.0}", fps));
+            if self.render.uniforms.accumulates() {
+                ui.label(format!(
+                    "Samples: {}",
+                    self.render.uniforms.path_samples + 1
+                ));
+            }
             egui::CollapsingHeader::new("Render")
                 .default_open(true)
                 .show(ui, |ui| {
                     ui.horizontal(|ui| {
                         if ui.button("Open File").clicked() {
                             let path = native_dialog::FileDialog::new()
-                                .add_filter("Magica Voxel RSVO File", &["rsvo"])
+                                .add_filter("Magica Voxel RSVO File", &["rsvo", "gz"])
                                 .add_filter("Magica Voxel Vox File", &["vox"])
+                                .add_filter("Qubicle Binary File", &["qb"])
+                                .add_filter("Minecraft Schematic", &["schematic"])
+                                .add_filter("Point Cloud", &["ply"])
+                                .add_filter("Wavefront OBJ", &["obj"])
+                                .add_filter("Octree", &["bin", "svot"])
+                                .add_filter("Raw Grid", &["raw"])
                                 .show_open_single_file()
                                 .unwrap();
 
                             match path {
-                                Some(path) => match CpuOctree::load_file(
-                                    path.into_os_string().into_string().unwrap(),
-                                    self.settings.octree_depth,
-                                ) {
-                                    Ok(chunk) => {
-                                        self.world.chunks.remove(&0);
-                                        self.world.chunks.insert(0, chunk);
-                                        self.world.generate_mip_tree(0);
-
-                                        // Reset octree
-                                        let mask =
-                                            self.world.chunks.get(&0).unwrap().get_node_mask(0);
-                                        self.octree = Octree::new(mask);
-
-                                        let nodes = self.octree.raw_data();
-                                        self.gpu.queue.write_buffer(
-                                            &self.render.node_buffer,
-                                            0,
-                                            bytemuck::cast_slice(&nodes),
-                                        );
+                                Some(path) => {
+                                    let path = path.into_os_string().into_string().unwrap();
+                                    self.load_models(vec![path], LoadKind::Open);
+                                }
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
 
-                                        self.ui.error_string = "".to_string();
-                                    }
-                                    Err(e) => {
-                                        self.ui.error_string = e;
-                                    }
-                                },
+                        if ui.button("Merge File").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("Magica Voxel RSVO File", &["rsvo", "gz"])
+                                .add_filter("Magica Voxel Vox File", &["vox"])
+                                .add_filter("Qubicle Binary File", &["qb"])
+                                .add_filter("Minecraft Schematic", &["schematic"])
+                                .add_filter("Point Cloud", &["ply"])
+                                .add_filter("Wavefront OBJ", &["obj"])
+                                .show_open_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    let path = path.into_os_string().into_string().unwrap();
+                                    self.load_models(
+                                        vec![path],
+                                        LoadKind::Merge(self.ui.composite),
+                                    );
+                                }
                                 None => self.ui.error_string = "No file selected".to_string(),
                             }
                         }
 
+                        if ui.button("Open Gallery").clicked() {
+                            let paths = native_dialog::FileDialog::new()
+                                .add_filter("Magica Voxel RSVO File", &["rsvo", "gz"])
+                                .add_filter("Magica Voxel Vox File", &["vox"])
+                                .add_filter("Qubicle Binary File", &["qb"])
+                                .add_filter("Minecraft Schematic", &["schematic"])
+                                .add_filter("Point Cloud", &["ply"])
+                                .add_filter("Wavefront OBJ", &["obj"])
+                                .show_open_multiple_file()
+                                .unwrap();
+
+                            if paths.is_empty() {
+                                self.ui.error_string = "No file selected".to_string();
+                            } else {
+                                let paths = paths
+                                    .iter()
+                                    .map(|path| path.to_str().unwrap().to_string())
+                                    .collect();
+                                self.load_models(paths, LoadKind::Gallery);
+                            }
+                        }
+
+                        if ui.button("Open Frames").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .show_open_single_dir()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    let path = path.into_os_string().into_string().unwrap();
+                                    self.load_models(vec![path], LoadKind::Frames);
+                                }
+                                None => self.ui.error_string = "No directory selected".to_string(),
+                            }
+                        }
+
                         if ui.button("Open World").clicked() {
                             let path = native_dialog::FileDialog::new()
                                 .add_filter("Bin in world folder", &["bin"])
@@ -190,6 +645,7 @@ impl App {
                                     // Reset octree
                                     let mask = self.world.chunks.get(&0).unwrap().get_node_mask(0);
                                     self.octree = Octree::new(mask);
+                                    self.render.set_palette(&self.gpu, &self.octree);
 
                                     let nodes = self.octree.raw_data();
                                     self.gpu.queue.write_buffer(
@@ -197,6 +653,7 @@ impl App {
                                         0,
                                         bytemuck::cast_slice(&nodes),
                                     );
+                                    self.render.reset_accumulation();
 
                                     self.ui.error_string = "".to_string();
                                 }
@@ -227,14 +684,20 @@ impl App {
 
                             match path {
                                 Some(path) => {
-                                    World::generate_world(&path, &mut self.procedural, &self.gpu)
-                                        .unwrap();
+                                    World::generate_world(
+                                        &path,
+                                        &mut self.procedural,
+                                        &self.gpu,
+                                        self.gen_settings.world_depth,
+                                    )
+                                    .unwrap();
 
                                     self.world = World::load_world(path).unwrap();
 
                                     // Reset octree
                                     let mask = self.world.chunks.get(&0).unwrap().get_node_mask(0);
                                     self.octree = Octree::new(mask);
+                                    self.render.set_palette(&self.gpu, &self.octree);
 
                                     let nodes = self.octree.raw_data();
                                     self.gpu.queue.write_buffer(
@@ -242,6 +705,7 @@ impl App {
                                         0,
                                         bytemuck::cast_slice(&nodes),
                                     );
+                                    self.render.reset_accumulation();
 
                                     self.ui.error_string = "".to_string();
                                 }
@@ -249,15 +713,362 @@ impl App {
                             }
                         }
                     });
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::Slider::new(&mut self.gen_settings.world_depth, 1..=4)
+                                .text("World depth"),
+                        );
+                        ui.add(
+                            egui::Slider::new(&mut self.settings.preload_radius, 0.0..=2.0)
+                                .text("Preload radius"),
+                        );
+                    });
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Screenshot").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("PNG Image", &["png"])
+                                .show_save_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    match self.capture(path.to_str().unwrap(), self.ui.supersample)
+                                    {
+                                        Ok(_) => self.ui.error_string = "".to_string(),
+                                        Err(e) => self.ui.error_string = e,
+                                    }
+                                }
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+                        if ui.button("Quick (F2)").clicked() {
+                            self.quick_capture();
+                        }
+                        ui.add(
+                            egui::Slider::new(&mut self.ui.supersample, 1..=MAX_SUPERSAMPLE)
+                                .text("x"),
+                        );
+                        ui.checkbox(&mut self.ui.screenshot_alpha, "Alpha");
+
+                        if ui.button("CPU Reference").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("PNG Image", &["png"])
+                                .show_save_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => match self.capture_cpu(path.to_str().unwrap()) {
+                                    Ok(_) => self.ui.error_string = "".to_string(),
+                                    Err(e) => self.ui.error_string = e,
+                                },
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Downsample").clicked() {
+                            let policy = if self.ui.downsample_majority {
+                                Downsample::Majority
+                            } else {
+                                Downsample::Any
+                            };
+                            let lod = self
+                                .world
+                                .chunks
+                                .get(&0)
+                                .unwrap()
+                                .downsample(self.ui.downsample_depth, policy);
+                            self.set_model(lod);
+                        }
+                        ui.add(
+                            egui::DragValue::new(&mut self.ui.downsample_depth)
+                                .clamp_range(0..=MAX_PRECISE_DEPTH)
+                                .prefix("Depth: "),
+                        );
+                        ui.checkbox(&mut self.ui.downsample_majority, "Majority");
+                    });
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Export LOD").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("Octree", &["svot"])
+                                .show_save_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    // Freeze the view so it keeps matching the exported tree
+                                    self.render.uniforms.pause_adaptive = true;
+                                    match self.octree.export_lod(&path) {
+                                        Ok(_) => self.ui.error_string = "".to_string(),
+                                        Err(e) => self.ui.error_string = e,
+                                    }
+                                }
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+
+                        if ui.button("Export Volume").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("NRRD Volume", &["nrrd"])
+                                .add_filter("Raw Volume", &["raw"])
+                                .show_save_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    match self.world.chunks.get(&0).unwrap().export_volume(
+                                        path.to_str().unwrap(),
+                                        self.settings.octree_depth,
+                                    ) {
+                                        Ok(_) => self.ui.error_string = "".to_string(),
+                                        Err(e) => self.ui.error_string = e,
+                                    }
+                                }
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+
+                        if ui.button("Export Mask").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("Raw Grid", &["raw"])
+                                .show_save_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => match self
+                                    .octree
+                                    .export_mask(path.to_str().unwrap(), self.settings.octree_depth)
+                                {
+                                    Ok(_) => self.ui.error_string = "".to_string(),
+                                    Err(e) => self.ui.error_string = e,
+                                },
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+
+                        if ui.button("Export rsvo").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("Sparse Voxel Octree", &["rsvo"])
+                                .show_save_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    let result =
+                                        self.world.chunks.get(&0).unwrap().to_octree().and_then(
+                                            |octree| {
+                                                octree.save_rsvo(
+                                                    path.to_str().unwrap(),
+                                                    self.settings.octree_depth,
+                                                )
+                                            },
+                                        );
+                                    match result {
+                                        Ok(_) => self.ui.error_string = "".to_string(),
+                                        Err(e) => self.ui.error_string = e,
+                                    }
+                                }
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+
+                        if ui.button("Dump Nodes").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("Node dump", &["nodes"])
+                                .show_save_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => match self.octree.dump_nodes(path) {
+                                    Ok(_) => self.ui.error_string = "".to_string(),
+                                    Err(e) => self.ui.error_string = e,
+                                },
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+
+                        if ui.button("Load Nodes").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("Node dump", &["nodes"])
+                                .show_open_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => match Octree::load_nodes(path) {
+                                    Ok(octree) => {
+                                        let capacity = NODE_BUFFER_LENGTH;
+                                        if octree.node_count() > capacity {
+                                            self.ui.error_string = format!(
+                                                "Node dump has {} nodes, buffer fits {}",
+                                                octree.node_count(),
+                                                capacity
+                                            );
+                                        } else {
+                                            self.octree = octree;
+                                            self.render.set_palette(&self.gpu, &self.octree);
+
+                                            // The dump doesn't match the loaded world so keep it as is
+                                            self.render.uniforms.pause_adaptive = true;
+
+                                            let nodes = self.octree.raw_data();
+                                            self.gpu.queue.write_buffer(
+                                                &self.render.node_buffer,
+                                                0,
+                                                bytemuck::cast_slice(&nodes),
+                                            );
+                                            self.render.reset_accumulation();
+
+                                            self.ui.error_string = "".to_string();
+                                        }
+                                    }
+                                    Err(e) => self.ui.error_string = e,
+                                },
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        egui::ComboBox::from_label("Merge mode")
+                            .selected_text(format!("{:?}", self.ui.composite))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(
+                                    &mut self.ui.composite,
+                                    Composite::Replace,
+                                    "Replace",
+                                );
+                                // Keeps the opacity when Over is picked again
+                                let over = matches!(self.ui.composite, Composite::Over(_));
+                                if ui.selectable_label(over, "Over").clicked() && !over {
+                                    self.ui.composite = Composite::Over(0.5);
+                                }
+                                ui.selectable_value(&mut self.ui.composite, Composite::Add, "Add");
+                            });
+                        if let Composite::Over(opacity) = &mut self.ui.composite {
+                            ui.add(egui::Slider::new(opacity, 0.0..=1.0).text("Opacity"));
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        if ui.button("CSG File").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("Magica Voxel RSVO File", &["rsvo", "gz"])
+                                .add_filter("Magica Voxel Vox File", &["vox"])
+                                .add_filter("Qubicle Binary File", &["qb"])
+                                .add_filter("Minecraft Schematic", &["schematic"])
+                                .add_filter("Point Cloud", &["ply"])
+                                .add_filter("Wavefront OBJ", &["obj"])
+                                .show_open_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    let path = path.into_os_string().into_string().unwrap();
+                                    self.load_models(vec![path], LoadKind::Csg(self.ui.csg));
+                                }
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+                        egui::ComboBox::from_label("CSG mode")
+                            .selected_text(format!("{:?}", self.ui.csg))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.ui.csg, Csg::Union, "Union");
+                                ui.selectable_value(&mut self.ui.csg, Csg::Intersect, "Intersect");
+                                ui.selectable_value(
+                                    &mut self.ui.csg,
+                                    Csg::Difference,
+                                    "Difference",
+                                );
+                            });
+                    });
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Generate Noise").clicked() {
+                            let pattern = GenPattern::Noise {
+                                seed: self.gen_settings.seed,
+                                threshold: self.gen_settings.threshold,
+                            };
+                            self.animation = Default::default();
+                            self.set_model(pattern.generate(self.settings.octree_depth.min(8)));
+                        }
+                        ui.add(egui::DragValue::new(&mut self.gen_settings.seed).prefix("Seed: "));
+                        ui.add(
+                            egui::Slider::new(&mut self.gen_settings.threshold, 0.0..=1.0)
+                                .text("Threshold"),
+                        );
+                    });
+                    if ui.button("Generate Shapes").clicked() {
+                        self.animation = Default::default();
+                        self.set_model(
+                            GenPattern::Shapes.generate(self.settings.octree_depth.min(10)),
+                        );
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.ui.paint_bucket, "Paint bucket");
+                        ui.color_edit_button_srgb(&mut self.ui.paint_colour);
+                        ui.checkbox(&mut self.ui.eraser, "Eraser");
+                    });
+
+                    ui.add(
+                        egui::Slider::new(&mut self.ui.gallery_columns, 0..=16)
+                            .text("Gallery columns (0 is auto)"),
+                    );
+
+                    if self.animation.frames.len() > 1 {
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut self.animation.playing, "Play");
+                            ui.add(
+                                egui::Slider::new(&mut self.settings.frame_rate, 1.0..=60.0)
+                                    .text("FPS"),
+                            );
+                        });
+
+                        let mut frame = self.animation.frame;
+                        if ui
+                            .add(
+                                egui::Slider::new(&mut frame, 0..=self.animation.frames.len() - 1)
+                                    .text("Frame"),
+                            )
+                            .changed()
+                        {
+                            self.set_frame(frame);
+                        }
+                    }
+
+                    if let Some(load) = &self.loading {
+                        ui.label(format!("Loading {}", load.name()));
+                        ui.add(
+                            egui::ProgressBar::new(load.progress())
+                                .show_percentage()
+                                .animate(true),
+                        );
+                    }
 
                     if self.ui.error_string != "" {
                         ui.colored_label(egui::Color32::RED, &self.ui.error_string);
                     }
+                    if self.ui.warning_string != "" {
+                        ui.colored_label(egui::Color32::YELLOW, &self.ui.warning_string);
+                    }
 
                     ui.add(
-                        egui::Slider::new(&mut self.settings.octree_depth, 0..=20)
-                            .text("Octree depth"),
+                        egui::Slider::new(
+                            &mut self.settings.octree_depth,
+                            0..=self.settings.max_octree_depth,
+                        )
+                        .text("Octree depth"),
                     );
+                    // Cached meshes were voxelised with the old setting
+                    if ui
+                        .checkbox(&mut self.settings.solid_meshes, "Solid meshes")
+                        .changed()
+                    {
+                        self.model_cache.clear();
+                    }
 
                     ui.horizontal(|ui| {
                         ui.add(
@@ -277,10 +1088,364 @@ impl App {
                         );
                     });
 
+                    let mut present_mode = self.settings.present_mode;
+                    egui::ComboBox::from_label("Present mode")
+                        .selected_text(format!("{:?}", present_mode))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(
+                                &mut present_mode,
+                                wgpu::PresentMode::Fifo,
+                                "Fifo (vsync)",
+                            );
+                            ui.selectable_value(
+                                &mut present_mode,
+                                wgpu::PresentMode::Immediate,
+                                "Immediate",
+                            );
+                            ui.selectable_value(
+                                &mut present_mode,
+                                wgpu::PresentMode::Mailbox,
+                                "Mailbox",
+                            );
+                        });
+                    if present_mode != self.settings.present_mode {
+                        let chosen = self.render.set_present_mode(&self.gpu, present_mode);
+                        if chosen != present_mode {
+                            self.ui.warning_string =
+                                format!("{:?} isn't supported, using {:?}", present_mode, chosen);
+                        }
+                        self.settings.present_mode = chosen;
+                        self.config.present_mode = chosen;
+                        if let Err(e) = self
+                            .config
+                            .save_value("present_mode", &format!("{:?}", chosen))
+                        {
+                            self.ui.error_string = e;
+                        }
+                    }
+
                     ui.checkbox(&mut self.render.uniforms.show_steps, "Show ray steps");
                     ui.checkbox(&mut self.render.uniforms.show_hits, "Show ray hits");
-                    ui.checkbox(&mut self.render.uniforms.shadows, "Shadows");
+                    if self.render.uniforms.show_steps || self.render.uniforms.show_hits {
+                        ui.add(
+                            egui::Slider::new(
+                                &mut self.render.uniforms.debug_brightness,
+                                0.1..=10.0,
+                            )
+                            .text("Debug brightness")
+                            .logarithmic(true),
+                        );
+                        ui.add(
+                            egui::Slider::new(&mut self.render.uniforms.debug_gamma, 0.1..=10.0)
+                                .text("Debug gamma")
+                                .logarithmic(true),
+                        );
+                    }
+                    ui.checkbox(&mut self.render.uniforms.sun_enabled, "Sun");
+                    ui.horizontal(|ui| {
+                        let uniforms = &mut self.render.uniforms;
+                        ui.checkbox(&mut uniforms.shadows, "Shadows");
+                        if uniforms.shadows {
+                            ui.add(
+                                egui::Slider::new(&mut uniforms.sun_radius, 0.0..=10.0)
+                                    .text("Sun radius"),
+                            );
+                            if uniforms.sun_radius > 0.0 {
+                                ui.add(
+                                    egui::Slider::new(&mut uniforms.shadow_samples, 1..=32)
+                                        .text("Samples"),
+                                );
+                            }
+                        }
+                    });
+                    egui::CollapsingHeader::new(format!("Lights ({})", self.render.lights.len()))
+                        .id_source("lights")
+                        .show(ui, |ui| {
+                            let mut removed = None;
+                            for (i, light) in self.render.lights.iter_mut().enumerate() {
+                                ui.horizontal(|ui| {
+                                    egui::ComboBox::from_id_source(("light_kind", i))
+                                        .selected_text(if light.kind == LIGHT_POINT {
+                                            "Point"
+                                        } else {
+                                            "Directional"
+                                        })
+                                        .show_ui(ui, |ui| {
+                                            ui.selectable_value(
+                                                &mut light.kind,
+                                                LIGHT_POINT,
+                                                "Point",
+                                            );
+                                            ui.selectable_value(
+                                                &mut light.kind,
+                                                LIGHT_DIRECTIONAL,
+                                                "Directional",
+                                            );
+                                        });
+                                    for value in &mut light.position[..3] {
+                                        ui.add(egui::DragValue::new(value).speed(0.01));
+                                    }
+                                    if ui.button("Camera").clicked() {
+                                        let v = if light.kind == LIGHT_POINT {
+                                            self.character.pos.to_vec()
+                                        } else {
+                                            self.character.look
+                                        };
+                                        light.position = [v.x, v.y, v.z, 0.0];
+                                    }
+
+                                    let mut colour =
+                                        [light.colour[0], light.colour[1], light.colour[2]];
+                                    ui.color_edit_button_rgb(&mut colour);
+                                    light.colour[..3].copy_from_slice(&colour);
+                                    ui.add(
+                                        egui::Slider::new(&mut light.colour[3], 0.0..=10.0)
+                                            .text("Intensity")
+                                            .logarithmic(true),
+                                    );
+                                    if ui.button("Remove").clicked() {
+                                        removed = Some(i);
+                                    }
+                                });
+                            }
+                            if let Some(i) = removed {
+                                self.render.lights.remove(i);
+                            }
+
+                            if self.render.lights.len() < MAX_LIGHTS
+                                && ui.button("Add light").clicked()
+                            {
+                                self.render.lights.push(Light::default());
+                            }
+                        });
+                    ui.horizontal(|ui| {
+                        let uniforms = &mut self.render.uniforms;
+                        ui.checkbox(&mut uniforms.path_trace, "Path trace");
+                        if uniforms.path_trace {
+                            ui.add(
+                                egui::Slider::new(&mut uniforms.path_bounces, 0..=8)
+                                    .text("Bounces"),
+                            );
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let uniforms = &mut self.render.uniforms;
+                        ui.checkbox(&mut uniforms.reflections, "Reflections");
+                        if uniforms.reflections {
+                            ui.add(
+                                egui::Slider::new(&mut uniforms.reflection_bounces, 1..=8)
+                                    .text("Bounces"),
+                            );
+                        }
+                    });
+                    ui.checkbox(&mut self.render.uniforms.ao, "Ambient occlusion");
+                    if self.render.uniforms.ao {
+                        ui.add(
+                            egui::Slider::new(&mut self.render.uniforms.ao_strength, 0.0..=1.0)
+                                .text("AO strength"),
+                        );
+                        ui.add(
+                            egui::Slider::new(&mut self.render.uniforms.ao_rays, 1..=16)
+                                .text("AO rays"),
+                        );
+                    }
+                    ui.checkbox(&mut self.render.uniforms.surface_only, "Surface only");
+                    ui.checkbox(&mut self.render.uniforms.show_empty, "Show empty space");
                     ui.checkbox(&mut self.render.uniforms.pause_adaptive, "Pause adaptive");
+                    ui.add(
+                        egui::Slider::new(&mut self.render.uniforms.distance_lod_bias, 0.0..=10.0)
+                            .text("Distance LOD bias"),
+                    );
+                    ui.checkbox(&mut self.render.uniforms.smooth_normals, "Smooth normals");
+                    if !self.render.uniforms.smooth_normals {
+                        ui.add(
+                            egui::Slider::new(&mut self.render.uniforms.bevel_amount, 0.0..=0.5)
+                                .text("Bevel"),
+                        );
+                    }
+                    ui.add(
+                        egui::Slider::new(&mut self.render.uniforms.face_tint_strength, 0.0..=1.0)
+                            .text("Face tint"),
+                    );
+                    ui.horizontal(|ui| {
+                        if ui.button("Texture").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("PNG Image", &["png"])
+                                .show_open_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    let result = load_png(path.to_str().unwrap()).and_then(
+                                        |(size, pixels)| {
+                                            self.render.set_texture(&self.gpu, size, &pixels)
+                                        },
+                                    );
+                                    match result {
+                                        Ok(_) => self.ui.error_string = "".to_string(),
+                                        Err(e) => self.ui.error_string = e,
+                                    }
+                                }
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+                        if ui.button("Clear").clicked() {
+                            self.render.clear_texture(&self.gpu);
+                        }
+                        ui.add(
+                            egui::Slider::new(&mut self.render.uniforms.texture_scale, 0.5..=64.0)
+                                .logarithmic(true)
+                                .text("Texture scale"),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("Environment").clicked() {
+                            let path = native_dialog::FileDialog::new()
+                                .add_filter("Radiance HDR", &["hdr"])
+                                .show_open_single_file()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => {
+                                    let result = std::fs::read(&path)
+                                        .map_err(|e| e.to_string())
+                                        .and_then(|data| load_hdr(&data))
+                                        .and_then(|(size, pixels)| {
+                                            self.render.set_environment(&self.gpu, size, &pixels)
+                                        });
+                                    match result {
+                                        Ok(_) => self.ui.error_string = "".to_string(),
+                                        Err(e) => self.ui.error_string = e,
+                                    }
+                                }
+                                None => self.ui.error_string = "No file selected".to_string(),
+                            }
+                        }
+                        if self.render.uniforms.environment && ui.button("Clear").clicked() {
+                            self.render.clear_environment(&self.gpu);
+                        }
+                    });
+                    if !self.render.uniforms.environment {
+                        ui.horizontal(|ui| {
+                            let uniforms = &mut self.render.uniforms;
+                            ui.checkbox(&mut uniforms.sky, "Sky");
+                            if uniforms.sky {
+                                let mut ground = [
+                                    uniforms.ground_colour[0],
+                                    uniforms.ground_colour[1],
+                                    uniforms.ground_colour[2],
+                                ];
+                                ui.label("Ground");
+                                ui.color_edit_button_rgb(&mut ground);
+                                uniforms.ground_colour[..3].copy_from_slice(&ground);
+                                ui.add(
+                                    egui::Slider::new(&mut uniforms.sky_turbidity, 1.0..=10.0)
+                                        .text("Turbidity"),
+                                );
+                            }
+                        });
+                    }
+                    if self.render.uniforms.environment {
+                        ui.checkbox(
+                            &mut self.render.uniforms.environment_ambient,
+                            "Environment ambient",
+                        );
+                        ui.add(
+                            egui::Slider::new(
+                                &mut self.render.uniforms.environment_intensity,
+                                0.0..=4.0,
+                            )
+                            .text("Environment intensity"),
+                        );
+                    }
+                    ui.add(
+                        egui::Slider::new(&mut self.render.uniforms.bloom_intensity, 0.0..=2.0)
+                            .text("Bloom"),
+                    );
+                    if self.render.uniforms.bloom_intensity > 0.0 {
+                        ui.add(
+                            egui::Slider::new(&mut self.render.uniforms.bloom_threshold, 0.0..=4.0)
+                                .text("Bloom threshold"),
+                        );
+                    }
+                    ui.horizontal(|ui| {
+                        let uniforms = &mut self.render.uniforms;
+                        let mut background = [
+                            uniforms.background[0],
+                            uniforms.background[1],
+                            uniforms.background[2],
+                        ];
+                        ui.label("Background");
+                        ui.color_edit_button_rgb(&mut background);
+                        uniforms.background[..3].copy_from_slice(&background);
+
+                        if uniforms.checker_size > 0.0 {
+                            let mut checker = [
+                                uniforms.checker_colour[0],
+                                uniforms.checker_colour[1],
+                                uniforms.checker_colour[2],
+                            ];
+                            ui.color_edit_button_rgb(&mut checker);
+                            uniforms.checker_colour[..3].copy_from_slice(&checker);
+                        }
+                        ui.add(
+                            egui::Slider::new(&mut uniforms.checker_size, 0.0..=64.0)
+                                .text("Checker size"),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        let uniforms = &mut self.render.uniforms;
+                        ui.checkbox(&mut uniforms.fog, "Fog");
+                        if uniforms.fog {
+                            let mut fog_colour = [
+                                uniforms.fog_colour[0],
+                                uniforms.fog_colour[1],
+                                uniforms.fog_colour[2],
+                            ];
+                            ui.color_edit_button_rgb(&mut fog_colour);
+                            uniforms.fog_colour[..3].copy_from_slice(&fog_colour);
+                            ui.add(
+                                egui::Slider::new(&mut uniforms.fog_density, 0.01..=20.0)
+                                    .logarithmic(true)
+                                    .text("Density"),
+                            );
+                        }
+                    });
+                    ui.add(
+                        egui::Slider::new(&mut self.render.uniforms.transparency_layers, 0..=8)
+                            .text("Transparency layers"),
+                    );
+                    if self.render.uniforms.transparency_layers > 0 {
+                        ui.checkbox(&mut self.render.uniforms.depth_peel, "Depth peeling");
+                    }
+                    ui.add(
+                        egui::Slider::new(&mut self.render.uniforms.slab_thickness, 0.0..=2.0)
+                            .text("Slab thickness"),
+                    );
+                    if self.render.uniforms.slab_thickness > 0.0 {
+                        ui.add(
+                            egui::Slider::new(&mut self.render.uniforms.slab_center, -1.0..=1.0)
+                                .text("Slab position"),
+                        );
+                        ui.horizontal(|ui| {
+                            let normal = &mut self.render.uniforms.slab_normal;
+                            ui.label("Slab normal:");
+                            for (i, axis) in ["X", "Y", "Z"].iter().enumerate() {
+                                if ui.button(*axis).clicked() {
+                                    *normal = [0.0; 4];
+                                    normal[i] = 1.0;
+                                }
+                            }
+                            if ui.button("View").clicked() {
+                                let look = self.character.look.normalize();
+                                *normal = [look.x, look.y, look.z, 0.0];
+                            }
+                        });
+                    }
+                    ui.add(
+                        egui::Slider::new(&mut self.render.uniforms.edge_aa_samples, 1..=16)
+                            .text("Edge AA samples"),
+                    );
                     ui.add(
                         egui::Slider::new(&mut self.render.uniforms.misc_value, 0.0000001..=0.0001)
                             .text("Misc")
@@ -288,18 +1453,124 @@ impl App {
                     );
                     ui.checkbox(&mut self.render.uniforms.misc_bool, "Misc");
 
-                    ui.label(format!(
+                    // Stats walk the whole tree so only work them out while open
+                    egui::CollapsingHeader::new(format!(
                         "Nodes: {:.2} million ({:.0}% holes)",
-                        self.octree.nodes.len() as f32 / 1000000.0,
+                        self.octree.node_count() as f32 / 1000000.0,
                         hole_percentage,
+                    ))
+                    .id_source("octree_stats")
+                    .show(ui, |ui| {
+                        let stats = self.octree.stats();
+                        ui.label(format!(
+                            "Live nodes: {} of {}",
+                            stats.live_nodes, stats.total_nodes
+                        ));
+                        ui.label(format!(
+                            "Leaves: {} ({} filled)",
+                            stats.leaves, stats.filled_leaves
+                        ));
+                        ui.label(format!("Max depth: {}", stats.max_depth));
+                        // A full tree would reach every one of the 8^depth cells
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::DragValue::new(&mut self.ui.balance_depth)
+                                    .clamp_range(1..=stats.max_depth.max(1)),
+                            );
+                            self.octree
+                                .nodes_at_depth(self.ui.balance_depth, &mut self.ui.cells);
+                            ui.label(format!(
+                                "Cells at depth: {} of {}",
+                                self.ui.cells.len(),
+                                8f64.powi(self.ui.balance_depth as i32)
+                            ));
+                        });
+                        ui.label(format!("Used: {:.1} MB", stats.bytes as f32 / 1000000.0));
+
+                        if ui.button("Compact").clicked() {
+                            self.octree.compact();
+
+                            let nodes = self.octree.raw_data();
+                            self.gpu.queue.write_buffer(
+                                &self.render.node_buffer,
+                                0,
+                                bytemuck::cast_slice(&nodes),
+                            );
+                        }
+                    });
+                    ui.label(format!(
+                        "Filled leaves: {}",
+                        self.octree.occupied_leaf_count()
+                    ));
+                    {
+                        let chunk = self.world.chunks.get(&0).unwrap();
+                        if !chunk.materials.is_empty() {
+                            let count =
+                                |kind| chunk.materials.values().filter(|m| m.kind == kind).count();
+                            ui.label(format!(
+                                "Materials: {} metal, {} glass, {} emissive",
+                                count(MaterialKind::Metal),
+                                count(MaterialKind::Glass),
+                                count(MaterialKind::Emit),
+                            ));
+                        }
+                    }
+
+                    let stats = self.compute.stats;
+                    ui.label(format!(
+                        "Subdivided: {} (avg {:.0}), collapsed: {} (avg {:.0})",
+                        stats.subdivided,
+                        stats.average_subdivided,
+                        stats.unsubdivided,
+                        stats.average_unsubdivided,
                     ));
+
+                    ui.checkbox(&mut self.settings.grid_units, "Grid coordinates");
+                    ui.horizontal(|ui| {
+                        let report = self.octree.memory_report();
+                        ui.label(format!(
+                            "Memory: {:.1} MB ({:.0}% of node capacity used)",
+                            report.total_bytes() as f32 / 1000000.0,
+                            100.0 * report.nodes.len as f32 / report.nodes.capacity.max(1) as f32,
+                        ));
+                        if ui.button("Shrink").clicked() {
+                            self.octree.shrink_to_fit();
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "Model cache: {} models, {:.1} MB",
+                            self.model_cache.len(),
+                            self.model_cache.bytes() as f32 / 1000000.0,
+                        ));
+                        if ui.button("Clear").clicked() {
+                            self.model_cache.clear();
+                        }
+                    });
+
+                    match self.ui.picked {
+                        Some(index) if (index as usize) < self.octree.node_count() => {
+                            let pos = self.octree.positions[index as usize];
+                            let distance = (Point3::from_vec(pos) - self.character.pos).magnitude();
+                            ui.label(format!(
+                                "Picked node: {} {} {:.3} m away, {} faces exposed",
+                                index,
+                                self.settings.format_pos(pos),
+                                self.settings.to_physical(distance),
+                                self.octree.exposed_faces(index as usize)
+                            ));
+                        }
+                        _ => {
+                            ui.label("Picked node: none");
+                        }
+                    }
                 });
 
             egui::CollapsingHeader::new("Character")
                 .default_open(false)
                 .show(ui, |ui| {
                     ui.add(
-                        egui::Slider::new(&mut self.settings.fov, 0.01..=100.0)
+                        egui::Slider::new(&mut self.settings.fov, MIN_FOV..=MAX_FOV)
                             .prefix("FOV: ")
                             .logarithmic(true),
                     );
@@ -308,6 +1579,59 @@ impl App {
                             .prefix("Sensitivity")
                             .logarithmic(true),
                     );
+                    ui.add(
+                        egui::Slider::new(&mut self.settings.ui_scale, MIN_UI_SCALE..=MAX_UI_SCALE)
+                            .prefix("UI scale: "),
+                    );
+                    ui.checkbox(&mut self.settings.show_gizmo, "Axis gizmo");
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.settings.show_crosshair, "Crosshair");
+                        ui.add(
+                            egui::Slider::new(&mut self.settings.crosshair_size, 1.0..=32.0)
+                                .prefix("Size: "),
+                        );
+                    });
+                    if ui.button("Reset view").clicked() {
+                        self.reset_view();
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Speed: {:.2}", self.character.speed));
+                        for (name, speed) in SPEED_PRESETS {
+                            if ui.button(name).clicked() {
+                                self.character.speed = self.settings.clamp_speed(speed);
+                            }
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut self.settings.min_speed)
+                                .speed(0.1)
+                                .clamp_range(f32::MIN..=self.settings.max_speed)
+                                .prefix("Min speed: "),
+                        );
+                        ui.add(
+                            egui::DragValue::new(&mut self.settings.max_speed)
+                                .speed(0.1)
+                                .clamp_range(self.settings.min_speed..=f32::MAX)
+                                .prefix("Max speed: "),
+                        );
+                    });
+                    self.character.speed = self.settings.clamp_speed(self.character.speed);
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut self.settings.model_size)
+                                .speed(0.1)
+                                .clamp_range(0.001..=f32::MAX)
+                                .prefix("Model size: ")
+                                .suffix(" m"),
+                        );
+                        ui.checkbox(&mut self.settings.show_scale_bar, "Scale bar");
+                    });
+                    ui.add(
+                        egui::Slider::new(&mut self.settings.precise_factor, 0.001..=1.0)
+                            .prefix("Precise factor: ")
+                            .logarithmic(true),
+                    );
                 });
 
             // fn update_world_gen(app: &mut App) {
@@ -347,6 +1671,164 @@ impl App {
             //         }
             //     });
         });
+
+        if self.settings.show_scale_bar {
+            self.scale_bar();
+        }
+
+        if self.settings.show_gizmo {
+            self.gizmo();
+        }
+
+        if !self.ui.gallery_labels.is_empty() {
+            self.gallery_labels();
+        }
+
+        if self.settings.show_crosshair && self.character.cursour_grabbed {
+            self.crosshair();
+        }
+    }
+
+    /// Projects a point in octree space to egui points, None when behind the camera
+    fn project(&self, pos: Vector3<f32>) -> Option<egui::Pos2> {
+        let clip = Matrix4::from(self.render.uniforms.camera) * pos.extend(1.0);
+        if clip.z <= 0.0 {
+            return None;
+        }
+
+        let ndc = Vector2::new(clip.x / clip.z, clip.y / clip.z);
+        let points_per_pixel = 1.0 / self.render.egui_platform.context().pixels_per_point();
+        Some(egui::pos2(
+            (ndc.x + 1.0) / 2.0 * self.render.size.width as f32 * points_per_pixel,
+            (1.0 - ndc.y) / 2.0 * self.render.size.height as f32 * points_per_pixel,
+        ))
+    }
+
+    fn gallery_labels(&self) {
+        let ctx = self.render.egui_platform.context();
+        for (i, (label, cell)) in self.ui.gallery_labels.iter().enumerate() {
+            if let Some(pos) = self.project(*cell) {
+                egui::Area::new(format!("gallery_label_{}", i))
+                    .fixed_pos(pos)
+                    .order(egui::Order::Background)
+                    .interactable(false)
+                    .show(&ctx, |ui| {
+                        ui.colored_label(egui::Color32::WHITE, label);
+                    });
+            }
+        }
+    }
+
+    /// X, Y and Z axes in red, green and blue in the bottom right corner
+    fn gizmo(&self) {
+        let ctx = self.render.egui_platform.context();
+        let painter = ctx.layer_painter(egui::LayerId::new(
+            egui::Order::Foreground,
+            egui::Id::new("gizmo"),
+        ));
+
+        let size = 30.0;
+        let centre =
+            ctx.input().screen_rect().right_bottom() - egui::vec2(size + 15.0, size + 15.0);
+        let colours = [
+            egui::Color32::from_rgb(230, 60, 60),
+            egui::Color32::from_rgb(60, 200, 60),
+            egui::Color32::from_rgb(70, 110, 240),
+        ];
+
+        let endpoints = gizmo_endpoints(self.character.look);
+
+        // Draw the axes pointing away first so the closer ones end up on top
+        let mut order = [0, 1, 2];
+        order.sort_by(|a, b| endpoints[*a].z.total_cmp(&endpoints[*b].z));
+        for i in order {
+            let end = centre + egui::vec2(endpoints[i].x, endpoints[i].y) * size;
+            painter.line_segment([centre, end], egui::Stroke::new(2.0, colours[i]));
+            painter.circle_filled(end, 3.0, colours[i]);
+        }
+    }
+
+    fn crosshair(&self) {
+        let ctx = self.render.egui_platform.context();
+        let painter = ctx.layer_painter(egui::LayerId::new(
+            egui::Order::Foreground,
+            egui::Id::new("crosshair"),
+        ));
+
+        // Painter is in points so the size follows the ui scale
+        let centre = ctx.input().screen_rect().center();
+        let size = self.settings.crosshair_size;
+        let stroke = egui::Stroke::new(1.5, egui::Color32::WHITE);
+        painter.line_segment(
+            [
+                centre - egui::vec2(size, 0.0),
+                centre + egui::vec2(size, 0.0),
+            ],
+            stroke,
+        );
+        painter.line_segment(
+            [
+                centre - egui::vec2(0.0, size),
+                centre + egui::vec2(0.0, size),
+            ],
+            stroke,
+        );
+    }
+
+    /// Draws a bar in the bottom left corner with a round physical length at the
+    /// distance of the picked voxel, or the model centre if nothing is picked
+    fn scale_bar(&self) {
+        let target = match self.ui.picked {
+            Some(index) if (index as usize) < self.octree.positions.len() => {
+                Point3::from_vec(self.octree.positions[index as usize])
+            }
+            _ => Point3::origin(),
+        };
+        let distance = (target - self.character.pos).magnitude();
+
+        // Same projection as the renderer, one pixel is 2 / height in clip space
+        let height = self.render.size.height.max(1) as f32;
+        let s = 1.0 / ((self.settings.fov / 2.0) * (std::f32::consts::PI / 180.0)).tan();
+        let meters_per_pixel = self.settings.to_physical(distance * 2.0 / (height * s));
+        if !meters_per_pixel.is_finite() || meters_per_pixel <= 0.0 {
+            return;
+        }
+
+        // Largest 1, 2 or 5 times a power of ten that fits in 200 pixels
+        let max_length = meters_per_pixel * 200.0;
+        let magnitude = 10f32.powf(max_length.log10().floor());
+        let length = [5.0, 2.0, 1.0]
+            .iter()
+            .map(|m| m * magnitude)
+            .find(|l| *l <= max_length)
+            .unwrap_or(magnitude);
+        let points_per_pixel = 1.0 / self.render.egui_platform.context().pixels_per_point();
+        let bar_width = length / meters_per_pixel * points_per_pixel;
+
+        let label = if length >= 1000.0 {
+            format!("{} km", length / 1000.0)
+        } else if length >= 1.0 {
+            format!("{} m", length)
+        } else if length >= 0.01 {
+            format!("{} cm", length * 100.0)
+        } else {
+            format!("{} mm", length * 1000.0)
+        };
+
+        egui::Area::new("scale_bar")
+            .anchor(egui::Align2::LEFT_BOTTOM, [10.0, -10.0])
+            .show(&self.render.egui_platform.context(), |ui| {
+                ui.label(label);
+                let (rect, _) =
+                    ui.allocate_exact_size(egui::Vec2::new(bar_width, 6.0), egui::Sense::hover());
+                let stroke = egui::Stroke::new(2.0, egui::Color32::WHITE);
+                ui.painter()
+                    .line_segment([rect.left_bottom(), rect.right_bottom()], stroke);
+                ui.painter()
+                    .line_segment([rect.left_top(), rect.left_bottom()], stroke);
+                ui.painter()
+                    .line_segment([rect.right_top(), rect.right_bottom()], stroke);
+            });
     }
 
     pub fn input(&mut self, window: &Window, event: &Event<()>) {
@@ -379,6 +1861,30 @@ impl App {
                     Some(VirtualKeyCode::LShift) => {
                         self.input.down = *state == ElementState::Pressed;
                     }
+                    Some(VirtualKeyCode::LControl) => {
+                        self.input.precise = *state == ElementState::Pressed;
+                    }
+                    Some(VirtualKeyCode::LAlt) => {
+                        self.input.zoom = *state == ElementState::Pressed;
+                    }
+                    Some(VirtualKeyCode::R) => {
+                        if *state == ElementState::Pressed {
+                            self.reset_view();
+                        }
+                    }
+                    Some(VirtualKeyCode::F2) => {
+                        if *state == ElementState::Pressed {
+                            self.quick_capture();
+                        }
+                    }
+                    Some(VirtualKeyCode::F5) => {
+                        if *state == ElementState::Pressed {
+                            match self.render.reload_shader(&self.gpu) {
+                                Ok(_) => self.ui.error_string = "".to_string(),
+                                Err(e) => self.ui.error_string = e,
+                            }
+                        }
+                    }
                     //
                     Some(VirtualKeyCode::Escape) => {
                         if *state == ElementState::Pressed {
@@ -391,21 +1897,32 @@ impl App {
                     }
                     _ => {}
                 },
+                WindowEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    button: winit::event::MouseButton::Left,
+                    ..
+                } => {
+                    if !self.render.egui_platform.context().wants_pointer_input() {
+                        self.input.clicked = true;
+                    }
+                }
+                WindowEvent::CursorMoved { position, .. } => {
+                    self.input.cursor_pos = Vector2::new(position.x as f32, position.y as f32);
+                }
                 _ => {}
             },
             Event::DeviceEvent { event, .. } => match event {
                 DeviceEvent::MouseMotion { delta } => {
                     self.input.mouse_delta = Vector2::new(delta.0 as f32, delta.1 as f32);
                 }
-                DeviceEvent::MouseWheel {
-                    delta:
-                        winit::event::MouseScrollDelta::PixelDelta(winit::dpi::PhysicalPosition {
-                            y,
-                            ..
-                        }),
-                    ..
-                } => {
-                    self.character.speed += *y as f32 / 200.0;
+                DeviceEvent::MouseWheel { delta } => {
+                    let amount = scroll_amount(*delta);
+                    if self.input.zoom {
+                        self.settings.zoom(amount);
+                    } else {
+                        self.character.speed =
+                            self.settings.clamp_speed(self.character.speed + amount);
+                    }
                 }
                 _ => {}
             },
@@ -414,7 +1931,119 @@ impl App {
     }
 }
 
+/// Screen space directions of the x, y and z axes for a camera looking along `look`,
+/// y points down the screen and z is the depth towards the camera
+fn gizmo_endpoints(look: Vector3<f32>) -> [Vector3<f32>; 3] {
+    let forward = look.normalize();
+    // Looking straight up or down the right vector comes from z instead of y
+    let reference = if forward.y.abs() > 0.999 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    };
+    let right = forward.cross(reference).normalize();
+    let up = right.cross(forward);
+
+    [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()]
+        .map(|axis| Vector3::new(axis.dot(right), -axis.dot(up), -axis.dot(forward)))
+}
+
+// Roughly how far a trackpad scrolls for one notch of a mouse wheel
+const PIXELS_PER_LINE: f32 = 40.0;
+const PIXELS_PER_SCROLL_STEP: f32 = 200.0;
+
+/// Warning for a model that came out shallower than the octree depth it was loaded at
+fn shallow_model_warning(depth: u32, requested: u32) -> Option<String> {
+    if depth < requested {
+        Some(format!(
+            "The model is only {} levels deep, octree depth lowered from {}",
+            depth, requested
+        ))
+    } else {
+        None
+    }
+}
+
+/// Scroll steps for a wheel or trackpad delta, so both move speed and zoom by the same amount
+fn scroll_amount(delta: MouseScrollDelta) -> f32 {
+    let pixels = match delta {
+        MouseScrollDelta::LineDelta(_, y) => y * PIXELS_PER_LINE,
+        MouseScrollDelta::PixelDelta(position) => position.y as f32,
+    };
+    pixels / PIXELS_PER_SCROLL_STEP
+}
+
+#[derive(Default)]
+struct Animation {
+    frames: Vec<CpuOctree>,
+    frame: usize,
+    playing: bool,
+    last_frame_time: f64,
+}
+
 #[derive(Default)]
 struct Ui {
     error_string: String,
+    warning_string: String,
+    composite: Composite,
+    csg: Csg,
+    paint_bucket: bool,
+    paint_colour: [u8; 3],
+    eraser: bool,
+    downsample_depth: u32,
+    // Keeps collapsed cells that are over half full instead of any that have a voxel
+    downsample_majority: bool,
+    supersample: u32,
+    // Keeps the background transparent in screenshots
+    screenshot_alpha: bool,
+    gallery_columns: u32,
+    gallery_labels: Vec<(String, Vector3<f32>)>,
+    picked: Option<u32>,
+    balance_depth: u32,
+    // Reused by the stats panel's `nodes_at_depth` each frame
+    cells: Vec<(usize, Vector3<f32>)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gizmo_endpoints_finite_looking_up_and_down() {
+        for look in [
+            Vector3::unit_y(),
+            -Vector3::unit_y(),
+            Vector3::new(0.0, 1.0, 1e-6),
+        ] {
+            for endpoint in gizmo_endpoints(look) {
+                assert!(endpoint.x.is_finite() && endpoint.y.is_finite() && endpoint.z.is_finite());
+            }
+        }
+    }
+
+    #[test]
+    fn gizmo_endpoints_forward_axis_points_away() {
+        let endpoints = gizmo_endpoints(Vector3::unit_z());
+        assert!((endpoints[2].z + 1.0).abs() < 1e-6);
+        assert!(endpoints[1].y < -0.99);
+    }
+
+    #[test]
+    fn shallow_models_warn() {
+        assert!(shallow_model_warning(13, 12).is_none());
+        assert!(shallow_model_warning(12, 12).is_none());
+        let warning = shallow_model_warning(8, 12).unwrap();
+        assert!(warning.contains('8') && warning.contains("12"));
+    }
+
+    #[test]
+    fn wheel_and_trackpad_scroll_alike() {
+        let lines = scroll_amount(MouseScrollDelta::LineDelta(0.0, 5.0));
+        let pixels = scroll_amount(MouseScrollDelta::PixelDelta(
+            winit::dpi::PhysicalPosition::new(0.0, 200.0),
+        ));
+        assert_eq!(lines, 1.0);
+        assert_eq!(pixels, 1.0);
+        assert_eq!(scroll_amount(MouseScrollDelta::LineDelta(3.0, -1.0)), -0.2);
+    }
 }
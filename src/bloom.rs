@@ -0,0 +1,342 @@
+use super::*;
+
+// Format the tracer renders into so emissive colours can go past 1
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+// Halvings of the bright pixels, more gives a wider glow
+pub const BLOOM_MIPS: u32 = 6;
+
+/// Same as `BloomUniforms` in bloom.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct BloomUniforms {
+    pub threshold: f32,
+    pub intensity: f32,
+    pub gamma: f32,
+    pub junk: f32,
+}
+
+/// HDR target the tracer draws into and the passes that blur its bright pixels and
+/// composite them onto the output. Everything is sized to the frame being drawn.
+pub struct Bloom {
+    pub size: [u32; 2],
+    pub hdr_view: wgpu::TextureView,
+    // Half size and smaller, one view per mip so passes can read one and write the next
+    mip_views: Vec<wgpu::TextureView>,
+    uniform_buffer: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    bind_group_layout: wgpu::BindGroupLayout,
+    prefilter_pipeline: wgpu::RenderPipeline,
+    downsample_pipeline: wgpu::RenderPipeline,
+    upsample_pipeline: wgpu::RenderPipeline,
+    composite_pipeline: wgpu::RenderPipeline,
+}
+
+impl Bloom {
+    /// `format` is the format of the views `draw` composites into
+    pub fn new(gpu: &Gpu, size: [u32; 2], format: wgpu::TextureFormat) -> Self {
+        let shader = gpu
+            .device
+            .create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: Some("Bloom Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("bloom.wgsl").into()),
+            });
+
+        let uniform_buffer = gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Bloom Buffer"),
+                contents: bytemuck::cast_slice(&[BloomUniforms {
+                    threshold: 1.0,
+                    intensity: 0.0,
+                    gamma: 2.2,
+                    junk: 0.0,
+                }]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let sampler = gpu.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Bloom Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bind_group_layout =
+            gpu.device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        texture_entry(1),
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                            count: None,
+                        },
+                        texture_entry(3),
+                    ],
+                    label: Some("bloom_bind_group_layout"),
+                });
+
+        let layout = gpu
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Bloom Pipeline Layout"),
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::REPLACE,
+        };
+        let pipeline = |entry_point, format, blend| {
+            Bloom::create_pipeline(gpu, &layout, &shader, entry_point, format, blend)
+        };
+
+        let (hdr_view, mip_views) = Bloom::create_textures(gpu, size);
+
+        Self {
+            size,
+            hdr_view,
+            mip_views,
+            uniform_buffer,
+            sampler,
+            bind_group_layout,
+            prefilter_pipeline: pipeline("fs_prefilter", HDR_FORMAT, wgpu::BlendState::REPLACE),
+            downsample_pipeline: pipeline("fs_downsample", HDR_FORMAT, wgpu::BlendState::REPLACE),
+            upsample_pipeline: pipeline("fs_upsample", HDR_FORMAT, additive),
+            composite_pipeline: pipeline("fs_composite", format, wgpu::BlendState::REPLACE),
+        }
+    }
+
+    fn create_pipeline(
+        gpu: &Gpu,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        entry_point: &str,
+        format: wgpu::TextureFormat,
+        blend: wgpu::BlendState,
+    ) -> wgpu::RenderPipeline {
+        gpu.device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(layout),
+                vertex: wgpu::VertexState {
+                    module: shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: shader,
+                    entry_point,
+                    targets: &[wgpu::ColorTargetState {
+                        format,
+                        blend: Some(blend),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleStrip,
+                    ..Default::default()
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+    }
+
+    /// Full size hdr view and the views of each bloom mip
+    fn create_textures(gpu: &Gpu, size: [u32; 2]) -> (wgpu::TextureView, Vec<wgpu::TextureView>) {
+        let usage = wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING;
+        let extent = |size: [u32; 2]| wgpu::Extent3d {
+            width: size[0].max(1),
+            height: size[1].max(1),
+            depth_or_array_layers: 1,
+        };
+
+        let hdr = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("HDR Texture"),
+            size: extent(size),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage,
+        });
+
+        // Stop before the smallest side gets under a pixel
+        let half = [size[0] / 2, size[1] / 2];
+        let levels = 32 - half[0].min(half[1]).max(1).leading_zeros();
+        let bloom = gpu.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Bloom Texture"),
+            size: extent(half),
+            mip_level_count: levels.min(BLOOM_MIPS),
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage,
+        });
+        let mip_views = (0..levels.min(BLOOM_MIPS))
+            .map(|mip| {
+                bloom.create_view(&wgpu::TextureViewDescriptor {
+                    base_mip_level: mip,
+                    mip_level_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        (
+            hdr.create_view(&wgpu::TextureViewDescriptor::default()),
+            mip_views,
+        )
+    }
+
+    /// Reallocates the textures for frames of a new size, does nothing for the same size
+    pub fn resize(&mut self, gpu: &Gpu, size: [u32; 2]) {
+        if size != self.size {
+            let (hdr_view, mip_views) = Bloom::create_textures(gpu, size);
+            self.hdr_view = hdr_view;
+            self.mip_views = mip_views;
+            self.size = size;
+        }
+    }
+
+    pub fn set_uniforms(&self, gpu: &Gpu, uniforms: BloomUniforms) {
+        gpu.queue
+            .write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniforms]));
+    }
+
+    fn bind_group(
+        &self,
+        gpu: &Gpu,
+        source: &wgpu::TextureView,
+        bloom: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(bloom),
+                },
+            ],
+            label: Some("bloom_bind_group"),
+        })
+    }
+
+    fn pass(
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        bind_group: &wgpu::BindGroup,
+        target: &wgpu::TextureView,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Bloom Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: true },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..4, 0..1);
+    }
+
+    /// Blurs the bright parts of `hdr_view` when `intensity` is above 0 and composites
+    /// the result into `view`, which must be in the format given to `new`
+    pub fn draw(
+        &self,
+        gpu: &Gpu,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        intensity: f32,
+    ) {
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::BLACK);
+        if intensity > 0.0 {
+            // Bright pixels into the first mip, then halve down the chain
+            let bind_group = self.bind_group(gpu, &self.hdr_view, &self.hdr_view);
+            Bloom::pass(
+                encoder,
+                &self.prefilter_pipeline,
+                &bind_group,
+                &self.mip_views[0],
+                clear,
+            );
+            for mip in 1..self.mip_views.len() {
+                let bind_group = self.bind_group(gpu, &self.mip_views[mip - 1], &self.hdr_view);
+                Bloom::pass(
+                    encoder,
+                    &self.downsample_pipeline,
+                    &bind_group,
+                    &self.mip_views[mip],
+                    clear,
+                );
+            }
+
+            // Back up, adding each blurred mip onto the one above it
+            for mip in (0..self.mip_views.len() - 1).rev() {
+                let bind_group = self.bind_group(gpu, &self.mip_views[mip + 1], &self.hdr_view);
+                Bloom::pass(
+                    encoder,
+                    &self.upsample_pipeline,
+                    &bind_group,
+                    &self.mip_views[mip],
+                    wgpu::LoadOp::Load,
+                );
+            }
+        }
+
+        let bind_group = self.bind_group(gpu, &self.hdr_view, &self.mip_views[0]);
+        Bloom::pass(
+            encoder,
+            &self.composite_pipeline,
+            &bind_group,
+            view,
+            wgpu::LoadOp::Load,
+        );
+    }
+}
@@ -0,0 +1,77 @@
+// Same as bloom.rs:BloomUniforms
+struct BloomUniforms {
+    threshold: f32;
+    intensity: f32;
+    gamma: f32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> b: BloomUniforms;
+[[group(0), binding(1)]]
+var source: texture_2d<f32>; // texture being downsampled, upsampled or composited
+[[group(0), binding(2)]]
+var source_sampler: sampler;
+[[group(0), binding(3)]]
+var bloom: texture_2d<f32>; // blurred bright pixels, only read by the composite
+
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] in_vertex_index: u32) -> [[builtin(position)]] vec4<f32> {
+    let x = f32(in_vertex_index & 1u) * 2.0 - 1.0;
+    let y = f32(in_vertex_index >> 1u) * 2.0 - 1.0;
+    return vec4<f32>(x, y, 0.0, 1.0);
+}
+
+fn texel_uv(t: texture_2d<f32>, frag_pos: vec4<f32>, target_scale: f32) -> vec2<f32> {
+    return frag_pos.xy * target_scale / vec2<f32>(textureDimensions(t));
+}
+
+// Average of four bilinear taps, a 4x4 box over the source
+fn box_sample(uv: vec2<f32>) -> vec3<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    var colour = vec3<f32>(0.0);
+    colour = colour + textureSampleLevel(source, source_sampler, uv + texel * vec2<f32>(-1.0, -1.0), 0.0).xyz;
+    colour = colour + textureSampleLevel(source, source_sampler, uv + texel * vec2<f32>(1.0, -1.0), 0.0).xyz;
+    colour = colour + textureSampleLevel(source, source_sampler, uv + texel * vec2<f32>(-1.0, 1.0), 0.0).xyz;
+    colour = colour + textureSampleLevel(source, source_sampler, uv + texel * vec2<f32>(1.0, 1.0), 0.0).xyz;
+    return colour * 0.25;
+}
+
+// First downsample, keeps only what's above the threshold
+[[stage(fragment)]]
+fn fs_prefilter([[builtin(position)]] frag_pos: vec4<f32>) -> [[location(0)]] vec4<f32> {
+    let colour = box_sample(texel_uv(source, frag_pos, 2.0));
+    let brightness = max(colour.x, max(colour.y, colour.z));
+    let bright = colour * max(brightness - b.threshold, 0.0) / max(brightness, 0.0001);
+    return vec4<f32>(bright, 1.0);
+}
+
+[[stage(fragment)]]
+fn fs_downsample([[builtin(position)]] frag_pos: vec4<f32>) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(box_sample(texel_uv(source, frag_pos, 2.0)), 1.0);
+}
+
+// 3x3 tent over the smaller mip, added onto the target by the blend state
+[[stage(fragment)]]
+fn fs_upsample([[builtin(position)]] frag_pos: vec4<f32>) -> [[location(0)]] vec4<f32> {
+    let uv = texel_uv(source, frag_pos, 0.5);
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    var colour = vec3<f32>(0.0);
+    for (var i = 0; i < 9; i = i + 1) {
+        let offset = vec2<f32>(f32(i % 3 - 1), f32(i / 3 - 1));
+        let weight = (2.0 - abs(offset.x)) * (2.0 - abs(offset.y)) / 16.0;
+        colour = colour + weight * textureSampleLevel(source, source_sampler, uv + offset * texel, 0.0).xyz;
+    }
+    return vec4<f32>(colour, 1.0);
+}
+
+// Scene plus bloom, clamped and gamma corrected for the surface
+[[stage(fragment)]]
+fn fs_composite([[builtin(position)]] frag_pos: vec4<f32>) -> [[location(0)]] vec4<f32> {
+    let uv = texel_uv(source, frag_pos, 1.0);
+    let scene = textureSampleLevel(source, source_sampler, uv, 0.0);
+    var colour = scene.xyz;
+    if (b.intensity > 0.0) {
+        colour = colour + b.intensity * textureSampleLevel(bloom, source_sampler, uv, 0.0).xyz;
+    }
+    return vec4<f32>(pow(clamp(colour, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(b.gamma)), scene.w);
+}
@@ -0,0 +1,147 @@
+use super::*;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+pub const MODEL_CACHE_ENTRIES: usize = 4;
+pub const MODEL_CACHE_BYTES: usize = 512 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Debug)]
+struct CacheKey {
+    path: String,
+    depth: u32,
+    modified: Option<SystemTime>,
+}
+
+impl CacheKey {
+    fn new(path: &str, depth: u32) -> Self {
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        Self {
+            path: path.to_string(),
+            depth,
+            modified,
+        }
+    }
+}
+
+/// Keeps the last few loaded models so switching between them skips parsing.
+/// Entries are keyed on the file's modified time so edited files get reloaded.
+pub struct ModelCache {
+    // Most recently used at the front
+    entries: VecDeque<(CacheKey, CpuOctree)>,
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    // Successful loads including cache hits
+    pub files_loaded: usize,
+}
+
+impl ModelCache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            max_entries,
+            max_bytes,
+            files_loaded: 0,
+        }
+    }
+
+    /// Cached copy of `path` loaded at `depth` if the file hasn't changed since
+    pub fn get(&mut self, path: &str, depth: u32) -> Option<CpuOctree> {
+        let key = CacheKey::new(path, depth);
+
+        // Anything for this file with a different mtime is stale
+        self.entries
+            .retain(|(k, _)| k.path != key.path || k.modified == key.modified);
+
+        let i = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(i).unwrap();
+        let octree = entry.1.clone();
+        self.entries.push_front(entry);
+        self.files_loaded += 1;
+        Some(octree)
+    }
+
+    /// Adds a model loaded elsewhere, like on a worker thread
+    pub fn insert(&mut self, path: String, depth: u32, octree: CpuOctree) {
+        self.entries
+            .push_front((CacheKey::new(&path, depth), octree));
+        self.evict();
+        self.files_loaded += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(_, octree)| octree.nodes.len() * std::mem::size_of::<Node>())
+            .sum()
+    }
+
+    // Drops least recently used entries until under both caps, always keeping the newest
+    fn evict(&mut self) {
+        while self.entries.len() > 1
+            && (self.entries.len() > self.max_entries || self.bytes() > self.max_bytes)
+        {
+            self.entries.pop_back();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_models_go_first() {
+        let mut cache = ModelCache::new(2, usize::MAX);
+        cache.insert("a.vox".to_string(), 4, CpuOctree::new(0));
+        cache.insert("b.vox".to_string(), 4, CpuOctree::new(0));
+        assert!(cache.get("a.vox", 4).is_some());
+        cache.insert("c.vox".to_string(), 4, CpuOctree::new(0));
+
+        assert!(cache.get("b.vox", 4).is_none());
+        assert!(cache.get("a.vox", 4).is_some());
+        assert!(cache.get("c.vox", 4).is_some());
+        // Other depths are other models
+        assert!(cache.get("a.vox", 5).is_none());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.files_loaded, 6);
+    }
+
+    #[test]
+    fn byte_cap_keeps_the_newest_model() {
+        let mut cache = ModelCache::new(4, 8 * std::mem::size_of::<Node>());
+        cache.insert("a.vox".to_string(), 4, CpuOctree::new(0));
+        cache.insert("b.vox".to_string(), 4, CpuOctree::new(0));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("b.vox", 4).is_some());
+
+        cache.max_bytes = 0;
+        cache.insert("c.vox".to_string(), 4, CpuOctree::new(0));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("c.vox", 4).is_some());
+    }
+
+    #[test]
+    fn changed_files_are_loaded_again() {
+        let path = std::env::temp_dir().join(format!("cache_test_{}.vox", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, b"model").unwrap();
+
+        let mut cache = ModelCache::new(4, usize::MAX);
+        cache.insert(path.clone(), 4, CpuOctree::new(0));
+        assert!(cache.get(&path, 4).is_some());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(cache.get(&path, 4).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,395 @@
+use super::*;
+
+// Frames traced at the capture resolution before the final one, to let subdivision catch up
+pub const CAPTURE_SETTLE_FRAMES: u32 = 30;
+pub const MAX_SUPERSAMPLE: u32 = 8;
+
+/// Clamps `factor` so the supersampled size fits in `max_dimension`.
+/// Returns (factor, supersampled size)
+pub fn supersample_size(size: [u32; 2], factor: u32, max_dimension: u32) -> (u32, [u32; 2]) {
+    let largest = size[0].max(size[1]).max(1);
+    let factor = factor
+        .min(MAX_SUPERSAMPLE)
+        .min(max_dimension / largest)
+        .max(1);
+
+    (factor, [size[0] * factor, size[1] * factor])
+}
+
+/// Traces a frame into a new texture of `size` instead of the window surface. With
+/// `accumulate` the frame adds a sample to the on screen path traced average instead of
+/// overwriting it, so `size` must be the window size.
+pub fn render_offscreen(
+    render: &mut Render,
+    gpu: &Gpu,
+    size: [u32; 2],
+    accumulate: bool,
+) -> wgpu::Texture {
+    let texture = gpu.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Capture Texture"),
+        size: wgpu::Extent3d {
+            width: size[0],
+            height: size[1],
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: render.config.format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    // Same camera at the new resolution, without touching the pick request
+    let uniforms = render.uniforms;
+    let scale = uniforms.dimensions[1] / size[1] as f32;
+    render.uniforms.dimensions = [
+        size[0] as f32,
+        size[1] as f32,
+        uniforms.dimensions[2] * scale,
+        uniforms.dimensions[3],
+    ];
+    render.uniforms.pick_x = u32::MAX;
+    render.uniforms.pick_y = u32::MAX;
+    render.uniforms.path_samples = if accumulate {
+        uniforms.path_samples + 1
+    } else {
+        0
+    };
+    gpu.queue.write_buffer(
+        &render.uniform_buffer,
+        0,
+        bytemuck::cast_slice(&[render.uniforms]),
+    );
+
+    let mut encoder = gpu
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Capture Encoder"),
+        });
+    render.draw(gpu, &mut encoder, &view, size);
+    gpu.queue.submit(std::iter::once(encoder.finish()));
+
+    // The offscreen frame either added to the accumulated samples or overwrote them
+    let path_samples = render.uniforms.path_samples;
+    render.uniforms = uniforms;
+    if accumulate {
+        render.uniforms.path_samples = path_samples;
+    } else {
+        render.reset_accumulation();
+    }
+    gpu.queue.write_buffer(
+        &render.uniform_buffer,
+        0,
+        bytemuck::cast_slice(&[render.uniforms]),
+    );
+
+    texture
+}
+
+/// Copies a texture from `render_offscreen` back as tightly packed RGBA bytes. Alpha is
+/// 255 where the tracer hit something and 0 for the background, or 255 everywhere without
+/// `keep_alpha`
+pub fn read_texture(
+    gpu: &Gpu,
+    texture: &wgpu::Texture,
+    size: [u32; 2],
+    format: wgpu::TextureFormat,
+    keep_alpha: bool,
+) -> Vec<u8> {
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let row_bytes = size[0] * 4;
+    let padded_row_bytes = (row_bytes + align - 1) / align * align;
+
+    let buffer = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Capture Buffer"),
+        size: (padded_row_bytes * size[1]) as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = gpu
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Capture Copy Encoder"),
+        });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(padded_row_bytes),
+                rows_per_image: None,
+            },
+        },
+        wgpu::Extent3d {
+            width: size[0],
+            height: size[1],
+            depth_or_array_layers: 1,
+        },
+    );
+    gpu.queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let future = slice.map_async(wgpu::MapMode::Read);
+
+    gpu.device.poll(wgpu::Maintain::Wait);
+
+    let mut pixels = Vec::with_capacity((row_bytes * size[1]) as usize);
+    if let Ok(()) = pollster::block_on(future) {
+        let data = slice.get_mapped_range();
+        for row in data.chunks(padded_row_bytes as usize) {
+            pixels.extend_from_slice(&row[..row_bytes as usize]);
+        }
+    } else {
+        panic!("Failed to read capture buffer!")
+    }
+
+    let bgra = matches!(
+        format,
+        wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+    );
+    for pixel in pixels.chunks_mut(4) {
+        if bgra {
+            pixel.swap(0, 2);
+        }
+        if !keep_alpha {
+            pixel[3] = 255;
+        }
+    }
+
+    pixels
+}
+
+/// Box filters RGBA pixels down by `factor` in both directions
+pub fn downsample(pixels: &[u8], size: [u32; 2], factor: u32) -> Vec<u8> {
+    let (width, height) = (size[0] / factor, size[1] / factor);
+    let samples = factor * factor;
+
+    let mut output = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = [0u32; 4];
+            for sy in 0..factor {
+                for sx in 0..factor {
+                    let i = (((y * factor + sy) * size[0] + x * factor + sx) * 4) as usize;
+                    for c in 0..4 {
+                        sum[c] += pixels[i + c] as u32;
+                    }
+                }
+            }
+
+            output.extend(sum.iter().map(|s| (s / samples) as u8));
+        }
+    }
+
+    output
+}
+
+/// `screenshot-YYYY-MM-DD-HHMMSS.png` in the working directory from the UTC time, with a
+/// counter on the end if that's taken
+pub fn screenshot_path() -> String {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let name = screenshot_name(seconds);
+
+    let mut path = format!("{}.png", name);
+    let mut i = 1;
+    while std::path::Path::new(&path).exists() {
+        path = format!("{}-{}.png", name, i);
+        i += 1;
+    }
+    path
+}
+
+/// `screenshot-YYYY-MM-DD-HHMMSS` for a time in seconds since 1970
+fn screenshot_name(seconds: u64) -> String {
+    // Days since 1970 to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    let time = seconds % 86400;
+    format!(
+        "screenshot-{}-{:02}-{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+pub fn save_png(path: &str, pixels: &[u8], size: [u32; 2]) -> Result<(), String> {
+    let file = std::fs::File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), size[0], size[1]);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(pixels).map_err(|e| e.to_string())
+}
+
+/// Reads a png as rgba8, expanding grey, palette and 16 bit images
+pub fn load_png(path: &str) -> Result<([u32; 2], Vec<u8>), String> {
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut decoder = png::Decoder::new(std::io::BufReader::new(file));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).map_err(|e| e.to_string())?;
+    data.truncate(info.buffer_size());
+
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => data,
+        png::ColorType::Rgb => data
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => data
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => data.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        png::ColorType::Indexed => return Err("Unexpanded palette png".to_string()),
+    };
+    Ok(([info.width, info.height], pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn screenshot_names_use_the_utc_date() {
+        assert_eq!(screenshot_name(0), "screenshot-1970-01-01-000000");
+        // Leap day in a year divisible by 400
+        assert_eq!(screenshot_name(951782400), "screenshot-2000-02-29-000000");
+        assert_eq!(screenshot_name(1700000000), "screenshot-2023-11-14-221320");
+        assert_eq!(screenshot_name(4107542399), "screenshot-2100-02-28-235959");
+    }
+
+    #[test]
+    fn screenshot_paths_are_pngs() {
+        let path = screenshot_path();
+        assert!(path.starts_with("screenshot-") && path.ends_with(".png"));
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn supersampling_fits_the_texture_limit() {
+        assert_eq!(supersample_size([800, 600], 2, 8192), (2, [1600, 1200]));
+        assert_eq!(supersample_size([800, 600], 4, 2048), (2, [1600, 1200]));
+        assert_eq!(
+            supersample_size([800, 600], 100, 100000),
+            (MAX_SUPERSAMPLE, [6400, 4800])
+        );
+        // Never below the window size
+        assert_eq!(supersample_size([4096, 100], 2, 2048), (1, [4096, 100]));
+        assert_eq!(supersample_size([800, 600], 0, 8192), (1, [800, 600]));
+    }
+
+    #[test]
+    fn downsampling_averages_each_block() {
+        #[rustfmt::skip]
+        let pixels = [
+            0, 0, 0, 255,  100, 0, 0, 255,    10, 10, 10, 0,  10, 10, 10, 0,
+            0, 200, 0, 255,  0, 0, 40, 255,   10, 10, 10, 0,  10, 10, 10, 0,
+        ];
+        assert_eq!(
+            downsample(&pixels, [4, 2], 2),
+            [25, 50, 10, 255, 10, 10, 10, 0]
+        );
+    }
+
+    fn png_pixels(
+        color: png::ColorType,
+        depth: png::BitDepth,
+        palette: Option<Vec<u8>>,
+        data: &[u8],
+    ) -> ([u32; 2], Vec<u8>) {
+        let path = std::env::temp_dir().join(format!(
+            "png_test_{}_{:?}_{:?}.png",
+            std::process::id(),
+            color,
+            depth
+        ));
+        {
+            let file = std::fs::File::create(&path).unwrap();
+            let mut encoder = png::Encoder::new(file, 2, 1);
+            encoder.set_color(color);
+            encoder.set_depth(depth);
+            if let Some(palette) = palette {
+                encoder.set_palette(palette);
+            }
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(data).unwrap();
+        }
+        let result = load_png(path.to_str().unwrap());
+        std::fs::remove_file(&path).ok();
+        result.unwrap()
+    }
+
+    #[test]
+    fn pngs_expand_to_rgba8() {
+        use png::{BitDepth, ColorType};
+        let eight = BitDepth::Eight;
+
+        assert_eq!(
+            png_pixels(ColorType::Grayscale, eight, None, &[10, 200]),
+            ([2, 1], vec![10, 10, 10, 255, 200, 200, 200, 255])
+        );
+        assert_eq!(
+            png_pixels(ColorType::GrayscaleAlpha, eight, None, &[10, 20, 200, 0]).1,
+            [10, 10, 10, 20, 200, 200, 200, 0]
+        );
+        assert_eq!(
+            png_pixels(ColorType::Rgb, eight, None, &[1, 2, 3, 4, 5, 6]).1,
+            [1, 2, 3, 255, 4, 5, 6, 255]
+        );
+        let rgba = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(png_pixels(ColorType::Rgba, eight, None, &rgba).1, rgba);
+
+        // Palette indices are looked up
+        let palette = vec![0, 0, 0, 255, 128, 0];
+        assert_eq!(
+            png_pixels(ColorType::Indexed, eight, Some(palette), &[1, 0]).1,
+            [255, 128, 0, 255, 0, 0, 0, 255]
+        );
+
+        // 16 bit channels keep their high byte
+        let rgb16 = [
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xff, 0xff, 0, 0, 0x80, 0x01,
+        ];
+        assert_eq!(
+            png_pixels(ColorType::Rgb, BitDepth::Sixteen, None, &rgb16).1,
+            [0x12, 0x56, 0x9a, 255, 0xff, 0, 0x80, 255]
+        );
+        assert_eq!(
+            png_pixels(
+                ColorType::Grayscale,
+                BitDepth::Sixteen,
+                None,
+                &[0xab, 1, 0xcd, 2]
+            )
+            .1,
+            [0xab, 0xab, 0xab, 255, 0xcd, 0xcd, 0xcd, 255]
+        );
+    }
+
+    #[test]
+    fn missing_pngs_error() {
+        assert!(load_png("not a file.png").is_err());
+    }
+}
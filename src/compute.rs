@@ -10,6 +10,7 @@ pub struct Compute {
     pub subdivision_buffer: wgpu::Buffer,
     pub unsubdivision_buffer: wgpu::Buffer,
     compute_bind_group: wgpu::BindGroup,
+    pub stats: AdaptiveStats,
 }
 
 impl Compute {
@@ -93,11 +94,17 @@ impl Compute {
             subdivision_buffer,
             unsubdivision_buffer,
             compute_bind_group,
+            stats: AdaptiveStats::default(),
         }
     }
 
+    /// Deepest level the adaptive octree is allowed to subdivide to
+    pub fn set_max_depth(&mut self, depth: u32) {
+        self.uniforms.max_depth = depth;
+    }
+
     pub fn update(&mut self, gpu: &Gpu, octree: &Octree) {
-        let iterations = octree.nodes.len();
+        let iterations = octree.node_count();
         let dispatch_size_x =
             (iterations as f32 / WORK_GROUP_SIZE as f32 / DISPATCH_SIZE_Y as f32).ceil() as u32;
 
@@ -116,7 +123,7 @@ impl Compute {
             compute_pass.dispatch(dispatch_size_x, DISPATCH_SIZE_Y, 1);
         }
 
-        self.uniforms.node_length = octree.nodes.len() as u32;
+        self.uniforms.node_length = octree.node_count() as u32;
         gpu.queue.write_buffer(
             &self.uniform_buffer,
             0,
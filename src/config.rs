@@ -0,0 +1,255 @@
+use super::*;
+
+pub const CONFIG_FILE: &str = "config.txt";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WindowConfig {
+    pub width: u32,
+    pub height: u32,
+    pub title: String,
+    // Borderless fullscreen on the current monitor
+    pub fullscreen: bool,
+    // Settings::ui_scale at startup, written back when it changes
+    pub ui_scale: f32,
+    // Settings::present_mode at startup, also written back
+    pub present_mode: wgpu::PresentMode,
+    // File the config was read from and settings are saved to
+    pub path: String,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 1280,
+            height: 720,
+            title: "octree-tracer".to_string(),
+            fullscreen: false,
+            ui_scale: 1.0,
+            present_mode: wgpu::PresentMode::Fifo,
+            path: CONFIG_FILE.to_string(),
+        }
+    }
+}
+
+impl WindowConfig {
+    /// Reads `key = value` lines from the config file, then `--key value` arguments on top.
+    /// `--config <path>` picks another config file.
+    pub fn load(args: &[String]) -> Result<Self, String> {
+        let mut config = WindowConfig::default();
+
+        let path = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => Some(
+                args.get(i + 1)
+                    .ok_or("--config needs a path".to_string())?
+                    .clone(),
+            ),
+            None => None,
+        };
+        match path {
+            Some(path) => {
+                let file =
+                    std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                config.parse_file(&file)?;
+                config.path = path;
+            }
+            None => {
+                if let Ok(file) = std::fs::read_to_string(CONFIG_FILE) {
+                    config.parse_file(&file)?;
+                }
+            }
+        }
+
+        config.parse_args(args)?;
+        Ok(config)
+    }
+
+    pub fn parse_file(&mut self, file: &str) -> Result<(), String> {
+        for line in file.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(format!("Config line '{}' is not key = value", line))?;
+            self.set(key.trim(), value.trim())?;
+        }
+
+        Ok(())
+    }
+
+    /// Skips the program name and any arguments that aren't window options
+    pub fn parse_args(&mut self, args: &[String]) -> Result<(), String> {
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--fullscreen" => self.fullscreen = true,
+                "--width" | "--height" | "--title" => {
+                    let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                    self.set(&arg[2..], value)?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let parse_size = |value: &str| match value.parse::<u32>() {
+            Ok(size) if size > 0 => Ok(size),
+            _ => Err(format!("Invalid {} '{}'", key, value)),
+        };
+
+        match key {
+            "width" => self.width = parse_size(value)?,
+            "height" => self.height = parse_size(value)?,
+            "title" => self.title = value.to_string(),
+            "fullscreen" => {
+                self.fullscreen = value
+                    .parse()
+                    .map_err(|_| format!("Invalid fullscreen '{}'", value))?
+            }
+            "ui_scale" => match value.parse::<f32>() {
+                Ok(scale) if (MIN_UI_SCALE..=MAX_UI_SCALE).contains(&scale) => {
+                    self.ui_scale = scale
+                }
+                _ => return Err(format!("Invalid ui_scale '{}'", value)),
+            },
+            "present_mode" => {
+                self.present_mode = match value.to_lowercase().as_str() {
+                    "fifo" => wgpu::PresentMode::Fifo,
+                    "immediate" => wgpu::PresentMode::Immediate,
+                    "mailbox" => wgpu::PresentMode::Mailbox,
+                    _ => return Err(format!("Invalid present_mode '{}'", value)),
+                }
+            }
+            _ => return Err(format!("Unknown config key '{}'", key)),
+        }
+
+        Ok(())
+    }
+
+    /// Writes `key = value` into the config file, replacing the line for `key` if there is one
+    /// and keeping everything else
+    pub fn save_value(&self, key: &str, value: &str) -> Result<(), String> {
+        let file = std::fs::read_to_string(&self.path).unwrap_or_default();
+        std::fs::write(&self.path, set_config_line(&file, key, value))
+            .map_err(|e| format!("{}: {}", self.path, e))
+    }
+
+    pub fn window_builder(&self) -> WindowBuilder {
+        let mut builder = WindowBuilder::new()
+            .with_title(&self.title)
+            .with_inner_size(winit::dpi::PhysicalSize::new(self.width, self.height));
+        if self.fullscreen {
+            builder = builder.with_fullscreen(Some(winit::window::Fullscreen::Borderless(None)));
+        }
+
+        builder
+    }
+}
+
+/// `file` with the line setting `key` changed to `value`, or the line added at the end
+pub fn set_config_line(file: &str, key: &str, value: &str) -> String {
+    let line = format!("{} = {}", key, value);
+    let mut found = false;
+    let mut lines: Vec<String> = file
+        .lines()
+        .map(|l| {
+            let setting = l.split('#').next().unwrap();
+            match setting.split_once('=') {
+                Some((k, _)) if k.trim() == key && !found => {
+                    found = true;
+                    line.clone()
+                }
+                _ => l.to_string(),
+            }
+        })
+        .collect();
+    if !found {
+        lines.push(line);
+    }
+
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ui_scale_is_read_from_the_file() {
+        let mut config = WindowConfig::default();
+        config
+            .parse_file("width = 800\nui_scale = 1.5 # big\n")
+            .unwrap();
+        assert_eq!(config.ui_scale, 1.5);
+        assert!(config.parse_file("ui_scale = 10").is_err());
+    }
+
+    #[test]
+    fn present_mode_round_trips_through_the_file() {
+        let mut config = WindowConfig::default();
+        let file = set_config_line(
+            "",
+            "present_mode",
+            &format!("{:?}", wgpu::PresentMode::Mailbox),
+        );
+        config.parse_file(&file).unwrap();
+        assert_eq!(config.present_mode, wgpu::PresentMode::Mailbox);
+        assert!(config.parse_file("present_mode = vsync").is_err());
+    }
+
+    #[test]
+    fn saving_replaces_only_the_setting() {
+        let file = "# window\nwidth = 800\nui_scale = 1.5\n";
+        assert_eq!(
+            set_config_line(file, "ui_scale", "2"),
+            "# window\nwidth = 800\nui_scale = 2\n"
+        );
+        assert_eq!(set_config_line("", "ui_scale", "2"), "ui_scale = 2\n");
+
+        let mut config = WindowConfig::default();
+        config
+            .parse_file(&set_config_line(file, "ui_scale", "2"))
+            .unwrap();
+        assert_eq!((config.width, config.ui_scale), (800, 2.0));
+    }
+
+    #[test]
+    fn window_size_and_title_from_file_and_arguments() {
+        let mut config = WindowConfig::default();
+        config
+            .parse_file("width = 800 # comment\n\n  title = Voxels  \nfullscreen = false\n")
+            .unwrap();
+        let args: Vec<String> = [
+            "octree-tracer",
+            "--height",
+            "600",
+            "--fullscreen",
+            "model.vox",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        config.parse_args(&args).unwrap();
+
+        assert_eq!((config.width, config.height), (800, 600));
+        assert_eq!(config.title, "Voxels");
+        assert!(config.fullscreen);
+    }
+
+    #[test]
+    fn bad_config_lines_error() {
+        let mut config = WindowConfig::default();
+        assert!(config.parse_file("width = 0").is_err());
+        assert!(config.parse_file("width").is_err());
+        assert!(config.parse_file("colour = red").is_err());
+        assert!(config
+            .parse_args(&["x".to_string(), "--width".to_string()])
+            .is_err());
+        assert_eq!(config, WindowConfig::default());
+    }
+}
@@ -1,6 +1,15 @@
 use super::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 pub const CHUNK_OFFSET: u32 = 2147483648;
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// Largest dense volume export_volume will write
+pub const MAX_VOLUME_BYTES: usize = 256 * 1024 * 1024;
+// Largest a gzipped model file may inflate to, stops a small bomb using all the memory
+pub const MAX_INFLATED_BYTES: u64 = 1024 * 1024 * 1024;
+// Voxels from_voxel_iter sorts and inserts at a time
+const INSERT_BATCH_SIZE: usize = 1 << 16;
 
 #[derive(Copy, Clone)]
 pub struct Node {
@@ -12,11 +21,72 @@ impl Node {
     pub fn new(pointer: u32, value: Voxel) -> Self {
         Node { value, pointer }
     }
+
+    /// Plain leaf without colour, block references always count as filled
+    pub fn is_empty(&self) -> bool {
+        self.pointer == CHUNK_OFFSET && self.value == Voxel::new(0, 0, 0)
+    }
+}
+
+/// How `CpuOctree::merge` combines voxels where both models are filled
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Composite {
+    // Other model wins
+    Replace,
+    // Other model drawn over this one with the given opacity
+    Over(f32),
+    // Colours summed and clamped
+    Add,
+}
+
+impl Default for Composite {
+    fn default() -> Self {
+        Composite::Replace
+    }
+}
+
+impl Composite {
+    pub fn apply(&self, base: Voxel, top: Voxel) -> Voxel {
+        match *self {
+            Composite::Replace => top,
+            Composite::Over(opacity) => {
+                let opacity = opacity.clamp(0.0, 1.0);
+                let mix = |b: u8, t: u8| (t as f32 * opacity + b as f32 * (1.0 - opacity)) as u8;
+                Voxel::new(mix(base.r, top.r), mix(base.g, top.g), mix(base.b, top.b))
+            }
+            Composite::Add => Voxel::new(
+                base.r.saturating_add(top.r),
+                base.g.saturating_add(top.g),
+                base.b.saturating_add(top.b),
+            ),
+        }
+    }
+}
+
+/// Set operation `CpuOctree::csg` applies to the filled space of two models
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Csg {
+    Union,
+    Intersect,
+    Difference,
+}
+
+impl Default for Csg {
+    fn default() -> Self {
+        Csg::Union
+    }
 }
 
+#[derive(Clone)]
 pub struct CpuOctree {
     pub nodes: Vec<Node>,
     pub top_mip: Voxel,
+    // Non diffuse materials by leaf colour
+    pub materials: HashMap<Voxel, Material>,
+    // Colours of the file it was loaded from as rgba8, empty for formats without one
+    pub file_palette: Vec<u32>,
+    // First children of blocks edits have freed, reused before the buffer grows
+    pub hole_stack: Vec<usize>,
 }
 
 impl CpuOctree {
@@ -24,6 +94,9 @@ impl CpuOctree {
         let mut octree = Self {
             top_mip: Voxel::new(50, 255, 50),
             nodes: Vec::new(),
+            materials: HashMap::new(),
+            file_palette: Vec::new(),
+            hole_stack: Vec::new(),
         };
         octree.add_voxels(mask);
         octree
@@ -66,7 +139,7 @@ impl CpuOctree {
             node_pos += Octree::pos_offset(child_index, depth);
 
             if self.nodes[node_index + child_index].pointer >= CHUNK_OFFSET
-                || depth == max_depth.unwrap_or(u32::MAX)
+                || depth == max_depth.unwrap_or(MAX_DEPTH).min(MAX_DEPTH)
             {
                 return (node_index + child_index, depth, node_pos);
             }
@@ -75,6 +148,23 @@ impl CpuOctree {
         }
     }
 
+    /// Deepest level actually present, chunk references count as leaves
+    pub fn depth(&self) -> u32 {
+        let mut max_depth = 0;
+        let mut stack = vec![(0, 1)];
+        while let Some((index, depth)) = stack.pop() {
+            max_depth = max_depth.max(depth);
+            for i in 0..8 {
+                let pointer = self.nodes[index + i].pointer;
+                if pointer < CHUNK_OFFSET && depth < MAX_DEPTH {
+                    stack.push((pointer as usize, depth + 1));
+                }
+            }
+        }
+
+        max_depth
+    }
+
     /// Takes a pointer to the first child NOT to the parent
     pub fn get_node_mask(&self, node: usize) -> [Voxel; 8] {
         let mut mask = [Voxel::new(0, 0, 0); 8];
@@ -98,71 +188,438 @@ impl CpuOctree {
     }
 
     pub fn put_in_voxel(&mut self, pos: Vector3<f32>, voxel: Voxel, depth: u32) {
+        self.set_voxel(pos, voxel.to_cpu_value(), depth);
+    }
+
+    /// Sets the cell at `depth` containing `pos` to the 0xRRGGBB `value`, splitting leaves on
+    /// the way down and replacing anything below it. Returns whether the tree changed, false
+    /// when `pos` is outside the octree or `depth` is past `MAX_PRECISE_DEPTH`.
+    pub fn set_voxel(&mut self, pos: Vector3<f32>, value: u32, depth: u32) -> bool {
+        if !in_bounds(pos) || depth == 0 || depth > MAX_PRECISE_DEPTH {
+            return false;
+        }
+
+        let voxel = Voxel::from_value(value);
         loop {
-            let (node, node_depth, _) = self.find_voxel(pos, None);
-            if depth == node_depth {
+            let (node, node_depth, _) = self.find_voxel(pos, Some(depth));
+            if node_depth == depth {
+                let old = self.nodes[node];
+                if old.pointer == CHUNK_OFFSET && old.value == voxel {
+                    return false;
+                }
+
+                self.free_children(node);
                 self.nodes[node] = Node::new(CHUNK_OFFSET, voxel);
-                return;
-            } else {
-                self.nodes[node].pointer = self.nodes.len() as u32;
-                self.add_voxels(0);
+                return true;
             }
+
+            let leaf = self.nodes[node];
+            if leaf.pointer == CHUNK_OFFSET && leaf.value == voxel {
+                return false;
+            }
+            self.split_leaf(node);
         }
     }
 
-    pub fn load_file(file: String, octree_depth: u32) -> Result<CpuOctree, String> {
-        let path = std::path::Path::new(&file);
-        let data = std::fs::read(path).map_err(|e| e.to_string())?;
-        use std::ffi::OsStr;
-        let octree = match path.extension().and_then(OsStr::to_str) {
-            Some("rsvo") => CpuOctree::load_octree(&data, octree_depth)?,
-            Some("vox") => CpuOctree::load_vox(&data)?,
-            _ => return Err("Unknown file type".to_string()),
+    /// Gives a leaf 8 children with its colour, block references split into empty children.
+    /// Returns the first child.
+    fn split_leaf(&mut self, node: usize) -> usize {
+        let leaf = self.nodes[node];
+        let child = if leaf.pointer == CHUNK_OFFSET {
+            leaf
+        } else {
+            Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0))
         };
 
-        // println!("SVO size: {}", octree.nodes.len());
-        return Ok(octree);
+        let children = match self.hole_stack.pop() {
+            Some(hole) => {
+                self.nodes[hole..hole + 8].fill(child);
+                hole
+            }
+            None => {
+                self.nodes.extend_from_slice(&[child; 8]);
+                self.nodes.len() - 8
+            }
+        };
+        self.nodes[node].pointer = children as u32;
+        children
     }
 
-    // Models from https://github.com/ephtracy/voxel-model/tree/master/svo
-    fn load_octree(data: &[u8], octree_depth: u32) -> Result<CpuOctree, String> {
-        let top_level_start = 16;
-        let node_count_start = 20;
+    /// Turns `node` into an empty leaf, putting every block of children under it on the hole
+    /// stack
+    fn free_children(&mut self, node: usize) {
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            let children = self.nodes[node].pointer;
+            self.nodes[node] = Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0));
+            if children < CHUNK_OFFSET {
+                let children = children as usize;
+                stack.extend(children..children + 8);
+                self.hole_stack.push(children);
+            }
+        }
+    }
+
+    /// Empties the cell at `depth` containing `pos`, then collapses parents whose children
+    /// are all empty back into empty leaves, freeing the children for later splits.
+    /// Returns whether the tree changed.
+    pub fn remove_voxel(&mut self, pos: Vector3<f32>, depth: u32) -> bool {
+        if !self.set_voxel(pos, 0, depth) {
+            return false;
+        }
+
+        for parent_depth in (1..depth).rev() {
+            let (parent, node_depth, _) = self.find_voxel(pos, Some(parent_depth));
+            let children = self.nodes[parent].pointer;
+            if node_depth != parent_depth || children >= CHUNK_OFFSET {
+                break;
+            }
+
+            let children = children as usize;
+            if !(0..8).all(|i| self.nodes[children + i].is_empty()) {
+                break;
+            }
+            self.free_children(parent);
+        }
+
+        true
+    }
+
+    /// Same result as calling `set_voxel` on each `(position, 0xRRGGBB)` pair in order, but
+    /// sorted into depth first order so each voxel only walks down from where it leaves the
+    /// previous one's path
+    pub fn insert_batch(&mut self, voxels: &[(Vector3<f32>, u32)], depth: u32) {
+        // Keys need 3 bits a level
+        if depth == 0 || depth > 21 {
+            for &(pos, value) in voxels {
+                self.set_voxel(pos, value, depth);
+            }
+            return;
+        }
+
+        let mut keyed: Vec<(u64, Voxel)> = voxels
+            .iter()
+            .filter(|(pos, _)| in_bounds(*pos))
+            .map(|&(pos, value)| (Octree::path_to_morton(pos, depth), Voxel::from_value(value)))
+            .collect();
+        // Stable so repeated cells still end with the last value
+        keyed.sort_by_key(|(key, _)| *key);
+
+        // Block of children for each level along the last voxel's path
+        let mut path = vec![0];
+        let mut last_key = None;
+        for (key, voxel) in keyed {
+            if let Some(last_key) = last_key {
+                let diff: u64 = key ^ last_key;
+                let common = if diff == 0 {
+                    depth
+                } else {
+                    depth - (63 - diff.leading_zeros()) / 3 - 1
+                };
+                path.truncate(common as usize + 1);
+            }
+            last_key = Some(key);
+
+            loop {
+                let level = path.len() as u32;
+                let child = ((key >> (3 * (depth - level))) & 7) as usize;
+                let node = path[level as usize - 1] + child;
+                if level == depth {
+                    self.free_children(node);
+                    self.nodes[node] = Node::new(CHUNK_OFFSET, voxel);
+                    break;
+                }
+
+                let leaf = self.nodes[node];
+                if leaf.pointer < CHUNK_OFFSET {
+                    path.push(leaf.pointer as usize);
+                    continue;
+                }
+                if leaf.pointer == CHUNK_OFFSET && leaf.value == voxel {
+                    break;
+                }
+
+                path.push(self.split_leaf(node));
+            }
+        }
+    }
+
+    /// Fills the box from `min` to `max` with the 0xRRGGBB `value`. Cells inside the box
+    /// become single leaves and only cells on its surface are split down to `depth`.
+    pub fn fill_box(&mut self, min: Vector3<f32>, max: Vector3<f32>, value: u32, depth: u32) {
+        self.fill_shape(Voxel::from_value(value), depth, &|centre, half| {
+            let (lo, hi) = (centre.map(|c| c - half), centre.map(|c| c + half));
+            // Cells only touching a face have nothing inside
+            if hi.x <= min.x || hi.y <= min.y || hi.z <= min.z {
+                Overlap::Outside
+            } else if lo.x >= max.x || lo.y >= max.y || lo.z >= max.z {
+                Overlap::Outside
+            } else if lo.x >= min.x
+                && lo.y >= min.y
+                && lo.z >= min.z
+                && hi.x <= max.x
+                && hi.y <= max.y
+                && hi.z <= max.z
+            {
+                Overlap::Inside
+            } else {
+                Overlap::Partial
+            }
+        });
+    }
+
+    /// Fills a solid sphere, coarse cells inside it become single leaves. A radius of zero
+    /// fills the one cell at `depth` containing `center` if it's in the octree.
+    pub fn fill_sphere(&mut self, center: Vector3<f32>, radius: f32, value: u32, depth: u32) {
+        if radius <= 0.0 {
+            self.set_voxel(center, value, depth);
+            return;
+        }
 
-        let top_level = data[top_level_start] as usize;
+        self.fill_shape(Voxel::from_value(value), depth, &|centre, half| {
+            let offset = (centre - center).map(f32::abs);
+            let near = offset.map(|o| (o - half).max(0.0)).magnitude();
+            let far = offset.map(|o| o + half).magnitude();
+            if near > radius {
+                Overlap::Outside
+            } else if far <= radius {
+                Overlap::Inside
+            } else {
+                Overlap::Partial
+            }
+        });
+    }
 
-        let data_start = node_count_start + 4 * (top_level + 1);
+    /// Fills every cell `overlap` says is inside with `voxel`, splitting partial cells until
+    /// `depth` where the cell's centre decides. `overlap` takes a cell centre and half size.
+    fn fill_shape(
+        &mut self,
+        voxel: Voxel,
+        depth: u32,
+        overlap: &dyn Fn(Vector3<f32>, f32) -> Overlap,
+    ) {
+        if depth > 0 {
+            self.fill_children(0, Vector3::zero(), 1, voxel, depth, overlap);
+        }
+    }
 
-        let mut node_counts = Vec::new();
-        for i in 0..(top_level + 1) {
-            let node_count = u32::from_be_bytes([
-                data[node_count_start + i * 4 + 3],
-                data[node_count_start + i * 4 + 2],
-                data[node_count_start + i * 4 + 1],
-                data[node_count_start + i * 4],
-            ]);
+    fn fill_children(
+        &mut self,
+        children: usize,
+        centre: Vector3<f32>,
+        level: u32,
+        voxel: Voxel,
+        depth: u32,
+        overlap: &dyn Fn(Vector3<f32>, f32) -> Overlap,
+    ) {
+        let half = 0.5f32.powi(level as i32);
+        for i in 0..8 {
+            let node = children + i;
+            let child_centre = centre + Octree::pos_offset(i, level);
+            let cell = if level == depth {
+                overlap(child_centre, 0.0)
+            } else {
+                overlap(child_centre, half)
+            };
 
-            node_counts.push(node_count);
+            match cell {
+                Overlap::Outside => {}
+                Overlap::Inside => {
+                    // Whatever was split under the cell goes back on the hole stack
+                    self.free_children(node);
+                    self.nodes[node] = Node::new(CHUNK_OFFSET, voxel);
+                }
+                Overlap::Partial if level == depth => {}
+                Overlap::Partial => {
+                    let grandchildren = match self.nodes[node].pointer {
+                        pointer if pointer < CHUNK_OFFSET => pointer as usize,
+                        _ => self.split_leaf(node),
+                    };
+                    self.fill_children(
+                        grandchildren,
+                        child_centre,
+                        level + 1,
+                        voxel,
+                        depth,
+                        overlap,
+                    );
+                }
+            }
         }
+    }
 
-        if octree_depth as usize > top_level {
+    /// Builds a white model from a dense occupancy grid indexed x + y * size + z * size^2,
+    /// the layout `Octree::to_grid` writes. A size of 1 fills or empties the whole cube
+    pub fn from_grid(grid: &[bool], size: usize) -> Result<CpuOctree, String> {
+        if !size.is_power_of_two() {
+            return Err(format!("Grid size {} is not a power of two", size));
+        }
+        if grid.len() != size * size * size {
             return Err(format!(
-                "Octree depth ({}) is greater than top level ({})",
-                octree_depth, top_level
+                "Grid has {} cells, a size of {} needs {}",
+                grid.len(),
+                size,
+                size * size * size
             ));
         }
 
-        let node_end = node_counts[0..octree_depth as usize].iter().sum::<u32>() as usize;
+        if size == 1 {
+            let mut octree = CpuOctree::new(0);
+            if grid[0] {
+                octree
+                    .nodes
+                    .fill(Node::new(CHUNK_OFFSET, Voxel::new(255, 255, 255)));
+            }
+            return Ok(octree);
+        }
+
+        let depth = size.trailing_zeros();
+        let voxels: Vec<(Vector3<f32>, u32)> = grid
+            .iter()
+            .enumerate()
+            .filter(|(_, filled)| **filled)
+            .map(|(i, _)| {
+                let cell = Vector3::new(i % size, i / size % size, i / (size * size));
+                let pos = (cell.map(|c| c as f32) + Vector3::new(0.5, 0.5, 0.5)) / size as f32
+                    * 2.0
+                    - Vector3::new(1.0, 1.0, 1.0);
+                (pos, Voxel::new(255, 255, 255).to_cpu_value())
+            })
+            .collect();
+
+        let mut octree = CpuOctree::new(0);
+        octree.insert_batch(&voxels, depth);
+        Ok(octree.collapsed())
+    }
+
+    /// Builds an octree from `(position, 0xRRGGBB)` pairs at `depth`, inserting them in
+    /// batches so huge point sets never need to be collected first. Later points overwrite
+    /// earlier.
+    pub fn from_voxel_iter<I>(voxels: I, depth: u32) -> CpuOctree
+    where
+        I: IntoIterator<Item = (Vector3<f32>, u32)>,
+    {
+        let mut octree = CpuOctree::new(0);
+        let mut batch = Vec::with_capacity(INSERT_BATCH_SIZE);
+        for voxel in voxels {
+            batch.push(voxel);
+            if batch.len() == INSERT_BATCH_SIZE {
+                octree.insert_batch(&batch, depth);
+                batch.clear();
+            }
+        }
+        octree.insert_batch(&batch, depth);
+        octree
+    }
+
+    /// Meshes come out as surfaces, see `load_file_progress` to fill them
+    pub fn load_file(file: String, octree_depth: u32) -> Result<CpuOctree, String> {
+        let (data, extension) = read_model_file(&file)?;
+        CpuOctree::load_bytes(&data, &extension, octree_depth, false)
+    }
+
+    /// `load_file` that keeps `progress` updated with the percent done, for loading on
+    /// another thread. `solid` fills the inside of meshes
+    pub fn load_file_progress(
+        file: String,
+        octree_depth: u32,
+        solid: bool,
+        progress: &AtomicU32,
+    ) -> Result<CpuOctree, String> {
+        let (data, extension) = read_model_file(&file)?;
+        let octree =
+            CpuOctree::load_bytes_progress(&data, &extension, octree_depth, solid, progress)?;
+        progress.store(100, Ordering::Relaxed);
+
+        // println!("SVO size: {}", octree.nodes.len());
+        return Ok(octree);
+    }
+
+    /// Parses file contents by extension, for data that didn't come from disk. `solid` fills
+    /// the inside of meshes
+    pub fn load_bytes(
+        data: &[u8],
+        extension: &str,
+        octree_depth: u32,
+        solid: bool,
+    ) -> Result<CpuOctree, String> {
+        CpuOctree::load_bytes_progress(data, extension, octree_depth, solid, &AtomicU32::new(0))
+    }
+
+    // Only rsvo files report progress along the way, the rest finish in one step
+    fn load_bytes_progress(
+        data: &[u8],
+        extension: &str,
+        octree_depth: u32,
+        solid: bool,
+        progress: &AtomicU32,
+    ) -> Result<CpuOctree, String> {
+        match extension {
+            "rsvo" => CpuOctree::load_octree(data, octree_depth, progress),
+            "vox" => CpuOctree::load_vox(data),
+            "qb" => CpuOctree::load_qb(data),
+            "schematic" => CpuOctree::load_schematic(data),
+            "ply" => CpuOctree::load_ply(data, octree_depth),
+            "obj" => CpuOctree::load_obj(data, octree_depth, solid),
+            "bin" => CpuOctree::load_bin(data),
+            "svot" => Ok(Octree::from_svot_bytes(data)?.to_cpu_octree()),
+            "raw" => CpuOctree::load_raw(data),
+            _ => Err("Unknown file type".to_string()),
+        }
+    }
+
+    /// Deepest `octree_depth` a file can be loaded at, for rsvo this is the top level
+    /// in the header and for vox the depth of its grid
+    pub fn file_depth(file: &str) -> Result<u32, String> {
+        let (data, extension) = read_model_file(file)?;
+        match extension.as_str() {
+            "rsvo" => Ok(rsvo_header(&data)?.0),
+            "vox" => {
+                let vox_data = dot_vox::load_bytes(&data)?;
+                let (cube, _) = vox_layout(&vox_data)?;
+                Ok(cube.trailing_zeros())
+            }
+            "qb" => Ok(qb_voxels(&data)?.0.trailing_zeros()),
+            "schematic" => Ok(schematic_voxels(&data)?.0.trailing_zeros()),
+            "bin" => Ok(CpuOctree::load_bin(&data)?.depth()),
+            "svot" => Ok(Octree::from_svot_bytes(&data)?.max_depth()),
+            "raw" => Ok(raw_size(&data)?.trailing_zeros()),
+            // Point clouds and meshes can be voxelised at any depth
+            "ply" | "obj" => Ok(MAX_PRECISE_DEPTH),
+            _ => Err("Unknown file type".to_string()),
+        }
+    }
+
+    // Models from https://github.com/ephtracy/voxel-model/tree/master/svo
+    fn load_octree(
+        data: &[u8],
+        octree_depth: u32,
+        progress: &AtomicU32,
+    ) -> Result<CpuOctree, String> {
+        let (top_level, node_counts) = rsvo_header(data)?;
+        let data_start = RSVO_NODE_COUNTS + 4 * node_counts.len();
+        let byte = |offset: usize, reading: &str| rsvo_byte(data, offset, reading);
+
+        // Deeper than the file goes loads all of it, the app clamps its slider to the depth
+        let octree_depth = octree_depth.min(top_level) as usize;
+        let node_end = node_counts[0..octree_depth]
+            .iter()
+            .map(|count| *count as usize)
+            .sum::<usize>();
 
-        let mut octree = CpuOctree::new(data[data_start]);
+        let mut octree = CpuOctree::new(byte(data_start, "the root node")?);
         let mut data_index = 1;
         let mut node_index = 0;
         while node_index < octree.nodes.len() {
             if octree.nodes[node_index].pointer > CHUNK_OFFSET {
                 if data_index < node_end {
-                    let child_mask = data[data_start + data_index];
+                    let child_mask = byte(data_start + data_index, "node masks")?;
                     octree.nodes[node_index].pointer = octree.nodes.len() as u32;
                     octree.add_voxels(child_mask);
+
+                    if data_index % 65536 == 0 {
+                        progress.store((data_index * 100 / node_end) as u32, Ordering::Relaxed);
+                    }
                 }
 
                 data_index += 1;
@@ -176,37 +633,163 @@ impl CpuOctree {
 
     fn load_vox(file: &[u8]) -> Result<CpuOctree, String> {
         let vox_data = dot_vox::load_bytes(file)?;
-        let size = vox_data.models[0].size;
-        if size.x != size.y || size.x != size.z || size.y != size.z {
-            return Err("Voxel model is not a cube!".to_string());
+        let (cube, placements) = vox_layout(&vox_data)?;
+        Ok(CpuOctree::vox_to_octree(&vox_data, cube, &placements))
+    }
+
+    /// Qubicle binary, every matrix placed at its position and centred together in one cube
+    fn load_qb(data: &[u8]) -> Result<CpuOctree, String> {
+        let (cube, voxels) = qb_voxels(data)?;
+        Ok(CpuOctree::from_cells(cube, voxels))
+    }
+
+    fn load_schematic(data: &[u8]) -> Result<CpuOctree, String> {
+        let (cube, voxels) = schematic_voxels(data)?;
+        Ok(CpuOctree::from_cells(cube, voxels))
+    }
+
+    /// Point cloud with one voxel per point at `octree_depth`, its bounding box scaled to fill
+    /// the cube. Points without a colour are white.
+    fn load_ply(data: &[u8], octree_depth: u32) -> Result<CpuOctree, String> {
+        let points = ply_points(data)?;
+        if points.is_empty() {
+            return Err("PLY file has no points".to_string());
+        }
+        let depth = octree_depth.min(MAX_PRECISE_DEPTH);
+
+        let (centre, scale) = fit_to_cube(points.iter().map(|(pos, _)| *pos));
+        // Points on the far faces go in the last cell instead of out of bounds
+        let edge = 1.0 - 0.5f32.powi(depth as i32);
+
+        let voxels: Vec<(Vector3<f32>, u32)> = points
+            .into_iter()
+            .map(|(pos, colour)| {
+                let pos = ((pos - centre) * scale).map(|v| v.clamp(-1.0, edge));
+                (pos, colour.unwrap_or(0xFFFFFF))
+            })
+            .collect();
+
+        let mut octree = CpuOctree::new(0);
+        octree.insert_batch(&voxels, depth);
+        Ok(octree)
+    }
+
+    /// Surface voxelises a triangle mesh at `octree_depth` with its bounding box scaled to
+    /// fill the cube, `solid` also fills the enclosed cells. Meshes are white.
+    pub fn load_obj(data: &[u8], octree_depth: u32, solid: bool) -> Result<CpuOctree, String> {
+        let triangles = obj_triangles(data)?;
+        let depth = octree_depth.min(MAX_PRECISE_DEPTH);
+        // Checked before voxelising the surface, which is slow this deep
+        if solid && depth > OBJ_SOLID_MAX_DEPTH {
+            return Err(format!(
+                "Solid fills are limited to depth {}",
+                OBJ_SOLID_MAX_DEPTH
+            ));
         }
 
-        let size = size.x as i32;
+        let (centre, scale) = fit_to_cube(triangles.iter().flatten().copied());
+        let triangles: Vec<[Vector3<f32>; 3]> = triangles
+            .into_iter()
+            .map(|triangle| triangle.map(|p| (p - centre) * scale))
+            .collect();
 
-        let depth = (size as f32).log2();
-        if depth != depth.floor() {
-            return Err("Voxel model size is not a power of 2!".to_string());
+        let mut cells = voxelise_triangles(&triangles, depth);
+        if solid {
+            cells.extend(fill_interior(&cells, depth)?);
         }
 
+        let white = Voxel::new(255, 255, 255).to_cpu_value();
+        let voxels: Vec<(Vector3<f32>, u32)> = cells.into_iter().map(|pos| (pos, white)).collect();
         let mut octree = CpuOctree::new(0);
-        for voxel in &vox_data.models[0].voxels {
-            let colour = vox_data.palette[voxel.i as usize].to_le_bytes();
-            let mut pos = Vector3::new(
-                size as f32 - voxel.x as f32 - 1.0,
-                voxel.z as f32,
-                voxel.y as f32,
-            );
-            pos /= size as f32;
-            pos = pos * 2.0 - Vector3::new(1.0, 1.0, 1.0);
+        octree.insert_batch(&voxels, depth);
+        Ok(octree)
+    }
 
-            octree.put_in_voxel(
-                pos,
-                Voxel::new(colour[0], colour[1], colour[2]),
-                depth as u32,
-            );
+    /// Builds a `cube` sized model from .vox style cells and 0xRRGGBB colours
+    fn from_cells(cube: u32, cells: Vec<(Vector3<i32>, u32)>) -> CpuOctree {
+        let depth = cube.trailing_zeros();
+        let voxels: Vec<(Vector3<f32>, u32)> = cells
+            .into_iter()
+            .map(|(cell, colour)| (grid_to_normalized(cell, depth), colour))
+            .collect();
+
+        let mut octree = CpuOctree::new(0);
+        octree.insert_batch(&voxels, depth);
+        octree
+    }
+
+    /// Loads every .vox file in a directory sorted by name, padded to a common cube
+    pub fn load_frames(dir: &str) -> Result<Vec<CpuOctree>, String> {
+        use std::ffi::OsStr;
+        let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(dir)
+            .map_err(|e| e.to_string())?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().and_then(OsStr::to_str) == Some("vox"))
+            .collect();
+        paths.sort();
+
+        if paths.is_empty() {
+            return Err("No .vox files in directory".to_string());
         }
 
-        return Ok(octree);
+        let mut frames = Vec::new();
+        for path in &paths {
+            let data = std::fs::read(path).map_err(|e| e.to_string())?;
+            let vox_data =
+                dot_vox::load_bytes(&data).map_err(|e| format!("{}: {}", path.display(), e))?;
+            if vox_data.models.is_empty() {
+                return Err(format!("{}: no models", path.display()));
+            }
+            frames.push(vox_data);
+        }
+
+        let size = frames
+            .iter()
+            .map(|vox_data| {
+                let size = vox_data.models[0].size;
+                size.x.max(size.y).max(size.z)
+            })
+            .max()
+            .unwrap()
+            .max(1)
+            .next_power_of_two();
+
+        Ok(frames
+            .iter()
+            .map(|vox_data| CpuOctree::vox_to_octree(vox_data, size, &[(0, Vector3::zero())]))
+            .collect())
+    }
+
+    /// Voxelizes models into a cube of `size` (a power of 2), each placement being a model
+    /// and where its first cell goes in the cube. Later placements win where they overlap.
+    fn vox_to_octree(
+        vox_data: &dot_vox::DotVoxData,
+        size: u32,
+        placements: &[(usize, Vector3<i32>)],
+    ) -> CpuOctree {
+        let depth = (size as f32).log2() as u32;
+
+        let voxels = placements.iter().flat_map(|&(model, offset)| {
+            vox_data.models[model].voxels.iter().map(move |voxel| {
+                let colour = vox_data
+                    .palette
+                    .get(voxel.i as usize)
+                    .unwrap_or(&u32::MAX)
+                    .to_le_bytes();
+                let cell = Vector3::new(voxel.x as i32, voxel.y as i32, voxel.z as i32) + offset;
+
+                (
+                    grid_to_normalized(cell, depth),
+                    Voxel::new(colour[0], colour[1], colour[2]).to_cpu_value(),
+                )
+            })
+        });
+
+        let mut octree = CpuOctree::from_voxel_iter(voxels, depth);
+        octree.materials = vox_materials(vox_data);
+        octree.file_palette = vox_data.palette.clone();
+
+        octree
     }
 
     #[allow(dead_code)]
@@ -229,83 +812,1820 @@ impl CpuOctree {
         return voxels;
     }
 
-    #[allow(dead_code)]
-    pub fn to_octree(&self) -> Octree {
-        let mut octree = Octree {
-            nodes: Vec::new(),
-            positions: Vec::new(),
-            hole_stack: Vec::new(),
-        };
+    pub fn to_octree(&self) -> Result<Octree, String> {
+        let mut nodes = Vec::new();
 
         for i in 0..self.nodes.len() {
             let node = self.nodes[i];
             if node.pointer < CHUNK_OFFSET {
-                octree
-                    .nodes
-                    .push(octree::create_node(node.pointer as usize));
+                nodes.push(octree::create_node(node.pointer as usize));
             } else {
-                octree.nodes.push(node.value.to_value());
+                nodes.push(node.value.to_value());
             }
         }
 
-        octree
+        let mut octree = Octree::from_nodes(nodes)?;
+        octree.palette = self.file_palette.clone();
+        Ok(octree)
     }
 
-    pub fn raw(&self) -> Vec<u32> {
-        let mut raw = Vec::new();
+    /// Colours the bytes of `to_volume` index, byte 1 being the first. The file's palette
+    /// when there is one, otherwise the leaf colours in the order they're stored, up to 255
+    pub fn volume_palette(&self) -> Vec<Voxel> {
+        if !self.file_palette.is_empty() {
+            return self
+                .file_palette
+                .iter()
+                .take(255)
+                .map(|colour| {
+                    let [r, g, b, _] = colour.to_le_bytes();
+                    Voxel::new(r, g, b)
+                })
+                .collect();
+        }
+
+        let mut palette = Vec::new();
         for node in &self.nodes {
-            raw.push(node.pointer);
+            if node.pointer >= CHUNK_OFFSET && !node.is_empty() && !palette.contains(&node.value) {
+                palette.push(node.value);
+                if palette.len() == 255 {
+                    break;
+                }
+            }
         }
-        raw
+        palette
     }
 
-    pub unsafe fn bin(&self) -> &[u8] {
-        reinterpret::reinterpret_slice(&self.nodes)
-    }
+    /// Rasterizes the octree into a dense `2^depth` cubed grid of `volume_palette` indices
+    /// with 0 as empty, indexed x + y * size + z * size^2. Leaves above `depth` fill their
+    /// whole cell and colours missing from the palette use the closest entry.
+    pub fn to_volume(&self, depth: u32) -> Result<Vec<u8>, String> {
+        let size = 1usize
+            .checked_shl(depth)
+            .ok_or(format!("Volume depth {} is too large", depth))?;
+        let bytes = size
+            .checked_pow(3)
+            .filter(|bytes| *bytes <= MAX_VOLUME_BYTES)
+            .ok_or(format!(
+                "Volume of {0}x{0}x{0} is larger than {1} MiB",
+                size,
+                MAX_VOLUME_BYTES / (1024 * 1024)
+            ))?;
 
-    pub unsafe fn from_bin(bin: Vec<u8>) -> CpuOctree {
-        let nodes = reinterpret::reinterpret_vec(bin);
-        CpuOctree {
-            nodes,
-            top_mip: Voxel::new(0, 0, 0),
+        let mut volume = vec![0u8; bytes];
+
+        let palette = self.volume_palette();
+        let mut indices: HashMap<Voxel, u8> = HashMap::new();
+        for (i, colour) in palette.iter().enumerate() {
+            indices.entry(*colour).or_insert(i as u8 + 1);
+        }
+        let mut index_of = |v: Voxel| {
+            *indices.entry(v).or_insert_with(|| {
+                let distance = |p: &Voxel| {
+                    [(p.r, v.r), (p.g, v.g), (p.b, v.b)]
+                        .iter()
+                        .map(|&(a, b)| (a as i32 - b as i32).pow(2))
+                        .sum::<i32>()
+                };
+                (0..palette.len())
+                    .min_by_key(|&i| distance(&palette[i]))
+                    .map_or(1, |i| i as u8 + 1)
+            })
+        };
+
+        // (first child, depth of children, corner of parent in voxels)
+        let mut stack = vec![(0, 1, [0usize; 3])];
+        while let Some((children, node_depth, corner)) = stack.pop() {
+            let cell = size >> node_depth.min(depth);
+            for i in 0..8 {
+                let node = self.nodes[children + i];
+                let corner = [
+                    corner[0] + ((i >> 2) & 1) * cell,
+                    corner[1] + ((i >> 1) & 1) * cell,
+                    corner[2] + (i & 1) * cell,
+                ];
+
+                if node.pointer < CHUNK_OFFSET && node_depth < depth {
+                    stack.push((node.pointer as usize, node_depth + 1, corner));
+                } else if !node.is_empty() {
+                    let value = index_of(node.value);
+                    for z in corner[2]..corner[2] + cell {
+                        for y in corner[1]..corner[1] + cell {
+                            let row = y * size + z * size * size;
+                            volume[row + corner[0]..row + corner[0] + cell].fill(value);
+                        }
+                    }
+                }
+            }
         }
+
+        Ok(volume)
     }
-}
 
-impl std::fmt::Debug for Node {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let voxel = self.value;
-        if self.pointer < CHUNK_OFFSET {
-            write!(
-                f,
-                "{:25} Pointer: {}",
-                format!("  Voxel: ({}, {}, {})", voxel.r, voxel.g, voxel.b),
-                self.pointer
-            )
+    /// Writes `to_volume` as a .nrrd, or as headerless .raw with a .nhdr sidecar for
+    /// any other extension. The palette goes in the header as hex colours
+    pub fn export_volume(&self, path: &str, depth: u32) -> Result<(), String> {
+        let volume = self.to_volume(depth)?;
+        let size = 1usize << depth;
+        let palette: Vec<String> = self
+            .volume_palette()
+            .iter()
+            .map(|v| format!("#{:02x}{:02x}{:02x}", v.r, v.g, v.b))
+            .collect();
+
+        let path = std::path::Path::new(path);
+        let header = |data_file: Option<&str>| {
+            let mut header = format!(
+                "NRRD0004\ntype: uint8\ndimension: 3\nsizes: {0} {0} {0}\nencoding: raw\n",
+                size
+            );
+            header += "content: palette index, 0 is empty\n";
+            header += &format!("palette:={}\n", palette.join(" "));
+            if let Some(data_file) = data_file {
+                header += &format!("data file: {}\n", data_file);
+            }
+            header
+        };
+
+        if path.extension().and_then(std::ffi::OsStr::to_str) == Some("nrrd") {
+            let mut data = (header(None) + "\n").into_bytes();
+            data.extend_from_slice(&volume);
+            std::fs::write(path, data).map_err(|e| e.to_string())
         } else {
-            write!(
-                f,
-                "{:25} Pointer: BlockID: {}",
-                format!("  Voxel: ({}, {}, {})", voxel.r, voxel.g, voxel.b),
-                self.pointer - CHUNK_OFFSET
-            )
+            std::fs::write(path, &volume).map_err(|e| e.to_string())?;
+
+            let data_file = path.file_name().unwrap().to_string_lossy();
+            std::fs::write(path.with_extension("nhdr"), header(Some(&data_file)))
+                .map_err(|e| e.to_string())
         }
     }
-}
 
-impl std::fmt::Debug for CpuOctree {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Nodes ({}):\n", self.nodes.len())?;
-        let mut c = 0;
-        for value in &self.nodes {
-            write!(f, "{:?}\n", *value)?;
+    /// Recolours every leaf connected to the one at `pos` through faces that has the
+    /// same colour. Faces are sampled down to `max_depth` so mixed size leaves connect.
+    /// Returns the indices of the recoloured nodes.
+    pub fn flood_fill(&mut self, pos: Vector3<f32>, colour: Voxel, max_depth: u32) -> Vec<usize> {
+        let (start, _, _) = self.find_voxel(pos, None);
+        let target = self.nodes[start];
+        if target.is_empty() || target.value == colour {
+            return Vec::new();
+        }
 
-            c += 1;
-            if c % 8 == 0 {
-                write!(f, "\n")?;
+        let mut visited = std::collections::HashSet::new();
+        let mut queue = std::collections::VecDeque::new();
+        visited.insert(start);
+        queue.push_back(pos);
+
+        while let Some(pos) = queue.pop_front() {
+            let (_, depth, centre) = self.find_voxel(pos, None);
+            let half = 0.5f32.powi(depth as i32);
+
+            // Sample points just past each face, one per cell at max_depth
+            let samples = 1usize << max_depth.saturating_sub(depth).min(6);
+            let cell = 2.0 * half / samples as f32;
+            for axis in 0..3 {
+                for side in [-1.0, 1.0] {
+                    for u in 0..samples {
+                        for v in 0..samples {
+                            let mut offset = Vector3::zero();
+                            offset[axis] = side * (half + cell / 2.0);
+                            offset[(axis + 1) % 3] = -half + (u as f32 + 0.5) * cell;
+                            offset[(axis + 2) % 3] = -half + (v as f32 + 0.5) * cell;
+
+                            let sample = centre + offset;
+                            if sample.x.abs() >= 1.0
+                                || sample.y.abs() >= 1.0
+                                || sample.z.abs() >= 1.0
+                            {
+                                continue;
+                            }
+
+                            let (index, _, _) = self.find_voxel(sample, None);
+                            let node = self.nodes[index];
+                            if node.pointer >= CHUNK_OFFSET
+                                && node.value == target.value
+                                && visited.insert(index)
+                            {
+                                queue.push_back(sample);
+                            }
+                        }
+                    }
+                }
             }
         }
 
-        Ok(())
+        let mut dirty: Vec<usize> = visited.into_iter().collect();
+        dirty.sort_unstable();
+        for index in &dirty {
+            self.nodes[*index].value = colour;
+        }
+
+        dirty
+    }
+
+    /// Lays the models out left to right, top to bottom in `columns` columns on the middle
+    /// layer of the cube. Returns the combined octree and the centre of each model's cell.
+    pub fn gallery(models: &[CpuOctree], columns: u32) -> (CpuOctree, Vec<Vector3<f32>>) {
+        let (depth, cells) = gallery_cells(models.len(), columns);
+        if depth == 0 {
+            return (models[0].clone(), cells);
+        }
+
+        let mut octree = CpuOctree::new(0);
+        for (model, cell) in models.iter().zip(&cells) {
+            octree.insert_subtree(model, *cell, depth);
+        }
+
+        (octree, cells)
+    }
+
+    /// Places a copy of `other` as the node at `depth` containing `pos`, splitting leaves on
+    /// the way down. The node must not already be inside a subtree deeper than `depth`.
+    pub fn insert_subtree(&mut self, other: &CpuOctree, pos: Vector3<f32>, depth: u32) {
+        loop {
+            let (node, node_depth, _) = self.find_voxel(pos, Some(depth));
+            if depth == node_depth {
+                let offset = self.nodes.len() as u32;
+                self.nodes.extend(other.nodes.iter().map(|n| {
+                    if n.pointer < CHUNK_OFFSET {
+                        Node::new(n.pointer + offset, n.value)
+                    } else {
+                        *n
+                    }
+                }));
+                self.nodes[node] = Node::new(offset, other.top_mip);
+                self.materials.extend(&other.materials);
+                return;
+            } else {
+                self.nodes[node].pointer = self.nodes.len() as u32;
+                self.add_voxels(0);
+            }
+        }
+    }
+
+    /// Distinct colours of the filled leaves
+    pub fn palette(&self) -> std::collections::HashSet<Voxel> {
+        self.nodes
+            .iter()
+            .filter(|node| node.pointer >= CHUNK_OFFSET && !node.is_empty())
+            .map(|node| node.value)
+            .collect()
+    }
+
+    /// Overlays `other` onto this octree, blending overlapping voxels with `composite`
+    pub fn merge(&self, other: &CpuOctree, composite: Composite) -> CpuOctree {
+        self.combine(other, |a, b| match (a, b) {
+            (Some(a), Some(b)) => Some(composite.apply(a, b)),
+            (a, b) => b.or(a),
+        })
+    }
+
+    /// `union`, `intersect` or `difference` with `other`
+    pub fn csg(&self, other: &CpuOctree, op: Csg) -> CpuOctree {
+        match op {
+            Csg::Union => self.union(other),
+            Csg::Intersect => self.intersect(other),
+            Csg::Difference => self.difference(other),
+        }
+    }
+
+    /// Filled wherever either octree is, keeping this octree's colour where both are
+    pub fn union(&self, other: &CpuOctree) -> CpuOctree {
+        self.combine(other, |a, b| a.or(b))
+    }
+
+    /// Filled where both octrees are, with this octree's colour
+    pub fn intersect(&self, other: &CpuOctree) -> CpuOctree {
+        self.combine(other, |a, b| b.and(a))
+    }
+
+    /// This octree with everywhere `other` is filled cut out
+    pub fn difference(&self, other: &CpuOctree) -> CpuOctree {
+        self.combine(other, |a, b| if b.is_some() { None } else { a })
+    }
+
+    /// Walks both octrees together and builds a new one where every leaf is `f` of the
+    /// two leaves covering it, `None` being empty. Leaves are split to match the other side
+    /// and parents left with only empty children become empty leaves.
+    pub fn combine<F>(&self, other: &CpuOctree, f: F) -> CpuOctree
+    where
+        F: Fn(Option<Voxel>, Option<Voxel>) -> Option<Voxel>,
+    {
+        let mut octree = CpuOctree {
+            nodes: Vec::new(),
+            top_mip: f(Some(self.top_mip), Some(other.top_mip)).unwrap_or(self.top_mip),
+            materials: self.materials.clone(),
+            file_palette: self.file_palette.clone(),
+            hole_stack: Vec::new(),
+        };
+        octree.materials.extend(&other.materials);
+        octree.add_voxels(0);
+        octree.combine_children(
+            0,
+            Side::Children(self, 0, self.top_mip),
+            Side::Children(other, 0, other.top_mip),
+            &f,
+        );
+        octree
+    }
+
+    fn combine_children<F>(&mut self, index: usize, a: Side, b: Side, f: &F)
+    where
+        F: Fn(Option<Voxel>, Option<Voxel>) -> Option<Voxel>,
+    {
+        for i in 0..8 {
+            let (a, b) = (a.child(i), b.child(i));
+            let value = f(a.value(), b.value());
+
+            // Block references can't be split, they're kept whole when their side wins and
+            // otherwise only their colour goes down to meet the other side's children
+            let (a, b) = match (a.block(), b.block()) {
+                (Some(block), _) | (_, Some(block)) if value == Some(block.value) => {
+                    self.nodes[index + i] = block;
+                    continue;
+                }
+                _ => (a.unblocked(), b.unblocked()),
+            };
+
+            self.nodes[index + i] = match (a, b) {
+                (Side::Leaf(_), Side::Leaf(_)) => {
+                    Node::new(CHUNK_OFFSET, value.unwrap_or(Voxel::new(0, 0, 0)))
+                }
+                (a, b) => {
+                    let children = self.nodes.len();
+                    self.add_voxels(0);
+                    self.combine_children(children, a, b, f);
+
+                    // Everything after the children is below them so it can go too
+                    if (0..8).all(|i| self.nodes[children + i].is_empty()) {
+                        self.nodes.truncate(children);
+                        Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0))
+                    } else {
+                        Node::new(children as u32, value.unwrap_or(Voxel::new(0, 0, 0)))
+                    }
+                }
+            };
+        }
+    }
+
+    /// Copy with every subtree below `max_depth` collapsed into one leaf coloured with the
+    /// average of its filled leaves. The root block is always kept, at `max_depth` 0 the
+    /// whole tree is one leaf so its 8 children are all the same.
+    pub fn downsample(&self, max_depth: u32, policy: Downsample) -> CpuOctree {
+        let mut octree = CpuOctree {
+            nodes: Vec::new(),
+            top_mip: self.top_mip,
+            materials: self.materials.clone(),
+            file_palette: self.file_palette.clone(),
+            hole_stack: Vec::new(),
+        };
+        octree.add_voxels(0);
+        if max_depth == 0 {
+            let leaf = self.downsampled_leaf(0, policy);
+            octree.nodes.fill(leaf);
+        } else {
+            octree.downsample_children(self, 0, 0, 1, max_depth, policy);
+        }
+        octree
+    }
+
+    /// Leaf standing in for the block of children at `children`, empty when `policy`
+    /// doesn't keep it
+    fn downsampled_leaf(&self, children: usize, policy: Downsample) -> Node {
+        let (volume, colour, filled) = self.coverage(children);
+        let keep = match policy {
+            Downsample::Any => filled > 0,
+            Downsample::Majority => volume > 0.5,
+        };
+        if !keep {
+            return Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0));
+        }
+
+        let colour = colour / filled as f32;
+        // Keep dark averages from reading as empty
+        Node::new(
+            CHUNK_OFFSET,
+            Voxel::new(
+                (colour.x as u8).max(1),
+                (colour.y as u8).max(1),
+                (colour.z as u8).max(1),
+            ),
+        )
+    }
+
+    fn downsample_children(
+        &mut self,
+        source: &CpuOctree,
+        from: usize,
+        to: usize,
+        level: u32,
+        max_depth: u32,
+        policy: Downsample,
+    ) {
+        for i in 0..8 {
+            let node = source.nodes[from + i];
+            self.nodes[to + i] = if node.pointer >= CHUNK_OFFSET {
+                node
+            } else if level >= max_depth {
+                source.downsampled_leaf(node.pointer as usize, policy)
+            } else {
+                let children = self.nodes.len();
+                self.add_voxels(0);
+                self.downsample_children(
+                    source,
+                    node.pointer as usize,
+                    children,
+                    level + 1,
+                    max_depth,
+                    policy,
+                );
+                Node::new(children as u32, node.value)
+            };
+        }
+    }
+
+    /// Copy holding only nodes reachable from the root, with blocks of 8 identical leaves
+    /// merged into one leaf
+    pub fn collapsed(&self) -> CpuOctree {
+        let mut octree = CpuOctree {
+            nodes: Vec::new(),
+            top_mip: self.top_mip,
+            materials: self.materials.clone(),
+            file_palette: self.file_palette.clone(),
+            hole_stack: Vec::new(),
+        };
+        octree.add_voxels(0);
+        octree.collapse_children(self, 0, 0);
+        octree
+    }
+
+    fn collapse_children(&mut self, source: &CpuOctree, from: usize, to: usize) {
+        for i in 0..8 {
+            let node = source.nodes[from + i];
+            self.nodes[to + i] = if node.pointer >= CHUNK_OFFSET {
+                node
+            } else {
+                let children = self.nodes.len();
+                self.add_voxels(0);
+                self.collapse_children(source, node.pointer as usize, children);
+
+                let first = self.nodes[children];
+                let uniform = first.pointer == CHUNK_OFFSET
+                    && (1..8).all(|i| {
+                        let other = self.nodes[children + i];
+                        other.pointer == CHUNK_OFFSET && other.value == first.value
+                    });
+                if uniform {
+                    self.nodes.truncate(children);
+                    first
+                } else {
+                    Node::new(children as u32, node.value)
+                }
+            };
+        }
+    }
+
+    /// Fraction of the volume below `children` that is filled, the summed colour of the
+    /// filled leaves and how many there are
+    fn coverage(&self, children: usize) -> (f32, Vector3<f32>, usize) {
+        let (mut volume, mut colour, mut filled) = (0.0, Vector3::zero(), 0);
+        for i in 0..8 {
+            let node = self.nodes[children + i];
+            if node.pointer < CHUNK_OFFSET {
+                let (v, c, f) = self.coverage(node.pointer as usize);
+                volume += v / 8.0;
+                colour += c;
+                filled += f;
+            } else if !node.is_empty() {
+                let voxel = node.value;
+                volume += 1.0 / 8.0;
+                colour += Vector3::new(voxel.r as f32, voxel.g as f32, voxel.b as f32);
+                filled += 1;
+            }
+        }
+        (volume, colour, filled)
+    }
+
+    /// Size in bytes of the gpu node buffer if this model was fully subdivided
+    pub fn gpu_bytes(&self, capacity: usize) -> usize {
+        self.nodes.len().max(capacity) * std::mem::size_of::<u32>()
+    }
+
+    pub fn raw(&self) -> Vec<u32> {
+        let mut raw = Vec::new();
+        for node in &self.nodes {
+            raw.push(node.pointer);
+        }
+        raw
+    }
+
+    /// Nodes as .bin bytes, the pointer as little endian then r, g, b and a padding byte
+    pub fn bin(&self) -> Vec<u8> {
+        let mut bin = Vec::with_capacity(self.nodes.len() * std::mem::size_of::<Node>());
+        for node in &self.nodes {
+            bin.extend_from_slice(&node.pointer.to_le_bytes());
+            bin.extend_from_slice(&[node.value.r, node.value.g, node.value.b, 0]);
+        }
+        bin
+    }
+
+    /// Checked `from_bin` for files picked by the user
+    pub fn load_bin(bin: &[u8]) -> Result<CpuOctree, String> {
+        let node_size = std::mem::size_of::<Node>();
+        if bin.is_empty() || bin.len() % (node_size * 8) != 0 {
+            return Err("Bin file is not a whole number of nodes".to_string());
+        }
+
+        let octree = CpuOctree::from_bin(bin);
+        octree
+            .validate()
+            .map_err(|e| format!("Bin file is invalid: {}", e))?;
+
+        Ok(octree)
+    }
+
+    /// Raw occupancy grid like the ones "Export Mask" writes, a cube of bytes indexed
+    /// x + y * size + z * size^2 where anything but 0 is filled
+    pub fn load_raw(data: &[u8]) -> Result<CpuOctree, String> {
+        let size = raw_size(data)?;
+        let grid: Vec<bool> = data.iter().map(|&b| b != 0).collect();
+        CpuOctree::from_grid(&grid, size)
+    }
+
+    /// Checks every block of 8 reachable from the root is in range, 8 aligned and only
+    /// referenced once, so walking the tree can't loop or read past the end
+    pub fn validate(&self) -> Result<(), String> {
+        let len = self.nodes.len();
+        if len < 8 || len % 8 != 0 {
+            return Err(format!("Node buffer length {} is not a multiple of 8", len));
+        }
+
+        let mut live = vec![false; len / 8];
+        live[0] = true;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            for i in 0..8 {
+                let pointer = self.nodes[index + i].pointer;
+                if pointer >= CHUNK_OFFSET {
+                    continue;
+                }
+
+                let child = pointer as usize;
+                if child % 8 != 0 || child + 8 > len {
+                    return Err(format!(
+                        "Node {} has child pointer {} out of range",
+                        index + i,
+                        child
+                    ));
+                }
+                if live[child / 8] {
+                    return Err(format!(
+                        "Node {} points back to block {} which is already in the tree",
+                        index + i,
+                        child
+                    ));
+                }
+
+                live[child / 8] = true;
+                stack.push(child);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads nodes written by `bin`, trailing bytes short of a node are ignored. Use
+    /// `load_bin` for files that might not be a valid tree
+    pub fn from_bin(bin: &[u8]) -> CpuOctree {
+        let nodes = bin
+            .chunks_exact(std::mem::size_of::<Node>())
+            .map(|chunk| {
+                let pointer = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                Node::new(pointer, Voxel::new(chunk[4], chunk[5], chunk[6]))
+            })
+            .collect();
+        CpuOctree {
+            nodes,
+            top_mip: Voxel::new(0, 0, 0),
+            materials: HashMap::new(),
+            file_palette: Vec::new(),
+            hole_stack: Vec::new(),
+        }
+    }
+}
+
+// Where the .rsvo top level and the node counts of each level start
+const RSVO_TOP_LEVEL: usize = 16;
+const RSVO_NODE_COUNTS: usize = 20;
+
+// Errors give the byte so a bad file can be checked in a hex editor
+fn rsvo_byte(data: &[u8], offset: usize, reading: &str) -> Result<u8, String> {
+    data.get(offset).copied().ok_or(format!(
+        "Unexpected end of file at byte {} while reading {}",
+        offset, reading
+    ))
+}
+
+/// Top level of an .rsvo file and the node count of each level down to it
+fn rsvo_header(data: &[u8]) -> Result<(u32, Vec<u32>), String> {
+    let u32_at = |offset: usize, reading: &str| -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = rsvo_byte(data, offset + i, reading)?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    };
+
+    let top_level = u32_at(RSVO_TOP_LEVEL, "the top level")?;
+    if top_level > MAX_DEPTH {
+        return Err(format!(
+            "Top level {} is deeper than {} levels",
+            top_level, MAX_DEPTH
+        ));
+    }
+
+    let node_counts = (0..=top_level as usize)
+        .map(|i| u32_at(RSVO_NODE_COUNTS + i * 4, "node counts"))
+        .collect::<Result<_, _>>()?;
+    Ok((top_level, node_counts))
+}
+
+/// Cursor over a file that errors instead of reading past the end, shared by the binary
+/// loaders. Reads are little endian unless they say otherwise
+pub struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset.saturating_add(len))
+            .ok_or("File is truncated".to_string())?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u16_be(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn i32_be(&mut self) -> Result<i32, String> {
+        let b = self.bytes(4)?;
+        Ok(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+const QB_RUN: u32 = 2;
+const QB_NEXT_SLICE: u32 = 6;
+
+/// Size of the cube a .vox file loads into and where each model goes in it. Models are
+/// placed by the translations in the scene graph with rotations ignored, files without a
+/// scene just have their first model. The whole scene is centred in the cube.
+fn vox_layout(vox_data: &dot_vox::DotVoxData) -> Result<(u32, Vec<(usize, Vector3<i32>)>), String> {
+    if vox_data.models.is_empty() {
+        return Err("Voxel file has no models".to_string());
+    }
+
+    // Scene space first cell of each model
+    let mut placements = Vec::new();
+    if vox_data.scenes.is_empty() {
+        placements.push((0, Vector3::zero()));
+    } else {
+        // Scene graphs are trees, a node reached twice means a broken file that could loop
+        let mut visited = vec![false; vox_data.scenes.len()];
+        let mut stack = vec![(0, Vector3::zero())];
+        while let Some((node, translation)) = stack.pop() {
+            if let Some(visited) = visited.get_mut(node as usize) {
+                if *visited {
+                    return Err(format!("Voxel scene node {} is in the graph twice", node));
+                }
+                *visited = true;
+            }
+
+            match vox_data.scenes.get(node as usize) {
+                Some(dot_vox::SceneNode::Transform { frames, child, .. }) => {
+                    let offset = frames
+                        .first()
+                        .and_then(|frame| frame.get("_t"))
+                        .map(|t| parse_translation(t))
+                        .transpose()?
+                        .unwrap_or(Vector3::zero());
+                    stack.push((*child, translation + offset));
+                }
+                Some(dot_vox::SceneNode::Group { children, .. }) => {
+                    for child in children {
+                        stack.push((*child, translation));
+                    }
+                }
+                Some(dot_vox::SceneNode::Shape { models, .. }) => {
+                    for model in models {
+                        let id = model.model_id as usize;
+                        let size = vox_data
+                            .models
+                            .get(id)
+                            .ok_or(format!("Voxel scene uses missing model {}", id))?
+                            .size;
+                        // Translations are of the model's centre
+                        let half = Vector3::new(size.x / 2, size.y / 2, size.z / 2);
+                        placements.push((id, translation - half.cast().unwrap()));
+                    }
+                }
+                None => return Err(format!("Voxel scene node {} is missing", node)),
+            }
+        }
+        // Stack order visits children backwards, files list later models on top
+        placements.reverse();
+    }
+
+    let mut min = Vector3::new(i32::MAX, i32::MAX, i32::MAX);
+    let mut max = Vector3::new(i32::MIN, i32::MIN, i32::MIN);
+    for &(model, corner) in &placements {
+        let size = vox_data.models[model].size;
+        let size = Vector3::new(size.x as i32, size.y as i32, size.z as i32);
+        min = Vector3::new(
+            min.x.min(corner.x),
+            min.y.min(corner.y),
+            min.z.min(corner.z),
+        );
+        let end = corner + size;
+        max = Vector3::new(max.x.max(end.x), max.y.max(end.y), max.z.max(end.z));
+    }
+
+    let extent = max - min;
+    if extent.x <= 0 || extent.y <= 0 || extent.z <= 0 {
+        return Err("Voxel model is empty".to_string());
+    }
+    let cube = (extent.x.max(extent.y).max(extent.z) as u32).next_power_of_two();
+    if cube.trailing_zeros() > MAX_PRECISE_DEPTH {
+        return Err(format!("Voxel scene is {} voxels across, too large", cube));
+    }
+
+    // Centre the scene in the cube
+    let shift = (Vector3::new(cube as i32, cube as i32, cube as i32) - extent) / 2 - min;
+    let placements = placements
+        .into_iter()
+        .map(|(model, corner)| (model, corner + shift))
+        .collect();
+
+    Ok((cube, placements))
+}
+
+/// Side length of a raw grid, which has to be a power of two cubed
+fn raw_size(data: &[u8]) -> Result<usize, String> {
+    let size = (data.len() as f64).cbrt().round() as usize;
+    if data.is_empty() || size * size * size != data.len() || !size.is_power_of_two() {
+        return Err(format!(
+            "Raw grid of {} bytes isn't a power of two cubed",
+            data.len()
+        ));
+    }
+    Ok(size)
+}
+
+/// Size of the cube a .qb file loads into and its filled voxels as (.vox style cell, 0xRRGGBB)
+fn qb_voxels(data: &[u8]) -> Result<(u32, Vec<(Vector3<i32>, u32)>), String> {
+    let mut reader = Reader::new(data);
+    let _version = reader.u32()?;
+    let bgra = reader.u32()? == 1;
+    let right_handed = reader.u32()? == 1;
+    let compressed = reader.u32()? == 1;
+    let _visibility_mask = reader.u32()?;
+    let matrix_count = reader.u32()?;
+
+    // Qubicle space cell and colour
+    let mut voxels = Vec::new();
+    let mut min = Vector3::new(i32::MAX, i32::MAX, i32::MAX);
+    let mut max = Vector3::new(i32::MIN, i32::MIN, i32::MIN);
+    for _ in 0..matrix_count {
+        let name_len = reader.u8()? as usize;
+        reader.bytes(name_len)?;
+        let size = Vector3::new(reader.u32()?, reader.u32()?, reader.u32()?);
+        let pos = Vector3::new(reader.u32()?, reader.u32()?, reader.u32()?).map(|p| p as i32);
+        if size.x > 1024 || size.y > 1024 || size.z > 1024 {
+            return Err(format!(
+                "Qubicle matrix of {}x{}x{} is too large",
+                size.x, size.y, size.z
+            ));
+        }
+        if pos.x.abs() > 1 << 20 || pos.y.abs() > 1 << 20 || pos.z.abs() > 1 << 20 {
+            return Err("Qubicle matrix position is out of range".to_string());
+        }
+
+        min = Vector3::new(min.x.min(pos.x), min.y.min(pos.y), min.z.min(pos.z));
+        let end = pos + size.map(|s| s as i32);
+        max = Vector3::new(max.x.max(end.x), max.y.max(end.y), max.z.max(end.z));
+
+        let slice = (size.x * size.y) as usize;
+        let mut put = |index: usize, z: u32, colour: u32| {
+            let [r, g, b, a] = colour.to_le_bytes();
+            if a == 0 || index >= slice {
+                return;
+            }
+            let (r, b) = if bgra { (b, r) } else { (r, b) };
+            let cell = Vector3::new(
+                (index % size.x as usize) as i32,
+                (index / size.x as usize) as i32,
+                z as i32,
+            );
+            voxels.push((pos + cell, Voxel::new(r, g, b).to_cpu_value()));
+        };
+
+        for z in 0..size.z {
+            if !compressed {
+                for index in 0..slice {
+                    put(index, z, reader.u32()?);
+                }
+                continue;
+            }
+
+            // Run length encoded a slice at a time
+            let mut index = 0;
+            loop {
+                match reader.u32()? {
+                    QB_NEXT_SLICE => break,
+                    QB_RUN => {
+                        let count = reader.u32()? as usize;
+                        let colour = reader.u32()?;
+                        if count > slice {
+                            return Err("Qubicle run is longer than its slice".to_string());
+                        }
+                        for _ in 0..count {
+                            put(index, z, colour);
+                            index += 1;
+                        }
+                    }
+                    colour => {
+                        put(index, z, colour);
+                        index += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let extent = max - min;
+    if voxels.is_empty() || extent.x <= 0 || extent.y <= 0 || extent.z <= 0 {
+        return Err("Qubicle file has no voxels".to_string());
+    }
+    let cube = (extent.x.max(extent.y).max(extent.z) as u32).next_power_of_two();
+    if cube.trailing_zeros() > MAX_PRECISE_DEPTH {
+        return Err(format!(
+            "Qubicle model is {} voxels across, too large",
+            cube
+        ));
+    }
+
+    // Qubicle is y up like the octree, .vox cells are z up
+    let shift = (Vector3::new(cube as i32, cube as i32, cube as i32) - extent) / 2 - min;
+    let voxels = voxels
+        .into_iter()
+        .map(|(cell, colour)| {
+            let mut cell = cell + shift;
+            if right_handed {
+                cell.z = cube as i32 - 1 - cell.z;
+            }
+            (Vector3::new(cell.x, cell.z, cell.y), colour)
+        })
+        .collect();
+
+    Ok((cube, voxels))
+}
+
+fn parse_translation(t: &str) -> Result<Vector3<i32>, String> {
+    let values: Vec<i32> = t
+        .split_whitespace()
+        .map(|v| v.parse::<i32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Voxel scene has a bad translation \"{}\"", t))?;
+    match values[..] {
+        [x, y, z] if x.abs() < 1 << 24 && y.abs() < 1 << 24 && z.abs() < 1 << 24 => {
+            Ok(Vector3::new(x, y, z))
+        }
+        _ => Err(format!("Voxel scene has a bad translation \"{}\"", t)),
+    }
+}
+
+/// Contents of a model file and the extension to parse it by. Gzipped files are inflated
+/// while they're read, and a .gz extension is dropped so model.rsvo.gz loads as rsvo.
+pub fn read_model_file(file: &str) -> Result<(Vec<u8>, String), String> {
+    use std::ffi::OsStr;
+    use std::io::{BufRead, BufReader, Read};
+
+    let mut path = std::path::Path::new(file).to_path_buf();
+    let mut reader = BufReader::new(std::fs::File::open(&path).map_err(|e| e.to_string())?);
+    let gzipped = reader
+        .fill_buf()
+        .map_err(|e| e.to_string())?
+        .starts_with(&GZIP_MAGIC);
+
+    if path.extension() == Some(OsStr::new("gz")) {
+        path.set_extension("");
+    }
+    let extension = path.extension().and_then(OsStr::to_str).unwrap_or("");
+
+    let data = if gzipped {
+        let limit = match extension {
+            "schematic" => MAX_SCHEMATIC_BYTES,
+            _ => MAX_INFLATED_BYTES,
+        };
+        gunzip(reader, limit).map_err(|e| format!("{}: {}", file, e))?
+    } else {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| format!("{}: {}", file, e))?;
+        data
+    };
+
+    Ok((data, extension.to_string()))
+}
+
+/// Inflates gzipped `data`, erroring rather than going past `limit` bytes
+pub fn gunzip(data: impl std::io::BufRead, limit: u64) -> Result<Vec<u8>, String> {
+    use std::io::Read;
+
+    let mut inflated = Vec::new();
+    flate2::bufread::GzDecoder::new(data)
+        .take(limit + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|e| e.to_string())?;
+    if inflated.len() as u64 > limit {
+        return Err(format!(
+            "Inflates to more than {} MiB",
+            limit / (1024 * 1024)
+        ));
+    }
+    Ok(inflated)
+}
+
+/// Centre and scale that fit the bounding box of `points` into the -1..1 cube
+fn fit_to_cube(points: impl Iterator<Item = Vector3<f32>>) -> (Vector3<f32>, f32) {
+    let (min, max) = points.fold(
+        (
+            Vector3::new(f32::MAX, f32::MAX, f32::MAX),
+            Vector3::new(f32::MIN, f32::MIN, f32::MIN),
+        ),
+        |(min, max), pos| {
+            (
+                Vector3::new(min.x.min(pos.x), min.y.min(pos.y), min.z.min(pos.z)),
+                Vector3::new(max.x.max(pos.x), max.y.max(pos.y), max.z.max(pos.z)),
+            )
+        },
+    );
+    let extent = (max - min).x.max((max - min).y).max((max - min).z);
+    let scale = if extent > 0.0 { 2.0 / extent } else { 1.0 };
+    ((min + max) / 2.0, scale)
+}
+
+/// Centre of a .vox grid cell at `depth` in the -1..1 octree space. The vox x axis is
+/// flipped and its z is up, the same mapping `load_vox` uses.
+pub fn grid_to_normalized(cell: Vector3<i32>, depth: u32) -> Vector3<f32> {
+    let size = (1u32 << depth) as f32;
+    let pos = Vector3::new(
+        size - cell.x as f32 - 0.5,
+        cell.z as f32 + 0.5,
+        cell.y as f32 + 0.5,
+    );
+
+    pos / size * 2.0 - Vector3::new(1.0, 1.0, 1.0)
+}
+
+/// Inverse of `grid_to_normalized`, the .vox grid cell at `depth` containing `pos`
+pub fn normalized_to_grid(pos: Vector3<f32>, depth: u32) -> Vector3<i32> {
+    let size = (1u32 << depth) as f32;
+    let p = (pos + Vector3::new(1.0, 1.0, 1.0)) / 2.0 * size;
+
+    Vector3::new(
+        (size - p.x).floor() as i32,
+        p.z.floor() as i32,
+        p.y.floor() as i32,
+    )
+}
+
+/// Depth of the grid cells and the centre of the cell for each of `count` models, the
+/// grid is the smallest power of 2 that fits `columns` columns and the rows needed.
+/// Models sit in the layer just behind the centre.
+pub fn gallery_cells(count: usize, columns: u32) -> (u32, Vec<Vector3<f32>>) {
+    let columns = columns.max(1) as usize;
+    let rows = (count + columns - 1) / columns;
+    let size = columns.max(rows).max(1).next_power_of_two();
+    let depth = size.trailing_zeros();
+
+    let cell = 2.0 / size as f32;
+    let cells = (0..count)
+        .map(|i| {
+            let (x, y) = (i % columns, i / columns);
+            // Screen right is -x from the default view
+            Vector3::new(
+                1.0 - (x as f32 + 0.5) * cell,
+                1.0 - (y as f32 + 0.5) * cell,
+                -1.0 + ((size / 2) as f32 + 0.5) * cell,
+            )
+        })
+        .collect();
+
+    (depth, cells)
+}
+
+/// Colour counts when merging two models. Leaves store full rgb so palettes never
+/// need quantizing, this only reports how much they overlap.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PaletteReport {
+    pub base: usize,
+    pub other: usize,
+    pub shared: usize,
+    pub combined: usize,
+}
+
+impl PaletteReport {
+    pub fn new(base: &CpuOctree, other: &CpuOctree) -> Self {
+        let (base, other) = (base.palette(), other.palette());
+        let shared = base.intersection(&other).count();
+
+        Self {
+            base: base.len(),
+            other: other.len(),
+            shared,
+            combined: base.len() + other.len() - shared,
+        }
+    }
+}
+
+/// When `CpuOctree::downsample` keeps a collapsed subtree filled
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Downsample {
+    // Any filled leaf
+    Any,
+    // More than half the volume filled
+    Majority,
+}
+
+/// How a cell sits against a shape being filled
+#[derive(Copy, Clone, PartialEq)]
+enum Overlap {
+    Outside,
+    Partial,
+    Inside,
+}
+
+/// One side of a `combine`, either a block of children or a leaf covering the area
+#[derive(Copy, Clone)]
+enum Side<'a> {
+    // Octree, first child and the mip value of the parent
+    Children(&'a CpuOctree, usize, Voxel),
+    Leaf(Node),
+}
+
+impl<'a> Side<'a> {
+    fn child(&self, i: usize) -> Side<'a> {
+        match *self {
+            Side::Children(octree, index, _) => {
+                let node = octree.nodes[index + i];
+                if node.pointer < CHUNK_OFFSET {
+                    Side::Children(octree, node.pointer as usize, node.value)
+                } else {
+                    Side::Leaf(node)
+                }
+            }
+            Side::Leaf(node) => Side::Leaf(node),
+        }
+    }
+
+    /// The leaf when it's a block reference
+    fn block(&self) -> Option<Node> {
+        match *self {
+            Side::Leaf(node) if node.pointer > CHUNK_OFFSET => Some(node),
+            _ => None,
+        }
+    }
+
+    /// Block references as a plain leaf of their colour, so they aren't copied into children
+    fn unblocked(self) -> Side<'a> {
+        match self {
+            Side::Leaf(node) if node.pointer > CHUNK_OFFSET => {
+                Side::Leaf(Node::new(CHUNK_OFFSET, node.value))
+            }
+            side => side,
+        }
+    }
+
+    fn value(&self) -> Option<Voxel> {
+        match *self {
+            Side::Children(_, _, value) => Some(value),
+            Side::Leaf(node) if node.is_empty() => None,
+            Side::Leaf(node) => Some(node.value),
+        }
+    }
+}
+
+impl std::fmt::Debug for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let voxel = self.value;
+        if self.pointer < CHUNK_OFFSET {
+            write!(
+                f,
+                "{:25} Pointer: {}",
+                format!("  Voxel: ({}, {}, {})", voxel.r, voxel.g, voxel.b),
+                self.pointer
+            )
+        } else {
+            write!(
+                f,
+                "{:25} Pointer: BlockID: {}",
+                format!("  Voxel: ({}, {}, {})", voxel.r, voxel.g, voxel.b),
+                self.pointer - CHUNK_OFFSET
+            )
+        }
+    }
+}
+
+impl std::fmt::Debug for CpuOctree {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Nodes ({}):\n", self.nodes.len())?;
+        let mut c = 0;
+        for value in &self.nodes {
+            write!(f, "{:?}\n", *value)?;
+
+            c += 1;
+            if c % 8 == 0 {
+                write!(f, "\n")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flood_fill_recolours_rsvo_leaves() {
+        // Leaves made by add_voxels point past CHUNK_OFFSET like loaded rsvo leaves
+        let mut octree = CpuOctree::new(0b1111_1111);
+        let dirty = octree.flood_fill(Vector3::new(0.5, 0.5, 0.5), Voxel::new(0, 0, 255), 1);
+
+        assert_eq!(dirty, (0..8).collect::<Vec<_>>());
+        assert!(octree
+            .nodes
+            .iter()
+            .all(|n| n.value == Voxel::new(0, 0, 255)));
+    }
+
+    #[test]
+    fn flood_fill_stops_at_other_colours() {
+        let mut octree = CpuOctree::new(0);
+        octree.set_voxel(Vector3::new(0.5, 0.5, 0.5), 0xFF0000, 1);
+        octree.set_voxel(Vector3::new(-0.5, 0.5, 0.5), 0xFF0000, 1);
+        octree.set_voxel(Vector3::new(0.5, -0.5, 0.5), 0x00FF00, 1);
+
+        let dirty = octree.flood_fill(Vector3::new(0.5, 0.5, 0.5), Voxel::new(0, 0, 255), 1);
+        assert_eq!(dirty.len(), 2);
+        assert_eq!(
+            octree.nodes[octree.find_voxel(Vector3::new(0.5, -0.5, 0.5), None).0].value,
+            Voxel::new(0, 255, 0)
+        );
+    }
+
+    #[test]
+    fn bin_pointing_back_at_the_root_is_rejected() {
+        let mut octree = CpuOctree::new(0);
+        octree.nodes[3].pointer = 0;
+        assert!(octree.validate().is_err());
+
+        assert!(CpuOctree::load_bin(&octree.bin()).is_err());
+    }
+
+    #[test]
+    fn bin_sharing_a_block_is_rejected() {
+        let mut octree = CpuOctree::new(0);
+        octree.add_voxels(0);
+        octree.nodes[0].pointer = 8;
+        octree.nodes[1].pointer = 8;
+        assert!(octree.validate().is_err());
+
+        octree.nodes[1].pointer = CHUNK_OFFSET;
+        assert!(octree.validate().is_ok());
+        assert_eq!(octree.depth(), 2);
+    }
+
+    #[test]
+    fn bin_round_trips() {
+        let mut octree = CpuOctree::new(0);
+        octree.set_voxel(Vector3::new(0.1, 0.2, 0.3), 0x123456, 4);
+
+        let bin = octree.bin();
+        assert_eq!(bin.len(), octree.nodes.len() * 8);
+        let first = octree.nodes[0];
+        assert_eq!(bin[..4], first.pointer.to_le_bytes());
+        assert_eq!(bin[4..8], [first.value.r, first.value.g, first.value.b, 0]);
+
+        let loaded = CpuOctree::load_bin(&bin).unwrap();
+        assert_eq!(loaded.raw(), octree.raw());
+        assert_eq!(loaded.depth(), 4);
+
+        // Decoding doesn't need the bytes aligned for a Node
+        let mut shifted = vec![0];
+        shifted.extend_from_slice(&bin);
+        assert_eq!(
+            CpuOctree::load_bin(&shifted[1..]).unwrap().raw(),
+            octree.raw()
+        );
+    }
+
+    #[test]
+    fn statuette_depth_matches_load_depth() {
+        assert_eq!(CpuOctree::file_depth("files/statuette.rsvo").unwrap(), 13);
+
+        let chunk = CpuOctree::load_file("files/statuette.rsvo".to_string(), 5).unwrap();
+        assert_eq!(chunk.depth(), 5);
+        assert_eq!(chunk.to_octree().unwrap().max_depth(), 5);
+    }
+
+    #[test]
+    fn gunzip_stops_at_the_limit() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&vec![0u8; 4 * 1024 * 1024]).unwrap();
+        let bomb = encoder.finish().unwrap();
+
+        assert!(gunzip(&bomb[..], 1024 * 1024).is_err());
+        assert_eq!(
+            gunzip(&bomb[..], 4 * 1024 * 1024).unwrap().len(),
+            4 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn read_model_file_rejects_gzip_bombs() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("svo_tracer_bomb.schematic.gz");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        for _ in 0..(MAX_SCHEMATIC_BYTES / (1024 * 1024) + 1) {
+            encoder.write_all(&[0u8; 1024 * 1024]).unwrap();
+        }
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let result = read_model_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).ok();
+        assert!(result.unwrap_err().contains("Inflates to more than"));
+    }
+
+    fn vox_scene(scenes: Vec<dot_vox::SceneNode>) -> dot_vox::DotVoxData {
+        let model = || dot_vox::Model {
+            size: dot_vox::Size { x: 2, y: 2, z: 2 },
+            voxels: vec![dot_vox::Voxel {
+                x: 0,
+                y: 0,
+                z: 0,
+                i: 0,
+            }],
+        };
+        dot_vox::DotVoxData {
+            version: 150,
+            models: vec![model(), model()],
+            palette: vec![0xFF0000FF; 256],
+            materials: Vec::new(),
+            scenes,
+            layers: Vec::new(),
+        }
+    }
+
+    fn transform(t: &str, child: u32) -> dot_vox::SceneNode {
+        let frame = [("_t".to_string(), t.to_string())].into_iter().collect();
+        dot_vox::SceneNode::Transform {
+            attributes: Default::default(),
+            frames: vec![frame],
+            child,
+        }
+    }
+
+    fn shape(model_id: u32) -> dot_vox::SceneNode {
+        dot_vox::SceneNode::Shape {
+            attributes: Default::default(),
+            models: vec![dot_vox::ShapeModel {
+                model_id,
+                attributes: Default::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn vox_layout_places_two_models() {
+        let vox_data = vox_scene(vec![
+            transform("0 0 0", 1),
+            dot_vox::SceneNode::Group {
+                attributes: Default::default(),
+                children: vec![2, 4],
+            },
+            transform("10 0 0", 3),
+            shape(0),
+            transform("-10 0 0", 5),
+            shape(1),
+        ]);
+
+        let (cube, placements) = vox_layout(&vox_data).unwrap();
+        assert_eq!(cube, 32);
+        assert_eq!(
+            placements,
+            vec![(0, Vector3::new(25, 15, 15)), (1, Vector3::new(5, 15, 15))]
+        );
+    }
+
+    #[test]
+    fn vox_group_listing_itself_is_rejected() {
+        let vox_data = vox_scene(vec![dot_vox::SceneNode::Group {
+            attributes: Default::default(),
+            children: vec![0, 0],
+        }]);
+        assert!(vox_layout(&vox_data).is_err());
+    }
+
+    #[test]
+    fn vox_palette_reaches_the_octree() {
+        let mut vox_data = vox_scene(Vec::new());
+        vox_data.palette[1] = 0x80402010;
+        let octree = CpuOctree::vox_to_octree(&vox_data, 2, &[(0, Vector3::zero())]);
+        assert_eq!(octree.file_palette, vox_data.palette);
+
+        let octree = octree.to_octree().unwrap();
+        assert_eq!(octree.palette.len(), 256);
+        assert_eq!(
+            octree.palette_bytes()[..8],
+            [0xFF, 0, 0, 0xFF, 0x10, 0x20, 0x40, 0x80]
+        );
+    }
+
+    #[test]
+    fn removed_voxels_free_their_blocks_for_reuse() {
+        let mut octree = CpuOctree::new(0);
+        let pos = Vector3::new(0.3, 0.3, 0.3);
+        octree.set_voxel(pos, 0xFF0000, 4);
+        let len = octree.nodes.len();
+
+        assert!(octree.remove_voxel(pos, 4));
+        assert_eq!(octree.hole_stack.len(), 3);
+        assert!(octree.nodes[..8].iter().all(|node| node.is_empty()));
+
+        // Setting it again fills the holes instead of growing the buffer
+        octree.set_voxel(pos, 0x00FF00, 4);
+        assert_eq!(octree.nodes.len(), len);
+        assert!(octree.hole_stack.is_empty());
+        assert!(octree.validate().is_ok());
+        let (index, depth, _) = octree.find_voxel(pos, None);
+        assert_eq!(
+            (octree.nodes[index].value, depth),
+            (Voxel::new(0, 255, 0), 4)
+        );
+    }
+
+    #[test]
+    fn overwriting_a_subtree_frees_it() {
+        let mut octree = CpuOctree::new(0);
+        octree.set_voxel(Vector3::new(0.3, 0.3, 0.3), 0xFF0000, 3);
+        octree.set_voxel(Vector3::new(0.3, 0.3, 0.3), 0x0000FF, 1);
+        assert_eq!(octree.hole_stack.len(), 2);
+        assert_eq!(octree.depth(), 1);
+    }
+
+    fn leaves(octree: &CpuOctree) -> impl Iterator<Item = (Vector3<f32>, u32, u32)> {
+        octree
+            .to_octree()
+            .unwrap()
+            .leaves()
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    // Same voxels as monu10.vox would give, placed one by one and as a batch
+    fn monument_voxels() -> (Vec<(Vector3<f32>, u32)>, u32) {
+        let vox_data = dot_vox::load_bytes(&std::fs::read("files/monu10.vox").unwrap()).unwrap();
+        let (cube, placements) = vox_layout(&vox_data).unwrap();
+        let depth = cube.trailing_zeros();
+        let mut voxels = Vec::new();
+        for (model, offset) in placements {
+            for voxel in &vox_data.models[model].voxels {
+                let cell = Vector3::new(voxel.x as i32, voxel.y as i32, voxel.z as i32) + offset;
+                let colour = vox_data.palette[voxel.i as usize].to_le_bytes();
+                let value = Voxel::new(colour[0], colour[1], colour[2]).to_cpu_value();
+                voxels.push((grid_to_normalized(cell, depth), value));
+            }
+        }
+        (voxels, depth)
+    }
+
+    #[test]
+    fn batches_match_single_inserts() {
+        // More voxels than a batch, overlapping so later ones have to win
+        let mut state = 0x5eedu64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let depth = 5;
+        let voxels: Vec<(Vector3<f32>, u32)> = (0..INSERT_BATCH_SIZE + 1000)
+            .map(|_| {
+                let cell = Vector3::new(next() % 32, next() % 32, next() % 32);
+                let pos = grid_to_normalized(cell.map(|c| c as i32), depth);
+                (pos, [0xFF0000, 0x00FF00][next() as usize % 2])
+            })
+            .collect();
+
+        let mut single = CpuOctree::new(0);
+        for &(pos, value) in &voxels {
+            single.set_voxel(pos, value, depth);
+        }
+        let batched = CpuOctree::from_voxel_iter(voxels.iter().copied(), depth);
+
+        assert!(leaves(&single).eq(leaves(&batched)));
+    }
+
+    // cargo test --release insert_batch_speedup -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn insert_batch_speedup() {
+        let (voxels, depth) = monument_voxels();
+
+        let start = std::time::Instant::now();
+        let mut single = CpuOctree::new(0);
+        for &(pos, value) in &voxels {
+            single.set_voxel(pos, value, depth);
+        }
+        let single_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let batched = CpuOctree::from_voxel_iter(voxels.iter().copied(), depth);
+        let batch_time = start.elapsed();
+
+        println!(
+            "{} voxels: set_voxel {:?}, from_voxel_iter {:?}, {:.1}x faster",
+            voxels.len(),
+            single_time,
+            batch_time,
+            single_time.as_secs_f64() / batch_time.as_secs_f64()
+        );
+        assert!(leaves(&single).eq(leaves(&batched)));
+    }
+
+    #[test]
+    fn downsample_to_zero_is_one_leaf() {
+        let mut octree = CpuOctree::new(0);
+        octree.set_voxel(Vector3::new(0.3, 0.3, 0.3), 0xFF0000, 3);
+        octree.set_voxel(Vector3::new(-0.7, 0.3, 0.3), 0x0000FF, 2);
+
+        let root = octree.downsample(0, Downsample::Any);
+        assert_eq!(root.depth(), 1);
+        let leaf = root.nodes[0];
+        assert!(!leaf.is_empty());
+        assert_eq!(leaf.value, Voxel::new(127, 1, 127));
+        assert!(root.nodes[..8]
+            .iter()
+            .all(|node| node.pointer == leaf.pointer && node.value == leaf.value));
+
+        // Two small voxels are far from half the volume
+        let root = octree.downsample(0, Downsample::Majority);
+        assert!(root.nodes[..8].iter().all(|node| node.is_empty()));
+        assert!(CpuOctree::new(0).downsample(0, Downsample::Any).nodes[0].is_empty());
+    }
+
+    #[test]
+    fn downsample_keeps_shallow_structure() {
+        let mut octree = CpuOctree::new(0);
+        octree.set_voxel(Vector3::new(0.3, 0.3, 0.3), 0xFF0000, 4);
+        let lod = octree.downsample(2, Downsample::Any);
+        assert_eq!(lod.depth(), 2);
+        let (index, depth, _) = lod.find_voxel(Vector3::new(0.3, 0.3, 0.3), None);
+        assert_eq!((lod.nodes[index].value, depth), (Voxel::new(255, 1, 1), 2));
+    }
+
+    #[test]
+    fn rsvo_top_level_is_read_as_a_u32() {
+        let mut data = vec![0; 16];
+        data.extend(256u32.to_le_bytes());
+        assert!(CpuOctree::load_bytes(&data, "rsvo", 5, false).is_err());
+        assert!(rsvo_header(&data).is_err());
+
+        let mut data = vec![0; 16];
+        for value in [1u32, 1, 1] {
+            data.extend(value.to_le_bytes());
+        }
+        data.push(0b0000_0001);
+        assert_eq!(rsvo_header(&data).unwrap(), (1, vec![1, 1]));
+        assert_eq!(
+            CpuOctree::load_bytes(&data, "rsvo", 5, false)
+                .unwrap()
+                .depth(),
+            1
+        );
+    }
+
+    #[test]
+    fn volume_holds_palette_indices() {
+        let mut octree = CpuOctree::new(0);
+        octree.set_voxel(Vector3::new(0.5, -0.5, -0.5), 0xFF0000, 1);
+        octree.set_voxel(Vector3::new(-0.5, 0.5, 0.5), 0x00FF00, 1);
+
+        // Without a file palette the colours are numbered as they're found
+        let palette = octree.volume_palette();
+        assert_eq!(palette.len(), 2);
+        let index = |colour| palette.iter().position(|&p| p == colour).unwrap() as u8 + 1;
+        let mut expected = vec![0; 8];
+        expected[1] = index(Voxel::new(255, 0, 0));
+        expected[2 + 4] = index(Voxel::new(0, 255, 0));
+        assert_eq!(octree.to_volume(1).unwrap(), expected);
+
+        // Green is entry 3 of the file's palette and red isn't in it, so it takes the closest
+        octree.file_palette = vec![0xFF000000, 0xFF0000F0, 0xFF00FF00];
+        let mut expected = vec![0; 8];
+        expected[1] = 2;
+        expected[2 + 4] = 3;
+        assert_eq!(octree.to_volume(1).unwrap(), expected);
+    }
+
+    #[test]
+    fn over_blends_overlapping_voxels() {
+        let pos = Vector3::new(0.5, 0.5, 0.5);
+        let mut base = CpuOctree::new(0);
+        base.set_voxel(pos, 0xC80000, 1);
+        let mut top = CpuOctree::new(0);
+        top.set_voxel(pos, 0x0000C8, 1);
+
+        let merged = base.merge(&top, Composite::Over(0.5));
+        let (index, _, _) = merged.find_voxel(pos, None);
+        assert!(merged.nodes[index].value == Voxel::new(100, 0, 100));
+
+        let merged = base.merge(&top, Composite::Replace);
+        let (index, _, _) = merged.find_voxel(pos, None);
+        assert!(merged.nodes[index].value == Voxel::new(0, 0, 200));
+    }
+
+    #[test]
+    fn block_references_stay_whole_in_combines() {
+        let block = Node::new(CHUNK_OFFSET + 3, Voxel::new(10, 20, 30));
+        let mut base = CpuOctree::new(0);
+        base.nodes[0] = block;
+        let mut top = CpuOctree::new(0);
+        top.set_voxel(Vector3::new(-0.75, -0.75, -0.75), 0xFF0000, 2);
+
+        // Where the block wins it's kept as it is instead of being copied into children
+        let union = base.union(&top);
+        assert_eq!(union.nodes[0].pointer, block.pointer);
+
+        // Where the other side wins the block's colour fills around its children
+        let merged = base.merge(&top, Composite::Replace);
+        assert!(merged.nodes[0].pointer < CHUNK_OFFSET);
+        assert!(merged.nodes.iter().all(|node| node.pointer <= CHUNK_OFFSET));
+        let (index, depth, _) = merged.find_voxel(Vector3::new(-0.25, -0.25, -0.25), None);
+        assert_eq!(depth, 2);
+        assert!(merged.nodes[index].value == block.value);
+    }
+
+    #[test]
+    fn grids_round_trip() {
+        for (grid, size) in [
+            (vec![true], 1),
+            (vec![false], 1),
+            (vec![true, false, false, true, false, true, true, false], 2),
+            ((0..64).map(|i| i % 3 == 0 || i == 63).collect(), 4),
+        ] {
+            let octree = CpuOctree::from_grid(&grid, size)
+                .unwrap()
+                .to_octree()
+                .unwrap();
+            assert_eq!(octree.to_grid(size.trailing_zeros()), (grid, size));
+        }
+
+        assert!(CpuOctree::from_grid(&[true; 27], 3).is_err());
+        assert!(CpuOctree::from_grid(&[true; 7], 2).is_err());
+    }
+
+    #[test]
+    fn file_depth_reads_the_header() {
+        let path = std::env::temp_dir().join(format!("depth_test_{}.raw", std::process::id()));
+        std::fs::write(&path, [1u8; 512]).unwrap();
+        let depth = CpuOctree::file_depth(path.to_str().unwrap());
+        std::fs::remove_file(&path).ok();
+        assert_eq!(depth, Ok(3));
+
+        assert_eq!(CpuOctree::file_depth("files/statuette.rsvo"), Ok(13));
+        assert!(CpuOctree::file_depth("model.txt").is_err());
+    }
+
+    #[test]
+    fn frames_need_vox_files() {
+        let dir = std::env::temp_dir().join(format!("frames_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a frame").unwrap();
+        let frames = CpuOctree::load_frames(dir.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(frames.err(), Some("No .vox files in directory".to_string()));
+        assert!(CpuOctree::load_frames(dir.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn refining_copies_the_cpu_children() {
+        let mut grid = vec![false; 64];
+        grid[0] = true;
+        grid[5] = true;
+        let cpu = CpuOctree::from_grid(&grid, 4).unwrap();
+
+        let mut octree = Octree::new(cpu.get_node_mask(0));
+        let children = cpu.nodes[0].pointer as usize;
+        let dirty = octree.refine_from(&cpu, children, 0, 2).unwrap();
+        assert_eq!(dirty, 8..16);
+        assert_eq!(octree.to_grid(2), (grid, 4));
+        assert!(octree.refine_from(&cpu, children, 0, 2).is_err());
+    }
+
+    #[test]
+    fn gallery_lays_models_out_in_rows() {
+        let (depth, cells) = gallery_cells(3, 2);
+        assert_eq!(depth, 1);
+        assert_eq!(
+            cells,
+            vec![
+                Vector3::new(0.5, 0.5, 0.5),
+                Vector3::new(-0.5, 0.5, 0.5),
+                Vector3::new(0.5, -0.5, 0.5),
+            ]
+        );
+        assert_eq!(gallery_cells(5, 1).0, 3);
+
+        let full = CpuOctree::from_grid(&[true], 1).unwrap();
+        let (octree, _) = CpuOctree::gallery(&[full.clone(), full.clone(), full], 2);
+        let (grid, _) = octree.to_octree().unwrap().to_grid(1);
+        assert_eq!(grid, [false, false, false, false, false, true, true, true]);
+    }
+
+    #[test]
+    fn palette_report_counts_shared_colours() {
+        let mut base = CpuOctree::new(0);
+        base.set_voxel(Vector3::new(-0.5, -0.5, -0.5), 0xFF0000, 1);
+        base.set_voxel(Vector3::new(0.5, -0.5, -0.5), 0x00FF00, 1);
+        let mut other = CpuOctree::new(0);
+        other.set_voxel(Vector3::new(-0.5, 0.5, -0.5), 0x00FF00, 1);
+        other.set_voxel(Vector3::new(0.5, 0.5, -0.5), 0x0000FF, 1);
+
+        assert_eq!(
+            PaletteReport::new(&base, &other),
+            PaletteReport {
+                base: 2,
+                other: 2,
+                shared: 1,
+                combined: 3,
+            }
+        );
+    }
+
+    fn filled_cells(octree: &CpuOctree, depth: u32) -> usize {
+        let (grid, _) = octree.to_octree().unwrap().to_grid(depth);
+        grid.iter().filter(|filled| **filled).count()
+    }
+
+    #[test]
+    fn boxes_fill_whole_cells_as_single_leaves() {
+        let mut octree = CpuOctree::new(0);
+        let (min, max) = (Vector3::new(-1.0, -1.0, -1.0), Vector3::new(0.0, 0.0, 1.0));
+        octree.fill_box(min, max, 0xFF0000, 2);
+        assert_eq!(filled_cells(&octree, 2), 16);
+        let (_, depth, _) = octree.find_voxel(Vector3::new(-0.5, -0.5, 0.5), None);
+        assert_eq!(depth, 1);
+
+        // Cells only partly inside are split and filled by their centres
+        let mut octree = CpuOctree::new(0);
+        let max = Vector3::new(-0.4, -0.4, -0.4);
+        octree.fill_box(min, max, 0xFF0000, 2);
+        assert_eq!(filled_cells(&octree, 2), 1);
+        assert_eq!(octree.to_octree().unwrap().max_depth(), 2);
+    }
+
+    #[test]
+    fn filling_over_split_cells_frees_them() {
+        let mut octree = CpuOctree::new(0);
+        octree.set_voxel(Vector3::new(-0.9, -0.9, -0.9), 0x00FF00, 4);
+        octree.set_voxel(Vector3::new(-0.1, -0.6, -0.3), 0x0000FF, 3);
+        let nodes = octree.nodes.len();
+
+        // The octant holding both splits becomes one leaf and the 4 blocks under it are freed
+        assert_eq!(nodes, 8 + 4 * 8);
+        let (min, max) = (Vector3::new(-1.0, -1.0, -1.0), Vector3::zero());
+        octree.fill_box(min, max, 0xFF0000, 4);
+        assert_eq!(octree.hole_stack.len(), 4);
+        let (index, depth, _) = octree.find_voxel(Vector3::new(-0.9, -0.9, -0.9), None);
+        assert_eq!(
+            (depth, octree.nodes[index].value),
+            (1, Voxel::new(255, 0, 0))
+        );
+        assert_eq!(filled_cells(&octree, 4), 8 * 8 * 8);
+
+        // Later splits reuse the holes before growing the buffer
+        octree.fill_sphere(Vector3::new(0.6, 0.6, 0.6), 0.0, 0xFFFFFF, 3);
+        assert_eq!(octree.nodes.len(), nodes);
+        assert_eq!(octree.hole_stack.len(), 2);
+    }
+
+    #[test]
+    fn csg_combines_filled_space() {
+        let a = CpuOctree::from_grid(&[true, true, false, false, true, false, false, false], 2)
+            .unwrap();
+        let b = CpuOctree::from_grid(&[false, true, true, false, false, false, false, true], 2)
+            .unwrap();
+        let grid = |octree: CpuOctree| octree.to_octree().unwrap().to_grid(1).0;
+
+        assert_eq!(
+            grid(a.csg(&b, Csg::Union)),
+            [true, true, true, false, true, false, false, true]
+        );
+        assert_eq!(
+            grid(a.csg(&b, Csg::Intersect)),
+            [false, true, false, false, false, false, false, false]
+        );
+        assert_eq!(
+            grid(a.csg(&b, Csg::Difference)),
+            [true, false, false, false, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn non_cube_vox_models_are_centred() {
+        let mut vox_data = vox_scene(Vec::new());
+        vox_data.models[0].size = dot_vox::Size { x: 5, y: 1, z: 3 };
+        let (cube, placements) = vox_layout(&vox_data).unwrap();
+        assert_eq!(cube, 8);
+        assert_eq!(placements, vec![(0, Vector3::new(1, 3, 2))]);
+
+        let octree = CpuOctree::vox_to_octree(&vox_data, cube, &placements);
+        assert_eq!(filled_cells(&octree, 3), 1);
+    }
+
+    fn qb_file(compressed: bool, colours: &[u32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in [0x0101, 0, 0, compressed as u32, 0, 1] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend([1, b'm']);
+        for value in [colours.len() as u32, 1, 1, 0, 0, 0] {
+            data.extend(value.to_le_bytes());
+        }
+        if compressed {
+            // The first colour repeated as a run, then the rest one by one
+            data.extend(QB_RUN.to_le_bytes());
+            data.extend(2u32.to_le_bytes());
+            data.extend(colours[0].to_le_bytes());
+            for colour in &colours[2..] {
+                data.extend(colour.to_le_bytes());
+            }
+            data.extend(QB_NEXT_SLICE.to_le_bytes());
+        } else {
+            for colour in colours {
+                data.extend(colour.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn qb_matrices_load_their_colours() {
+        let red = u32::from_le_bytes([255, 0, 0, 255]);
+        let clear = u32::from_le_bytes([0, 255, 0, 0]);
+        let (cube, voxels) = qb_voxels(&qb_file(false, &[red, clear, red])).unwrap();
+        assert_eq!(cube, 4);
+        assert_eq!(voxels.len(), 2);
+        assert!(voxels.iter().all(|(_, colour)| *colour == 0xFF0000));
+
+        let (cube, voxels) = qb_voxels(&qb_file(true, &[red, red, clear, red])).unwrap();
+        assert_eq!(cube, 4);
+        assert_eq!(voxels.len(), 3);
+        let octree =
+            CpuOctree::load_bytes(&qb_file(true, &[red, red, clear, red]), "qb", 2, false).unwrap();
+        assert_eq!(filled_cells(&octree, 2), 3);
+
+        let data = qb_file(false, &[red, red]);
+        assert_eq!(
+            qb_voxels(&data[..data.len() - 1]).err(),
+            Some("File is truncated".to_string())
+        );
+        assert!(qb_voxels(&qb_file(false, &[clear])).is_err());
     }
 }
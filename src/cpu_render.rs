@@ -0,0 +1,1047 @@
+use super::*;
+
+/// Everything a `VoxelShader` gets besides the hit itself
+#[allow(dead_code)]
+pub struct ShadeContext<'a> {
+    pub octree: &'a Octree,
+    pub ray_pos: Vector3<f32>,
+    pub ray_dir: Vector3<f32>,
+    pub pixel: Vector2<f32>,
+    pub sun_dir: Vector3<f32>,
+    pub sun_enabled: bool,
+    pub shadows: bool,
+    pub bevel_amount: f32,
+    pub smooth_normals: bool,
+    pub face_tint_strength: f32,
+    pub ao: bool,
+    pub ao_strength: f32,
+    pub ao_rays: u32,
+    pub sun_radius: f32,
+    pub shadow_samples: u32,
+    // Accumulated sample this is, turns the soft shadow rays
+    pub sample: u32,
+}
+
+impl<'a> ShadeContext<'a> {
+    /// Colour stored in the leaf that was hit
+    pub fn colour(&self, hit: &RayHit) -> [f32; 3] {
+        let voxel = Voxel::from_value(self.octree.get_node(hit.index) - VOXEL_OFFSET);
+        [
+            voxel.r as f32 / 255.0,
+            voxel.g as f32 / 255.0,
+            voxel.b as f32 / 255.0,
+        ]
+    }
+}
+
+/// Hook for experimenting with shading on the cpu tracer, only called on hits
+pub trait VoxelShader {
+    fn shade(&self, hit: &RayHit, ctx: &ShadeContext) -> [f32; 3];
+
+    /// How much of what's behind the hit it covers, only asked for when depth peeling
+    fn alpha(&self, _hit: &RayHit, _ctx: &ShadeContext) -> f32 {
+        1.0
+    }
+}
+
+/// Same as the default shading in shader.wgsl
+pub struct DiffuseShader;
+
+impl VoxelShader for DiffuseShader {
+    fn shade(&self, hit: &RayHit, ctx: &ShadeContext) -> [f32; 3] {
+        let sun_dir = ctx.sun_dir.normalize();
+
+        let mut ambient = 0.3;
+        if ctx.ao {
+            ambient *= 1.0 - ctx.ao_strength * ambient_occlusion(hit, ctx);
+        }
+        let mut diffuse = 0.0;
+
+        if ctx.sun_enabled {
+            let normal = if ctx.smooth_normals {
+                smooth_normal(hit, ctx.octree)
+            } else {
+                bevel_normal(hit, ctx.bevel_amount)
+            };
+            diffuse = normal.dot(-sun_dir).max(0.0);
+
+            if ctx.shadows && diffuse > 0.0 {
+                diffuse *= sun_visibility(hit, sun_dir, ctx);
+            }
+        }
+
+        let tint = face_tint(hit.normal, ctx.face_tint_strength);
+        ctx.colour(hit).map(|c| (ambient + diffuse) * tint * c)
+    }
+}
+
+/// Tilts the face normal towards the edges within `amount` of a voxel edge, measured
+/// as a fraction of the voxel size. Flat at 0
+pub fn bevel_normal(hit: &RayHit, amount: f32) -> Vector3<f32> {
+    if amount <= 0.0 || hit.depth >= 32 {
+        return hit.normal;
+    }
+
+    let voxel_size = 2.0 / (1u32 << hit.depth) as f32;
+    let mut normal = hit.normal;
+    for i in 0..3 {
+        if hit.normal[i] == 0.0 {
+            let local = ((hit.pos[i] + 1.0) / voxel_size).fract();
+            let edge = local.min(1.0 - local);
+            let t = (1.0 - edge / amount).max(0.0);
+            normal[i] = (local - 0.5).signum() * t;
+        }
+    }
+    normal.normalize()
+}
+
+/// Fraction of the sun visible from the hit, spreading `shadow_samples` rays over the
+/// sun's disc when `sun_radius` is set. Same as `sun_visibility` in shader.wgsl
+pub fn sun_visibility(hit: &RayHit, sun_dir: Vector3<f32>, ctx: &ShadeContext) -> f32 {
+    let origin = hit.pos + hit.normal * 0.0000025;
+    if ctx.sun_radius <= 0.0 || ctx.shadow_samples <= 1 {
+        return !ctx.octree.trace(origin, -sun_dir).hit as u32 as f32;
+    }
+
+    let mut side = sun_dir.cross(Vector3::unit_y());
+    if side.magnitude() < 0.001 {
+        side = sun_dir.cross(Vector3::unit_x());
+    }
+    let side = side.normalize();
+    let up = side.cross(sun_dir);
+    let spread = ctx.sun_radius.to_radians().tan();
+    // Turned per pixel and sample so few samples give noise that averages out instead of bands
+    let angle = (ctx.pixel.dot(Vector2::new(12.9898, 78.233)).sin() * 43758.547
+        + ctx.sample as f32 * 0.618034)
+        .fract()
+        * std::f32::consts::TAU;
+    let rotation = Basis2::from_angle(Rad(angle));
+
+    let mut lit = 0;
+    for i in 0..ctx.shadow_samples {
+        let r = ((i as f32 + 0.5) / ctx.shadow_samples as f32).sqrt();
+        let theta = i as f32 * 2.399963;
+        let offset = rotation.rotate_vector(Vector2::new(theta.cos(), theta.sin()) * r * spread);
+        let dir = (-sun_dir + side * offset.x + up * offset.y).normalize();
+        if !ctx.octree.trace(origin, dir).hit {
+            lit += 1;
+        }
+    }
+    lit as f32 / ctx.shadow_samples as f32
+}
+
+/// Fraction of `ao_rays` rays over the hemisphere of the face that hit something within
+/// two voxels of the hit, same as `ambient_occlusion` in shader.wgsl
+pub fn ambient_occlusion(hit: &RayHit, ctx: &ShadeContext) -> f32 {
+    if ctx.ao_rays == 0 || hit.depth >= 32 {
+        return 0.0;
+    }
+
+    let radius = 4.0 / (1u32 << hit.depth) as f32;
+    let origin = hit.pos + hit.normal * 0.0000025;
+    let tangent = Vector3::new(hit.normal.y, hit.normal.z, hit.normal.x);
+    let bitangent = Vector3::new(hit.normal.z, hit.normal.x, hit.normal.y);
+
+    let mut occluded = 0;
+    for i in 0..ctx.ao_rays {
+        // Spiral over the disc like the shader's sample_offset, lifted onto the hemisphere
+        let r = ((i as f32 + 0.5) / ctx.ao_rays as f32).sqrt();
+        let theta = i as f32 * 2.399963;
+        let offset = Vector2::new(theta.cos(), theta.sin()) * r;
+        let up = (1.0 - offset.magnitude2()).max(0.0).sqrt();
+        let dir = (tangent * offset.x + bitangent * offset.y + hit.normal * up).normalize();
+
+        let ao_hit = ctx.octree.trace(origin, dir);
+        if ao_hit.hit && (ao_hit.pos - origin).magnitude() < radius {
+            occluded += 1;
+        }
+    }
+    occluded as f32 / ctx.ao_rays as f32
+}
+
+/// Normal pointing away from the filled cells among the 26 around the hit voxel, the face
+/// normal for isolated voxels. Same as `smooth_normal` in shader.wgsl
+pub fn smooth_normal(hit: &RayHit, octree: &Octree) -> Vector3<f32> {
+    if hit.depth >= 32 {
+        return hit.normal;
+    }
+
+    let voxel_size = 2.0 / (1u32 << hit.depth) as f32;
+    let centre = ((hit.pos + Vector3::new(1.0, 1.0, 1.0)) / voxel_size)
+        .map(|c| (c.floor() + 0.5) * voxel_size - 1.0);
+
+    let mut gradient = Vector3::zero();
+    for i in 0..27 {
+        let offset = Vector3::new(i / 9, i / 3 % 3, i % 3).map(|c| c as f32 - 1.0);
+        let pos = centre + offset * voxel_size;
+        if i != 13 && in_bounds(pos) {
+            if octree
+                .get_node_checked(pos)
+                .map_or(false, |node| node > VOXEL_OFFSET)
+            {
+                gradient += offset.normalize();
+            }
+        }
+    }
+
+    if (-gradient).dot(hit.normal) <= 0.0 {
+        return hit.normal;
+    }
+    (-gradient).normalize()
+}
+
+/// Brightness multiplier by face direction, top faces lighter and bottoms darker. 1 at a
+/// strength of 0
+pub fn face_tint(normal: Vector3<f32>, strength: f32) -> f32 {
+    let factor = 1.0 + 0.2 * normal.y - 0.1 * normal.x.abs() - 0.05 * normal.z.abs();
+    1.0 + (factor - 1.0) * strength
+}
+
+/// Colour of missed rays at a pixel, same as `background` in shader.wgsl
+pub fn background_colour(uniforms: &render::Uniforms, pixel: Vector2<f32>) -> [f32; 3] {
+    let colour = if uniforms.checker_size > 0.0 {
+        let cell = (pixel / uniforms.checker_size).map(|c| c.floor() as i32);
+        if (cell.x + cell.y) & 1 == 1 {
+            uniforms.checker_colour
+        } else {
+            uniforms.background
+        }
+    } else {
+        uniforms.background
+    };
+    [colour[0], colour[1], colour[2]]
+}
+
+/// Tint added for every empty cell a ray crosses in the empty space view, same as
+/// `empty_colour` in shader.wgsl
+pub fn empty_colour(depth: u32) -> [f32; 3] {
+    let t = depth as f32 / 6.0;
+    [0.0, 0.33, 0.67].map(|offset| 0.5 + 0.5 * (6.28318 * (t + offset)).cos())
+}
+
+/// Grey for a debug view value from 0 to 1 after the debug brightness and gamma, same as
+/// `debug_adjust` in shader.wgsl
+pub fn debug_adjust(value: f32, uniforms: &render::Uniforms) -> [f32; 3] {
+    let value = (value * uniforms.debug_brightness).clamp(0.0, 1.0);
+    [value.powf(1.0 / uniforms.debug_gamma); 3]
+}
+
+/// Blends a hit's colour towards the fog colour by its distance, same as `fog` in
+/// shader.wgsl
+pub fn apply_fog(colour: [f32; 3], distance: f32, uniforms: &render::Uniforms) -> [f32; 3] {
+    if !uniforms.fog {
+        return colour;
+    }
+    let amount = 1.0 - (-distance * uniforms.fog_density).exp();
+    [0, 1, 2].map(|i| colour[i] + (uniforms.fog_colour[i] - colour[i]) * amount)
+}
+
+/// Origin and normalised direction of the camera ray through a point in clip space
+pub fn camera_ray(
+    uniforms: &render::Uniforms,
+    clip_space: Vector2<f32>,
+) -> (Vector3<f32>, Vector3<f32>) {
+    let camera_inverse = Matrix4::from(uniforms.camera_inverse);
+    let pos = camera_inverse * Vector4::new(0.0, 0.0, 0.0, 1.0);
+    let pos = pos.truncate() / pos.w;
+    let dir = camera_inverse * Vector4::new(clip_space.x, clip_space.y, 1.0, 1.0);
+    (pos, (dir.truncate() / dir.w - pos).normalize())
+}
+
+/// Offset from the pixel centre of sample `i` of `count`, a spiral over the pixel. Same as
+/// `sample_offset` in shader.wgsl
+fn sample_offset(i: u32, count: u32) -> Vector2<f32> {
+    let r = ((i as f32 + 0.5) / count as f32).sqrt() * 0.5;
+    let theta = i as f32 * 2.399963;
+    Vector2::new(theta.cos(), theta.sin()) * r
+}
+
+/// Samples each pixel of an image gets from the edge detection in shader.wgsl, `samples`
+/// where depth or normal change within the pixel's 2x2 quad the way `fwidth` sees it and 1
+/// elsewhere. `depths` and `normals` are of the centre rays, row major, 1000 for misses.
+pub fn edge_samples(
+    depths: &[f32],
+    normals: &[Vector3<f32>],
+    size: [u32; 2],
+    samples: u32,
+) -> Vec<u32> {
+    let width = size[0] as usize;
+    let height = size[1] as usize;
+    let mut counts = vec![1; width * height];
+    if samples <= 1 {
+        return counts;
+    }
+
+    for y in 0..height {
+        for x in 0..width {
+            // The other pixel of the quad along each axis, pixels without one see no change
+            let across = y * width + (x ^ 1).min(width - 1);
+            let down = (y ^ 1).min(height - 1) * width + x;
+            let i = y * width + x;
+
+            let depth_edge =
+                ((depths[across] - depths[i]).abs() + (depths[down] - depths[i]).abs()) / depths[i];
+            let normal_edge = (normals[across] - normals[i]).map(f32::abs).sum()
+                + (normals[down] - normals[i]).map(f32::abs).sum();
+            if depth_edge > 0.01 || normal_edge > 0.0 {
+                counts[i] = samples;
+            }
+        }
+    }
+    counts
+}
+
+/// Colour and coverage built up over the depth peeling passes so far, and the distance
+/// along the camera ray the next layer starts from. Same as `Peel` in shader.wgsl
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Peel {
+    pub colour: [f32; 3],
+    pub coverage: f32,
+    pub start: f32,
+    pub done: bool,
+}
+
+impl Peel {
+    /// Blends the next layer in under the ones peeled before it, done once one is opaque
+    pub fn add(&mut self, colour: [f32; 3], alpha: f32) {
+        let weight = (1.0 - self.coverage) * alpha;
+        self.colour = [0, 1, 2].map(|c| self.colour[c] + weight * colour[c]);
+        self.coverage += weight;
+        self.done = alpha >= 1.0;
+    }
+}
+
+/// Just past where a ray going along `dir` leaves the voxel it hit, same as `voxel_exit` in
+/// shader.wgsl
+fn voxel_exit(hit: &RayHit, dir: Vector3<f32>) -> Vector3<f32> {
+    let voxel_size = 2.0 / (1u64 << hit.depth) as f32;
+    let centre = ((hit.pos + Vector3::new(1.0, 1.0, 1.0)) / voxel_size)
+        .map(|p| (p.floor() + 0.5) * voxel_size - 1.0);
+    let t_max = (centre - hit.pos + dir.map(f32::signum) * voxel_size / 2.0).div_element_wise(dir);
+    let t_exit = t_max.x.min(t_max.y).min(t_max.z);
+    hit.pos + dir * (t_exit + 0.000002)
+}
+
+/// Colour of the camera ray through `pixel`, and the hit with the distance to it. With
+/// `Uniforms::peels` this is every `fs_peel` pass of the pixel one after the other
+fn trace_pixel(
+    octree: &Octree,
+    uniforms: &render::Uniforms,
+    size: [u32; 2],
+    shader: &dyn VoxelShader,
+    pixel: Vector2<f32>,
+) -> ([f32; 3], RayHit, f32) {
+    // Matches get_clip_space
+    let clip_space = Vector2::new(
+        pixel.x / size[0] as f32 * 2.0 - 1.0,
+        -(pixel.y / size[1] as f32 * 2.0 - 1.0),
+    );
+    let (pos, dir) = camera_ray(uniforms, clip_space);
+
+    let ctx = ShadeContext {
+        octree,
+        ray_pos: pos,
+        ray_dir: dir,
+        pixel,
+        sun_dir: Vector4::from(uniforms.sun_dir).truncate(),
+        sun_enabled: uniforms.sun_enabled,
+        shadows: uniforms.shadows,
+        bevel_amount: uniforms.bevel_amount,
+        smooth_normals: uniforms.smooth_normals,
+        face_tint_strength: uniforms.face_tint_strength,
+        ao: uniforms.ao,
+        ao_strength: uniforms.ao_strength,
+        ao_rays: uniforms.ao_rays,
+        sun_radius: uniforms.sun_radius,
+        shadow_samples: uniforms.shadow_samples,
+        sample: uniforms.path_samples,
+    };
+
+    // Colour of the first thing along the camera ray past `start`
+    let shade_from = |start: f32| {
+        let mut empty = [0.0; 3];
+        let hit = octree.trace_visiting(pos + dir * start, dir, &mut |depth| {
+            if uniforms.show_empty {
+                let tint = empty_colour(depth);
+                empty = [0, 1, 2].map(|c| empty[c] + tint[c] * 0.02);
+            }
+        });
+        let distance = (hit.pos - pos).magnitude();
+        if uniforms.show_steps {
+            return (
+                debug_adjust(hit.steps as f32 / 64.0, uniforms),
+                hit,
+                distance,
+            );
+        }
+        if !hit.hit {
+            let colour = background_colour(uniforms, pixel);
+            return ([0, 1, 2].map(|c| colour[c] + empty[c]), hit, distance);
+        }
+
+        let colour = shader.shade(&hit, &ctx);
+        let colour = [0, 1, 2].map(|c| colour[c] + empty[c]);
+        (apply_fog(colour, distance, uniforms), hit, distance)
+    };
+
+    let first = shade_from(0.0);
+    if !uniforms.peels() {
+        return first;
+    }
+
+    let mut peel = Peel::default();
+    for layer in 0..=uniforms.transparency_layers {
+        let (colour, hit, _) = match layer {
+            0 => first,
+            _ => shade_from(peel.start),
+        };
+        let mut alpha = 1.0;
+        if hit.hit && layer < uniforms.transparency_layers && !uniforms.show_steps {
+            alpha = shader.alpha(&hit, &ctx);
+        }
+
+        peel.add(colour, alpha);
+        if peel.done || hit.depth >= 32 {
+            break;
+        }
+        peel.start = (voxel_exit(&hit, dir) - pos).magnitude();
+    }
+    (peel.colour, first.1, first.2)
+}
+
+/// Traces an image on the cpu with the same camera as the gpu, row major from the top left.
+/// Edges get `edge_aa_samples` samples like on the gpu unless depth peeling, `show_steps`
+/// shows the step count and `show_empty` tints the empty cells crossed.
+pub fn trace_cpu(
+    octree: &Octree,
+    uniforms: &render::Uniforms,
+    size: [u32; 2],
+    shader: &dyn VoxelShader,
+) -> Vec<[f32; 3]> {
+    let pixels = (size[0] * size[1]) as usize;
+    let mut image = Vec::with_capacity(pixels);
+    let mut depths = Vec::with_capacity(pixels);
+    let mut normals = Vec::with_capacity(pixels);
+    for y in 0..size[1] {
+        for x in 0..size[0] {
+            let pixel = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+            let (colour, hit, distance) = trace_pixel(octree, uniforms, size, shader, pixel);
+            image.push(colour);
+            depths.push(if hit.hit { distance } else { 1000.0 });
+            normals.push(hit.normal);
+        }
+    }
+
+    // Like fs_peel, depth peeling doesn't look for edges
+    let samples = match uniforms.peels() {
+        true => vec![1; pixels],
+        false => edge_samples(&depths, &normals, size, uniforms.edge_aa_samples),
+    };
+    for (i, colour) in image.iter_mut().enumerate() {
+        if samples[i] == 1 {
+            continue;
+        }
+        let x = i as u32 % size[0];
+        let y = i as u32 / size[0];
+        let centre = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+        for sample in 1..samples[i] {
+            let pixel = centre + sample_offset(sample, samples[i]);
+            let (extra, _, _) = trace_pixel(octree, uniforms, size, shader, pixel);
+            *colour = [0, 1, 2].map(|c| colour[c] + extra[c]);
+        }
+        *colour = colour.map(|c| c / samples[i] as f32);
+    }
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(octree: &Octree) -> ShadeContext<'_> {
+        ShadeContext {
+            octree,
+            ray_pos: Vector3::zero(),
+            ray_dir: -Vector3::unit_y(),
+            pixel: Vector2::new(10.5, 20.5),
+            sun_dir: -Vector3::unit_y(),
+            sun_enabled: true,
+            shadows: true,
+            bevel_amount: 0.0,
+            smooth_normals: false,
+            face_tint_strength: 0.0,
+            ao: false,
+            ao_strength: 0.0,
+            ao_rays: 0,
+            sun_radius: 10.0,
+            shadow_samples: 4,
+            sample: 0,
+        }
+    }
+
+    // Floor across the bottom with one voxel hanging above its middle
+    fn floor_and_block() -> Octree {
+        let mut cpu_octree = CpuOctree::new(0);
+        for x in 0..8 {
+            for z in 0..8 {
+                let pos = Vector3::new(x as f32 * 0.25 - 0.875, -0.875, z as f32 * 0.25 - 0.875);
+                cpu_octree.set_voxel(pos, 0xFFFFFF, 3);
+            }
+        }
+        cpu_octree.set_voxel(Vector3::new(0.125, 0.375, 0.125), 0xFFFFFF, 3);
+        cpu_octree.to_octree().unwrap()
+    }
+
+    #[test]
+    fn soft_shadow_rays_turn_per_sample() {
+        let octree = floor_and_block();
+        // On the floor just past the edge of the block's shadow
+        let hit = octree.trace(Vector3::new(0.26, 0.9, 0.125), -Vector3::unit_y());
+        assert!(hit.hit);
+
+        let mut ctx = context(&octree);
+        let visibility: Vec<f32> = (0..16)
+            .map(|sample| {
+                ctx.sample = sample;
+                sun_visibility(&hit, ctx.sun_dir, &ctx)
+            })
+            .collect();
+
+        assert!(visibility.iter().any(|&v| v != visibility[0]));
+        let mean = visibility.iter().sum::<f32>() / visibility.len() as f32;
+        assert!(mean > 0.0 && mean < 1.0);
+    }
+
+    #[test]
+    fn hard_shadows_ignore_the_sample() {
+        let octree = floor_and_block();
+        let hit = octree.trace(Vector3::new(0.125, 0.2, 0.125), -Vector3::unit_y());
+        let mut ctx = context(&octree);
+        ctx.sun_radius = 0.0;
+        for sample in 0..4 {
+            ctx.sample = sample;
+            assert_eq!(sun_visibility(&hit, ctx.sun_dir, &ctx), 0.0);
+        }
+    }
+
+    struct NormalShader;
+
+    impl VoxelShader for NormalShader {
+        fn shade(&self, hit: &RayHit, _ctx: &ShadeContext) -> [f32; 3] {
+            hit.normal.into()
+        }
+    }
+
+    #[test]
+    fn custom_shaders_shade_the_hits() {
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.camera_inverse = Matrix4::from_translation(Vector3::new(0.0, 0.0, -3.0)).into();
+        uniforms.background = [0.1, 0.2, 0.3, 0.0];
+
+        let octree = Octree::new([Voxel::new(255, 0, 0); 8]);
+        assert_eq!(
+            trace_cpu(&octree, &uniforms, [1, 1], &NormalShader),
+            [[0.0, 0.0, -1.0]]
+        );
+        let empty = Octree::new([Voxel::new(0, 0, 0); 8]);
+        assert_eq!(
+            trace_cpu(&empty, &uniforms, [1, 1], &NormalShader),
+            [[0.1, 0.2, 0.3]]
+        );
+    }
+
+    #[test]
+    fn turning_the_sun_off_leaves_ambient() {
+        let octree = Octree::new([Voxel::new(255, 0, 0); 8]);
+        let hit = octree.trace(Vector3::new(-0.5, 2.0, -0.5), -Vector3::unit_y());
+        let mut ctx = context(&octree);
+        assert_eq!(DiffuseShader.shade(&hit, &ctx), [1.3, 0.0, 0.0]);
+
+        ctx.sun_enabled = false;
+        assert_eq!(DiffuseShader.shade(&hit, &ctx), [0.3, 0.0, 0.0]);
+    }
+
+    fn face_hit(pos: Vector3<f32>, normal: Vector3<f32>, depth: u32) -> RayHit {
+        RayHit {
+            hit: true,
+            index: 0,
+            pos,
+            normal,
+            depth,
+            steps: 0,
+            exhausted: false,
+        }
+    }
+
+    #[test]
+    fn bevels_only_tilt_near_edges() {
+        let up = Vector3::unit_y();
+        let middle = face_hit(Vector3::new(-0.5, 0.0, -0.5), up, 1);
+        assert_eq!(bevel_normal(&middle, 0.1), up);
+
+        let edge = face_hit(Vector3::new(-0.98, 0.0, -0.5), up, 1);
+        assert_eq!(bevel_normal(&edge, 0.0), up);
+        let normal = bevel_normal(&edge, 0.1);
+        assert!(normal.x < 0.0 && normal.y > 0.0 && normal.z == 0.0);
+        assert!((normal.magnitude() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn checkerboard_alternates_cells() {
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.background = [0.2, 0.2, 0.2, 0.0];
+        uniforms.checker_colour = [0.4, 0.4, 0.4, 0.0];
+        let (first, second) = (Vector2::new(4.0, 4.0), Vector2::new(12.0, 4.0));
+        assert_eq!(background_colour(&uniforms, second), [0.2; 3]);
+
+        uniforms.checker_size = 8.0;
+        assert_eq!(background_colour(&uniforms, first), [0.2; 3]);
+        assert_eq!(background_colour(&uniforms, second), [0.4; 3]);
+        assert_eq!(
+            background_colour(&uniforms, Vector2::new(12.0, 12.0)),
+            [0.2; 3]
+        );
+    }
+
+    #[test]
+    fn tops_are_lighter_than_bottoms() {
+        assert_eq!(face_tint(Vector3::unit_y(), 0.0), 1.0);
+        assert!((face_tint(Vector3::unit_y(), 1.0) - 1.2).abs() < 1e-6);
+        assert!((face_tint(-Vector3::unit_y(), 1.0) - 0.8).abs() < 1e-6);
+        assert!((face_tint(Vector3::unit_x(), 0.5) - 0.95).abs() < 1e-6);
+    }
+
+    #[test]
+    fn camera_rays_start_at_the_camera() {
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.camera_inverse = Matrix4::from_translation(Vector3::new(0.0, 2.0, 0.0)).into();
+        let (pos, dir) = camera_ray(&uniforms, Vector2::new(0.0, 0.0));
+        assert_eq!(pos, Vector3::new(0.0, 2.0, 0.0));
+        assert_eq!(dir, Vector3::unit_z());
+    }
+
+    // Depth 3 cells by x, y, z index
+    fn filled(cells: impl Iterator<Item = (i32, i32, i32)>) -> Octree {
+        let mut cpu_octree = CpuOctree::new(0);
+        for cell in cells {
+            let pos = Vector3::new(cell.0, cell.1, cell.2).map(|c| c as f32 * 0.25 - 0.875);
+            cpu_octree.set_voxel(pos, 0xFFFFFF, 3);
+        }
+        cpu_octree.to_octree().unwrap()
+    }
+
+    #[test]
+    fn occlusion_only_comes_from_nearby_voxels() {
+        // Floor with one voxel sitting on it
+        let octree = filled(
+            (0..64)
+                .map(|i| (i % 8, 0, i / 8))
+                .chain(std::iter::once((4, 1, 4))),
+        );
+        let mut ctx = context(&octree);
+        ctx.ao_rays = 16;
+
+        let beside = octree.trace(Vector3::new(0.375, 0.9, 0.125), -Vector3::unit_y());
+        let open = octree.trace(Vector3::new(-0.625, 0.9, -0.625), -Vector3::unit_y());
+        assert!(beside.hit && open.hit);
+        let ao = ambient_occlusion(&beside, &ctx);
+        assert!(ao > 0.0 && ao < 1.0);
+        assert_eq!(ambient_occlusion(&open, &ctx), 0.0);
+
+        ctx.ao_rays = 0;
+        assert_eq!(ambient_occlusion(&beside, &ctx), 0.0);
+    }
+
+    #[test]
+    fn open_floors_see_the_sun() {
+        let octree = floor_and_block();
+        let hit = octree.trace(Vector3::new(-0.625, 0.9, -0.625), -Vector3::unit_y());
+        let mut ctx = context(&octree);
+        assert_eq!(sun_visibility(&hit, ctx.sun_dir, &ctx), 1.0);
+        ctx.sun_radius = 0.0;
+        assert_eq!(sun_visibility(&hit, ctx.sun_dir, &ctx), 1.0);
+    }
+
+    #[test]
+    fn smooth_normals_round_off_edges() {
+        let octree = floor_and_block();
+        let block = octree.trace(Vector3::new(0.125, 0.9, 0.125), -Vector3::unit_y());
+        assert_eq!(smooth_normal(&block, &octree), Vector3::unit_y());
+
+        // Top of the last column of a two layer slab over the negative x half
+        let octree = filled((0..64).map(|i| (i % 4, i / 32, i / 4 % 8)));
+        let edge = octree.trace(Vector3::new(-0.125, 0.9, -0.125), -Vector3::unit_y());
+        assert_eq!((edge.depth, edge.normal), (3, Vector3::unit_y()));
+        let normal = smooth_normal(&edge, &octree);
+        let expected = Vector3::new(1.0, 1.0, 0.0).normalize();
+        assert!((normal - expected).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn fog_thickens_with_distance() {
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.fog_colour = [1.0, 1.0, 1.0, 0.0];
+        uniforms.fog_density = 0.5;
+        let colour = [0.2, 0.4, 0.0];
+        assert_eq!(apply_fog(colour, 4.0, &uniforms), colour);
+
+        uniforms.fog = true;
+        assert_eq!(apply_fog(colour, 0.0, &uniforms), colour);
+        let amount = 1.0 - (-2.0f32).exp();
+        let fogged = apply_fog(colour, 4.0, &uniforms);
+        for i in 0..3 {
+            assert!((fogged[i] - (colour[i] + (1.0 - colour[i]) * amount)).abs() < 1e-6);
+        }
+        assert!(apply_fog(colour, 1000.0, &uniforms)
+            .iter()
+            .all(|c| (c - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn node_dumps_render_identically() {
+        let mut octree = floor_and_block();
+        // A hole in the buffer has to come back as a hole
+        let (node, _, _) = octree.find_voxel(Vector3::new(0.125, 0.375, 0.125), Some(2));
+        octree.unsubdivide(node).unwrap();
+        let path = std::env::temp_dir().join(format!("render_dump_{}.bin", std::process::id()));
+        octree.dump_nodes(&path).unwrap();
+        let loaded = Octree::load_nodes(&path);
+        std::fs::remove_file(&path).ok();
+        let loaded = loaded.unwrap();
+
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.camera_inverse = (Matrix4::from_translation(Vector3::new(0.2, 0.6, -2.5))
+            * Matrix4::from_angle_x(Deg(20.0)))
+        .into();
+        uniforms.sun_dir = [-0.3, -1.0, 0.2, 0.0];
+        uniforms.sun_enabled = true;
+        uniforms.shadows = true;
+        uniforms.background = [0.1, 0.2, 0.3, 0.0];
+
+        let image = trace_cpu(&octree, &uniforms, [16, 16], &DiffuseShader);
+        assert_eq!(
+            trace_cpu(&loaded, &uniforms, [16, 16], &DiffuseShader),
+            image
+        );
+        let misses = image.iter().filter(|c| **c == [0.1, 0.2, 0.3]).count();
+        assert!(misses > 0 && misses < image.len());
+    }
+
+    #[test]
+    fn exported_lods_render_like_the_live_view() {
+        let mut octree = floor_and_block();
+        let (node, _, _) = octree.find_voxel(Vector3::new(0.125, 0.375, 0.125), Some(2));
+        octree.unsubdivide(node).unwrap();
+        assert!(!octree.hole_stack.is_empty());
+        let path = std::env::temp_dir().join(format!("render_lod_{}.svot", std::process::id()));
+        octree.export_lod(&path).unwrap();
+        let data = std::fs::read(&path);
+        std::fs::remove_file(&path).ok();
+        let lod = Octree::from_svot_bytes(&data.unwrap()).unwrap();
+        assert!(lod.raw_data().len() < octree.raw_data().len());
+
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.camera_inverse = (Matrix4::from_translation(Vector3::new(-0.3, 0.8, -2.5))
+            * Matrix4::from_angle_x(Deg(25.0)))
+        .into();
+        uniforms.sun_dir = [0.4, -1.0, 0.3, 0.0];
+        uniforms.sun_enabled = true;
+        uniforms.shadows = true;
+        uniforms.ao = true;
+        uniforms.ao_strength = 0.5;
+        uniforms.ao_rays = 4;
+        uniforms.background = [0.1, 0.2, 0.3, 0.0];
+
+        let live = trace_cpu(&octree, &uniforms, [16, 16], &DiffuseShader);
+        let exported = trace_cpu(&lod, &uniforms, [16, 16], &DiffuseShader);
+        for (a, b) in live.iter().zip(&exported) {
+            assert!(
+                (0..3).all(|c| (a[c] - b[c]).abs() < 1e-4),
+                "{:?} {:?}",
+                a,
+                b
+            );
+        }
+        assert!(live.iter().any(|c| *c != [0.1, 0.2, 0.3]));
+    }
+
+    #[test]
+    fn only_edges_get_extra_samples() {
+        // Two quads of a 4x2 image, the right one straddling a step in depth
+        let up = Vector3::unit_y();
+        let depths = [2.0, 2.0, 2.0, 3.0, 2.0, 2.0, 2.0, 3.0];
+        let normals = [up; 8];
+        assert_eq!(
+            edge_samples(&depths, &normals, [4, 2], 4),
+            [1, 1, 4, 4, 1, 1, 4, 4]
+        );
+        assert_eq!(edge_samples(&depths, &normals, [4, 2], 1), [1; 8]);
+
+        // Small changes in depth don't count but any in the normal do
+        let depths = [2.0, 2.01, 2.0, 2.0, 2.0, 2.01, 2.0, 2.0];
+        let mut normals = [up; 8];
+        normals[6] = Vector3::unit_x();
+        // Diagonal pixels aren't compared
+        assert_eq!(
+            edge_samples(&depths, &normals, [4, 2], 4),
+            [1, 1, 4, 1, 1, 1, 4, 4]
+        );
+
+        // A pixel without a quad partner only compares along the other axis
+        let depths = [2.0, 1000.0, 2.0];
+        assert_eq!(edge_samples(&depths, &[up; 3], [3, 1], 8), [8, 8, 1]);
+    }
+
+    #[test]
+    fn debug_views_apply_brightness_then_gamma() {
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.debug_brightness = 1.0;
+        uniforms.debug_gamma = 1.0;
+        assert_eq!(debug_adjust(0.25, &uniforms), [0.25; 3]);
+        assert_eq!(debug_adjust(-1.0, &uniforms), [0.0; 3]);
+
+        uniforms.debug_brightness = 2.0;
+        assert_eq!(debug_adjust(0.25, &uniforms), [0.5; 3]);
+        assert_eq!(debug_adjust(0.75, &uniforms), [1.0; 3]);
+        uniforms.debug_gamma = 2.0;
+        assert_eq!(debug_adjust(0.125, &uniforms), [0.5; 3]);
+
+        // The step view shows misses too
+        uniforms.show_steps = true;
+        uniforms.camera_inverse = Matrix4::from_translation(Vector3::new(0.0, 0.0, -3.0)).into();
+        let empty = Octree::new([Voxel::new(0, 0, 0); 8]);
+        let steps = empty
+            .trace(Vector3::new(0.0, 0.0, -3.0), Vector3::unit_z())
+            .steps;
+        let expected = debug_adjust(steps as f32 / 64.0, &uniforms);
+        assert_eq!(
+            trace_cpu(&empty, &uniforms, [1, 1], &DiffuseShader),
+            [expected]
+        );
+        assert!(expected[0] > 0.0);
+    }
+
+    // Same as `lod_wanted` in shader.wgsl, adaptive counting only runs on the gpu
+    fn lod_wanted(node_pos: Vector3<f32>, depth: u32, uniforms: &render::Uniforms) -> bool {
+        let eye = Matrix4::from(uniforms.camera_inverse) * Vector4::new(0.0, 0.0, 0.0, 1.0);
+        let distance = (node_pos - eye.truncate() / eye.w).magnitude();
+        let node_size = 2.0 / (1u32 << depth) as f32;
+        let threshold =
+            uniforms.dimensions[2] * ((uniforms.distance_lod_bias * distance).exp2() - 1.0);
+        node_size > distance * threshold
+    }
+
+    #[test]
+    fn distance_bias_limits_the_counted_depth() {
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.camera_inverse = Matrix4::identity().into();
+        uniforms.dimensions = [800.0, 600.0, 0.001, 0.0];
+
+        // (distance, bias, deepest depth still counted)
+        let table = [
+            (1.0, 0.0, MAX_PRECISE_DEPTH),
+            (8.0, 0.0, MAX_PRECISE_DEPTH),
+            (1.0, 1.0, 10),
+            (2.0, 1.0, 8),
+            (4.0, 1.0, 5),
+            (2.0, 0.5, 9),
+        ];
+        for (distance, bias, deepest) in table {
+            uniforms.distance_lod_bias = bias;
+            let node_pos = Vector3::new(0.0, 0.0, distance);
+            let counted = (1..=MAX_PRECISE_DEPTH)
+                .take_while(|&depth| lod_wanted(node_pos, depth, &uniforms))
+                .last();
+            assert_eq!(
+                counted,
+                Some(deepest),
+                "distance {} bias {}",
+                distance,
+                bias
+            );
+        }
+    }
+
+    #[test]
+    fn empty_cells_are_tinted_by_depth() {
+        assert_eq!(empty_colour(0)[0], 1.0);
+        assert!((empty_colour(3)[0] - 0.0).abs() < 1e-4);
+        // The hue comes round again every 6 levels
+        for c in 0..3 {
+            assert!((empty_colour(1)[c] - empty_colour(7)[c]).abs() < 1e-4);
+        }
+
+        // Through two empty cells at depth 2 then one at depth 1 before leaving the tree
+        let empty = Voxel::new(0, 0, 0);
+        let mut octree = Octree::new([empty; 8]);
+        octree.subdivide(6, [empty; 8], 2).unwrap();
+        let mut depths = Vec::new();
+        let origin = Vector3::new(0.25, 0.25, -3.0);
+        let hit = octree.trace_visiting(origin, Vector3::unit_z(), &mut |depth| depths.push(depth));
+        assert!(!hit.hit);
+        assert_eq!(depths, [2, 2, 1]);
+
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.camera_inverse = Matrix4::from_translation(origin).into();
+        uniforms.background = [0.1, 0.2, 0.3, 0.0];
+        assert_eq!(
+            trace_cpu(&octree, &uniforms, [1, 1], &DiffuseShader),
+            [[0.1, 0.2, 0.3]]
+        );
+
+        uniforms.show_empty = true;
+        let colour = trace_cpu(&octree, &uniforms, [1, 1], &DiffuseShader)[0];
+        for c in 0..3 {
+            let expected =
+                [0.1, 0.2, 0.3][c] + 0.02 * (2.0 * empty_colour(2)[c] + empty_colour(1)[c]);
+            assert!((colour[c] - expected).abs() < 1e-5, "{:?}", colour);
+        }
+    }
+
+    // Same as `slab_entry` in shader.wgsl
+    fn slab_entry(
+        pos: Vector3<f32>,
+        dir: Vector3<f32>,
+        uniforms: &render::Uniforms,
+    ) -> Vector2<f32> {
+        let normal = Vector4::from(uniforms.slab_normal).truncate().normalize();
+        let d = normal.dot(pos) - uniforms.slab_center;
+        let speed = normal.dot(dir);
+        let half = uniforms.slab_thickness / 2.0;
+        if speed.abs() < 0.000001 {
+            if d.abs() <= half {
+                return Vector2::new(0.0, 1000.0);
+            }
+            return Vector2::new(1.0, 0.0);
+        }
+
+        let t1 = (-half - d) / speed;
+        let t2 = (half - d) / speed;
+        Vector2::new(t1.min(t2).max(0.0), t1.max(t2))
+    }
+
+    // Same as `voxel_in_slab` in shader.wgsl
+    fn voxel_in_slab(pos: Vector3<f32>, depth: u32, uniforms: &render::Uniforms) -> bool {
+        let normal = Vector4::from(uniforms.slab_normal).truncate().normalize();
+        let half_size = 1.0 / (1u32 << depth) as f32;
+        let extent = half_size * normal.map(f32::abs).sum();
+        (normal.dot(pos) - uniforms.slab_center).abs() <= uniforms.slab_thickness / 2.0 + extent
+    }
+
+    #[test]
+    fn rays_enter_the_slab_between_its_planes() {
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        // Only the direction of the normal counts
+        uniforms.slab_normal = [0.0, 2.0, 0.0, 0.0];
+        uniforms.slab_thickness = 0.5;
+        let inside = |range: Vector2<f32>| range.x <= range.y;
+
+        // Parallel rays are inside all the way or not at all, tangent ones count as inside
+        let along = Vector3::unit_x();
+        assert_eq!(
+            slab_entry(Vector3::zero(), along, &uniforms),
+            Vector2::new(0.0, 1000.0)
+        );
+        assert_eq!(
+            slab_entry(Vector3::new(0.0, 0.25, 0.0), along, &uniforms),
+            Vector2::new(0.0, 1000.0)
+        );
+        assert!(!inside(slab_entry(
+            Vector3::new(0.0, 0.5, 0.0),
+            along,
+            &uniforms
+        )));
+
+        // From above, from inside and pointing away
+        let down = -Vector3::unit_y();
+        let above = Vector3::new(0.0, 1.0, 0.0);
+        assert_eq!(slab_entry(above, down, &uniforms), Vector2::new(0.75, 1.25));
+        assert_eq!(
+            slab_entry(Vector3::zero(), -down, &uniforms),
+            Vector2::new(0.0, 0.25)
+        );
+        assert!(!inside(slab_entry(above, -down, &uniforms)));
+
+        // Moving the centre moves the entry
+        uniforms.slab_center = 0.5;
+        assert_eq!(slab_entry(above, down, &uniforms), Vector2::new(0.25, 0.75));
+    }
+
+    #[test]
+    fn voxels_touching_the_slab_are_kept() {
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.slab_normal = [0.0, 1.0, 0.0, 0.0];
+        uniforms.slab_thickness = 0.5;
+
+        // Depth 3 voxels are 0.25 across
+        assert!(voxel_in_slab(Vector3::new(0.6, 0.125, -0.3), 3, &uniforms));
+        assert!(voxel_in_slab(Vector3::new(0.0, -0.375, 0.0), 3, &uniforms));
+        assert!(!voxel_in_slab(Vector3::new(0.0, 0.625, 0.0), 3, &uniforms));
+        // Bigger voxels reach further
+        assert!(voxel_in_slab(Vector3::new(0.0, 0.5, 0.0), 2, &uniforms));
+
+        // A tilted slab reaches out to the corner of the voxel, 0.125 * sqrt(2) past its centre
+        uniforms.slab_normal = [1.0, 1.0, 0.0, 0.0];
+        assert!(voxel_in_slab(Vector3::new(0.3, 0.3, 0.0), 3, &uniforms));
+        assert!(!voxel_in_slab(Vector3::new(0.32, 0.32, 0.0), 3, &uniforms));
+    }
+
+    // Unlit leaf colours at half coverage
+    struct GlassShader;
+
+    impl VoxelShader for GlassShader {
+        fn shade(&self, hit: &RayHit, ctx: &ShadeContext) -> [f32; 3] {
+            ctx.colour(hit)
+        }
+
+        fn alpha(&self, _hit: &RayHit, _ctx: &ShadeContext) -> f32 {
+            0.5
+        }
+    }
+
+    #[test]
+    fn peeled_layers_blend_front_to_back() {
+        let mut peel = Peel::default();
+        peel.add([1.0, 0.0, 0.0], 0.5);
+        peel.add([0.0, 1.0, 0.0], 0.5);
+        assert_eq!((peel.coverage, peel.done), (0.75, false));
+        peel.add([0.0, 0.0, 1.0], 1.0);
+        assert_eq!(peel.colour, [0.5, 0.25, 0.25]);
+        assert_eq!((peel.coverage, peel.done), (1.0, true));
+
+        // A row of four voxels straight ahead, red nearest
+        let mut cpu_octree = CpuOctree::new(0);
+        for (i, colour) in [0xFF0000, 0x00FF00, 0x0000FF, 0xFFFFFF].iter().enumerate() {
+            let pos = Vector3::new(0.25, 0.25, i as f32 * 0.5 - 0.75);
+            cpu_octree.set_voxel(pos, *colour, 2);
+        }
+        let octree = cpu_octree.to_octree().unwrap();
+
+        let mut uniforms: render::Uniforms = bytemuck::Zeroable::zeroed();
+        uniforms.camera_inverse = Matrix4::from_translation(Vector3::new(0.3, 0.2, -3.0)).into();
+        uniforms.background = [0.1, 0.2, 0.3, 0.0];
+        uniforms.transparency_layers = 3;
+        let trace =
+            |uniforms: &render::Uniforms| trace_cpu(&octree, uniforms, [1, 1], &GlassShader)[0];
+        assert_eq!(trace(&uniforms), [1.0, 0.0, 0.0]);
+
+        // One pass per layer and the last one opaque
+        uniforms.depth_peel = true;
+        assert_eq!(trace(&uniforms), [0.625, 0.375, 0.25]);
+        uniforms.transparency_layers = 1;
+        assert_eq!(trace(&uniforms), [0.5, 0.5, 0.0]);
+
+        // Past the last voxel the background shows through
+        uniforms.transparency_layers = 8;
+        let behind = 0.0625;
+        assert_eq!(
+            trace(&uniforms),
+            [
+                0.5 + behind + behind * 0.1,
+                0.25 + behind + behind * 0.2,
+                0.125 + behind + behind * 0.3
+            ]
+        );
+    }
+}
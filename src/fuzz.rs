@@ -0,0 +1,132 @@
+use super::*;
+
+const FUZZ_SEED: u64 = 0x5eed;
+const FUZZ_ITERATIONS: usize = 2000;
+// Longest one input may take to load, a debug build spending more is stuck on bad sizes
+const FUZZ_INPUT_BUDGET: std::time::Duration = std::time::Duration::from_secs(2);
+// Formats `CpuOctree::load_bytes` dispatches on
+const FUZZ_EXTENSIONS: [&str; 9] = [
+    "rsvo",
+    "vox",
+    "qb",
+    "schematic",
+    "ply",
+    "obj",
+    "bin",
+    "svot",
+    "raw",
+];
+
+/// Small xorshift so runs are repeatable from the seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+/// Buffer that gets past the first checks of a format some of the time
+fn fuzz_input(rng: &mut Rng, extension: &str) -> Vec<u8> {
+    let mut data = match (extension, rng.below(3)) {
+        (_, 0) => Vec::new(),
+        ("rsvo", _) => {
+            let mut data = rng.bytes(16);
+            data.push(rng.below(8) as u8);
+            data.extend(rng.bytes(3));
+            data
+        }
+        ("qb", _) => {
+            // Header with one small matrix, compressed or not
+            let mut data = Vec::new();
+            for value in [0x0101, 0, 0, rng.below(2) as u32, 0, 1] {
+                data.extend(value.to_le_bytes());
+            }
+            data.push(0);
+            for value in [4u32, 4, 4, 0, 0, 0] {
+                data.extend(value.to_le_bytes());
+            }
+            data
+        }
+        ("ply", _) => {
+            let format = ["ascii", "binary_little_endian", "binary_big_endian"][rng.below(3)];
+            format!(
+                "ply\nformat {} 1.0\nelement vertex {}\nproperty float x\nproperty float y\nproperty float z\nproperty uchar red\nproperty uchar green\nproperty uchar blue\nend_header\n",
+                format,
+                rng.below(64)
+            )
+            .into_bytes()
+        }
+        ("vox", _) => {
+            let mut data = b"VOX ".to_vec();
+            data.extend(150u32.to_le_bytes());
+            data
+        }
+        ("bin", _) => {
+            // A real tree with some bytes flipped, so it gets past the length check
+            let mut octree = CpuOctree::new(rng.below(256) as u8);
+            octree.set_voxel(
+                Vector3::new(0.3, -0.2, 0.6),
+                0xFF8000,
+                1 + rng.below(4) as u32,
+            );
+            let mut data = octree.bin();
+            for _ in 0..rng.below(4) {
+                let i = rng.below(data.len());
+                data[i] = rng.next() as u8;
+            }
+            return data;
+        }
+        _ => Vec::new(),
+    };
+
+    let len = rng.below(512);
+    data.extend(rng.bytes(len));
+    data
+}
+
+#[test]
+fn loaders_reject_random_input_without_panicking() {
+    let mut rng = Rng(FUZZ_SEED);
+    let (mut loaded, mut errored) = (0, 0);
+    for i in 0..FUZZ_ITERATIONS {
+        for extension in FUZZ_EXTENSIONS {
+            let data = fuzz_input(&mut rng, extension);
+            let start = Instant::now();
+            let result = std::panic::catch_unwind(|| {
+                CpuOctree::load_bytes(&data, extension, (i % 8) as u32, i % 2 == 0).is_ok()
+            });
+
+            match result {
+                Ok(true) => loaded += 1,
+                Ok(false) => errored += 1,
+                Err(_) => panic!(
+                    "{} loader panicked on iteration {} ({} bytes)",
+                    extension,
+                    i,
+                    data.len()
+                ),
+            }
+            assert!(
+                start.elapsed() < FUZZ_INPUT_BUDGET,
+                "{} loader took {:?} on iteration {} ({} bytes)",
+                extension,
+                start.elapsed(),
+                i,
+                data.len()
+            );
+        }
+    }
+    assert_eq!(loaded + errored, FUZZ_ITERATIONS * FUZZ_EXTENSIONS.len());
+}
@@ -0,0 +1,255 @@
+// Most pixels decoded, twice an 8k by 4k panorama
+const MAX_HDR_PIXELS: usize = 64 * 1024 * 1024;
+
+/// Decodes a Radiance RGBE image into linear rgb, top row first.
+/// Handles flat and run length encoded scanlines in the usual `-Y h +X w` orientation.
+pub fn load_hdr(data: &[u8]) -> Result<([u32; 2], Vec<[f32; 3]>), String> {
+    let mut offset = 0;
+    let mut line = || -> Result<String, String> {
+        let end = data[offset..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or("Unexpected end of header")?;
+        let line = String::from_utf8_lossy(&data[offset..offset + end]).to_string();
+        offset += end + 1;
+        Ok(line)
+    };
+
+    let magic = line()?;
+    if !magic.starts_with("#?RADIANCE") && !magic.starts_with("#?RGBE") {
+        return Err("Not a Radiance hdr file".to_string());
+    }
+    loop {
+        let header = line()?;
+        if header.is_empty() {
+            break;
+        }
+        if let Some(format) = header.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(format!("Unsupported hdr format {}", format));
+            }
+        }
+    }
+
+    let resolution = line()?;
+    let parts: Vec<&str> = resolution.split_whitespace().collect();
+    let (height, width) = match parts[..] {
+        ["-Y", height, "+X", width] => (
+            height.parse::<u32>().map_err(|e| e.to_string())?,
+            width.parse::<u32>().map_err(|e| e.to_string())?,
+        ),
+        _ => return Err(format!("Unsupported hdr orientation {}", resolution)),
+    };
+    let pixel_count = width as usize * height as usize;
+    if pixel_count == 0 || pixel_count > MAX_HDR_PIXELS {
+        return Err(format!("Bad hdr size {}x{}", width, height));
+    }
+
+    let mut pixels = Vec::with_capacity(pixel_count);
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    for _ in 0..height {
+        read_scanline(data, &mut offset, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_rgb(rgbe)));
+    }
+
+    Ok(([width, height], pixels))
+}
+
+fn read_scanline(data: &[u8], offset: &mut usize, scanline: &mut [[u8; 4]]) -> Result<(), String> {
+    let mut byte = || -> Result<u8, String> {
+        let b = *data
+            .get(*offset)
+            .ok_or(format!("Unexpected end of file at byte {}", offset))?;
+        *offset += 1;
+        Ok(b)
+    };
+
+    let width = scanline.len();
+    let start = [byte()?, byte()?, byte()?, byte()?];
+    let encoded =
+        start[0] == 2 && start[1] == 2 && start[2] & 0x80 == 0 && (8..0x8000).contains(&width);
+    if !encoded {
+        // Flat pixels, the four bytes already read are the first one
+        scanline[0] = start;
+        for pixel in scanline.iter_mut().skip(1) {
+            *pixel = [byte()?, byte()?, byte()?, byte()?];
+        }
+        return Ok(());
+    }
+
+    if ((start[2] as usize) << 8 | start[3] as usize) != width {
+        return Err("Hdr scanline width doesn't match the image".to_string());
+    }
+
+    // Each channel is run length encoded separately
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = byte()? as usize;
+            if count > 128 {
+                let count = count - 128;
+                if x + count > width {
+                    return Err("Hdr run goes past the end of a scanline".to_string());
+                }
+                let value = byte()?;
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[channel] = value;
+                }
+                x += count;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err("Bad hdr run length".to_string());
+                }
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[channel] = byte()?;
+                }
+                x += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn rgbe_to_rgb(rgbe: [u8; 4]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+    let scale = 2f32.powi(rgbe[3] as i32 - 136);
+    [
+        rgbe[0] as f32 * scale,
+        rgbe[1] as f32 * scale,
+        rgbe[2] as f32 * scale,
+    ]
+}
+
+/// Half float bits of `value`, clamped to the largest half and flushing tiny values to 0
+pub fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let value = value.abs();
+    if value.is_nan() {
+        return 0x7e00;
+    }
+    if value >= 65504.0 {
+        return sign | 0x7bff;
+    }
+    if value < 6.1035156e-5 {
+        return sign;
+    }
+
+    let bits = value.to_bits();
+    let exponent = ((bits >> 23) as i32 - 127 + 15) as u16;
+    let mantissa = ((bits >> 13) & 0x3ff) as u16;
+    sign | exponent << 10 | mantissa
+}
+
+/// Box filtered mip chain of an rgb image, as rgba16 float texture data with the full size
+/// level first. Returns the number of levels and the data
+pub fn hdr_mips(size: [u32; 2], pixels: &[[f32; 3]]) -> (u32, Vec<u16>) {
+    let mut levels = 1;
+    let mut data = Vec::new();
+    let mut size = size;
+    let mut level = pixels.to_vec();
+    loop {
+        for pixel in &level {
+            data.extend([
+                f16_bits(pixel[0]),
+                f16_bits(pixel[1]),
+                f16_bits(pixel[2]),
+                0x3c00,
+            ]);
+        }
+        if size[0] == 1 && size[1] == 1 {
+            break;
+        }
+
+        let next_size = [(size[0] / 2).max(1), (size[1] / 2).max(1)];
+        let mut next = Vec::with_capacity((next_size[0] * next_size[1]) as usize);
+        for y in 0..next_size[1] {
+            for x in 0..next_size[0] {
+                let mut sum = [0.0; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (x * 2 + dx).min(size[0] - 1);
+                    let sy = (y * 2 + dy).min(size[1] - 1);
+                    let pixel = level[(sy * size[0] + sx) as usize];
+                    for c in 0..3 {
+                        sum[c] += pixel[c] * 0.25;
+                    }
+                }
+                next.push(sum);
+            }
+        }
+
+        level = next;
+        size = next_size;
+        levels += 1;
+    }
+
+    (levels, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(resolution: &str) -> Vec<u8> {
+        format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", resolution).into_bytes()
+    }
+
+    #[test]
+    fn flat_pixels() {
+        let mut data = header("-Y 1 +X 2");
+        data.extend([128, 64, 32, 129, 0, 0, 0, 0]);
+
+        let (size, pixels) = load_hdr(&data).unwrap();
+        assert_eq!(size, [2, 1]);
+        assert_eq!(pixels, vec![[1.0, 0.5, 0.25], [0.0; 3]]);
+    }
+
+    #[test]
+    fn run_length_encoded_scanline() {
+        let mut data = header("-Y 1 +X 8");
+        data.extend([2, 2, 0, 8]);
+        for value in [128, 64, 32, 129] {
+            data.extend([128 + 8, value]);
+        }
+
+        let (size, pixels) = load_hdr(&data).unwrap();
+        assert_eq!(size, [8, 1]);
+        assert!(pixels.iter().all(|p| *p == [1.0, 0.5, 0.25]));
+    }
+
+    #[test]
+    fn huge_sizes_are_rejected_without_overflowing() {
+        assert!(load_hdr(&header("-Y 65536 +X 65536")).is_err());
+        assert!(load_hdr(&header("-Y 4294967295 +X 4294967295")).is_err());
+    }
+
+    #[test]
+    fn truncated_data_errors() {
+        let mut data = header("-Y 2 +X 2");
+        data.extend([128, 64, 32, 129]);
+        assert!(load_hdr(&data).is_err());
+    }
+
+    #[test]
+    fn f16_bits_matches_known_halves() {
+        assert_eq!(f16_bits(0.0), 0);
+        assert_eq!(f16_bits(1.0), 0x3c00);
+        assert_eq!(f16_bits(-2.0), 0xc000);
+        assert_eq!(f16_bits(0.5), 0x3800);
+        assert_eq!(f16_bits(65504.0), 0x7bff);
+        assert_eq!(f16_bits(1e10), 0x7bff);
+        assert_eq!(f16_bits(1e-8), 0);
+        assert_eq!(f16_bits(f32::NAN), 0x7e00);
+    }
+
+    #[test]
+    fn mips_go_down_to_one_pixel() {
+        let (levels, data) = hdr_mips([4, 2], &[[1.0, 0.0, 0.0]; 8]);
+        assert_eq!(levels, 3);
+        // 4x2, 2x1 and 1x1 rgba pixels
+        assert_eq!(data.len(), (8 + 2 + 1) * 4);
+        assert_eq!(data[data.len() - 4..], [0x3c00, 0, 0, 0x3c00]);
+    }
+}
@@ -0,0 +1,184 @@
+use super::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+
+/// What the models of a finished `FileLoad` are for
+#[derive(Copy, Clone, Debug)]
+pub enum LoadKind {
+    // Replaces the current model
+    Open,
+    // Merged onto the current model with the composite
+    Merge(Composite),
+    // Combined with the current model by a set operation
+    Csg(Csg),
+    // Laid out side by side as a gallery
+    Gallery,
+    // Every .vox file in the one path, a directory, played as an animation
+    Frames,
+}
+
+/// Models from a finished `FileLoad`
+pub struct Loaded {
+    pub models: Vec<CpuOctree>,
+    // Deepest the file can be loaded at, only read when opening one
+    pub file_depth: Option<u32>,
+}
+
+/// Files being parsed on a worker thread so the window keeps drawing while they load
+pub struct FileLoad {
+    pub paths: Vec<String>,
+    pub depth: u32,
+    pub kind: LoadKind,
+    // Paths that came from the model cache instead of the worker
+    pub cached: Vec<bool>,
+    // Percent done of the file being parsed, written by the loader
+    progress: Arc<AtomicU32>,
+    // Files finished so far
+    done: Arc<AtomicU32>,
+    receiver: Receiver<Result<Loaded, String>>,
+}
+
+impl FileLoad {
+    /// Loads every path without a model in `cached` at `depth`, keeping the order of `paths`.
+    /// Opening a file also reads how deep it goes, and frames load the directory in `paths`
+    pub fn spawn(
+        paths: Vec<String>,
+        cached: Vec<Option<CpuOctree>>,
+        depth: u32,
+        solid: bool,
+        kind: LoadKind,
+    ) -> Self {
+        let progress = Arc::new(AtomicU32::new(0));
+        let done = Arc::new(AtomicU32::new(0));
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        let is_cached = cached.iter().map(Option::is_some).collect();
+        let (thread_paths, thread_progress, thread_done) =
+            (paths.clone(), progress.clone(), done.clone());
+        std::thread::spawn(move || {
+            let file_depth = match kind {
+                LoadKind::Open => CpuOctree::file_depth(&thread_paths[0]).ok(),
+                _ => None,
+            };
+            let models = match kind {
+                LoadKind::Frames => {
+                    let frames = CpuOctree::load_frames(&thread_paths[0]);
+                    thread_done.fetch_add(1, Ordering::Relaxed);
+                    frames
+                }
+                _ => thread_paths
+                    .into_iter()
+                    .zip(cached)
+                    .map(|(path, cached)| {
+                        let model = match cached {
+                            Some(model) => Ok(model),
+                            None => {
+                                CpuOctree::load_file_progress(path, depth, solid, &thread_progress)
+                            }
+                        };
+                        thread_progress.store(0, Ordering::Relaxed);
+                        thread_done.fetch_add(1, Ordering::Relaxed);
+                        model
+                    })
+                    .collect(),
+            };
+            let result = models.map(|models| Loaded { models, file_depth });
+            // Nobody is listening if the load was replaced by another
+            sender.send(result).ok();
+        });
+
+        Self {
+            paths,
+            depth,
+            kind,
+            cached: is_cached,
+            progress,
+            done,
+            receiver,
+        }
+    }
+
+    /// File name being loaded, or how many files for several
+    pub fn name(&self) -> String {
+        match &self.paths[..] {
+            [path] => std::path::Path::new(path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            paths => format!("{} files", paths.len()),
+        }
+    }
+
+    /// Fraction done from 0 to 1
+    pub fn progress(&self) -> f32 {
+        let file = self.progress.load(Ordering::Relaxed).min(100) as f32 / 100.0;
+        let done = self.done.load(Ordering::Relaxed) as f32;
+        ((done + file) / self.paths.len().max(1) as f32).min(1.0)
+    }
+
+    /// The loaded models or the first error once the worker has finished
+    pub fn poll(&self) -> Option<Result<Loaded, String>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(format!("Loading {} failed", self.name()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait(load: &FileLoad) -> Result<Loaded, String> {
+        loop {
+            if let Some(result) = load.poll() {
+                return result;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn cached_models_come_back_in_order() {
+        let models = vec![Some(CpuOctree::new(0b1)), Some(CpuOctree::new(0b11))];
+        let paths = vec!["a.vox".to_string(), "b.vox".to_string()];
+        let load = FileLoad::spawn(paths, models, 4, false, LoadKind::Gallery);
+        assert_eq!(load.name(), "2 files");
+
+        let models = wait(&load).unwrap().models;
+        assert!(models[0].get_node_mask(0) == CpuOctree::new(0b1).get_node_mask(0));
+        assert!(models[1].get_node_mask(0) == CpuOctree::new(0b11).get_node_mask(0));
+        assert_eq!(load.progress(), 1.0);
+    }
+
+    #[test]
+    fn missing_files_error() {
+        let paths = vec!["not a file.vox".to_string()];
+        let load = FileLoad::spawn(paths, vec![None], 4, false, LoadKind::Open);
+        assert_eq!(load.name(), "not a file.vox");
+        assert!(wait(&load).is_err());
+    }
+
+    #[test]
+    fn opened_files_send_their_depth() {
+        let paths = vec!["files/statuette.rsvo".to_string()];
+        let load = FileLoad::spawn(paths, vec![None], 4, false, LoadKind::Open);
+        let loaded = wait(&load).unwrap();
+        assert_eq!(loaded.file_depth, Some(13));
+        assert_eq!(loaded.models[0].depth(), 4);
+
+        let paths = vec!["files/statuette.rsvo".to_string()];
+        let load = FileLoad::spawn(paths, vec![None], 4, false, LoadKind::Gallery);
+        assert_eq!(wait(&load).unwrap().file_depth, None);
+    }
+
+    #[test]
+    fn frames_load_on_the_worker() {
+        let paths = vec!["src".to_string()];
+        let load = FileLoad::spawn(paths, vec![None], 4, false, LoadKind::Frames);
+        assert_eq!(wait(&load).err().unwrap(), "No .vox files in directory");
+        assert_eq!(load.progress(), 1.0);
+    }
+}
@@ -9,21 +9,49 @@ use winit::{
 
 mod adaptive;
 mod app;
+mod bloom;
+mod cache;
+mod capture;
 mod compute;
+mod config;
 mod cpu_octree;
+mod cpu_render;
+#[cfg(test)]
+mod fuzz;
 mod gpu;
+mod hdr;
+mod loader;
+mod material;
+mod metrics;
+mod morton;
+mod obj;
 mod octree;
+mod ply;
 mod procedural;
 mod render;
+mod schematic;
 mod world;
 use adaptive::*;
 use app::*;
+use bloom::*;
+use cache::*;
+use capture::*;
 use compute::*;
+use config::*;
 use cpu_octree::*;
+use cpu_render::*;
 use gpu::*;
+use hdr::*;
+use loader::*;
+use material::*;
+use metrics::*;
+use morton::*;
+use obj::*;
 use octree::*;
+use ply::*;
 use procedural::*;
 use render::*;
+use schematic::*;
 use world::*;
 
 #[tokio::main]
@@ -31,14 +59,25 @@ async fn main() {
     println!("octree-tracer v0.1.0");
 
     env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().build(&event_loop).unwrap();
+    let window_config = match WindowConfig::load(&args) {
+        Ok(window_config) => window_config,
+        Err(e) => {
+            eprintln!("Failed to read window config, using defaults: {}", e);
+            WindowConfig::default()
+        }
+    };
+    let window = window_config.window_builder().build(&event_loop).unwrap();
 
-    let mut app = pollster::block_on(App::new(&window));
+    // Prints a summary of the run on exit
+    let metrics = SessionMetrics::new(args.iter().any(|arg| arg == "--stats"));
+    let mut app = pollster::block_on(App::new(&window, metrics, window_config));
 
     let now = Instant::now();
     event_loop.run(move |event, _, control_flow| {
-        app.render.egui_platform.handle_event(&event);
+        app.render.handle_ui_event(&event);
         app.input(&window, &event);
         match event {
             Event::RedrawRequested(_) => {
@@ -83,6 +122,11 @@ async fn main() {
                     _ => {}
                 }
             }
+            Event::LoopDestroyed => {
+                if app.metrics.enabled {
+                    println!("{}", app.metrics.summary());
+                }
+            }
             _ => {}
         }
     });
@@ -95,7 +139,12 @@ pub struct Input {
     left: bool,
     up: bool,
     down: bool,
+    precise: bool,
+    // Scroll changes the fov instead of the speed while alt is held
+    zoom: bool,
     mouse_delta: Vector2<f32>,
+    cursor_pos: Vector2<f32>,
+    clicked: bool,
 }
 
 impl Input {
@@ -107,17 +156,83 @@ impl Input {
             left: false,
             up: false,
             down: false,
+            precise: false,
+            zoom: false,
             mouse_delta: Vector2::zero(),
+            cursor_pos: Vector2::zero(),
+            clicked: false,
         }
     }
 }
 
 pub struct Settings {
     octree_depth: u32,
+    // Upper bound of the depth slider, set from the loaded file
+    max_octree_depth: u32,
     fov: f32,
     sensitivity: f32,
+    // Movement and look scale while ctrl is held
+    precise_factor: f32,
+    // Physical edge length of the model cube in meters
+    model_size: f32,
+    show_scale_bar: bool,
+    // Playback speed of frame directories
+    frame_rate: f32,
+    // Multiplier on the egui scale and the crosshair
+    ui_scale: f32,
+    show_crosshair: bool,
+    crosshair_size: f32,
+    present_mode: wgpu::PresentMode,
+    show_gizmo: bool,
+    // Show positions as .vox grid cells at octree_depth instead of -1..1
+    grid_units: bool,
+    // Scroll can't take the speed outside these
+    min_speed: f32,
+    max_speed: f32,
+    // Saved world chunks this close to the camera are loaded ahead of time
+    preload_radius: f32,
+    // Fill the inside of .obj meshes when loading them
+    solid_meshes: bool,
+}
+
+const MIN_FOV: f32 = 0.01;
+const MAX_FOV: f32 = 100.0;
+const MIN_UI_SCALE: f32 = 0.5;
+const MAX_UI_SCALE: f32 = 3.0;
+
+impl Settings {
+    /// Scales the fov by `e^-amount`, kept within the fov slider's range
+    fn zoom(&mut self, amount: f32) {
+        self.fov = (self.fov * (-amount).exp()).clamp(MIN_FOV, MAX_FOV);
+    }
+
+    fn format_pos(&self, pos: Vector3<f32>) -> String {
+        if self.grid_units {
+            let cell = normalized_to_grid(pos, self.octree_depth);
+            format!("[{}, {}, {}]", cell.x, cell.y, cell.z)
+        } else {
+            format!("({:.4}, {:.4}, {:.4})", pos.x, pos.y, pos.z)
+        }
+    }
+
+    fn clamp_speed(&self, speed: f32) -> f32 {
+        speed.max(self.min_speed).min(self.max_speed)
+    }
+
+    /// Converts a distance in octree space, where the cube spans -1..1, to meters
+    fn to_physical(&self, distance: f32) -> f32 {
+        distance * self.model_size / 2.0
+    }
 }
 
+// Movement speed is e^speed octree units per second
+const SPEED_PRESETS: [(&str, f32); 4] = [
+    ("Slow", -8.0),
+    ("Normal", -5.0),
+    ("Fast", -2.0),
+    ("Warp", 1.0),
+];
+
 pub struct Character {
     pos: Point3<f32>,
     look: Vector3<f32>,
@@ -134,6 +249,30 @@ impl Character {
             speed: -5.0,
         }
     }
+
+    /// Step this frame for the held movement keys, relative to where the camera looks
+    fn movement(&self, input: &Input, scale: f32) -> Vector3<f32> {
+        let keys = Vector3::new(
+            input.right as u32 as f32 - input.left as u32 as f32,
+            input.up as u32 as f32 - input.down as u32 as f32,
+            input.forward as u32 as f32 - input.backward as u32 as f32,
+        ) * std::f32::consts::E.powf(self.speed)
+            * scale;
+
+        let forward: Vector3<f32> = self.look.normalize();
+        let right = forward.cross(Vector3::unit_y()).normalize();
+        let up = right.cross(forward);
+
+        forward * keys.z + right * keys.x + up * keys.y
+    }
+
+    /// Restores the startup view, keeping the cursor state
+    fn reset(&mut self) {
+        *self = Self {
+            cursour_grabbed: self.cursour_grabbed,
+            ..Self::new()
+        };
+    }
 }
 
 fn create_proj_matrix(fov: f32, aspect: f32) -> Matrix4<f32> {
@@ -160,3 +299,99 @@ fn create_proj_matrix(fov: f32, aspect: f32) -> Matrix4<f32> {
         1.0,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precise_movement_nudges_along_the_view() {
+        let mut character = Character::new();
+        character.look = Vector3::unit_z();
+        character.speed = 0.0;
+        let mut input = Input::new();
+        input.forward = true;
+
+        assert_eq!(character.movement(&input, 1.0), Vector3::unit_z());
+        let nudge = character.movement(&input, 0.1);
+        assert!((nudge - Vector3::unit_z() * 0.1).magnitude() < 1e-6);
+
+        input.forward = false;
+        input.up = true;
+        assert!((character.movement(&input, 1.0) - Vector3::unit_y()).magnitude() < 1e-6);
+    }
+
+    fn settings() -> Settings {
+        Settings {
+            octree_depth: 2,
+            max_octree_depth: 20,
+            fov: 90.0,
+            sensitivity: 0.00005,
+            precise_factor: 0.1,
+            model_size: 10.0,
+            show_scale_bar: false,
+            frame_rate: 10.0,
+            ui_scale: 1.0,
+            show_crosshair: true,
+            crosshair_size: 8.0,
+            present_mode: wgpu::PresentMode::Fifo,
+            show_gizmo: true,
+            grid_units: false,
+            min_speed: -12.0,
+            max_speed: 4.0,
+            preload_radius: 0.5,
+            solid_meshes: false,
+        }
+    }
+
+    #[test]
+    fn distances_scale_to_the_model_size() {
+        let settings = settings();
+        // The cube is 2 units across
+        assert_eq!(settings.to_physical(2.0), 10.0);
+        assert_eq!(settings.to_physical(0.5), 2.5);
+    }
+
+    #[test]
+    fn reset_view_keeps_the_cursor() {
+        let mut character = Character::new();
+        character.pos = Point3::new(5.0, 5.0, 5.0);
+        character.speed = 2.0;
+        character.cursour_grabbed = false;
+        character.reset();
+
+        assert_eq!(character.pos, Character::new().pos);
+        assert_eq!(character.speed, Character::new().speed);
+        assert!(!character.cursour_grabbed);
+    }
+
+    #[test]
+    fn positions_in_grid_units() {
+        let mut settings = settings();
+        let pos = Vector3::new(0.75, -0.75, 0.25);
+        assert_eq!(settings.format_pos(pos), "(0.7500, -0.7500, 0.2500)");
+
+        // .vox cells, x mirrored and y and z swapped
+        settings.grid_units = true;
+        assert_eq!(settings.format_pos(pos), "[0, 2, 0]");
+    }
+
+    #[test]
+    fn zoom_stays_in_range() {
+        let mut settings = settings();
+        settings.zoom(1.0);
+        assert!((settings.fov - 90.0 / std::f32::consts::E).abs() < 1e-4);
+        settings.zoom(-100.0);
+        assert_eq!(settings.fov, MAX_FOV);
+        settings.zoom(100.0);
+        assert_eq!(settings.fov, MIN_FOV);
+    }
+
+    #[test]
+    fn speed_stays_between_the_limits() {
+        let settings = settings();
+        assert_eq!(settings.clamp_speed(10.0), 4.0);
+        assert_eq!(settings.clamp_speed(-20.0), -12.0);
+        assert_eq!(settings.clamp_speed(1.0), 1.0);
+    }
+}
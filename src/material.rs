@@ -0,0 +1,144 @@
+use super::*;
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaterialKind {
+    Diffuse,
+    Metal,
+    Glass,
+    Emit,
+}
+
+/// Surface properties for a colour, parsed from MagicaVoxel's MATL chunks.
+/// Anything a file doesn't specify is diffuse and opaque.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Material {
+    pub kind: MaterialKind,
+    pub reflectivity: f32,
+    pub roughness: f32,
+    pub emission: f32,
+    pub alpha: f32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            kind: MaterialKind::Diffuse,
+            reflectivity: 0.0,
+            roughness: 1.0,
+            emission: 0.0,
+            alpha: 1.0,
+        }
+    }
+}
+
+impl Material {
+    pub fn from_properties(properties: &dot_vox::Dict) -> Self {
+        let get = |key: &str, default: f32| {
+            properties
+                .get(key)
+                .and_then(|value| value.parse::<f32>().ok())
+                .unwrap_or(default)
+        };
+
+        let kind = match properties.get("_type").map(String::as_str) {
+            Some("_metal") => MaterialKind::Metal,
+            Some("_glass") => MaterialKind::Glass,
+            Some("_emit") => MaterialKind::Emit,
+            _ => MaterialKind::Diffuse,
+        };
+
+        let mut material = Material {
+            kind,
+            roughness: get("_rough", 1.0),
+            ..Default::default()
+        };
+        match kind {
+            MaterialKind::Metal => material.reflectivity = get("_metal", 0.0),
+            // Glass stores how see through it is, older files use _alpha for the same thing
+            MaterialKind::Glass => {
+                material.alpha = 1.0 - get("_trans", get("_alpha", 0.0));
+                material.reflectivity = get("_sp", 0.0);
+            }
+            MaterialKind::Emit => material.emission = get("_emit", 0.0) * get("_flux", 1.0),
+            MaterialKind::Diffuse => {}
+        }
+
+        material
+    }
+}
+
+/// Material of every palette entry of a .vox file, default where there's no MATL chunk.
+/// Palette entry `i` is MATL id `i + 1`.
+pub fn vox_palette_materials(vox_data: &dot_vox::DotVoxData) -> Vec<Material> {
+    let mut materials = vec![Material::default(); vox_data.palette.len()];
+    for matl in &vox_data.materials {
+        let index = matl.id as usize;
+        if index == 0 || index > materials.len() {
+            continue;
+        }
+        materials[index - 1] = Material::from_properties(&matl.properties);
+    }
+    materials
+}
+
+/// Materials of a .vox file keyed by the colour they apply to, since leaves only keep their
+/// colour. Only non default materials are kept.
+pub fn vox_materials(vox_data: &dot_vox::DotVoxData) -> HashMap<Voxel, Material> {
+    let mut materials = HashMap::new();
+    for (i, material) in vox_palette_materials(vox_data).into_iter().enumerate() {
+        if material != Material::default() {
+            let colour = vox_data.palette[i].to_le_bytes();
+            materials.insert(Voxel::new(colour[0], colour[1], colour[2]), material);
+        }
+    }
+    materials
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vox_with_material(properties: &[(&str, &str)]) -> dot_vox::DotVoxData {
+        dot_vox::DotVoxData {
+            version: 150,
+            models: Vec::new(),
+            palette: vec![0xFF0000FF, 0xFF00FF00],
+            materials: vec![dot_vox::Material {
+                id: 2,
+                properties: properties
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            }],
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn glass_material_from_vox() {
+        let vox_data =
+            vox_with_material(&[("_type", "_glass"), ("_trans", "0.75"), ("_sp", "0.3")]);
+        let materials = vox_materials(&vox_data);
+
+        assert_eq!(materials.len(), 1);
+        let glass = materials[&Voxel::new(0, 255, 0)];
+        assert_eq!(glass.kind, MaterialKind::Glass);
+        assert!((glass.alpha - 0.25).abs() < 1e-6);
+        assert!((glass.reflectivity - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn glass_falls_back_to_alpha() {
+        let vox_data = vox_with_material(&[("_type", "_glass"), ("_alpha", "0.5")]);
+        let glass = vox_palette_materials(&vox_data)[1];
+        assert!((glass.alpha - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn diffuse_materials_are_dropped() {
+        let vox_data = vox_with_material(&[("_type", "_diffuse")]);
+        assert!(vox_materials(&vox_data).is_empty());
+    }
+}
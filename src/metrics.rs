@@ -0,0 +1,99 @@
+use super::*;
+
+/// Numbers collected over a whole run for the `--stats` summary
+#[derive(Clone, Debug, Default)]
+pub struct SessionMetrics {
+    pub enabled: bool,
+    // Frames recorded, one more than frame_times since the first has nothing to time against
+    pub frames: usize,
+    // Seconds between frames
+    pub frame_times: Vec<f32>,
+    pub peak_nodes: usize,
+    pub peak_gpu_bytes: usize,
+    pub files_loaded: usize,
+    last_time: Option<f64>,
+}
+
+impl SessionMetrics {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    /// `time` is seconds since startup, like `App::update` gets
+    pub fn record_frame(&mut self, time: f64, octree: &Octree) {
+        if !self.enabled {
+            return;
+        }
+
+        self.frames += 1;
+        if let Some(last_time) = self.last_time {
+            self.frame_times.push((time - last_time) as f32);
+        }
+        self.last_time = Some(time);
+
+        self.peak_nodes = self.peak_nodes.max(octree.node_count());
+        self.peak_gpu_bytes = self.peak_gpu_bytes.max(octree.gpu_bytes(0));
+    }
+
+    /// Frame time in seconds that `percentile` (0..100) of frames are at or under
+    pub fn frame_time_percentile(&self, percentile: f32) -> f32 {
+        if self.frame_times.is_empty() {
+            return 0.0;
+        }
+
+        let mut sorted = self.frame_times.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let rank = (percentile / 100.0 * sorted.len() as f32).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
+
+    pub fn summary(&self) -> String {
+        let average = if !self.frame_times.is_empty() {
+            self.frame_times.iter().sum::<f32>() / self.frame_times.len() as f32
+        } else {
+            0.0
+        };
+
+        format!(
+            "Session stats:\n  frames: {}\n  frame time: {:.2} ms average, {:.2} ms 99th percentile\n  peak nodes: {:.2} million\n  peak gpu buffer: {:.1} MB\n  files loaded: {}",
+            self.frames,
+            average * 1000.0,
+            self.frame_time_percentile(99.0) * 1000.0,
+            self.peak_nodes as f32 / 1000000.0,
+            self.peak_gpu_bytes as f32 / 1000000.0,
+            self.files_loaded,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_frame_is_counted() {
+        let octree = Octree::new([Voxel::new(255, 0, 0); 8]);
+        let mut metrics = SessionMetrics::new(true);
+        for frame in 0..3 {
+            metrics.record_frame(frame as f64 * 0.5, &octree);
+        }
+
+        assert_eq!(metrics.frames, 3);
+        assert_eq!(metrics.frame_times, vec![0.5, 0.5]);
+        assert!(metrics.summary().contains("frames: 3\n"));
+        assert_eq!(metrics.frame_time_percentile(99.0), 0.5);
+        assert_eq!(metrics.peak_nodes, 8);
+    }
+
+    #[test]
+    fn disabled_metrics_record_nothing() {
+        let octree = Octree::new([Voxel::new(255, 0, 0); 8]);
+        let mut metrics = SessionMetrics::new(false);
+        metrics.record_frame(0.0, &octree);
+        assert_eq!(metrics.frames, 0);
+        assert_eq!(metrics.frame_time_percentile(50.0), 0.0);
+    }
+}
@@ -0,0 +1,56 @@
+/// Interleaves the low 21 bits of each coordinate so every 3 bits are a child index
+/// `x * 4 + y * 2 + z`, the root's child being the highest. Sorting by code visits cells
+/// depth first.
+pub fn morton_encode(x: u32, y: u32, z: u32) -> u64 {
+    spread(x) << 2 | spread(y) << 1 | spread(z)
+}
+
+pub fn morton_decode(code: u64) -> (u32, u32, u32) {
+    (compact(code >> 2), compact(code >> 1), compact(code))
+}
+
+// Moves bit i to bit 3i
+fn spread(v: u32) -> u64 {
+    let mut v = v as u64 & 0x1fffff;
+    v = (v | v << 32) & 0x1f00000000ffff;
+    v = (v | v << 16) & 0x1f0000ff0000ff;
+    v = (v | v << 8) & 0x100f00f00f00f00f;
+    v = (v | v << 4) & 0x10c30c30c30c30c3;
+    v = (v | v << 2) & 0x1249249249249249;
+    v
+}
+
+fn compact(v: u64) -> u32 {
+    let mut v = v & 0x1249249249249249;
+    v = (v | v >> 2) & 0x10c30c30c30c30c3;
+    v = (v | v >> 4) & 0x100f00f00f00f00f;
+    v = (v | v >> 8) & 0x1f0000ff0000ff;
+    v = (v | v >> 16) & 0x1f00000000ffff;
+    v = (v | v >> 32) & 0x1fffff;
+    v as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for (x, y, z) in [
+            (0, 0, 0),
+            (1, 2, 3),
+            (0x1fffff, 0, 0x155555),
+            (12345, 67890, 0x1fffff),
+        ] {
+            assert_eq!(morton_decode(morton_encode(x, y, z)), (x, y, z));
+        }
+    }
+
+    #[test]
+    fn each_level_is_a_child_index() {
+        // x * 4 + y * 2 + z, the coarsest level highest
+        assert_eq!(morton_encode(1, 0, 0), 4);
+        assert_eq!(morton_encode(0, 1, 1), 3);
+        assert_eq!(morton_decode(0b110_001), (2, 2, 1));
+    }
+}
@@ -0,0 +1,240 @@
+use super::*;
+use std::collections::VecDeque;
+
+// Solid fills flood a dense grid, 2^27 cells at this depth
+pub const OBJ_SOLID_MAX_DEPTH: u32 = 9;
+
+/// Triangles of a Wavefront .obj from its `v` and `f` lines, polygons are split into fans
+pub fn obj_triangles(data: &[u8]) -> Result<Vec<[Vector3<f32>; 3]>, String> {
+    let text = String::from_utf8_lossy(data);
+
+    let mut vertices = Vec::new();
+    let mut triangles = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("v") => {
+                let values: Vec<f32> = words
+                    .take(3)
+                    .map(|w| w.parse::<f32>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("OBJ line {} has a bad vertex", line_number + 1))?;
+                match values[..] {
+                    [x, y, z] if x.is_finite() && y.is_finite() && z.is_finite() => {
+                        vertices.push(Vector3::new(x, y, z))
+                    }
+                    _ => return Err(format!("OBJ line {} has a bad vertex", line_number + 1)),
+                }
+            }
+            Some("f") => {
+                // Indices are 1 based or negative from the latest vertex, texture and normal
+                // indices after a / are ignored
+                let face: Vec<Vector3<f32>> = words
+                    .map(|w| {
+                        let index = w.split('/').next().unwrap_or("");
+                        let index = match index.parse::<i64>() {
+                            Ok(i) if i > 0 => i as usize - 1,
+                            Ok(i) if i < 0 && (-i) as usize <= vertices.len() => {
+                                vertices.len() - (-i) as usize
+                            }
+                            _ => usize::MAX,
+                        };
+                        vertices.get(index).copied().ok_or(format!(
+                            "OBJ line {} has a bad face index \"{}\"",
+                            line_number + 1,
+                            w
+                        ))
+                    })
+                    .collect::<Result<_, _>>()?;
+                for i in 2..face.len() {
+                    triangles.push([face[0], face[i - 1], face[i]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if triangles.is_empty() {
+        return Err("OBJ file has no faces".to_string());
+    }
+    Ok(triangles)
+}
+
+/// Centres of the cells at `depth` that any triangle touches
+pub fn voxelise_triangles(triangles: &[[Vector3<f32>; 3]], depth: u32) -> Vec<Vector3<f32>> {
+    let mut cells = Vec::new();
+    let all: Vec<usize> = (0..triangles.len()).collect();
+    voxelise_cell(triangles, &all, Vector3::zero(), 1.0, 0, depth, &mut cells);
+    cells
+}
+
+fn voxelise_cell(
+    triangles: &[[Vector3<f32>; 3]],
+    candidates: &[usize],
+    centre: Vector3<f32>,
+    half: f32,
+    level: u32,
+    depth: u32,
+    cells: &mut Vec<Vector3<f32>>,
+) {
+    let touching: Vec<usize> = candidates
+        .iter()
+        .copied()
+        .filter(|&i| triangle_box_overlap(triangles[i], centre, half))
+        .collect();
+    if touching.is_empty() {
+        return;
+    }
+
+    if level == depth {
+        cells.push(centre);
+        return;
+    }
+    for i in 0..8 {
+        let child_centre = centre + Octree::pos_offset(i, level + 1);
+        voxelise_cell(
+            triangles,
+            &touching,
+            child_centre,
+            half / 2.0,
+            level + 1,
+            depth,
+            cells,
+        );
+    }
+}
+
+/// Separating axis test of a triangle against the cube at `centre` with half size `half`
+pub fn triangle_box_overlap(triangle: [Vector3<f32>; 3], centre: Vector3<f32>, half: f32) -> bool {
+    let v = triangle.map(|p| p - centre);
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+
+    // Whether the triangle and box projections onto `axis` are apart
+    let separated = |axis: Vector3<f32>| {
+        let p = v.map(|p| p.dot(axis));
+        let r = half * (axis.x.abs() + axis.y.abs() + axis.z.abs());
+        p[0].min(p[1]).min(p[2]) > r || p[0].max(p[1]).max(p[2]) < -r
+    };
+
+    let box_axes = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+    for axis in box_axes {
+        if separated(axis) {
+            return false;
+        }
+    }
+    for axis in box_axes {
+        for edge in edges {
+            if separated(axis.cross(edge)) {
+                return false;
+            }
+        }
+    }
+    !separated(edges[0].cross(edges[1]))
+}
+
+/// Centres of the cells at `depth` enclosed by `surface`, found by flooding the empty cells
+/// reachable from the outside of the cube
+pub fn fill_interior(surface: &[Vector3<f32>], depth: u32) -> Result<Vec<Vector3<f32>>, String> {
+    if depth > OBJ_SOLID_MAX_DEPTH {
+        return Err(format!(
+            "Solid fills are limited to depth {}",
+            OBJ_SOLID_MAX_DEPTH
+        ));
+    }
+
+    let size = 1usize << depth;
+    let index = |cell: Vector3<usize>| cell.x + cell.y * size + cell.z * size * size;
+    // 0 empty, 1 surface, 2 outside
+    let mut grid = vec![0u8; size * size * size];
+    for &pos in surface {
+        let cell = pos.map(|p| (((p + 1.0) / 2.0 * size as f32) as usize).min(size - 1));
+        grid[index(cell)] = 1;
+    }
+
+    let mut queue = VecDeque::new();
+    for a in 0..size {
+        for b in 0..size {
+            for cell in [
+                Vector3::new(0, a, b),
+                Vector3::new(size - 1, a, b),
+                Vector3::new(a, 0, b),
+                Vector3::new(a, size - 1, b),
+                Vector3::new(a, b, 0),
+                Vector3::new(a, b, size - 1),
+            ] {
+                if grid[index(cell)] == 0 {
+                    grid[index(cell)] = 2;
+                    queue.push_back(cell);
+                }
+            }
+        }
+    }
+    while let Some(cell) = queue.pop_front() {
+        for axis in 0..3 {
+            for step in [-1i64, 1] {
+                let next = cell[axis] as i64 + step;
+                if next < 0 || next >= size as i64 {
+                    continue;
+                }
+                let mut neighbour = cell;
+                neighbour[axis] = next as usize;
+                if grid[index(neighbour)] == 0 {
+                    grid[index(neighbour)] = 2;
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+
+    let cell_size = 2.0 / size as f32;
+    Ok(grid
+        .iter()
+        .enumerate()
+        .filter(|(_, state)| **state == 0)
+        .map(|(i, _)| {
+            let cell = Vector3::new(i % size, i / size % size, i / (size * size));
+            cell.map(|c| (c as f32 + 0.5) * cell_size - 1.0)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TETRAHEDRON: &str =
+        "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\nf 1 3 2\nf 1 2 4\nf 1 4 3\nf 2 3 4\n";
+
+    fn cells(solid: bool) -> Vec<bool> {
+        let octree = CpuOctree::load_obj(TETRAHEDRON.as_bytes(), 4, solid).unwrap();
+        octree.to_octree().unwrap().to_grid(4).0
+    }
+
+    #[test]
+    fn tetrahedron_voxelises_inside_its_corner() {
+        let (surface, solid) = (cells(false), cells(true));
+        let count = |grid: &[bool]| grid.iter().filter(|filled| **filled).count();
+
+        // The right angle corner and the far ends of its three edges
+        for cell in [0, 15, 15 * 16, 15 * 256] {
+            assert!(surface[cell], "{}", cell);
+        }
+        // Cells past the slanted face stay empty
+        assert!(!surface[4095] && !solid[4095]);
+        assert!(!solid[15 + 15 * 16]);
+
+        // The fill adds the enclosed cells and keeps the surface
+        assert!(count(&solid) > count(&surface));
+        assert!(surface.iter().zip(&solid).all(|(s, f)| !s || *f));
+        // About a sixth of the cube
+        assert!((600..1000).contains(&count(&solid)));
+        let middle = 3 + 3 * 16 + 3 * 256;
+        assert!(!surface[middle] && solid[middle]);
+    }
+
+    #[test]
+    fn solid_fills_stop_at_their_depth_limit() {
+        let data = TETRAHEDRON.as_bytes();
+        assert!(CpuOctree::load_obj(data, OBJ_SOLID_MAX_DEPTH + 1, true).is_err());
+    }
+}
@@ -1,10 +1,13 @@
+use super::{morton_decode, morton_encode, CpuOctree, Node, CHUNK_OFFSET, MAX_VOLUME_BYTES};
 use cgmath::*;
 
-// First palette colour is empty voxel
-// const PALETTE: [u32; 3] = [0x00000000, 0x0000FF00, 0x000000FF];
 pub const VOXEL_OFFSET: u32 = 134217728;
+// Lookups give up here so a cycle in a broken tree can't hang them
+pub const MAX_DEPTH: u32 = 32;
+// Deepest level whose cell centres f32 positions can hold exactly
+pub const MAX_PRECISE_DEPTH: u32 = 23;
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
 pub struct Voxel {
     pub r: u8,
     pub g: u8,
@@ -16,7 +19,6 @@ impl Voxel {
         Voxel { r, g, b }
     }
 
-    #[allow(dead_code)]
     pub fn from_value(value: u32) -> Voxel {
         let r = (value >> 16) as u8;
         let g = (value >> 8) as u8;
@@ -32,19 +34,127 @@ impl Voxel {
     pub fn to_cpu_value(&self) -> u32 {
         (self.r as u32) << 16 | (self.g as u32) << 8 | self.b as u32
     }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Voxel::new(0, 0, 0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+const SVOT_MAGIC: [u8; 4] = *b"SVOT";
+const SVOT_VERSION: u8 = 1;
+
+/// Result of tracing a ray through an `Octree` on the cpu
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug)]
+pub struct RayHit {
+    pub hit: bool,
+    // Index of the leaf that was hit
+    pub index: usize,
+    pub pos: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub depth: u32,
+    // Number of leaves stepped through, also counted on a miss
+    pub steps: u32,
+    // Gave up after `trace_step_limit` steps without hitting or leaving the tree
+    pub exhausted: bool,
+}
+
+impl RayHit {
+    fn miss(steps: u32) -> Self {
+        Self {
+            hit: false,
+            index: 0,
+            pos: Vector3::zero(),
+            normal: Vector3::zero(),
+            depth: 0,
+            steps,
+            exhausted: false,
+        }
+    }
 }
 
-/// Layout (Outdated)
+pub const MAX_TRACE_STEPS: u32 = 100;
+// Extra steps allowed for each level the ray has been down to, deep trees have more cells to cross
+pub const TRACE_STEPS_PER_LEVEL: u32 = 64;
+
+/// Steps a trace may take once it has stepped through leaves `depth` levels down,
+/// same as the limit in `octree_ray` in shader.wgsl
+pub fn trace_step_limit(depth: u32) -> u32 {
+    MAX_TRACE_STEPS + TRACE_STEPS_PER_LEVEL * depth
+}
+
+// Most child masks save_rsvo writes, filled leaves above the top level multiply by 8 a level
+const MAX_RSVO_MASKS: u64 = 64 * 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BufferUsage {
+    pub len: usize,
+    pub capacity: usize,
+    // Bytes allocated, capacity times element size
+    pub bytes: usize,
+}
+
+impl BufferUsage {
+    fn of<T>(v: &Vec<T>) -> Self {
+        Self {
+            len: v.len(),
+            capacity: v.capacity(),
+            bytes: v.capacity() * std::mem::size_of::<T>(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MemoryReport {
+    pub nodes: BufferUsage,
+    pub positions: BufferUsage,
+    pub hole_stack: BufferUsage,
+    pub palette: BufferUsage,
+}
+
+impl MemoryReport {
+    pub fn total_bytes(&self) -> usize {
+        self.nodes.bytes + self.positions.bytes + self.hole_stack.bytes + self.palette.bytes
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OctreeStats {
+    pub total_nodes: usize,
+    // Nodes reachable from the root
+    pub live_nodes: usize,
+    pub leaves: usize,
+    pub filled_leaves: usize,
+    pub max_depth: u32,
+    // Bytes of the node and position buffers in use, holes included
+    pub bytes: usize,
+}
+
+/// Layout of a node, the low 4 bits count gpu hits for adaptive subdivision
 /// ```
-/// 01100101 01100101 01100101 01100101
-///  ^---- Node: pointer to children, Voxel: palette index
-/// ^----- 0: Node, 1: Voxel
+/// 1000RRRR RRRRGGGG GGGGBBBB BBBBCCCC  leaf, VOXEL_OFFSET + 0xRRGGBB
+/// 0PPPPPPP PPPPPPPP PPPPPPPP PPPPCCCC  node, index of the first of its 8 children
 /// ```
+/// Leaves hold their colour rather than an index into `palette`. Unsubdividing averages
+/// colours, edits and formats without a palette bring their own, and the shader reads the
+/// colour straight from the node, so `palette` only keeps the file's colours alongside.
 pub struct Octree {
-    pub nodes: Vec<u32>,
+    // Private so every edit goes through the methods that keep `occupied` right
+    nodes: Vec<u32>,
     // stays on cpu
     pub positions: Vec<Vector3<f32>>,
     pub hole_stack: Vec<usize>,
+    // Colours of the file the model came from as rgba8, empty for formats without one
+    pub palette: Vec<u32>,
+    // Filled leaves reachable from the root, kept up to date by every edit
+    occupied: usize,
 }
 
 impl Octree {
@@ -62,18 +172,70 @@ impl Octree {
             nodes,
             positions,
             hole_stack,
+            palette: Vec::new(),
+            occupied: mask.iter().filter(|voxel| !voxel.is_empty()).count(),
+        }
+    }
+
+    /// Adopts a raw node buffer like the one from `raw_data`, rebuilding the positions and
+    /// treating any block unreachable from the root as a hole
+    pub fn from_nodes(nodes: Vec<u32>) -> Result<Self, String> {
+        let mut octree = Self {
+            nodes,
+            positions: Vec::new(),
+            hole_stack: Vec::new(),
+            palette: Vec::new(),
+            occupied: 0,
+        };
+        octree.validate()?;
+        octree.fill_positions()?;
+
+        let mut live = vec![false; octree.nodes.len() / 8];
+        live[0] = true;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            for i in 0..8 {
+                let tnipt = octree.get_node(index + i);
+                if tnipt < VOXEL_OFFSET {
+                    live[tnipt as usize / 8] = true;
+                    stack.push(tnipt as usize);
+                }
+            }
         }
+        octree.hole_stack = (0..live.len())
+            .filter(|&block| !live[block])
+            .map(|block| block * 8)
+            .collect();
+
+        octree.occupied = octree.leaves().count();
+        Ok(octree)
     }
 
     pub fn get_node(&self, index: usize) -> u32 {
         self.nodes[index] >> 4
     }
 
-    pub fn subdivide(&mut self, node: usize, mask: [Voxel; 8], depth: u32) {
+    /// Returns the range of the new children. Children past `MAX_PRECISE_DEPTH` are refused
+    /// since their positions can't be held exactly
+    pub fn subdivide(
+        &mut self,
+        node: usize,
+        mask: [Voxel; 8],
+        depth: u32,
+    ) -> Result<std::ops::Range<usize>, String> {
         if self.get_node(node) < VOXEL_OFFSET {
-            panic!("Node already subdivided!");
+            return Err(format!("Node {} already subdivided!", node));
+        }
+        if depth > MAX_PRECISE_DEPTH {
+            return Err(format!(
+                "Can't subdivide to depth {}, the limit is {}",
+                depth, MAX_PRECISE_DEPTH
+            ));
         }
 
+        self.occupied -= self.is_occupied(node) as usize;
+        self.occupied += mask.iter().filter(|voxel| !voxel.is_empty()).count();
+
         let pos = self.positions[node];
         if let Some(index) = self.hole_stack.pop() {
             self.nodes[node] = create_node(index);
@@ -82,31 +244,196 @@ impl Octree {
                 self.nodes[index + i] = mask[i].to_value();
                 self.positions[index + i] = pos + Octree::pos_offset(i, depth);
             }
+
+            Ok(index..index + 8)
         } else {
-            self.nodes[node] = create_node(self.nodes.len());
+            let index = self.nodes.len();
+            self.nodes[node] = create_node(index);
 
             for i in 0..8 {
                 self.nodes.push(mask[i].to_value());
                 self.positions.push(pos + Octree::pos_offset(i, depth));
             }
+
+            Ok(index..index + 8)
         }
     }
 
-    pub fn unsubdivide(&mut self, node: usize) {
+    /// Subdivides `node` with the children starting at `children` in the cpu octree,
+    /// reusing holes. Returns the dirty range of children, `node` itself is also rewritten.
+    pub fn refine_from(
+        &mut self,
+        cpu: &CpuOctree,
+        children: usize,
+        node: usize,
+        depth: u32,
+    ) -> Result<std::ops::Range<usize>, String> {
+        self.subdivide(node, cpu.get_node_mask(children), depth)
+    }
+
+    /// Collapses a node whose children are all leaves back into one leaf coloured with
+    /// the average of its filled children, freeing the children for reuse
+    pub fn unsubdivide(&mut self, node: usize) -> Result<(), String> {
         let tnipt = self.get_node(node);
         if tnipt >= VOXEL_OFFSET {
-            println!("Node {} not subdivided!", node);
-            return;
+            return Err(format!("Node {} not subdivided!", node));
         }
 
-        self.hole_stack.push(tnipt as usize);
+        let children = tnipt as usize;
+        let mut colour = Vector3::zero();
+        let mut filled = 0.0;
+        for i in 0..8 {
+            let child = self.get_node(children + i);
+            if child < VOXEL_OFFSET {
+                return Err(format!(
+                    "Node {} has subdivided child {}",
+                    node,
+                    children + i
+                ));
+            }
+
+            let voxel = Voxel::from_value(child);
+            if !voxel.is_empty() {
+                colour += Vector3::new(voxel.r as f32, voxel.g as f32, voxel.b as f32);
+                filled += 1.0;
+            }
+        }
 
         let pos = self.positions[node];
         if pos == Vector3::zero() {
             panic!("Tried to unsubdivide a node without position!");
         }
 
-        self.nodes[node] = Voxel::new(255, 0, 0).to_value();
+        self.hole_stack.push(children);
+
+        let voxel = if filled > 0.0 {
+            colour /= filled;
+            // Keep dark averages from reading as empty
+            Voxel::new(
+                (colour.x as u8).max(1),
+                (colour.y as u8).max(1),
+                (colour.z as u8).max(1),
+            )
+        } else {
+            Voxel::new(0, 0, 0)
+        };
+
+        self.occupied -= self.count_occupied(children);
+        self.nodes[node] = voxel.to_value();
+        self.occupied += !voxel.is_empty() as usize;
+
+        Ok(())
+    }
+
+    /// Sets the colour of a leaf node
+    pub fn set_leaf(&mut self, node: usize, voxel: Voxel) {
+        if self.get_node(node) < VOXEL_OFFSET {
+            panic!("Tried to set the colour of a subdivided node!");
+        }
+
+        self.occupied -= self.is_occupied(node) as usize;
+        self.nodes[node] = voxel.to_value();
+        self.occupied += !voxel.is_empty() as usize;
+    }
+
+    pub fn occupied_leaf_count(&self) -> usize {
+        self.occupied
+    }
+
+    /// `palette` as r, g, b, a bytes ready to upload
+    pub fn palette_bytes(&self) -> Vec<u8> {
+        self.palette
+            .iter()
+            .flat_map(|colour| colour.to_le_bytes())
+            .collect()
+    }
+
+    /// Fills `out` with the index and centre of every node at `depth`, plus leaves that end
+    /// above it at their own depth. `out` is cleared first so it can be reused between calls.
+    pub fn nodes_at_depth(&self, depth: u32, out: &mut Vec<(usize, Vector3<f32>)>) {
+        out.clear();
+        let mut stack = vec![(0, 1, Vector3::zero())];
+        while let Some((index, level, pos)) = stack.pop() {
+            for i in 0..8 {
+                let child_pos = pos + Octree::pos_offset(i, level);
+                let tnipt = self.get_node(index + i);
+                if level >= depth || tnipt >= VOXEL_OFFSET {
+                    out.push((index + i, child_pos));
+                } else {
+                    stack.push((tnipt as usize, level + 1, child_pos));
+                }
+            }
+        }
+    }
+
+    /// Occupancy of every cell of a `2^depth` cubed grid and its side length, indexed
+    /// x + y * size + z * size^2 like `CpuOctree::to_volume`. Takes `8^depth` bytes, 16 MB at
+    /// depth 8 and 1 GB at depth 10, and one lookup from the root per cell.
+    pub fn to_grid(&self, depth: u32) -> (Vec<bool>, usize) {
+        let size = 1usize << depth;
+        let mut grid = vec![false; size * size * size];
+        // Morton order so neighbouring lookups walk down through the same nodes
+        for code in 0..grid.len() as u64 {
+            let (x, y, z) = morton_decode(code);
+            let (x, y, z) = (x as usize, y as usize, z as usize);
+            let pos = (Vector3::new(x as f32, y as f32, z as f32) + Vector3::new(0.5, 0.5, 0.5))
+                / size as f32
+                * 2.0
+                - Vector3::new(1.0, 1.0, 1.0);
+            let (index, _, _) = self.find_voxel(pos, None);
+            grid[x + y * size + z * size * size] = self.get_node(index) > VOXEL_OFFSET;
+        }
+
+        (grid, size)
+    }
+
+    /// Writes `to_grid` of what's currently subdivided as one byte a cell, 1 for filled.
+    /// Loads back through `CpuOctree::load_raw`.
+    pub fn export_mask(&self, path: &str, depth: u32) -> Result<(), String> {
+        let bytes = 1usize.checked_shl(3 * depth);
+        if bytes.filter(|bytes| *bytes <= MAX_VOLUME_BYTES).is_none() {
+            return Err(format!(
+                "Mask at depth {} is larger than {} MiB",
+                depth,
+                MAX_VOLUME_BYTES / (1024 * 1024)
+            ));
+        }
+
+        let (grid, _) = self.to_grid(depth);
+        let data: Vec<u8> = grid.iter().map(|&filled| filled as u8).collect();
+        std::fs::write(path, data).map_err(|e| e.to_string())
+    }
+
+    /// Morton code of the cell at `depth` containing `pos`, the child indices on the way
+    /// down to it packed 3 bits a level. `depth` is at most 21 so it fits.
+    pub fn path_to_morton(pos: Vector3<f32>, depth: u32) -> u64 {
+        let size = 1u32 << depth.min(21);
+        let cell = ((pos + Vector3::new(1.0, 1.0, 1.0)) / 2.0 * size as f32)
+            .map(|c| (c.max(0.0) as u32).min(size - 1));
+        morton_encode(cell.x, cell.y, cell.z)
+    }
+
+    /// Lazily walks the filled leaves depth first, yielding (centre, depth, value)
+    pub fn leaves(&self) -> impl Iterator<Item = (Vector3<f32>, u32, u32)> + '_ {
+        Leaves {
+            octree: self,
+            stack: vec![(0, 1, Vector3::zero(), 0)],
+        }
+    }
+
+    fn is_occupied(&self, node: usize) -> bool {
+        let tnipt = self.get_node(node);
+        tnipt >= VOXEL_OFFSET && !Voxel::from_value(tnipt).is_empty()
+    }
+
+    /// Filled leaves below the 8 children starting at `children`, found by walking the tree.
+    /// Only the count is wanted so the positions the walk yields don't matter.
+    fn count_occupied(&self, children: usize) -> usize {
+        Leaves {
+            octree: self,
+            stack: vec![(children, 1, Vector3::zero(), 0)],
+        }
+        .count()
     }
 
     /// Returns (index, depth, pos)
@@ -131,7 +458,7 @@ impl Octree {
             node_pos += Octree::pos_offset(child_index, depth);
 
             if self.get_node(node_index + child_index) >= VOXEL_OFFSET
-                || depth == max_depth.unwrap_or(u32::MAX)
+                || depth == max_depth.unwrap_or(MAX_DEPTH).min(MAX_DEPTH)
             {
                 return (node_index + child_index, depth, node_pos);
             }
@@ -140,6 +467,189 @@ impl Octree {
         }
     }
 
+    /// Leaf node at `pos`, or an error when the tree is still going at MAX_DEPTH, which only
+    /// a pointer cycle can do
+    pub fn get_node_checked(&self, pos: Vector3<f32>) -> Result<u32, String> {
+        let (index, depth, _) = self.find_voxel(pos, None);
+        let node = self.get_node(index);
+        if node < VOXEL_OFFSET {
+            return Err(format!(
+                "Octree has no leaf at {:?} by depth {}",
+                pos, depth
+            ));
+        }
+        Ok(node)
+    }
+
+    /// Node across the `face` of `node` on the positive or negative side. That is a leaf when
+    /// the neighbour is as coarse or coarser, otherwise the subdivided node of the same size.
+    /// Returns (index, depth), None past the edge of the octree.
+    pub fn neighbor(&self, node: usize, face: Axis, sign: bool) -> Option<(usize, u32)> {
+        let pos = *self.positions.get(node)?;
+
+        // Depth isn't stored so find the node again from its centre
+        let mut depth = 1;
+        loop {
+            let (index, _, _) = self.find_voxel(pos, Some(depth));
+            if index == node {
+                break;
+            }
+            if self.get_node(index) >= VOXEL_OFFSET || depth >= MAX_DEPTH {
+                return None;
+            }
+            depth += 1;
+        }
+
+        let size = 2.0 * 0.5f32.powi(depth as i32);
+        let step = match face {
+            Axis::X => Vector3::unit_x(),
+            Axis::Y => Vector3::unit_y(),
+            Axis::Z => Vector3::unit_z(),
+        } * if sign { size } else { -size };
+
+        let neighbor_pos = pos + step;
+        if !in_bounds(neighbor_pos) {
+            return None;
+        }
+
+        let (index, depth, _) = self.find_voxel(neighbor_pos, Some(depth));
+        Some((index, depth))
+    }
+
+    /// Faces of `node` with empty space or the edge of the octree on the other side
+    pub fn exposed_faces(&self, node: usize) -> usize {
+        [Axis::X, Axis::Y, Axis::Z]
+            .iter()
+            .flat_map(|&axis| [(axis, false), (axis, true)])
+            .filter(|&(axis, sign)| match self.neighbor(node, axis, sign) {
+                Some((index, _)) => self.get_node(index) == VOXEL_OFFSET,
+                None => true,
+            })
+            .count()
+    }
+
+    /// Cpu version of `octree_ray` in shader.wgsl. Rays that run past `trace_step_limit` of
+    /// the deepest level they've crossed come back as misses marked `exhausted`.
+    pub fn trace(&self, origin: Vector3<f32>, dir: Vector3<f32>) -> RayHit {
+        self.trace_visiting(origin, dir, &mut |_| {})
+    }
+
+    /// `trace` that passes the depth of every empty leaf stepped through to `empty`
+    pub fn trace_visiting(
+        &self,
+        origin: Vector3<f32>,
+        dir: Vector3<f32>,
+        empty: &mut dyn FnMut(u32),
+    ) -> RayHit {
+        let dir = dir.map(|d| if d == 0.0 { 0.000001 } else { d });
+
+        let mut pos = origin;
+        if !in_bounds(origin) {
+            // Get position on surface of the octree
+            let dist = ray_box_dist(origin, dir);
+            if dist == 0.0 {
+                return RayHit::miss(0);
+            }
+
+            pos = origin + dir * dist;
+        }
+
+        let r_sign = dir.map(|d| d.signum());
+
+        let mut voxel_pos = pos;
+        let mut normal = (pos * 1.000001).map(|p| p.trunc());
+        let mut steps = 0;
+        let mut deepest = 0;
+        loop {
+            let (index, depth, voxel_centre) = self.find_voxel(voxel_pos, None);
+            if self.get_node(index) > VOXEL_OFFSET {
+                return RayHit {
+                    hit: true,
+                    index,
+                    pos: voxel_pos,
+                    normal,
+                    depth,
+                    steps,
+                    exhausted: false,
+                };
+            }
+            empty(depth);
+            deepest = deepest.max(depth);
+
+            let voxel_size = 2.0 * 0.5f32.powi(depth as i32);
+            let t_max = (voxel_centre - pos + r_sign * voxel_size / 2.0).div_element_wise(dir);
+
+            // Step along the axis with the closest boundary
+            let t_current = t_max.x.min(t_max.y).min(t_max.z);
+            let mask = t_max.map(|t| (t <= t_current) as u32 as f32);
+            normal = -mask.mul_element_wise(r_sign);
+
+            voxel_pos = pos + dir * t_current - normal * 0.000002;
+
+            if !in_bounds(voxel_pos) {
+                return RayHit::miss(steps);
+            }
+
+            steps += 1;
+            if steps > trace_step_limit(deepest) {
+                return RayHit {
+                    exhausted: true,
+                    ..RayHit::miss(steps)
+                };
+            }
+        }
+    }
+
+    /// Exact hit against the tree, visiting children front to back and skipping empty space
+    /// without stepping through it. Returns the hit leaf index, hit point and face normal.
+    pub fn raycast(
+        &self,
+        origin: Vector3<f32>,
+        dir: Vector3<f32>,
+    ) -> Option<(usize, Vector3<f32>, Vector3<f32>)> {
+        let dir = dir.map(|d| if d == 0.0 { 0.000001 } else { d });
+        let inv_dir = dir.map(|d| 1.0 / d);
+
+        box_hit(Vector3::zero(), 1.0, origin, inv_dir)?;
+        let (index, t, normal) = self.raycast_children(0, Vector3::zero(), 1, origin, inv_dir)?;
+        Some((index, origin + dir * t, normal))
+    }
+
+    fn raycast_children(
+        &self,
+        children: usize,
+        centre: Vector3<f32>,
+        depth: u32,
+        origin: Vector3<f32>,
+        inv_dir: Vector3<f32>,
+    ) -> Option<(usize, f32, Vector3<f32>)> {
+        let half = 0.5f32.powi(depth as i32);
+
+        let mut hits = Vec::with_capacity(8);
+        for i in 0..8 {
+            let child_centre = centre + Octree::pos_offset(i, depth);
+            if let Some((t, normal)) = box_hit(child_centre, half, origin, inv_dir) {
+                hits.push((t, i, child_centre, normal));
+            }
+        }
+        hits.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        for (t, i, child_centre, normal) in hits {
+            let tnipt = self.get_node(children + i);
+            if tnipt < VOXEL_OFFSET {
+                let hit =
+                    self.raycast_children(tnipt as usize, child_centre, depth + 1, origin, inv_dir);
+                if hit.is_some() {
+                    return hit;
+                }
+            } else if tnipt > VOXEL_OFFSET {
+                return Some((children + i, t, normal));
+            }
+        }
+
+        None
+    }
+
     pub fn expanded(&self, size: usize) -> Vec<u32> {
         let mut nodes = self.nodes.clone();
         nodes.extend(std::iter::repeat(0).take(size - self.nodes.len()));
@@ -147,20 +657,552 @@ impl Octree {
         nodes
     }
 
+    /// Size in bytes of a node buffer holding this octree with at least `capacity` nodes
+    pub fn gpu_bytes(&self, capacity: usize) -> usize {
+        self.nodes.len().max(capacity) * std::mem::size_of::<u32>()
+    }
+
+    /// Counts for the whole tree from a single walk
+    pub fn stats(&self) -> OctreeStats {
+        let mut stats = OctreeStats {
+            total_nodes: self.nodes.len(),
+            bytes: self.nodes.len() * std::mem::size_of::<u32>()
+                + self.positions.len() * std::mem::size_of::<Vector3<f32>>(),
+            ..Default::default()
+        };
+
+        let mut stack = vec![(0, 1)];
+        while let Some((index, depth)) = stack.pop() {
+            stats.live_nodes += 8;
+            stats.max_depth = stats.max_depth.max(depth);
+            for i in 0..8 {
+                let tnipt = self.get_node(index + i);
+                if tnipt < VOXEL_OFFSET {
+                    stack.push((tnipt as usize, depth + 1));
+                } else {
+                    stats.leaves += 1;
+                    stats.filled_leaves += (tnipt > VOXEL_OFFSET) as usize;
+                }
+            }
+        }
+
+        stats
+    }
+
+    /// Frees spare capacity in every buffer
+    pub fn shrink_to_fit(&mut self) {
+        self.nodes.shrink_to_fit();
+        self.positions.shrink_to_fit();
+        self.hole_stack.shrink_to_fit();
+        self.palette.shrink_to_fit();
+    }
+
+    pub fn memory_report(&self) -> MemoryReport {
+        MemoryReport {
+            nodes: BufferUsage::of(&self.nodes),
+            positions: BufferUsage::of(&self.positions),
+            hole_stack: BufferUsage::of(&self.hole_stack),
+            palette: BufferUsage::of(&self.palette),
+        }
+    }
+
+    /// Copies the currently subdivided tree into a compact `CpuOctree`, leaving out
+    /// the holes. Interior colours aren't kept on the gpu so they come out empty.
+    pub fn to_cpu_octree(&self) -> CpuOctree {
+        let mut cpu = CpuOctree {
+            nodes: vec![Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0)); 8],
+            top_mip: Voxel::new(0, 0, 0),
+            materials: Default::default(),
+            file_palette: self.palette.clone(),
+            hole_stack: Vec::new(),
+        };
+
+        let mut stack = vec![(0, 0)];
+        while let Some((gpu_children, cpu_children)) = stack.pop() {
+            for i in 0..8 {
+                let tnipt = self.get_node(gpu_children + i);
+                cpu.nodes[cpu_children + i] = if tnipt >= VOXEL_OFFSET {
+                    Node::new(CHUNK_OFFSET, Voxel::from_value(tnipt))
+                } else {
+                    let children = cpu.nodes.len();
+                    cpu.nodes
+                        .extend_from_slice(&[Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0)); 8]);
+                    stack.push((tnipt as usize, children));
+                    Node::new(children as u32, Voxel::new(0, 0, 0))
+                };
+            }
+        }
+
+        cpu
+    }
+
+    /// Moves blocks of children from the end of the buffer into the holes before them, then
+    /// truncates. Returns where every old index ended up, `u32::MAX` for freed nodes.
+    pub fn compact(&mut self) -> Vec<u32> {
+        let len = self.nodes.len();
+        let mut remap: Vec<u32> = (0..len as u32).collect();
+
+        // Node pointing at each block of children, anything unreachable is free
+        let mut parents = vec![None; len / 8];
+        parents[0] = Some(usize::MAX);
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            for i in 0..8 {
+                let tnipt = self.get_node(index + i);
+                if tnipt < VOXEL_OFFSET {
+                    parents[tnipt as usize / 8] = Some(index + i);
+                    stack.push(tnipt as usize);
+                }
+            }
+        }
+
+        let holes: Vec<usize> = (0..len / 8)
+            .filter(|&block| parents[block].is_none())
+            .collect();
+        for &hole in &holes {
+            remap[hole * 8..hole * 8 + 8].fill(u32::MAX);
+        }
+
+        let mut holes = holes.into_iter();
+        let mut end = len / 8;
+        loop {
+            while end > 0 && parents[end - 1].is_none() {
+                end -= 1;
+            }
+            let hole = match holes.next() {
+                Some(hole) if hole < end => hole,
+                _ => break,
+            };
+
+            let (from, to) = ((end - 1) * 8, hole * 8);
+            for i in 0..8 {
+                self.nodes[to + i] = self.nodes[from + i];
+                self.positions[to + i] = self.positions[from + i];
+                remap[from + i] = (to + i) as u32;
+
+                let tnipt = self.get_node(to + i);
+                if tnipt < VOXEL_OFFSET {
+                    parents[tnipt as usize / 8] = Some(to + i);
+                }
+            }
+
+            // Keep the parent's counter bits
+            let parent = parents[end - 1].take().unwrap();
+            self.nodes[parent] = create_node(to) | (self.nodes[parent] & 0xF);
+            parents[hole] = Some(parent);
+        }
+
+        self.nodes.truncate(end * 8);
+        self.positions.truncate(end * 8);
+        self.hole_stack.clear();
+
+        remap
+    }
+
+    /// Length of the node buffer, holes included
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
     pub fn raw_data(&self) -> &Vec<u32> {
         &self.nodes
     }
 
+    /// Writes the raw node buffer, holes included, so it can be replayed with `load_nodes`.
+    /// Layout is little endian u32s: `[len, depth, hole_count, nodes.., holes..]`
+    pub fn dump_nodes<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), String> {
+        std::fs::write(path, self.dump_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn dump_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(3 + self.nodes.len() + self.hole_stack.len());
+        data.push(self.nodes.len() as u32);
+        data.push(self.max_depth());
+        data.push(self.hole_stack.len() as u32);
+        data.extend_from_slice(&self.nodes);
+        data.extend(self.hole_stack.iter().map(|hole| *hole as u32));
+
+        data.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    /// Saves the tree as it's subdivided right now without the holes. Compacted on a copy
+    /// so the gpu can keep using the live indices
+    pub fn export_lod<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), String> {
+        let mut lod = Octree::from_nodes(self.raw_data().clone())?;
+        lod.compact();
+        lod.save_svot(path)
+    }
+
+    /// Node dump behind a magic and version byte, for caching processed models between runs
+    pub fn save_svot<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), String> {
+        let mut bytes = SVOT_MAGIC.to_vec();
+        bytes.push(SVOT_VERSION);
+        bytes.extend(self.dump_bytes());
+        std::fs::write(path, bytes).map_err(|e| e.to_string())
+    }
+
+    /// Writes the .rsvo file `CpuOctree::load_file` reads back into the same nodes
+    pub fn save_rsvo(&self, path: &str, top_level: u32) -> Result<(), String> {
+        let data = self.rsvo_bytes(top_level)?;
+        std::fs::write(path, data).map_err(|e| e.to_string())
+    }
+
+    /// Header, node counts and breadth first child masks of an .rsvo file. Anything below
+    /// `top_level` is cut off, filled leaves above it have to be written as solid all the way
+    /// down so the top level is lowered to the depth of the tree first.
+    pub fn rsvo_bytes(&self, top_level: u32) -> Result<Vec<u8>, String> {
+        if top_level == 0 || top_level > 255 {
+            return Err(format!("Top level {} out of range", top_level));
+        }
+        let top_level = top_level.min(self.max_depth());
+
+        // Count first so a solid leaf far above the top level errors instead of filling memory
+        let (mut blocks, mut solid, mut masks) = (vec![0], 0u64, 0u64);
+        for depth in 1..=top_level {
+            masks += blocks.len() as u64 + solid;
+            if masks > MAX_RSVO_MASKS {
+                return Err(format!(
+                    "Filled leaves above depth {} need more than {} child masks, save with a lower top level",
+                    depth, MAX_RSVO_MASKS
+                ));
+            }
+
+            let mut next = Vec::new();
+            solid *= 8;
+            for block in blocks {
+                for i in 0..8 {
+                    let node = self.get_node(block + i);
+                    if node < VOXEL_OFFSET {
+                        next.push(node as usize);
+                    } else if self.is_occupied(block + i) {
+                        solid += 1;
+                    }
+                }
+            }
+            blocks = next;
+        }
+
+        // First child of each filled node on the current level, None for solid ones
+        let mut level = vec![Some(0)];
+        let mut node_counts = vec![1u32];
+        let mut data = vec![0; 16];
+        data.extend(top_level.to_le_bytes());
+        let mut stream = Vec::with_capacity(masks as usize);
+        for _ in 0..top_level {
+            let mut next = Vec::new();
+            for children in level {
+                let mut mask = 0;
+                for i in 0..8 {
+                    let child = match children {
+                        Some(children) => {
+                            let node = self.get_node(children + i);
+                            if node < VOXEL_OFFSET {
+                                Some(node as usize)
+                            } else if self.is_occupied(children + i) {
+                                None
+                            } else {
+                                continue;
+                            }
+                        }
+                        None => None,
+                    };
+
+                    mask |= 1 << i;
+                    next.push(child);
+                }
+                stream.push(mask);
+            }
+
+            node_counts.push(next.len() as u32);
+            level = next;
+        }
+
+        for count in node_counts {
+            data.extend(count.to_le_bytes());
+        }
+        data.extend(stream);
+        Ok(data)
+    }
+
+    /// Reads the contents of a file written by `save_svot`
+    pub fn from_svot_bytes(bytes: &[u8]) -> Result<Octree, String> {
+        if bytes.len() < 5 || bytes[0..4] != SVOT_MAGIC {
+            return Err("Not an octree file".to_string());
+        }
+        if bytes[4] != SVOT_VERSION {
+            return Err(format!(
+                "Octree file is version {}, expected {}",
+                bytes[4], SVOT_VERSION
+            ));
+        }
+
+        Octree::from_dump_bytes(&bytes[5..])
+    }
+
+    pub fn load_nodes<P: AsRef<std::path::Path>>(path: P) -> Result<Octree, String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        Octree::from_dump_bytes(&bytes)
+    }
+
+    fn from_dump_bytes(bytes: &[u8]) -> Result<Octree, String> {
+        if bytes.len() % 4 != 0 {
+            return Err("Node dump is not a whole number of u32s".to_string());
+        }
+
+        let data: Vec<u32> = bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        if data.len() < 3 {
+            return Err("Node dump is missing its header".to_string());
+        }
+
+        let len = data[0] as usize;
+        let hole_count = data[2] as usize;
+        if len < 8 || data.len() != 3 + len + hole_count {
+            return Err(format!(
+                "Node dump has {} values, header expects {}",
+                data.len() - 3,
+                len + hole_count
+            ));
+        }
+
+        let nodes = data[3..3 + len].to_vec();
+        let hole_stack = data[3 + len..].iter().map(|hole| *hole as usize).collect();
+
+        // Positions aren't stored so walk the tree to rebuild them
+        let mut octree = Self {
+            nodes,
+            positions: Vec::new(),
+            hole_stack,
+            palette: Vec::new(),
+            occupied: 0,
+        };
+        octree
+            .validate()
+            .map_err(|e| format!("Node dump is invalid: {}", e))?;
+        octree
+            .fill_positions()
+            .map_err(|e| format!("Node dump {}", e))?;
+        octree.occupied = octree.leaves().count();
+
+        if octree.max_depth() != data[1] {
+            return Err(format!(
+                "Node dump has depth {}, header says {}",
+                octree.max_depth(),
+                data[1]
+            ));
+        }
+
+        Ok(octree)
+    }
+
+    /// Checks the node buffer is a tree that the rest of the octree code can walk without
+    /// panicking. Every block of 8 reachable from the root must be in range, 8 aligned and
+    /// only referenced once, and holes must not overlap anything reachable.
+    pub fn validate(&self) -> Result<(), String> {
+        let len = self.nodes.len();
+        if len < 8 || len % 8 != 0 {
+            return Err(format!("Node buffer length {} is not a multiple of 8", len));
+        }
+
+        let mut live = vec![false; len / 8];
+        live[0] = true;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            for i in 0..8 {
+                let node = index + i;
+                let tnipt = self.get_node(node);
+                if tnipt >= VOXEL_OFFSET {
+                    if tnipt - VOXEL_OFFSET > 0xFFFFFF {
+                        return Err(format!(
+                            "Node {} has leaf value {:#x} past the last colour",
+                            node, tnipt
+                        ));
+                    }
+                    continue;
+                }
+
+                let child = tnipt as usize;
+                if child % 8 != 0 {
+                    return Err(format!(
+                        "Node {} has child pointer {} not aligned to 8",
+                        node, child
+                    ));
+                }
+                if child + 8 > len {
+                    return Err(format!(
+                        "Node {} has child pointer {} out of range",
+                        node, child
+                    ));
+                }
+                if live[child / 8] {
+                    return Err(format!(
+                        "Node {} points back to block {} which is already in the tree",
+                        node, child
+                    ));
+                }
+
+                live[child / 8] = true;
+                stack.push(child);
+            }
+        }
+
+        let mut holes = vec![false; len / 8];
+        for &hole in &self.hole_stack {
+            if hole % 8 != 0 || hole + 8 > len {
+                return Err(format!("Hole {} is not a block in the node buffer", hole));
+            }
+            if live[hole / 8] {
+                return Err(format!("Hole {} overlaps nodes still in the tree", hole));
+            }
+            if holes[hole / 8] {
+                return Err(format!("Hole {} is in the hole stack twice", hole));
+            }
+            holes[hole / 8] = true;
+        }
+
+        Ok(())
+    }
+
+    /// Rebuilds the centre position of every node reachable from the root, holes are left
+    /// at zero
+    pub fn fill_positions(&mut self) -> Result<(), String> {
+        let len = self.nodes.len();
+        self.positions = vec![Vector3::zero(); len];
+
+        let mut stack = vec![(0, 1, Vector3::zero())];
+        while let Some((index, depth, pos)) = stack.pop() {
+            if depth > MAX_DEPTH {
+                return Err("contains a cycle".to_string());
+            }
+            if index + 8 > len {
+                return Err(format!("has child pointer {} out of range", index));
+            }
+
+            for i in 0..8 {
+                let child_pos = pos + Octree::pos_offset(i, depth);
+                self.positions[index + i] = child_pos;
+
+                let child = self.get_node(index + i);
+                if child < VOXEL_OFFSET {
+                    stack.push((child as usize, depth + 1, child_pos));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Depth of the deepest leaf reachable from the root, at most MAX_DEPTH
+    pub fn max_depth(&self) -> u32 {
+        let mut max_depth = 0;
+        let mut stack = vec![(0, 1)];
+        while let Some((index, depth)) = stack.pop() {
+            max_depth = max_depth.max(depth);
+            for i in 0..8 {
+                let child = self.get_node(index + i);
+                if child < VOXEL_OFFSET && depth < MAX_DEPTH {
+                    stack.push((child as usize, depth + 1));
+                }
+            }
+        }
+
+        max_depth
+    }
+
     pub fn pos_offset(child_index: usize, depth: u32) -> Vector3<f32> {
         let x = (child_index >> 2) & 1;
         let y = (child_index >> 1) & 1;
         let z = child_index & 1;
 
+        // Powers of two are exact in f32 so only the sums of these lose precision, cell
+        // centres are exact up to MAX_PRECISE_DEPTH
         (Vector3::new(x as f32, y as f32, z as f32) * 2.0 - Vector3::new(1.0, 1.0, 1.0))
-            / (1 << depth) as f32
+            * 0.5f32.powi(depth as i32)
     }
 }
 
+/// Backs `Octree::leaves`, only holds one frame per level
+struct Leaves<'a> {
+    octree: &'a Octree,
+    // (children, depth, pos, next child)
+    stack: Vec<(usize, u32, Vector3<f32>, usize)>,
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = (Vector3<f32>, u32, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(frame) = self.stack.last_mut() {
+            let (index, depth, pos, i) = *frame;
+            if i == 8 {
+                self.stack.pop();
+                continue;
+            }
+            frame.3 += 1;
+
+            let child_pos = pos + Octree::pos_offset(i, depth);
+            let tnipt = self.octree.get_node(index + i);
+            if tnipt < VOXEL_OFFSET {
+                self.stack.push((tnipt as usize, depth + 1, child_pos, 0));
+            } else if tnipt > VOXEL_OFFSET {
+                return Some((child_pos, depth, tnipt));
+            }
+        }
+
+        None
+    }
+}
+
+pub fn in_bounds(v: Vector3<f32>) -> bool {
+    v.x >= -1.0 && v.x < 1.0 && v.y >= -1.0 && v.y < 1.0 && v.z >= -1.0 && v.z < 1.0
+}
+
+/// Distance along the ray to the octree cube, 0 if it misses
+fn ray_box_dist(pos: Vector3<f32>, dir: Vector3<f32>) -> f32 {
+    let t1 = (Vector3::new(-1.0, -1.0, -1.0) - pos).div_element_wise(dir);
+    let t2 = (Vector3::new(1.0, 1.0, 1.0) - pos).div_element_wise(dir);
+    let near = t1.x.min(t2.x).max(t1.y.min(t2.y)).max(t1.z.min(t2.z));
+    let far = t1.x.max(t2.x).min(t1.y.max(t2.y)).min(t1.z.max(t2.z));
+    if far < 0.0 || near > far {
+        return 0.0;
+    }
+
+    near
+}
+
+/// Entry distance and normal of the entry face for the cube at `centre` with half size
+/// `half`, the distance is 0 when starting inside
+fn box_hit(
+    centre: Vector3<f32>,
+    half: f32,
+    origin: Vector3<f32>,
+    inv_dir: Vector3<f32>,
+) -> Option<(f32, Vector3<f32>)> {
+    let t1 = (centre - Vector3::new(half, half, half) - origin).mul_element_wise(inv_dir);
+    let t2 = (centre + Vector3::new(half, half, half) - origin).mul_element_wise(inv_dir);
+    let t_min = Vector3::new(t1.x.min(t2.x), t1.y.min(t2.y), t1.z.min(t2.z));
+    let t_max = Vector3::new(t1.x.max(t2.x), t1.y.max(t2.y), t1.z.max(t2.z));
+
+    let near = t_min.x.max(t_min.y).max(t_min.z);
+    let far = t_max.x.min(t_max.y).min(t_max.z);
+    if far < near.max(0.0) {
+        return None;
+    }
+
+    let axis = if near == t_min.x {
+        Vector3::unit_x()
+    } else if near == t_min.y {
+        Vector3::unit_y()
+    } else {
+        Vector3::unit_z()
+    };
+    let normal = -axis.mul_element_wise(inv_dir.map(|d| d.signum()));
+
+    Some((near.max(0.0), normal))
+}
+
 pub fn create_node(value: usize) -> u32 {
     (value as u32) << 4
 }
@@ -180,25 +1222,15 @@ impl std::fmt::Debug for Octree {
         write!(f, "Nodes ({}):\n", self.nodes.len())?;
         let mut c = 0;
         for value in &self.nodes {
-            let pos = self.positions[c];
+            // Positions can be missing on a hand built octree
+            let pos = match self.positions.get(c) {
+                Some(pos) => format!(" ({}, {}, {})", pos.x, pos.y, pos.z),
+                None => String::new(),
+            };
             if *value >= VOXEL_OFFSET << 4 {
-                write!(
-                    f,
-                    "  Voxel: {} ({}, {}, {})\n",
-                    (*value >> 4) - VOXEL_OFFSET,
-                    pos.x,
-                    pos.y,
-                    pos.z
-                )?;
+                write!(f, "  Voxel: {}{}\n", (*value >> 4) - VOXEL_OFFSET, pos)?;
             } else {
-                write!(
-                    f,
-                    "  Node: {} ({}, {}, {})\n",
-                    *value >> 4,
-                    pos.x,
-                    pos.y,
-                    pos.z
-                )?;
+                write!(f, "  Node: {}{}\n", *value >> 4, pos)?;
             }
 
             c += 1;
@@ -216,3 +1248,536 @@ impl std::fmt::Debug for Voxel {
         write!(f, "({}, {}, {})", self.r, self.g, self.b)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cyclic_pointer_stops_at_max_depth() {
+        // Child 7 points back at the root block, the far corner keeps going into it. Built
+        // directly since from_nodes rejects the cycle
+        let mask = [Voxel::new(255, 0, 0); 8];
+        let mut nodes = Octree::new(mask).raw_data().clone();
+        nodes[7] = create_node(0);
+        let octree = Octree {
+            nodes,
+            ..Octree::new(mask)
+        };
+        let pos = Vector3::new(1.0, 1.0, 1.0);
+
+        let (index, depth, _) = octree.find_voxel(pos, None);
+        assert_eq!((index, depth), (7, MAX_DEPTH));
+        assert!(octree.get_node_checked(pos).is_err());
+        assert_eq!(
+            octree.get_node_checked(-pos),
+            Ok(Voxel::new(255, 0, 0).to_value() >> 4)
+        );
+        assert_eq!(octree.max_depth(), MAX_DEPTH);
+        assert!(octree.validate().is_err());
+    }
+
+    // Header, top level 2 and node counts, then the root's mask and its two children's
+    fn rsvo_fixture() -> Vec<u8> {
+        let mut data = vec![0; 16];
+        for value in [2u32, 1, 2, 5] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend([0b1000_0001, 0b0000_1111, 0b1000_0000]);
+        data
+    }
+
+    #[test]
+    fn rsvo_round_trips_byte_for_byte() {
+        let data = rsvo_fixture();
+        let octree = CpuOctree::load_bytes(&data, "rsvo", 2, false)
+            .unwrap()
+            .to_octree()
+            .unwrap();
+        assert_eq!(octree.max_depth(), 2);
+        assert_eq!(octree.rsvo_bytes(2).unwrap(), data);
+
+        // Asking for more levels than the tree has writes the same file
+        assert_eq!(octree.rsvo_bytes(9).unwrap(), data);
+    }
+
+    #[test]
+    fn rsvo_deeper_than_the_top_level_loads_everything() {
+        let cpu = CpuOctree::load_bytes(&rsvo_fixture(), "rsvo", 9, false).unwrap();
+        assert_eq!(cpu.depth(), 2);
+        assert_eq!(
+            cpu.to_octree().unwrap().rsvo_bytes(2).unwrap(),
+            rsvo_fixture()
+        );
+    }
+
+    #[test]
+    fn rsvo_truncates_below_the_top_level() {
+        let octree = CpuOctree::load_bytes(&rsvo_fixture(), "rsvo", 2, false)
+            .unwrap()
+            .to_octree()
+            .unwrap();
+        let data = octree.rsvo_bytes(1).unwrap();
+        assert_eq!(data[16..28], [1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(data[28..], [0b1000_0001]);
+    }
+
+    #[test]
+    fn solid_leaves_far_above_the_top_level_error() {
+        // One deep branch keeps the top level up while the other children are solid
+        let mut cpu = CpuOctree::new(0b1111_1110);
+        cpu.set_voxel(Vector3::new(-0.9, -0.9, -0.9), 0xFF0000, 20);
+        let octree = cpu.to_octree().unwrap();
+        assert!(octree.rsvo_bytes(20).is_err());
+        assert!(octree.rsvo_bytes(3).is_ok());
+    }
+
+    #[test]
+    fn compacted_svot_loads_back() {
+        let red = Voxel::new(255, 0, 0);
+        let mut octree = Octree::new([red; 8]);
+        octree.subdivide(0, [red; 8], 2).unwrap();
+        octree.subdivide(1, [red; 8], 2).unwrap();
+        octree.unsubdivide(0).unwrap();
+        assert!(!octree.hole_stack.is_empty());
+
+        let path = std::env::temp_dir().join(format!("svot_test_{}.svot", std::process::id()));
+        octree.export_lod(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = Octree::from_svot_bytes(&data).unwrap();
+        assert_eq!(loaded.raw_data().len(), 16);
+        assert!(loaded.leaves().eq(octree.leaves()));
+        assert_eq!(
+            CpuOctree::load_bytes(&data, "svot", 8, false)
+                .unwrap()
+                .depth(),
+            2
+        );
+        assert!(Octree::from_svot_bytes(&data[1..]).is_err());
+    }
+
+    #[test]
+    fn monument_svot_round_trips() {
+        let depth = CpuOctree::file_depth("files/monu9.vox").unwrap();
+        let octree = CpuOctree::load_file("files/monu9.vox".to_string(), depth)
+            .unwrap()
+            .to_octree()
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("monu_test_{}.svot", std::process::id()));
+        octree.save_svot(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = Octree::from_svot_bytes(&data).unwrap();
+        assert_eq!(loaded.raw_data(), octree.raw_data());
+        assert_eq!(loaded.hole_stack, octree.hole_stack);
+        assert_eq!(loaded.dump_bytes(), data[5..]);
+    }
+
+    // A row of voxels along the bottom edge with a ray skimming just above it, so every
+    // cell it crosses is as deep as the row. Returns the trace towards a voxel at the far end
+    fn skim_row(depth: u32) -> RayHit {
+        let cells = 1 << depth;
+        let size = 2.0 / cells as f32;
+        let centre = |x: u32, y: u32| {
+            Vector3::new(
+                -1.0 + (x as f32 + 0.5) * size,
+                -1.0 + (y as f32 + 0.5) * size,
+                -1.0 + size / 2.0,
+            )
+        };
+
+        let mut cpu = CpuOctree::new(0);
+        for x in 0..cells {
+            cpu.set_voxel(centre(x, 0), 0xFF0000, depth);
+        }
+        cpu.set_voxel(centre(cells - 1, 1), 0x00FF00, depth);
+
+        let octree = cpu.to_octree().unwrap();
+        octree.trace(centre(0, 1), Vector3::unit_x())
+    }
+
+    #[test]
+    fn long_traces_through_deep_trees_still_hit() {
+        let hit = skim_row(8);
+        assert!(hit.hit);
+        assert!(!hit.exhausted);
+        assert!(hit.steps > MAX_TRACE_STEPS);
+    }
+
+    #[test]
+    fn running_out_of_steps_is_reported() {
+        let hit = skim_row(10);
+        assert!(!hit.hit);
+        assert!(hit.exhausted);
+        assert_eq!(hit.steps, trace_step_limit(10) + 1);
+    }
+
+    #[test]
+    fn occupied_count_follows_edits() {
+        let red = Voxel::new(255, 0, 0);
+        let empty = Voxel::new(0, 0, 0);
+        let mut octree = Octree::new([red, empty, red, empty, empty, empty, empty, red]);
+        let check =
+            |octree: &Octree| assert_eq!(octree.occupied_leaf_count(), octree.count_occupied(0));
+        check(&octree);
+
+        let children = octree
+            .subdivide(0, [red, red, empty, empty, empty, empty, empty, red], 2)
+            .unwrap();
+        check(&octree);
+        octree.set_leaf(children.start + 2, red);
+        octree.set_leaf(7, empty);
+        check(&octree);
+        octree.unsubdivide(0).unwrap();
+        check(&octree);
+
+        // The freed children are reused by the next subdivide
+        let reused = octree.subdivide(1, [empty; 8], 2).unwrap();
+        assert_eq!(reused, children);
+        check(&octree);
+        assert_eq!(octree.occupied_leaf_count(), 2);
+    }
+
+    #[test]
+    fn subdivide_stops_at_the_precise_depth() {
+        let red = Voxel::new(255, 0, 0);
+        let mut octree = Octree::new([red; 8]);
+        let mut node = 0;
+        for depth in 2..=MAX_PRECISE_DEPTH {
+            node = octree.subdivide(node, [red; 8], depth).unwrap().start;
+        }
+        assert!(octree
+            .subdivide(node, [red; 8], MAX_PRECISE_DEPTH + 1)
+            .is_err());
+        assert!(octree.subdivide(0, [red; 8], 2).is_err());
+        assert!(octree.validate().is_ok());
+    }
+
+    // Depth 2 model with the cells at x + y * 4 + z * 16 filled
+    fn grid_model(cells: &[usize]) -> Octree {
+        let mut grid = vec![false; 64];
+        for &cell in cells {
+            grid[cell] = true;
+        }
+        CpuOctree::from_grid(&grid, 4).unwrap().to_octree().unwrap()
+    }
+
+    #[test]
+    fn node_dumps_round_trip() {
+        let mut octree = grid_model(&[0, 5, 63]);
+        octree.unsubdivide(7).unwrap();
+        let path = std::env::temp_dir().join(format!("nodes_test_{}.bin", std::process::id()));
+        octree.dump_nodes(&path).unwrap();
+        let loaded = Octree::load_nodes(&path);
+        std::fs::remove_file(&path).ok();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.raw_data(), octree.raw_data());
+        assert_eq!(loaded.hole_stack, octree.hole_stack);
+        // Holes come back without positions
+        assert_eq!(loaded.positions[..16], octree.positions[..16]);
+        assert_eq!(loaded.occupied_leaf_count(), 3);
+
+        let bytes = octree.dump_bytes();
+        assert!(Octree::from_dump_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Octree::from_dump_bytes(&bytes[..bytes.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn traces_count_the_empty_cells_crossed() {
+        let mut mask = [Voxel::new(0, 0, 0); 8];
+        mask[1] = Voxel::new(255, 0, 0);
+        let octree = Octree::new(mask);
+        let hit = octree.trace(Vector3::new(-0.5, -0.5, -2.0), Vector3::unit_z());
+        assert!(hit.hit);
+        assert_eq!((hit.index, hit.depth, hit.steps), (1, 1, 1));
+
+        let miss = octree.trace(Vector3::new(0.5, -0.5, -2.0), Vector3::unit_z());
+        assert!(!miss.hit && !miss.exhausted);
+        assert_eq!(miss.steps, 1);
+    }
+
+    #[test]
+    fn debug_lists_every_node() {
+        let mut mask = [Voxel::new(0, 0, 0); 8];
+        mask[0] = Voxel::new(0, 0, 1);
+        let text = format!("{:?}", Octree::new(mask));
+        assert!(text.starts_with("Nodes (8):\n  Voxel: 1 (-0.5, -0.5, -0.5)\n"));
+        assert_eq!(text.matches("Voxel: 0").count(), 7);
+        assert_eq!(format!("{:?}", Voxel::new(1, 2, 3)), "(1, 2, 3)");
+    }
+
+    #[test]
+    fn stats_and_memory_cover_the_buffers() {
+        let mut octree = grid_model(&[0, 63]);
+        let stats = octree.stats();
+        assert_eq!((stats.total_nodes, stats.live_nodes), (24, 24));
+        assert_eq!((stats.leaves, stats.filled_leaves), (22, 2));
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(octree.max_depth(), 2);
+        assert_eq!(octree.gpu_bytes(0), 24 * 4);
+        assert_eq!(octree.gpu_bytes(100), 400);
+
+        octree.unsubdivide(7).unwrap();
+        let stats = octree.stats();
+        assert_eq!((stats.total_nodes, stats.live_nodes), (24, 16));
+
+        let report = octree.memory_report();
+        assert_eq!(report.nodes.len, 24);
+        assert_eq!(report.nodes.bytes, report.nodes.capacity * 4);
+        assert_eq!(report.hole_stack.len, 1);
+        assert_eq!(
+            report.total_bytes(),
+            report.nodes.bytes
+                + report.positions.bytes
+                + report.hole_stack.bytes
+                + report.palette.bytes
+        );
+
+        // Spare capacity from earlier loads is given back once the holes are compacted away
+        octree.palette.reserve(64);
+        octree.positions.reserve(64);
+        let before = octree.memory_report();
+        octree.compact();
+        octree.shrink_to_fit();
+        let after = octree.memory_report();
+        assert_eq!(after.nodes.len, 16);
+        assert!(after.nodes.capacity < before.nodes.capacity);
+        assert!(after.total_bytes() < before.total_bytes());
+        for usage in [
+            after.nodes,
+            after.positions,
+            after.hole_stack,
+            after.palette,
+        ] {
+            assert_eq!(usage.capacity, usage.len);
+        }
+    }
+
+    #[test]
+    fn unsubdivide_averages_the_children() {
+        let mut mask = [Voxel::new(0, 0, 0); 8];
+        let mut octree = Octree::new(mask);
+        mask[0] = Voxel::new(200, 0, 0);
+        mask[1] = Voxel::new(100, 50, 0);
+        let children = octree.subdivide(3, mask, 2).unwrap();
+        assert_eq!(octree.occupied_leaf_count(), 2);
+
+        octree.unsubdivide(3).unwrap();
+        assert_eq!(octree.get_node(3), Voxel::new(150, 25, 1).to_value() >> 4);
+        assert_eq!(octree.occupied_leaf_count(), 1);
+        assert!(octree.unsubdivide(3).is_err());
+
+        // The freed block is reused by the next split
+        assert_eq!(octree.subdivide(5, mask, 2).unwrap(), children);
+        assert!(octree.hole_stack.is_empty());
+    }
+
+    #[test]
+    fn leaves_yield_every_filled_cell() {
+        let octree = grid_model(&[0, 5, 63]);
+        let leaves: Vec<(Vector3<f32>, u32)> = octree
+            .leaves()
+            .map(|(pos, depth, _)| (pos, depth))
+            .collect();
+        assert_eq!(leaves.len(), octree.occupied_leaf_count());
+        for pos in [
+            Vector3::new(-0.75, -0.75, -0.75),
+            Vector3::new(-0.25, -0.25, -0.75),
+            Vector3::new(0.75, 0.75, 0.75),
+        ] {
+            assert!(leaves.contains(&(pos, 2)));
+        }
+    }
+
+    #[test]
+    fn default_mask_has_five_leaves() {
+        let octree = CpuOctree::new(0b01011011).to_octree().unwrap();
+        assert_eq!(octree.leaves().count(), 5);
+
+        // Children are numbered x * 4 + y * 2 + z like the bits of the mask
+        let first: Vec<(Vector3<f32>, u32)> = octree
+            .leaves()
+            .take(2)
+            .map(|(pos, depth, _)| (pos, depth))
+            .collect();
+        assert_eq!(
+            first,
+            [
+                (Vector3::new(-0.5, -0.5, -0.5), 1),
+                (Vector3::new(-0.5, -0.5, 0.5), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn nodes_at_depth_stop_at_shallow_leaves() {
+        let octree = grid_model(&[0, 63]);
+        let mut out = Vec::new();
+        octree.nodes_at_depth(1, &mut out);
+        assert_eq!(out.len(), 8);
+        assert!(out.contains(&(0, Vector3::new(-0.5, -0.5, -0.5))));
+
+        // Only the two corners are split, the other six stay at depth 1
+        octree.nodes_at_depth(2, &mut out);
+        assert_eq!(out.len(), 6 + 2 * 8);
+        assert!(out.contains(&(1, Vector3::new(-0.5, -0.5, 0.5))));
+        assert!(out
+            .iter()
+            .any(|(_, pos)| *pos == Vector3::new(-0.75, -0.75, -0.75)));
+
+        octree.nodes_at_depth(3, &mut out);
+        assert_eq!(out.len(), 6 + 2 * 8);
+    }
+
+    #[test]
+    fn adopted_buffers_find_their_holes() {
+        let mut octree = grid_model(&[0, 63]);
+        let children = octree.get_node(0) as usize;
+        octree.unsubdivide(0).unwrap();
+        assert_eq!(octree.hole_stack, vec![children]);
+
+        let adopted = Octree::from_nodes(octree.raw_data().clone()).unwrap();
+        assert_eq!(adopted.hole_stack, vec![children]);
+        assert_eq!(adopted.occupied_leaf_count(), 2);
+
+        // Pointers out of range, unaligned or shared between parents
+        let mut nodes = octree.raw_data().clone();
+        nodes[1] = create_node(nodes.len());
+        assert!(Octree::from_nodes(nodes).is_err());
+        let mut nodes = octree.raw_data().clone();
+        nodes[1] = create_node(3);
+        assert!(Octree::from_nodes(nodes).is_err());
+        let mut nodes = octree.raw_data().clone();
+        nodes[1] = nodes[7];
+        assert!(Octree::from_nodes(nodes).is_err());
+    }
+
+    #[test]
+    fn raycast_agrees_with_trace() {
+        let octree = grid_model(&[0, 5, 63]);
+        for (origin, dir) in [
+            (Vector3::new(-0.75, 2.0, -0.75), -Vector3::unit_y()),
+            (Vector3::new(-2.0, -0.25, -0.75), Vector3::unit_x()),
+            (Vector3::new(0.75, 0.75, -2.0), Vector3::unit_z()),
+        ] {
+            let (index, pos, normal) = octree.raycast(origin, dir).unwrap();
+            let hit = octree.trace(origin, dir);
+            assert!(hit.hit);
+            assert_eq!((index, normal), (hit.index, hit.normal));
+            assert!((pos - hit.pos).magnitude() < 1e-4);
+        }
+
+        let (origin, dir) = (Vector3::new(0.75, 2.0, -0.75), -Vector3::unit_y());
+        assert!(octree.raycast(origin, dir).is_none());
+        assert!(!octree.trace(origin, dir).hit);
+    }
+
+    #[test]
+    fn neighbours_and_exposed_faces() {
+        let (red, empty) = (Voxel::new(255, 0, 0), Voxel::new(0, 0, 0));
+        let mut mask = [empty; 8];
+        mask[0] = red;
+        mask[4] = red;
+        let octree = Octree::new(mask);
+        assert_eq!(octree.neighbor(0, Axis::X, true), Some((4, 1)));
+        assert_eq!(octree.neighbor(0, Axis::Z, true), Some((1, 1)));
+        assert_eq!(octree.neighbor(0, Axis::X, false), None);
+        assert_eq!(octree.exposed_faces(0), 5);
+        assert_eq!(octree.exposed_faces(1), 5);
+        assert_eq!(octree.exposed_faces(7), 6);
+
+        // Across into a coarser empty leaf
+        let octree = grid_model(&[5]);
+        let (node, _, _) = octree.find_voxel(Vector3::new(-0.25, -0.25, -0.75), None);
+        let (index, depth) = octree.neighbor(node, Axis::X, true).unwrap();
+        assert_eq!((depth, octree.get_node(index)), (1, VOXEL_OFFSET));
+        assert_eq!(octree.exposed_faces(node), 6);
+    }
+
+    #[test]
+    fn masks_load_back_as_raw_grids() {
+        let octree = grid_model(&[0, 5, 63]);
+        let path = std::env::temp_dir().join(format!("mask_test_{}.raw", std::process::id()));
+        octree.export_mask(path.to_str().unwrap(), 2).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(data.len(), 64);
+        let loaded = CpuOctree::load_bytes(&data, "raw", 2, false).unwrap();
+        assert_eq!(loaded.to_octree().unwrap().to_grid(2), octree.to_grid(2));
+
+        assert!(octree.export_mask(path.to_str().unwrap(), 20).is_err());
+        for size in [0, 7, 27] {
+            assert!(CpuOctree::load_bytes(&vec![1; size], "raw", 2, false).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_rsvo_errors_name_the_byte() {
+        let data = rsvo_fixture();
+        let error = CpuOctree::load_bytes(&data[..34], "rsvo", 2, false)
+            .err()
+            .unwrap();
+        assert!(error.contains("byte 34"), "{}", error);
+        let error = CpuOctree::load_bytes(&data[..20], "rsvo", 2, false)
+            .err()
+            .unwrap();
+        assert!(error.contains("byte 20"), "{}", error);
+    }
+
+    #[test]
+    fn deep_offsets_stay_exact() {
+        assert_eq!(Octree::pos_offset(0, 1), Vector3::new(-0.5, -0.5, -0.5));
+        assert_eq!(Octree::pos_offset(6, 2), Vector3::new(0.25, 0.25, -0.25));
+
+        // The far corner cell keeps an exact centre all the way down
+        let mut pos = Vector3::zero();
+        for depth in 1..=MAX_PRECISE_DEPTH {
+            pos += Octree::pos_offset(7, depth);
+        }
+        let expected = 1.0 - 0.5f32.powi(MAX_PRECISE_DEPTH as i32);
+        assert_eq!(pos, Vector3::new(expected, expected, expected));
+    }
+
+    #[test]
+    fn depth_20_cells_round_trip_through_get_node() {
+        let depth = 20;
+        let pos = Vector3::new(0.123457, -0.654321, 0.999999);
+        let mut cpu_octree = CpuOctree::new(0);
+        assert!(cpu_octree.set_voxel(pos, 0x3366CC, depth));
+        let octree = cpu_octree.to_octree().unwrap();
+
+        let (index, found, centre) = octree.find_voxel(pos, None);
+        assert_eq!(found, depth);
+        let colour = Voxel::from_value(octree.get_node(index) - VOXEL_OFFSET);
+        assert_eq!(colour, Voxel::new(0x33, 0x66, 0xCC));
+        assert_eq!(octree.positions[index], centre);
+        let half_cell = 0.5f32.powi(depth as i32);
+        assert!((0..3).all(|i| (centre[i] - pos[i]).abs() <= half_cell));
+    }
+
+    #[test]
+    fn only_the_shell_of_a_solid_cube_is_exposed() {
+        let mut cpu_octree = CpuOctree::new(0);
+        for cell in 0..64 {
+            let pos =
+                Vector3::new(cell % 4, cell / 4 % 4, cell / 16).map(|c| c as f32 * 0.5 - 0.75);
+            cpu_octree.set_voxel(pos, 0xFFFFFF, 2);
+        }
+        let octree = cpu_octree.to_octree().unwrap();
+
+        for cell in 0..64 {
+            let cell = Vector3::new(cell % 4, cell / 4 % 4, cell / 16);
+            let (node, depth, _) = octree.find_voxel(cell.map(|c| c as f32 * 0.5 - 0.75), None);
+            assert_eq!(depth, 2);
+            // One face for each coordinate on the outside of the cube
+            let outside = cell.map(|c| (c == 0 || c == 3) as usize).sum();
+            assert_eq!(octree.exposed_faces(node), outside, "{:?}", cell);
+        }
+    }
+}
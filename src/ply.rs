@@ -0,0 +1,284 @@
+use super::*;
+
+#[derive(Copy, Clone, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => return Err(format!("PLY has unknown type {}", name)),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    kind: PlyType,
+    // Type of the length for list properties
+    list: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+/// Points of an ASCII or binary .ply file's vertex element, with their 0xRRGGBB colour
+/// when it has red, green and blue properties
+pub fn ply_points(data: &[u8]) -> Result<Vec<(Vector3<f32>, Option<u32>)>, String> {
+    if !data.starts_with(b"ply") {
+        return Err("Not a PLY file".to_string());
+    }
+    let header_end = data
+        .windows(10)
+        .position(|w| w == b"end_header")
+        .ok_or("PLY header has no end_header".to_string())?;
+    let body_start = data[header_end..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map(|i| header_end + i + 1)
+        .unwrap_or(data.len());
+    let header = String::from_utf8_lossy(&data[..header_end]);
+
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    for line in header.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["format", "ascii", _] => format = Some(PlyFormat::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(PlyFormat::LittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(PlyFormat::BigEndian),
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("PLY element {} has a bad count", name))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", length, kind, name] => elements
+                .last_mut()
+                .ok_or("PLY property comes before any element".to_string())?
+                .properties
+                .push(PlyProperty {
+                    name: name.to_string(),
+                    kind: PlyType::parse(kind)?,
+                    list: Some(PlyType::parse(length)?),
+                }),
+            ["property", kind, name] => elements
+                .last_mut()
+                .ok_or("PLY property comes before any element".to_string())?
+                .properties
+                .push(PlyProperty {
+                    name: name.to_string(),
+                    kind: PlyType::parse(kind)?,
+                    list: None,
+                }),
+            _ => {}
+        }
+    }
+    let format = format.ok_or("PLY header has no format".to_string())?;
+
+    let body = &data[body_start..];
+    let mut values = PlyValues {
+        format,
+        reader: Reader::new(body),
+        tokens: "".split_ascii_whitespace(),
+    };
+    if format == PlyFormat::Ascii {
+        let text = std::str::from_utf8(body).map_err(|_| "ASCII PLY isn't text".to_string())?;
+        values.tokens = text.split_ascii_whitespace();
+    }
+
+    for element in &elements {
+        // Every row takes at least a byte, this also stops rows with no properties looping
+        if element.count > body.len() {
+            return Err(format!(
+                "PLY element {} has {} rows but only {} bytes follow the header",
+                element.name,
+                element.count,
+                body.len()
+            ));
+        }
+
+        if element.name != "vertex" {
+            // Elements before the vertices still have to be read past
+            for _ in 0..element.count {
+                for property in &element.properties {
+                    values.property(property)?;
+                }
+            }
+            continue;
+        }
+
+        let find = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let (x, y, z) = match (find("x"), find("y"), find("z")) {
+            (Some(x), Some(y), Some(z)) => (x, y, z),
+            _ => return Err("PLY vertices have no position".to_string()),
+        };
+        let colour = match (find("red"), find("green"), find("blue")) {
+            (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+            _ => None,
+        };
+
+        let mut points = Vec::with_capacity(element.count.min(body.len()));
+        let mut row = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            for (i, property) in element.properties.iter().enumerate() {
+                row[i] = values.property(property)?;
+            }
+
+            let pos = Vector3::new(row[x], row[y], row[z]).cast::<f32>().unwrap();
+            if !(pos.x.is_finite() && pos.y.is_finite() && pos.z.is_finite()) {
+                continue;
+            }
+            let colour = colour.map(|channels| {
+                let [r, g, b] = channels.map(|i| {
+                    // Float colours are 0..1
+                    let value = match element.properties[i].kind {
+                        PlyType::F32 | PlyType::F64 => row[i] * 255.0,
+                        _ => row[i],
+                    };
+                    // Black is empty
+                    (value.clamp(0.0, 255.0) as u8).max(1)
+                });
+                Voxel::new(r, g, b).to_cpu_value()
+            });
+            points.push((pos, colour));
+        }
+
+        return Ok(points);
+    }
+
+    Err("PLY file has no vertex element".to_string())
+}
+
+/// Reads values from the body of a .ply, whitespace separated or packed binary
+struct PlyValues<'a> {
+    format: PlyFormat,
+    reader: Reader<'a>,
+    tokens: std::str::SplitAsciiWhitespace<'a>,
+}
+
+impl<'a> PlyValues<'a> {
+    fn value(&mut self, kind: PlyType) -> Result<f64, String> {
+        if self.format == PlyFormat::Ascii {
+            let token = self
+                .tokens
+                .next()
+                .ok_or("PLY file is truncated".to_string())?;
+            return token
+                .parse::<f64>()
+                .map_err(|_| format!("PLY has a bad value \"{}\"", token));
+        }
+
+        let size = kind.size();
+        let bytes = self.reader.bytes(size)?;
+
+        let mut b = [0; 8];
+        b[..size].copy_from_slice(bytes);
+        if self.format == PlyFormat::BigEndian {
+            b[..size].reverse();
+        }
+        Ok(match kind {
+            PlyType::I8 => b[0] as i8 as f64,
+            PlyType::U8 => b[0] as f64,
+            PlyType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            PlyType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            PlyType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            PlyType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            PlyType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            PlyType::F64 => f64::from_le_bytes(b),
+        })
+    }
+
+    /// Value of a scalar property, lists are read past and give 0
+    fn property(&mut self, property: &PlyProperty) -> Result<f64, String> {
+        match property.list {
+            None => self.value(property.kind),
+            Some(length) => {
+                let length = self.value(length)?;
+                for _ in 0..length as usize {
+                    self.value(property.kind)?;
+                }
+                Ok(0.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_points_with_colour() {
+        let data = b"ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nproperty float y\n\
+property float z\nproperty uchar red\nproperty uchar green\nproperty uchar blue\nend_header\n\
+0 0 0 255 0 0\n1 2 3 0 0 0\n";
+        let points = ply_points(data).unwrap();
+
+        assert_eq!(points.len(), 2);
+        // Channels are kept above 0 so black doesn't read as empty
+        assert_eq!(points[0].1, Some(0xFF0101));
+        assert_eq!(points[1].0, Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(points[1].1, Some(0x010101));
+    }
+
+    #[test]
+    fn binary_points_after_other_elements() {
+        let mut data = b"ply\nformat binary_little_endian 1.0\nelement face 1\n\
+property list uchar int vertex_indices\nelement vertex 1\nproperty float x\n\
+property float y\nproperty float z\nend_header\n"
+            .to_vec();
+        data.push(2);
+        data.extend(7i32.to_le_bytes());
+        data.extend(8i32.to_le_bytes());
+        for v in [0.5f32, -1.0, 2.0] {
+            data.extend(v.to_le_bytes());
+        }
+
+        let points = ply_points(&data).unwrap();
+        assert_eq!(points, vec![(Vector3::new(0.5, -1.0, 2.0), None)]);
+    }
+
+    #[test]
+    fn huge_element_without_properties_is_rejected() {
+        let data = b"ply\nformat binary_little_endian 1.0\nelement junk 4000000000\n\
+element vertex 0\nproperty float x\nproperty float y\nproperty float z\nend_header\n";
+        assert!(ply_points(data).is_err());
+    }
+}
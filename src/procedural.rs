@@ -8,6 +8,9 @@ pub struct GenSettings {
     pub seed: u32,
     pub scale: f32,
     pub height: f32,
+    pub threshold: f32,
+    // Chunks per side of a generated world is 2^world_depth
+    pub world_depth: u32,
 }
 
 impl Default for GenSettings {
@@ -16,10 +19,120 @@ impl Default for GenSettings {
             seed: 0,
             scale: 0.2,
             height: 0.2,
+            threshold: 0.5,
+            world_depth: 1,
         }
     }
 }
 
+/// Cpu generated test scenes
+pub enum GenPattern {
+    // Filled where seeded value noise in 0..1 is above threshold
+    Noise { seed: u32, threshold: f32 },
+    // A ball resting on a floor, filled shape by shape so any depth is cheap
+    Shapes,
+}
+
+impl GenPattern {
+    /// Fills leaves at `depth`, the same pattern always gives the same octree
+    pub fn generate(&self, depth: u32) -> CpuOctree {
+        match *self {
+            GenPattern::Noise { seed, threshold } => noise_octree(seed, threshold, depth),
+            GenPattern::Shapes => {
+                let mut octree = CpuOctree::new(0);
+                let floor = (
+                    Vector3::new(-1.0, -1.0, -1.0),
+                    Vector3::new(1.0, -0.75, 1.0),
+                );
+                octree.fill_box(floor.0, floor.1, 0x808080, depth);
+                octree.fill_sphere(Vector3::new(0.0, -0.25, 0.0), 0.5, 0xD04020, depth);
+                octree
+            }
+        }
+    }
+}
+
+fn noise_octree(seed: u32, threshold: f32, depth: u32) -> CpuOctree {
+    let size = 1u32 << depth;
+
+    let mut octree = CpuOctree::new(0);
+    for x in 0..size {
+        for y in 0..size {
+            for z in 0..size {
+                let pos = (Vector3::new(x as f32, y as f32, z as f32)
+                    + Vector3::new(0.5, 0.5, 0.5))
+                    / size as f32
+                    * 2.0
+                    - Vector3::new(1.0, 1.0, 1.0);
+
+                let value = fractal_noise(seed, pos);
+                if value > threshold {
+                    let shade = (value * 255.0) as u8;
+                    octree.put_in_voxel(
+                        pos,
+                        Voxel::new(shade, (shade as f32 * 0.8) as u8, 100),
+                        depth,
+                    );
+                }
+            }
+        }
+    }
+
+    octree
+}
+
+fn hash(seed: u32, x: i32, y: i32, z: i32) -> f32 {
+    let mut h = seed
+        ^ (x as u32).wrapping_mul(0x8da6b343)
+        ^ (y as u32).wrapping_mul(0xd8163841)
+        ^ (z as u32).wrapping_mul(0xcb1ab31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+
+    h as f32 / u32::MAX as f32
+}
+
+/// Trilinear value noise on the integer lattice, in 0..1
+fn value_noise(seed: u32, pos: Vector3<f32>) -> f32 {
+    let cell = pos.map(|p| p.floor());
+    let t = (pos - cell).map(|t| t * t * (3.0 - 2.0 * t));
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let corner = |dx, dy, dz| hash(seed, x + dx, y + dy, z + dz);
+
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(0, 0, 1), t.z),
+            lerp(corner(0, 1, 0), corner(0, 1, 1), t.z),
+            t.y,
+        ),
+        lerp(
+            lerp(corner(1, 0, 0), corner(1, 0, 1), t.z),
+            lerp(corner(1, 1, 0), corner(1, 1, 1), t.z),
+            t.y,
+        ),
+        t.x,
+    )
+}
+
+/// Three octaves of value noise over the -1..1 cube, in 0..1
+fn fractal_noise(seed: u32, pos: Vector3<f32>) -> f32 {
+    let mut value = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 4.0;
+    for octave in 0..3 {
+        value += value_noise(seed.wrapping_add(octave), pos * frequency) * amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+
+    value / 0.875
+}
+
 pub struct Procedural {
     pipeline: wgpu::ComputePipeline,
     pub uniforms: Uniforms,
@@ -98,7 +211,12 @@ impl Procedural {
         }
     }
 
-    pub fn generate_chunk(&mut self, gpu: &Gpu, pos: Vector3<f32>, base_depth: u32) -> Option<CpuOctree> {
+    pub fn generate_chunk(
+        &mut self,
+        gpu: &Gpu,
+        pos: Vector3<f32>,
+        base_depth: u32,
+    ) -> Option<CpuOctree> {
         let mut encoder = gpu
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
@@ -145,6 +263,9 @@ impl Procedural {
         let mut cpu_octree = CpuOctree {
             nodes: Vec::new(),
             top_mip: Voxel::new(0, 0, 0),
+            materials: Default::default(),
+            file_palette: Vec::new(),
+            hole_stack: Vec::new(),
         };
 
         let slice = self.cpu_octree.slice(..);
@@ -345,3 +466,51 @@ impl Uniforms {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_terrain_is_reproducible() {
+        let nodes = |seed| {
+            GenPattern::Noise {
+                seed,
+                threshold: 0.5,
+            }
+            .generate(3)
+            .to_octree()
+            .unwrap()
+            .raw_data()
+            .clone()
+        };
+        assert_eq!(nodes(1), nodes(1));
+        assert_ne!(nodes(1), nodes(2));
+    }
+
+    #[test]
+    fn shapes_only_split_their_surfaces() {
+        let octree = GenPattern::Shapes.generate(6).to_octree().unwrap();
+        let filled = |pos: Vector3<f32>| {
+            let (index, _, _) = octree.find_voxel(pos, None);
+            octree.get_node(index) > VOXEL_OFFSET
+        };
+        assert!(filled(Vector3::new(0.9, -0.9, -0.9)));
+        assert!(filled(Vector3::new(0.0, -0.25, 0.0)));
+        assert!(!filled(Vector3::new(0.6, 0.6, 0.6)));
+
+        // Far fewer leaves than the cells they cover
+        let leaves = octree.leaves().count();
+        let (grid, _) = octree.to_grid(6);
+        assert!(leaves * 4 < grid.iter().filter(|filled| **filled).count());
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        for i in 0..1000 {
+            let pos = Vector3::new(i as f32 * 0.37, i as f32 * -0.11, i as f32 * 0.05) % 2.0;
+            let value = fractal_noise(7, pos);
+            assert!((0.0..=1.0).contains(&value));
+        }
+    }
+}
@@ -1,14 +1,205 @@
 use super::*;
+use std::collections::HashMap;
+
+// Length of the gpu node buffer in u32s
+pub const NODE_BUFFER_LENGTH: usize = 10_000_000;
+// Colours with an alpha, reflectivity or emission the shader can look up
+pub const MAX_MATERIAL_COLOURS: usize = 256;
+// Colours of a model's file palette the shader can see, a full .vox palette
+pub const MAX_PALETTE_COLOURS: usize = 256;
+// Lights besides the sun the shader loops over
+pub const MAX_LIGHTS: usize = 16;
+pub const LIGHT_DIRECTIONAL: u32 = 0;
+pub const LIGHT_POINT: u32 = 1;
+
+/// Present modes wgpu 0.12 can give us on each backend, it can't query the surface
+pub fn supported_present_modes(backend: wgpu::Backend) -> &'static [wgpu::PresentMode] {
+    use wgpu::PresentMode::*;
+    match backend {
+        wgpu::Backend::Vulkan => &[Fifo, Immediate, Mailbox],
+        wgpu::Backend::Metal | wgpu::Backend::Dx12 | wgpu::Backend::Dx11 => &[Fifo, Immediate],
+        _ => &[Fifo],
+    }
+}
+
+/// Picks `requested` if supported, otherwise the closest low latency mode and finally Fifo
+/// which is always available
+pub fn choose_present_mode(
+    requested: wgpu::PresentMode,
+    supported: &[wgpu::PresentMode],
+) -> wgpu::PresentMode {
+    let fallbacks = match requested {
+        wgpu::PresentMode::Mailbox => [wgpu::PresentMode::Mailbox, wgpu::PresentMode::Immediate],
+        wgpu::PresentMode::Immediate => [wgpu::PresentMode::Immediate, wgpu::PresentMode::Mailbox],
+        wgpu::PresentMode::Fifo => [wgpu::PresentMode::Fifo, wgpu::PresentMode::Fifo],
+    };
+
+    fallbacks
+        .into_iter()
+        .find(|mode| supported.contains(mode))
+        .unwrap_or(wgpu::PresentMode::Fifo)
+}
+
+/// Egui pixels per point for the window's scale factor times the ui scale setting
+pub fn ui_pixels_per_point(scale_factor: f64, ui_scale: f32) -> f32 {
+    scale_factor as f32 * ui_scale
+}
+
+/// The cursor position or window size of `event` divided by `ui_scale`, None for other
+/// events. The egui platform only divides these by the window's scale factor, so they need
+/// the ui scale taken out to land in egui's points
+#[allow(deprecated)]
+pub fn scale_ui_event<'a>(event: &Event<'a, ()>, ui_scale: f32) -> Option<Event<'a, ()>> {
+    let (window_id, event) = match event {
+        Event::WindowEvent { window_id, event } => (*window_id, event),
+        _ => return None,
+    };
+    let event = match event {
+        WindowEvent::CursorMoved {
+            device_id,
+            position,
+            modifiers,
+        } => WindowEvent::CursorMoved {
+            device_id: *device_id,
+            position: winit::dpi::PhysicalPosition::new(
+                position.x / ui_scale as f64,
+                position.y / ui_scale as f64,
+            ),
+            modifiers: *modifiers,
+        },
+        WindowEvent::Resized(size) => WindowEvent::Resized(winit::dpi::PhysicalSize::new(
+            (size.width as f32 / ui_scale).round() as u32,
+            (size.height as f32 / ui_scale).round() as u32,
+        )),
+        _ => return None,
+    };
+
+    Some(Event::WindowEvent { window_id, event })
+}
+
+/// True when `bytes` is more than 75% of `limit`
+pub fn near_buffer_limit(bytes: usize, limit: usize) -> bool {
+    bytes > limit / 4 * 3
+}
+
+/// Material buffer contents, `[count, (colour, alpha, reflectivity, emission)..]`. Extras
+/// past the buffer size render plain and only metals reflect
+pub fn material_data(materials: &HashMap<Voxel, Material>) -> Vec<u32> {
+    let mut data = vec![0u32];
+    for (voxel, material) in materials {
+        let reflectivity = match material.kind {
+            MaterialKind::Metal => material.reflectivity,
+            _ => 0.0,
+        };
+        let special = material.alpha < 1.0 || reflectivity > 0.0 || material.emission > 0.0;
+        if special && data[0] < MAX_MATERIAL_COLOURS as u32 {
+            data.push(voxel.to_cpu_value());
+            data.push(material.alpha.to_bits());
+            data.push(reflectivity.to_bits());
+            data.push(material.emission.to_bits());
+            data[0] += 1;
+        }
+    }
+    data
+}
+
+/// Pixel the shader writes the pick for, off the frame when `pos` is outside the window
+pub fn pick_pixel(pos: Vector2<f32>, size: [u32; 2]) -> [u32; 2] {
+    if pos.x < 0.0 || pos.y < 0.0 || pos.x >= size[0] as f32 || pos.y >= size[1] as f32 {
+        return [u32::MAX; 2];
+    }
+    [pos.x as u32, pos.y as u32]
+}
+
+/// Whether a pick is being read back and the last one that was. A frame's pick is only
+/// copied when the previous copy has been read, so results lag instead of stalling.
+#[derive(Default)]
+struct PickState {
+    in_flight: bool,
+    picked: Option<u32>,
+}
+
+impl PickState {
+    /// Whether to copy this frame's pick for reading, marking it in flight if so
+    fn copy(&mut self) -> bool {
+        !std::mem::replace(&mut self.in_flight, true)
+    }
+
+    /// Takes the read back pick buffer, [hit, node index]
+    fn read(&mut self, result: &[u32]) {
+        self.picked = if result[0] != 0 {
+            Some(result[1])
+        } else {
+            None
+        };
+        self.in_flight = false;
+    }
+}
+
+/// The tracer's pipelines, built together from one shader module
+struct Pipelines {
+    main: wgpu::RenderPipeline,
+    // Drawn once per layer instead of main when `Uniforms::peels`
+    peel: wgpu::RenderPipeline,
+}
+
+// Shader files in the order they're joined into one module
+const SHADER_FILES: [&str; 2] = ["common.wgsl", "shader.wgsl"];
+
+/// Rebuilds `current` from the shader files `read` gives, leaving it as it was when one
+/// can't be read or `build` fails to compile them. Nothing else is touched.
+fn rebuild<T>(
+    current: &mut T,
+    read: impl Fn(&str) -> Result<String, String>,
+    build: impl FnOnce(String) -> Result<T, String>,
+) -> Result<(), String> {
+    let mut source = String::new();
+    for file in SHADER_FILES {
+        source += &read(file)?;
+    }
+    *current = build(source)?;
+    Ok(())
+}
 
 pub struct Render {
     pub config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
-    pub render_pipeline: wgpu::RenderPipeline,
+    pipelines: Pipelines,
+    pipeline_layout: wgpu::PipelineLayout,
     pub uniforms: Uniforms,
     pub uniform_buffer: wgpu::Buffer,
     pub node_buffer: wgpu::Buffer,
+    pub pick_buffer: wgpu::Buffer,
+    // Copy of the pick buffer mapped for reading while the next frames render
+    pick_readback: wgpu::Buffer,
+    // Finishes once pick_readback is mapped
+    pick_map: Option<std::sync::mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>>,
+    pick: PickState,
+    pub material_buffer: wgpu::Buffer,
+    pub palette_buffer: wgpu::Buffer,
+    // Uploaded every frame, past MAX_LIGHTS are ignored
+    pub lights: Vec<Light>,
+    pub light_buffer: wgpu::Buffer,
+    // Summed path traced colour of every pixel, rgb and a pad
+    accum_buffer: wgpu::Buffer,
+    // Colour, coverage and restart distance of every pixel between depth peeling passes
+    peel_buffer: wgpu::Buffer,
+    // Uniforms and lights the accumulated samples were traced with, minus the sample count
+    accum_key: Option<(Uniforms, Vec<Light>)>,
+    // Tiled over voxel faces, 1x1 white until one is set
+    texture_view: wgpu::TextureView,
+    texture_sampler: wgpu::Sampler,
+    // Equirectangular sky with mips, only sampled once `uniforms.environment` is set
+    environment_view: wgpu::TextureView,
+    main_bind_group_layout: wgpu::BindGroupLayout,
     pub main_bind_group: wgpu::BindGroup,
+    // The tracer draws into its hdr target, then it composites onto the output
+    bloom: Bloom,
     pub previous_frame_time: Option<f64>,
+    // Multiplier on the window scale factor for egui
+    pub ui_scale: f32,
+    // Last pixels per point given to egui
+    egui_pixels_per_point: f32,
     pub egui_platform: egui_winit_platform::Platform,
     pub egui_rpass: egui_wgpu_backend::RenderPass,
 }
@@ -50,7 +241,7 @@ impl Render {
                     | wgpu::BufferUsages::COPY_SRC,
             });
 
-        let nodes = octree.expanded(10_000_000);
+        let nodes = octree.expanded(NODE_BUFFER_LENGTH);
 
         let node_buffer = gpu
             .device
@@ -60,6 +251,67 @@ impl Render {
                 usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
             });
 
+        // [hit, node index] for the pixel at uniforms.pick_x/y
+        let pick_buffer = gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Pick Buffer"),
+                contents: bytemuck::cast_slice(&[0u32; 2]),
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+            });
+        let pick_readback = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Pick Readback Buffer"),
+            size: 8,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        // [count, (colour, alpha, reflectivity, emission)..] of the colours in the model that
+        // aren't plain
+        let material_buffer = gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Material Buffer"),
+                contents: bytemuck::cast_slice(&[0u32; 1 + 4 * MAX_MATERIAL_COLOURS]),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            });
+
+        // [count, rgba8 colours..] of the model's file palette
+        let palette_buffer = gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Palette Buffer"),
+                contents: bytemuck::cast_slice(&[0u32; 1 + MAX_PALETTE_COLOURS]),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let light_buffer = gpu
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Light Buffer"),
+                contents: bytemuck::cast_slice(&[Light::default(); MAX_LIGHTS]),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let accum_buffer = Render::create_accum_buffer(gpu, size);
+        let peel_buffer = Render::create_peel_buffer(gpu, size);
+
+        let texture_view = Render::create_texture(gpu, [1, 1], &[255; 4]);
+        let environment_view =
+            Render::create_environment(gpu, [1, 1], &hdr_mips([1, 1], &[[0.0; 3]]));
+        let texture_sampler = gpu.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Texture Sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
         let main_bind_group_layout =
             gpu.device
                 .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -84,24 +336,111 @@ impl Render {
                             },
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 3,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 4,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 5,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 6,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 7,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 8,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                multisampled: false,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 9,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 10,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
                     ],
                     label: Some("main_bind_group_layout"),
                 });
 
-        let main_bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &main_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: uniform_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: node_buffer.as_entire_binding(),
-                },
+        let main_bind_group = Render::create_main_bind_group(
+            gpu,
+            &main_bind_group_layout,
+            [
+                &uniform_buffer,
+                &node_buffer,
+                &pick_buffer,
+                &material_buffer,
+                &light_buffer,
+                &accum_buffer,
             ],
-            label: Some("uniform_bind_group"),
-        });
+            [&texture_view, &environment_view],
+            &texture_sampler,
+            [&palette_buffer, &peel_buffer],
+        );
         // #endregion
 
         let render_pipeline_layout =
@@ -112,21 +451,160 @@ impl Render {
                     push_constant_ranges: &[],
                 });
 
-        let render_pipeline = gpu
-            .device
+        let pipelines = Render::create_pipelines(gpu, &render_pipeline_layout, &shader);
+        let bloom = Bloom::new(gpu, [size.width, size.height], config.format);
+
+        // egui
+        let size = window.inner_size();
+        let egui_platform =
+            egui_winit_platform::Platform::new(egui_winit_platform::PlatformDescriptor {
+                physical_width: size.width as u32,
+                physical_height: size.height as u32,
+                scale_factor: window.scale_factor(),
+                font_definitions: egui::FontDefinitions::default(),
+                style: Default::default(),
+            });
+
+        // We use the egui_wgpu_backend crate as the render backend.
+        let egui_rpass = egui_wgpu_backend::RenderPass::new(&gpu.device, config.format, 1);
+
+        let previous_frame_time = None;
+
+        Self {
+            config,
+            size,
+            pipelines,
+            pipeline_layout: render_pipeline_layout,
+            uniforms,
+            uniform_buffer,
+            node_buffer,
+            pick_buffer,
+            pick_readback,
+            pick_map: None,
+            pick: PickState::default(),
+            material_buffer,
+            palette_buffer,
+            lights: Vec::new(),
+            light_buffer,
+            accum_buffer,
+            peel_buffer,
+            accum_key: None,
+            texture_view,
+            texture_sampler,
+            environment_view,
+            main_bind_group_layout,
+            main_bind_group,
+            bloom,
+            previous_frame_time,
+            ui_scale: 1.0,
+            egui_pixels_per_point: window.scale_factor() as f32,
+            egui_platform,
+            egui_rpass,
+        }
+    }
+
+    /// Buffers in binding order, followed by the surface texture, the sampler, the
+    /// environment, the palette and the peel buffer
+    fn create_main_bind_group(
+        gpu: &Gpu,
+        layout: &wgpu::BindGroupLayout,
+        buffers: [&wgpu::Buffer; 6],
+        texture_views: [&wgpu::TextureView; 2],
+        texture_sampler: &wgpu::Sampler,
+        [palette_buffer, peel_buffer]: [&wgpu::Buffer; 2],
+    ) -> wgpu::BindGroup {
+        let mut entries: Vec<wgpu::BindGroupEntry> = buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        entries.push(wgpu::BindGroupEntry {
+            binding: 6,
+            resource: wgpu::BindingResource::TextureView(texture_views[0]),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 7,
+            resource: wgpu::BindingResource::Sampler(texture_sampler),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 8,
+            resource: wgpu::BindingResource::TextureView(texture_views[1]),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 9,
+            resource: palette_buffer.as_entire_binding(),
+        });
+        entries.push(wgpu::BindGroupEntry {
+            binding: 10,
+            resource: peel_buffer.as_entire_binding(),
+        });
+
+        gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: Some("uniform_bind_group"),
+        })
+    }
+
+    fn create_accum_buffer(gpu: &Gpu, size: winit::dpi::PhysicalSize<u32>) -> wgpu::Buffer {
+        let pixels = (size.width.max(1) * size.height.max(1)) as u64;
+        gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Accumulation Buffer"),
+            size: pixels * 16,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_peel_buffer(gpu: &Gpu, size: winit::dpi::PhysicalSize<u32>) -> wgpu::Buffer {
+        let pixels = (size.width.max(1) * size.height.max(1)) as u64;
+        gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Peel Buffer"),
+            size: pixels * 32,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Makes the next path traced frame start from one sample
+    pub fn reset_accumulation(&mut self) {
+        self.accum_key = None;
+    }
+
+    fn create_pipelines(
+        gpu: &Gpu,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+    ) -> Pipelines {
+        Pipelines {
+            main: Render::create_pipeline(gpu, layout, shader, "fs_main"),
+            peel: Render::create_pipeline(gpu, layout, shader, "fs_peel"),
+        }
+    }
+
+    fn create_pipeline(
+        gpu: &Gpu,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        entry_point: &str,
+    ) -> wgpu::RenderPipeline {
+        gpu.device
             .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
                 label: Some("Render Pipeline"),
-                layout: Some(&render_pipeline_layout),
+                layout: Some(layout),
                 vertex: wgpu::VertexState {
-                    module: &shader,
+                    module: shader,
                     entry_point: "vs_main",
                     buffers: &[],
                 },
                 fragment: Some(wgpu::FragmentState {
-                    module: &shader,
-                    entry_point: "fs_main",
+                    module: shader,
+                    entry_point,
                     targets: &[wgpu::ColorTargetState {
-                        format: config.format,
+                        format: HDR_FORMAT,
                         blend: Some(wgpu::BlendState::REPLACE),
                         write_mask: wgpu::ColorWrites::ALL,
                     }],
@@ -147,36 +625,35 @@ impl Render {
                     alpha_to_coverage_enabled: false,
                 },
                 multiview: None,
-            });
-
-        // egui
-        let size = window.inner_size();
-        let egui_platform =
-            egui_winit_platform::Platform::new(egui_winit_platform::PlatformDescriptor {
-                physical_width: size.width as u32,
-                physical_height: size.height as u32,
-                scale_factor: window.scale_factor(),
-                font_definitions: egui::FontDefinitions::default(),
-                style: Default::default(),
-            });
+            })
+    }
 
-        // We use the egui_wgpu_backend crate as the render backend.
-        let egui_rpass = egui_wgpu_backend::RenderPass::new(&gpu.device, config.format, 1);
+    /// Rebuilds the pipelines from the shader files on disk, keeping the old ones if they
+    /// fail to compile
+    pub fn reload_shader(&mut self, gpu: &Gpu) -> Result<(), String> {
+        let read = |file: &str| {
+            let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("src")
+                .join(file);
+            std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))
+        };
 
-        let previous_frame_time = None;
+        let layout = &self.pipeline_layout;
+        rebuild(&mut self.pipelines, read, |source| {
+            gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let shader = gpu
+                .device
+                .create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some("Shader"),
+                    source: wgpu::ShaderSource::Wgsl(source.into()),
+                });
+            let pipelines = Render::create_pipelines(gpu, layout, &shader);
 
-        Self {
-            config,
-            size,
-            render_pipeline,
-            uniforms,
-            uniform_buffer,
-            node_buffer,
-            main_bind_group,
-            previous_frame_time,
-            egui_platform,
-            egui_rpass,
-        }
+            match pollster::block_on(gpu.device.pop_error_scope()) {
+                Some(error) => Err(error.to_string()),
+                None => Ok(pipelines),
+            }
+        })
     }
 
     pub fn resize(&mut self, gpu: &Gpu, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -185,7 +662,207 @@ impl Render {
             self.config.width = new_size.width;
             self.config.height = new_size.height;
             gpu.surface.configure(&gpu.device, &self.config);
+
+            self.accum_buffer = Render::create_accum_buffer(gpu, new_size);
+            self.peel_buffer = Render::create_peel_buffer(gpu, new_size);
+            self.recreate_main_bind_group(gpu);
+            self.reset_accumulation();
+        }
+    }
+
+    fn recreate_main_bind_group(&mut self, gpu: &Gpu) {
+        self.main_bind_group = Render::create_main_bind_group(
+            gpu,
+            &self.main_bind_group_layout,
+            [
+                &self.uniform_buffer,
+                &self.node_buffer,
+                &self.pick_buffer,
+                &self.material_buffer,
+                &self.light_buffer,
+                &self.accum_buffer,
+            ],
+            [&self.texture_view, &self.environment_view],
+            &self.texture_sampler,
+            [&self.palette_buffer, &self.peel_buffer],
+        );
+    }
+
+    /// `pixels` is rgba8, `size[0] * size[1] * 4` bytes
+    fn create_texture(gpu: &Gpu, size: [u32; 2], pixels: &[u8]) -> wgpu::TextureView {
+        gpu.device
+            .create_texture_with_data(
+                &gpu.queue,
+                &wgpu::TextureDescriptor {
+                    label: Some("Surface Texture"),
+                    size: wgpu::Extent3d {
+                        width: size[0],
+                        height: size[1],
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::Rgba8Unorm,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                },
+                pixels,
+            )
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    /// Replaces the texture tiled over voxel faces, `pixels` is rgba8
+    pub fn set_texture(&mut self, gpu: &Gpu, size: [u32; 2], pixels: &[u8]) -> Result<(), String> {
+        let max_dimension = gpu.device.limits().max_texture_dimension_2d;
+        if size[0] == 0 || size[1] == 0 || size[0].max(size[1]) > max_dimension {
+            return Err(format!(
+                "Texture is {}x{}, needs to be between 1 and {} pixels across",
+                size[0], size[1], max_dimension
+            ));
+        }
+        if pixels.len() != (size[0] * size[1] * 4) as usize {
+            return Err("Texture data doesn't match its size".to_string());
+        }
+
+        self.texture_view = Render::create_texture(gpu, size, pixels);
+        self.recreate_main_bind_group(gpu);
+        self.reset_accumulation();
+        Ok(())
+    }
+
+    /// `mips` is the level count and rgba16 float data from `hdr_mips`
+    fn create_environment(gpu: &Gpu, size: [u32; 2], mips: &(u32, Vec<u16>)) -> wgpu::TextureView {
+        gpu.device
+            .create_texture_with_data(
+                &gpu.queue,
+                &wgpu::TextureDescriptor {
+                    label: Some("Environment Texture"),
+                    size: wgpu::Extent3d {
+                        width: size[0],
+                        height: size[1],
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: mips.0,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::Rgba16Float,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                },
+                bytemuck::cast_slice(&mips.1),
+            )
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    /// Uses an equirectangular image for missed rays and, when `environment_ambient` is on,
+    /// for the ambient light of hits
+    pub fn set_environment(
+        &mut self,
+        gpu: &Gpu,
+        size: [u32; 2],
+        pixels: &[[f32; 3]],
+    ) -> Result<(), String> {
+        let max_dimension = gpu.device.limits().max_texture_dimension_2d;
+        if size[0].max(size[1]) > max_dimension {
+            return Err(format!(
+                "Environment is {}x{}, the gpu allows up to {} pixels across",
+                size[0], size[1], max_dimension
+            ));
         }
+
+        self.environment_view = Render::create_environment(gpu, size, &hdr_mips(size, pixels));
+        self.recreate_main_bind_group(gpu);
+        self.uniforms.environment = true;
+        self.reset_accumulation();
+        Ok(())
+    }
+
+    /// Back to the flat background
+    pub fn clear_environment(&mut self, gpu: &Gpu) {
+        self.environment_view =
+            Render::create_environment(gpu, [1, 1], &hdr_mips([1, 1], &[[0.0; 3]]));
+        self.recreate_main_bind_group(gpu);
+        self.uniforms.environment = false;
+        self.reset_accumulation();
+    }
+
+    /// Back to the plain palette colours
+    pub fn clear_texture(&mut self, gpu: &Gpu) {
+        self.set_texture(gpu, [1, 1], &[255; 4]).unwrap();
+    }
+
+    /// Reconfigures the surface with the closest supported mode and returns it
+    pub fn set_present_mode(&mut self, gpu: &Gpu, mode: wgpu::PresentMode) -> wgpu::PresentMode {
+        let supported = supported_present_modes(gpu.adapter.get_info().backend);
+        self.config.present_mode = choose_present_mode(mode, supported);
+        gpu.surface.configure(&gpu.device, &self.config);
+
+        self.config.present_mode
+    }
+
+    /// Requests the node under the window position `pos` for the next frame and returns the
+    /// latest result that has been read back. Reads are mapped in the background, so results
+    /// lag a frame or two.
+    pub fn pick(&mut self, gpu: &Gpu, pos: Vector2<f32>) -> Option<u32> {
+        gpu.device.poll(wgpu::Maintain::Poll);
+        if let Some(map) = &self.pick_map {
+            match map.try_recv() {
+                Ok(Ok(())) => {
+                    let slice = self.pick_readback.slice(..);
+                    let data = slice.get_mapped_range();
+                    self.pick.read(bytemuck::cast_slice(&data));
+
+                    drop(data);
+                    self.pick_readback.unmap();
+                    self.pick_map = None;
+                }
+                Ok(Err(_)) | Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    panic!("Failed to read pick buffer!")
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => {}
+            }
+        }
+
+        let [x, y] = pick_pixel(pos, [self.size.width, self.size.height]);
+        self.uniforms.pick_x = x;
+        self.uniforms.pick_y = y;
+
+        self.pick.picked
+    }
+
+    /// Copies this frame's pick into the readback buffer unless the last copy is still
+    /// being mapped
+    fn copy_pick(&mut self, encoder: &mut wgpu::CommandEncoder) -> bool {
+        if !self.pick.copy() {
+            return false;
+        }
+        encoder.copy_buffer_to_buffer(&self.pick_buffer, 0, &self.pick_readback, 0, 8);
+        true
+    }
+
+    /// Starts mapping the readback buffer once the copy has been submitted
+    fn map_pick(&mut self) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let future = self.pick_readback.slice(..).map_async(wgpu::MapMode::Read);
+        tokio::task::spawn(async move {
+            sender.send(future.await).ok();
+        });
+        self.pick_map = Some(receiver);
+    }
+
+    /// Uploads the colours that let light through, reflect or glow
+    pub fn set_materials(&mut self, gpu: &Gpu, materials: &HashMap<Voxel, Material>) {
+        let data = material_data(materials);
+        gpu.queue
+            .write_buffer(&self.material_buffer, 0, bytemuck::cast_slice(&data));
+        self.reset_accumulation();
+    }
+
+    /// Uploads the model's file palette, past MAX_PALETTE_COLOURS are left out
+    pub fn set_palette(&self, gpu: &Gpu, octree: &Octree) {
+        let count = octree.palette.len().min(MAX_PALETTE_COLOURS);
+        let mut data = (count as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&octree.palette_bytes()[..count * 4]);
+        gpu.queue.write_buffer(&self.palette_buffer, 0, &data);
     }
 
     pub fn update(&mut self, gpu: &Gpu, time: f64, settings: &mut Settings, character: &Character) {
@@ -201,19 +878,103 @@ impl Render {
         let camera = proj * view;
         let camera_inverse = camera.invert().unwrap();
 
-        self.uniforms.dimensions = [dimensions[0], dimensions[1], 0.0, 0.0];
+        // Angle covered by one pixel, used for lod
+        let pixel_angle = 2.0 / (dimensions[1] * proj[1][1]);
+        self.uniforms.dimensions = [dimensions[0], dimensions[1], pixel_angle, 0.0];
+        self.ui_scale = settings.ui_scale;
         self.uniforms.camera = camera.into();
         self.uniforms.camera_inverse = camera_inverse.into();
 
+        let light_count = self.lights.len().min(MAX_LIGHTS);
+        self.uniforms.light_count = light_count as u32;
+        if light_count > 0 {
+            gpu.queue.write_buffer(
+                &self.light_buffer,
+                0,
+                bytemuck::cast_slice(&self.lights[..light_count]),
+            );
+        }
+
+        // Anything changing besides the pick pixel restarts the accumulation. Compared field
+        // by field, the bools leave padding that isn't safe to read as bytes
+        let mut key = self.uniforms;
+        key.path_samples = 0;
+        key.pick_x = 0;
+        key.pick_y = 0;
+        let key = (key, self.lights[..light_count].to_vec());
+        let same = self.accum_key.as_ref() == Some(&key);
+        self.uniforms.path_samples = if self.uniforms.accumulates() && same {
+            self.uniforms.path_samples + 1
+        } else {
+            0
+        };
+        self.accum_key = Some(key);
+
         gpu.queue.write_buffer(
             &self.uniform_buffer,
             0,
             bytemuck::cast_slice(&[self.uniforms]),
         );
+        self.bloom.set_uniforms(
+            gpu,
+            BloomUniforms {
+                threshold: self.uniforms.bloom_threshold,
+                intensity: self.uniforms.bloom_intensity,
+                gamma: if self.uniforms.misc_bool { 1.0 } else { 2.2 },
+                junk: 0.0,
+            },
+        );
 
         self.egui_platform.update_time(time);
     }
 
+    /// Records the tracer and bloom passes into `view`, which must be in `config.format`
+    /// and `size` pixels
+    pub fn draw(
+        &mut self,
+        gpu: &Gpu,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        size: [u32; 2],
+    ) {
+        self.bloom.resize(gpu, size);
+
+        // Each layer gets its own pass so it sees the peel buffer the one before wrote
+        let (pipeline, layers) = match self.uniforms.peels() {
+            true => (&self.pipelines.peel, self.uniforms.transparency_layers + 1),
+            false => (&self.pipelines.main, 1),
+        };
+        for layer in 0..layers {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &self.bloom.hdr_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+            render_pass.draw(0..4, layer..layer + 1);
+        }
+
+        self.bloom
+            .draw(gpu, encoder, view, self.uniforms.bloom_intensity);
+    }
+
+    /// Passes a window event on to egui with positions and sizes in ui scaled pixels
+    pub fn handle_ui_event(&mut self, event: &Event<()>) {
+        match scale_ui_event(event, self.ui_scale) {
+            Some(scaled) => self.egui_platform.handle_event(&scaled),
+            None => self.egui_platform.handle_event(event),
+        }
+    }
+
     pub fn render(&mut self, gpu: &Gpu, window: &Window) -> Result<(), wgpu::SurfaceError> {
         let output = gpu.surface.get_current_texture()?;
         let size = window.inner_size();
@@ -228,24 +989,24 @@ impl Render {
                 label: Some("Render Encoder"),
             });
 
-        // Draw my app
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
-            });
+        // Draw my app, the shader only writes the pick when the cursor is over the frame
+        gpu.queue
+            .write_buffer(&self.pick_buffer, 0, bytemuck::cast_slice(&[0u32; 2]));
+        self.draw(gpu, &mut encoder, &view, [size.width, size.height]);
+        let picking = self.copy_pick(&mut encoder);
 
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.main_bind_group, &[]);
-            render_pass.draw(0..4, 0..1);
+        // Tell egui about the ui scale, also reapplied after the window scale changes. The
+        // window size is passed on again so egui's screen rect is in the new points
+        let pixels_per_point = ui_pixels_per_point(window.scale_factor(), self.ui_scale);
+        if pixels_per_point != self.egui_pixels_per_point {
+            self.egui_platform
+                .context()
+                .set_pixels_per_point(pixels_per_point);
+            self.handle_ui_event(&Event::WindowEvent {
+                window_id: window.id(),
+                event: WindowEvent::Resized(window.inner_size()),
+            });
+            self.egui_pixels_per_point = pixels_per_point;
         }
 
         // Draw the UI frame.
@@ -259,7 +1020,7 @@ impl Render {
         let screen_descriptor = egui_wgpu_backend::ScreenDescriptor {
             physical_width: size.width,
             physical_height: size.height,
-            scale_factor: window.scale_factor() as f32,
+            scale_factor: self.egui_pixels_per_point,
         };
         self.egui_rpass.update_texture(
             &gpu.device,
@@ -279,13 +1040,39 @@ impl Render {
         // Submit the command buffer.
         gpu.queue.submit(std::iter::once(encoder.finish()));
         output.present();
+        if picking {
+            self.map_pick();
+        }
 
         Ok(())
     }
 }
 
+/// A light on top of the sun, same as `Light` in shader.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Light {
+    // Direction the light shines in for directional lights, position for point lights
+    pub position: [f32; 4],
+    // Intensity in w
+    pub colour: [f32; 4],
+    pub kind: u32,
+    pub junk: [u32; 3],
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            position: [0.0, 0.5, 0.0, 0.0],
+            colour: [1.0, 1.0, 1.0, 0.1],
+            kind: LIGHT_POINT,
+            junk: [0; 3],
+        }
+    }
+}
+
 #[repr(C)]
-#[derive(Debug, Copy, Clone, bytemuck::Zeroable)]
+#[derive(Debug, Copy, Clone, PartialEq, bytemuck::Zeroable)]
 pub struct Uniforms {
     pub camera: [[f32; 4]; 4],
     pub camera_inverse: [[f32; 4]; 4],
@@ -297,13 +1084,80 @@ pub struct Uniforms {
     pub shadows: bool,
     pub misc_value: f32,
     pub misc_bool: bool,
-    pub junk: [u32; 8],
+    pub surface_only: bool,
+    pub sun_enabled: bool,
+    pub show_empty: bool,
+    pub pick_x: u32,
+    pub pick_y: u32,
+    pub edge_aa_samples: u32,
+    pub debug_gamma: f32,
+    pub debug_brightness: f32,
+    pub distance_lod_bias: f32,
+    pub bevel_amount: f32,
+    pub slab_thickness: f32,
+    pub slab_center: f32,
+    pub slab_normal: [f32; 4],
+    pub background: [f32; 4],
+    pub checker_colour: [f32; 4],
+    pub checker_size: f32,
+    pub transparency_layers: u32,
+    pub face_tint_strength: f32,
+    pub ao: bool,
+    pub ao_strength: f32,
+    pub ao_rays: u32,
+    pub sun_radius: f32,
+    pub shadow_samples: u32,
+    pub light_count: u32,
+    pub path_trace: bool,
+    // Samples already in the accumulation buffer, set by `Render::update`. Also what turns
+    // soft shadow rays between frames
+    pub path_samples: u32,
+    pub path_bounces: u32,
+    pub reflections: bool,
+    pub reflection_bounces: u32,
+    pub smooth_normals: bool,
+    // Texture repeats per unit of world space
+    pub texture_scale: f32,
+    // Brightness past which pixels glow and how strongly, 0 turns the blur passes off
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+    // Set by `Render::set_environment`
+    pub environment: bool,
+    pub environment_ambient: bool,
+    pub environment_intensity: f32,
+    // Analytic sky for missed rays when there's no environment
+    pub ground_colour: [f32; 4],
+    pub sky: bool,
+    pub sky_turbidity: f32,
+    // Exponential by hit distance, missed rays stay clear
+    pub fog: bool,
+    pub fog_density: f32,
+    pub fog_colour: [f32; 4],
+    // Transparency in one pass per layer instead of one loop, see `peels`
+    pub depth_peel: bool,
+    pub junk: [u32; 7],
 }
 
 // For bool
 unsafe impl bytemuck::Pod for Uniforms {}
 
 impl Uniforms {
+    /// Shadow rays spread over the sun's disc, same as `soft_shadows` in shader.wgsl
+    pub fn soft_shadows(&self) -> bool {
+        self.sun_enabled && self.shadows && self.sun_radius > 0.0 && self.shadow_samples > 1
+    }
+
+    /// Whether transparency is drawn as `transparency_layers + 1` depth peeling passes. Path
+    /// tracing has no transparency so it keeps the single pass
+    pub fn peels(&self) -> bool {
+        self.depth_peel && self.transparency_layers > 0 && !self.path_trace
+    }
+
+    /// Whether frames are averaged in the accumulation buffer while nothing changes
+    pub fn accumulates(&self) -> bool {
+        self.path_trace || self.soft_shadows()
+    }
+
     fn new() -> Self {
         Self {
             camera: [[0.0; 4]; 4],
@@ -316,7 +1170,248 @@ impl Uniforms {
             shadows: true,
             misc_value: 0.0,
             misc_bool: false,
-            junk: [0; 8],
+            surface_only: false,
+            sun_enabled: true,
+            show_empty: false,
+            pick_x: u32::MAX,
+            pick_y: u32::MAX,
+            edge_aa_samples: 4,
+            debug_gamma: 1.0,
+            debug_brightness: 1.0,
+            distance_lod_bias: 0.0,
+            bevel_amount: 0.0,
+            slab_thickness: 0.0,
+            slab_center: 0.0,
+            slab_normal: [0.0, 1.0, 0.0, 0.0],
+            background: [0.2, 0.2, 0.2, 0.0],
+            checker_colour: [0.4, 0.4, 0.4, 0.0],
+            checker_size: 0.0,
+            transparency_layers: 0,
+            face_tint_strength: 0.0,
+            ao: false,
+            ao_strength: 0.8,
+            ao_rays: 4,
+            sun_radius: 0.0,
+            shadow_samples: 8,
+            light_count: 0,
+            path_trace: false,
+            path_samples: 0,
+            path_bounces: 3,
+            reflections: true,
+            reflection_bounces: 2,
+            smooth_normals: false,
+            texture_scale: 8.0,
+            bloom_threshold: 1.5,
+            bloom_intensity: 0.5,
+            environment: false,
+            environment_ambient: true,
+            environment_intensity: 1.0,
+            ground_colour: [0.3, 0.27, 0.22, 0.0],
+            sky: false,
+            sky_turbidity: 3.0,
+            fog: false,
+            fog_density: 0.5,
+            fog_colour: [0.7, 0.75, 0.8, 0.0],
+            depth_peel: false,
+            junk: [0; 7],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ui_scale_multiplies_the_window_scale() {
+        assert_eq!(ui_pixels_per_point(2.0, 1.5), 3.0);
+        assert_eq!(ui_pixels_per_point(1.0, 1.0), 1.0);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn ui_events_are_in_scaled_pixels() {
+        let window_id = unsafe { winit::window::WindowId::dummy() };
+        let resized: Event<()> = Event::WindowEvent {
+            window_id,
+            event: WindowEvent::Resized(winit::dpi::PhysicalSize::new(1000, 600)),
+        };
+        match scale_ui_event(&resized, 2.0) {
+            Some(Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            }) => assert_eq!((size.width, size.height), (500, 300)),
+            _ => panic!("Resize wasn't scaled"),
+        }
+
+        let moved: Event<()> = Event::WindowEvent {
+            window_id,
+            event: WindowEvent::CursorMoved {
+                device_id: unsafe { winit::event::DeviceId::dummy() },
+                position: winit::dpi::PhysicalPosition::new(100.0, 50.0),
+                modifiers: Default::default(),
+            },
+        };
+        match scale_ui_event(&moved, 2.0) {
+            Some(Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            }) => assert_eq!((position.x, position.y), (50.0, 25.0)),
+            _ => panic!("Cursor position wasn't scaled"),
+        }
+
+        let focused: Event<()> = Event::WindowEvent {
+            window_id,
+            event: WindowEvent::Focused(true),
+        };
+        assert!(scale_ui_event(&focused, 2.0).is_none());
+    }
+
+    #[test]
+    fn unsupported_present_modes_fall_back() {
+        use wgpu::PresentMode::*;
+        assert_eq!(
+            choose_present_mode(Mailbox, &[Fifo, Immediate, Mailbox]),
+            Mailbox
+        );
+        assert_eq!(choose_present_mode(Mailbox, &[Fifo, Immediate]), Immediate);
+        assert_eq!(choose_present_mode(Immediate, &[Fifo, Mailbox]), Mailbox);
+        assert_eq!(choose_present_mode(Immediate, &[Fifo]), Fifo);
+        assert_eq!(
+            choose_present_mode(Mailbox, supported_present_modes(wgpu::Backend::Gl)),
+            Fifo
+        );
+    }
+
+    #[test]
+    fn buffer_warning_starts_past_three_quarters() {
+        assert!(!near_buffer_limit(750, 1000));
+        assert!(near_buffer_limit(751, 1000));
+    }
+
+    #[test]
+    fn soft_shadows_and_path_tracing_accumulate() {
+        let mut uniforms = Uniforms::new();
+        uniforms.path_trace = false;
+        uniforms.sun_radius = 0.0;
+        assert!(!uniforms.accumulates());
+
+        uniforms.sun_radius = 1.0;
+        uniforms.shadow_samples = 4;
+        assert!(uniforms.soft_shadows() && uniforms.accumulates());
+        uniforms.sun_enabled = false;
+        assert!(!uniforms.soft_shadows() && !uniforms.accumulates());
+
+        uniforms.path_trace = true;
+        assert!(uniforms.accumulates());
+    }
+
+    #[test]
+    fn only_special_materials_are_uploaded() {
+        let glass = Material {
+            kind: MaterialKind::Glass,
+            reflectivity: 0.5,
+            alpha: 0.25,
+            ..Default::default()
+        };
+        let materials = [
+            (Voxel::new(1, 2, 3), glass),
+            (Voxel::new(4, 5, 6), Material::default()),
+        ]
+        .into_iter()
+        .collect();
+        // Glass doesn't reflect, the plain diffuse colour is left out
+        let data = material_data(&materials);
+        let expected = [
+            1,
+            Voxel::new(1, 2, 3).to_cpu_value(),
+            0.25f32.to_bits(),
+            0,
+            0,
+        ];
+        assert_eq!(data, expected);
+
+        let metal = Material {
+            kind: MaterialKind::Metal,
+            reflectivity: 0.5,
+            ..Default::default()
+        };
+        let materials = (0..=MAX_MATERIAL_COLOURS)
+            .map(|i| (Voxel::new(i as u8, (i >> 8) as u8 + 1, 0), metal))
+            .collect();
+        let data = material_data(&materials);
+        assert_eq!(data[0] as usize, MAX_MATERIAL_COLOURS);
+        assert_eq!(data.len(), 1 + 4 * MAX_MATERIAL_COLOURS);
+        assert_eq!(data[3], 0.5f32.to_bits());
+    }
+
+    #[test]
+    fn picks_are_the_pixel_under_the_cursor() {
+        assert_eq!(pick_pixel(Vector2::new(10.7, 3.2), [20, 10]), [10, 3]);
+        assert_eq!(pick_pixel(Vector2::new(0.0, 9.9), [20, 10]), [0, 9]);
+        for pos in [(-0.5, 3.0), (20.0, 3.0), (5.0, 10.0)] {
+            let pos = Vector2::new(pos.0, pos.1);
+            assert_eq!(pick_pixel(pos, [20, 10]), [u32::MAX; 2]);
+        }
+    }
+
+    #[test]
+    fn picks_skip_copies_while_one_is_read_back() {
+        let mut pick = PickState::default();
+        assert!(pick.copy());
+        assert!(!pick.copy());
+        assert_eq!(pick.picked, None);
+
+        pick.read(&[1, 42]);
+        assert_eq!(pick.picked, Some(42));
+        assert!(pick.copy());
+        // Missing the model keeps nothing from the last pick
+        pick.read(&[0, 42]);
+        assert_eq!(pick.picked, None);
+    }
+
+    #[test]
+    fn failed_shader_rebuilds_keep_the_old_pipeline() {
+        // Stands in for `Render`, the pipeline being the source it was built from
+        struct Rendering {
+            uniforms: Uniforms,
+            pipeline: String,
+        }
+        let files = |shader: &'static str| {
+            move |file: &str| match file {
+                "common.wgsl" => Ok("common ".to_string()),
+                "shader.wgsl" => Ok(shader.to_string()),
+                _ => Err(format!("{}: not found", file)),
+            }
+        };
+        let compile = |source: String| match source.contains("typo") {
+            true => Err("expected ;".to_string()),
+            false => Ok(source),
+        };
+
+        let mut uniforms = Uniforms::new();
+        uniforms.fog = true;
+        uniforms.pick_x = 12;
+        let mut render = Rendering {
+            uniforms,
+            pipeline: "old".to_string(),
+        };
+
+        let result = rebuild(&mut render.pipeline, files("typo"), compile);
+        assert_eq!(result, Err("expected ;".to_string()));
+        assert_eq!(render.pipeline, "old");
+        let missing = |file: &str| Err(format!("{}: not found", file));
+        assert_eq!(
+            rebuild(&mut render.pipeline, missing, compile),
+            Err("common.wgsl: not found".to_string())
+        );
+        assert_eq!(render.pipeline, "old");
+
+        assert_eq!(
+            rebuild(&mut render.pipeline, files("shader"), compile),
+            Ok(())
+        );
+        assert_eq!(render.pipeline, "common shader");
+        assert_eq!(render.uniforms, uniforms);
+    }
+}
@@ -0,0 +1,233 @@
+use super::*;
+
+// Largest decompressed file read. Blocks is a byte per cell of width x height x length, so
+// this fits schematics of up to about 645 blocks a side
+pub const MAX_SCHEMATIC_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Colours for common pre 1.13 block ids, anything else is drawn grey
+const BLOCK_COLOURS: [(u8, [u8; 3]); 24] = [
+    (1, [125, 125, 125]),
+    (2, [95, 159, 53]),
+    (3, [134, 96, 67]),
+    (4, [122, 122, 122]),
+    (5, [162, 130, 78]),
+    (7, [85, 85, 85]),
+    (8, [47, 67, 244]),
+    (9, [47, 67, 244]),
+    (10, [207, 92, 20]),
+    (11, [207, 92, 20]),
+    (12, [219, 207, 163]),
+    (13, [136, 126, 126]),
+    (17, [102, 81, 51]),
+    (18, [60, 130, 40]),
+    (20, [200, 220, 230]),
+    (24, [216, 203, 155]),
+    (35, [220, 220, 220]),
+    (45, [150, 97, 83]),
+    (49, [20, 18, 30]),
+    (80, [250, 250, 250]),
+    (82, [160, 166, 179]),
+    (87, [97, 38, 38]),
+    (89, [250, 210, 110]),
+    (98, [122, 121, 122]),
+];
+const UNKNOWN_BLOCK_COLOUR: [u8; 3] = [180, 180, 180];
+
+/// Size of the cube an MCEdit .schematic loads into and its non air blocks as
+/// (.vox style cell, 0xRRGGBB), centred in the cube. The NBT can be gzipped or already
+/// inflated by `load_file`.
+pub fn schematic_voxels(data: &[u8]) -> Result<(u32, Vec<(Vector3<i32>, u32)>), String> {
+    let nbt = if data.starts_with(&GZIP_MAGIC) {
+        gunzip(data, MAX_SCHEMATIC_BYTES)
+            .map_err(|e| format!("Schematic is badly gzipped: {}", e))?
+    } else {
+        data.to_vec()
+    };
+
+    let mut reader = Reader::new(&nbt);
+    if reader.u8()? != TAG_COMPOUND {
+        return Err("Schematic doesn't start with a compound tag".to_string());
+    }
+    nbt_string(&mut reader)?;
+
+    let (mut width, mut height, mut length, mut blocks) = (None, None, None, None);
+    loop {
+        let tag = reader.u8()?;
+        if tag == TAG_END {
+            break;
+        }
+
+        let name = nbt_string(&mut reader)?;
+        match (tag, name) {
+            (TAG_SHORT, "Width") => width = Some(reader.u16_be()? as i16),
+            (TAG_SHORT, "Height") => height = Some(reader.u16_be()? as i16),
+            (TAG_SHORT, "Length") => length = Some(reader.u16_be()? as i16),
+            (TAG_BYTE_ARRAY, "Blocks") => {
+                let len = nbt_len(&mut reader)?;
+                blocks = Some(reader.bytes(len)?);
+            }
+            _ => skip_nbt(&mut reader, tag, 0)?,
+        }
+    }
+
+    let (width, height, length, blocks) = match (width, height, length, blocks) {
+        (Some(w), Some(h), Some(l), Some(b)) if w > 0 && h > 0 && l > 0 => {
+            (w as usize, h as usize, l as usize, b)
+        }
+        _ => return Err("Schematic is missing its size or blocks".to_string()),
+    };
+    if blocks.len() < width * height * length {
+        return Err(format!(
+            "Schematic has {} blocks, a {}x{}x{} schematic needs {}",
+            blocks.len(),
+            width,
+            height,
+            length,
+            width * height * length
+        ));
+    }
+
+    let cube = width.max(height).max(length).next_power_of_two() as u32;
+    let shift = Vector3::new(
+        (cube as usize - width) / 2,
+        (cube as usize - length) / 2,
+        (cube as usize - height) / 2,
+    );
+
+    let mut voxels = Vec::new();
+    for y in 0..height {
+        for z in 0..length {
+            for x in 0..width {
+                let id = blocks[(y * length + z) * width + x];
+                if id == 0 {
+                    continue;
+                }
+
+                let [r, g, b] = BLOCK_COLOURS
+                    .iter()
+                    .find(|(block, _)| *block == id)
+                    .map(|(_, colour)| *colour)
+                    .unwrap_or(UNKNOWN_BLOCK_COLOUR);
+                // Minecraft is y up, .vox cells are z up
+                let cell = Vector3::new(x, z, y) + shift;
+                voxels.push((cell.cast().unwrap(), Voxel::new(r, g, b).to_cpu_value()));
+            }
+        }
+    }
+
+    Ok((cube, voxels))
+}
+
+const TAG_END: u8 = 0;
+const TAG_SHORT: u8 = 2;
+const TAG_BYTE_ARRAY: u8 = 7;
+const TAG_COMPOUND: u8 = 10;
+
+// Array and list lengths are signed
+fn nbt_len(reader: &mut Reader) -> Result<usize, String> {
+    let len = reader.i32_be()?;
+    if len < 0 {
+        return Err("Schematic has a negative length".to_string());
+    }
+    Ok(len as usize)
+}
+
+fn nbt_string<'a>(reader: &mut Reader<'a>) -> Result<&'a str, String> {
+    let len = reader.u16_be()? as usize;
+    std::str::from_utf8(reader.bytes(len)?).map_err(|_| "Schematic has a bad name".to_string())
+}
+
+/// Steps over the payload of a tag we don't need
+fn skip_nbt(reader: &mut Reader, tag: u8, level: u32) -> Result<(), String> {
+    if level > 64 {
+        return Err("Schematic is nested too deep".to_string());
+    }
+
+    match tag {
+        1 => reader.bytes(1).map(|_| ()),
+        2 => reader.bytes(2).map(|_| ()),
+        3 | 5 => reader.bytes(4).map(|_| ()),
+        4 | 6 => reader.bytes(8).map(|_| ()),
+        7 => {
+            let len = nbt_len(reader)?;
+            reader.bytes(len).map(|_| ())
+        }
+        8 => nbt_string(reader).map(|_| ()),
+        9 => {
+            let tag = reader.u8()?;
+            let len = nbt_len(reader)?;
+            for _ in 0..len {
+                skip_nbt(reader, tag, level + 1)?;
+            }
+            Ok(())
+        }
+        10 => loop {
+            let tag = reader.u8()?;
+            if tag == TAG_END {
+                return Ok(());
+            }
+            nbt_string(reader)?;
+            skip_nbt(reader, tag, level + 1)?;
+        },
+        11 => {
+            let len = nbt_len(reader)?;
+            reader.bytes(len * 4).map(|_| ())
+        }
+        12 => {
+            let len = nbt_len(reader)?;
+            reader.bytes(len * 8).map(|_| ())
+        }
+        _ => Err(format!("Schematic has unknown tag {}", tag)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(data: &mut Vec<u8>, tag: u8, name: &str) {
+        data.push(tag);
+        data.extend((name.len() as u16).to_be_bytes());
+        data.extend(name.as_bytes());
+    }
+
+    fn schematic(blocks: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        tag(&mut data, TAG_COMPOUND, "Schematic");
+        for (name, size) in [("Width", blocks.len() as i16), ("Height", 1), ("Length", 1)] {
+            tag(&mut data, TAG_SHORT, name);
+            data.extend(size.to_be_bytes());
+        }
+        // A tag the loader doesn't use
+        tag(&mut data, 8, "Materials");
+        data.extend(5u16.to_be_bytes());
+        data.extend(b"Alpha");
+        tag(&mut data, TAG_BYTE_ARRAY, "Blocks");
+        data.extend((blocks.len() as i32).to_be_bytes());
+        data.extend(blocks);
+        data.push(TAG_END);
+        data
+    }
+
+    #[test]
+    fn blocks_become_coloured_cells() {
+        let (cube, voxels) = schematic_voxels(&schematic(&[1, 0, 200])).unwrap();
+        assert_eq!(cube, 4);
+        assert_eq!(voxels.len(), 2);
+        assert_eq!(voxels[0].1, Voxel::new(125, 125, 125).to_cpu_value());
+        assert_eq!(voxels[1].1, Voxel::new(180, 180, 180).to_cpu_value());
+        assert_eq!(voxels[1].0 - voxels[0].0, Vector3::new(2, 0, 0));
+    }
+
+    #[test]
+    fn broken_schematics_error() {
+        let data = schematic(&[1, 1]);
+        assert!(schematic_voxels(&data[..data.len() - 2]).is_err());
+        assert!(schematic_voxels(&data[1..]).is_err());
+
+        let mut data = schematic(&[1]);
+        let blocks = data.len() - 6;
+        data[blocks..blocks + 4].copy_from_slice(&(-1i32).to_be_bytes());
+        assert!(schematic_voxels(&data).is_err());
+    }
+}
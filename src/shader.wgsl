@@ -10,6 +10,49 @@ struct Uniforms {
     shadows: bool;
     misc_value: f32;
     misc_bool: bool;
+    surface_only: bool;
+    sun_enabled: bool;
+    show_empty: bool;
+    pick_x: u32;
+    pick_y: u32;
+    edge_aa_samples: u32;
+    debug_gamma: f32;
+    debug_brightness: f32;
+    distance_lod_bias: f32;
+    bevel_amount: f32;
+    slab_thickness: f32;
+    slab_center: f32;
+    slab_normal: vec4<f32>;
+    background: vec4<f32>;
+    checker_colour: vec4<f32>;
+    checker_size: f32;
+    transparency_layers: u32;
+    face_tint_strength: f32;
+    ao: bool;
+    ao_strength: f32;
+    ao_rays: u32;
+    sun_radius: f32;
+    shadow_samples: u32;
+    light_count: u32;
+    path_trace: bool;
+    path_samples: u32;
+    path_bounces: u32;
+    reflections: bool;
+    reflection_bounces: u32;
+    smooth_normals: bool;
+    texture_scale: f32;
+    bloom_threshold: f32;
+    bloom_intensity: f32;
+    environment: bool;
+    environment_ambient: bool;
+    environment_intensity: f32;
+    ground_colour: vec4<f32>;
+    sky: bool;
+    sky_turbidity: f32;
+    fog: bool;
+    fog_density: f32;
+    fog_colour: vec4<f32>;
+    depth_peel: bool;
 };
 
 struct U32s {
@@ -21,16 +64,96 @@ struct AtomicU32s {
     data: [[stride(4)]] array<u32>;
 };
 
+struct ColourMaterial {
+    colour: u32;
+    alpha: f32;
+    reflectivity: f32;
+    emission: f32;
+};
+
+// Same as render.rs:Light
+struct Light {
+    position: vec4<f32>;
+    colour: vec4<f32>;
+    kind: u32;
+};
+
+struct Lights {
+    data: [[stride(48)]] array<Light>;
+};
+
+struct Accumulation {
+    data: [[stride(16)]] array<vec4<f32>>;
+};
+
+struct ColourMaterials {
+    count: u32;
+    data: [[stride(16)]] array<ColourMaterial>;
+};
+
+struct Palette {
+    count: u32;
+    colours: [[stride(4)]] array<u32>;
+};
+
+struct Pick {
+    hit: u32;
+    node: u32;
+};
+
+// Colour and coverage a pixel has built up over the depth peeling passes so far, and the
+// distance along the camera ray the next layer starts from. Same as cpu_render.rs:Peel
+struct Peel {
+    colour: vec3<f32>;
+    coverage: f32;
+    start: f32;
+    done: u32;
+    hit: u32;
+};
+
+struct Peels {
+    data: [[stride(32)]] array<Peel>;
+};
+
 [[group(0), binding(0)]]
 var<uniform> u: Uniforms; // uniforms
 [[group(0), binding(1)]]
 var<storage, read_write> n: U32s; // nodes
+[[group(0), binding(2)]]
+var<storage, read_write> pick: Pick; // node under pick pixel
+[[group(0), binding(3)]]
+var<storage, read> materials: ColourMaterials; // colours with alpha or reflectivity
+[[group(0), binding(4)]]
+var<storage, read> lights: Lights; // lights besides the sun
+[[group(0), binding(5)]]
+var<storage, read_write> accum: Accumulation; // summed path traced samples
+[[group(0), binding(6)]]
+var surface_texture: texture_2d<f32>; // tiled over voxel faces, white when unset
+[[group(0), binding(7)]]
+var surface_sampler: sampler;
+[[group(0), binding(8)]]
+var environment_texture: texture_2d<f32>; // equirectangular sky, black when unset
+[[group(0), binding(9)]]
+var<storage, read> palette: Palette; // rgba8 colours of the model's file, none for most formats
+[[group(0), binding(10)]]
+var<storage, read_write> peels: Peels; // layers peeled so far of each pixel
 
 
 let VOXEL_OFFSET = 134217728u;
+let MAX_LIGHTS = 16u;
+let LIGHT_POINT = 1u;
+
+struct FSIn {
+    [[builtin(position)]] frag_pos: vec4<f32>;
+    // Instance drawn, the layer for fs_peel
+    [[location(0), interpolate(flat)]] layer: u32;
+};
 
 [[stage(vertex)]]
-fn vs_main([[builtin(vertex_index)]] in_vertex_index: u32) -> [[builtin(position)]] vec4<f32> {
+fn vs_main(
+    [[builtin(vertex_index)]] in_vertex_index: u32,
+    [[builtin(instance_index)]] instance: u32,
+) -> FSIn {
     var x = 0.0;
     var y = 0.0;
 
@@ -48,7 +171,7 @@ fn vs_main([[builtin(vertex_index)]] in_vertex_index: u32) -> [[builtin(position
         y = 1.0;
     }
 
-    return vec4<f32>(x, y, 0.0, 1.0);
+    return FSIn(vec4<f32>(x, y, 0.0, 1.0), instance);
 }
 
 fn get_clip_space(frag_pos: vec4<f32>, dimensions: vec2<f32>) -> vec2<f32> {
@@ -79,10 +202,6 @@ fn ray_box_dist(r: Ray, vmin: vec3<f32>, vmax: vec3<f32>) -> f32 {
     return v7;
 }
 
-struct FSIn {
-    [[builtin(position)]] frag_pos: vec4<f32>;
-};
-
 
 fn count_bits(n: u32) -> u32 {
     var count = 0u;
@@ -126,6 +245,16 @@ struct Voxel {
     depth: u32;
 };
 
+// False when distance_lod_bias says the node is too small to keep at its distance.
+// The threshold is a multiple of the pixel angle that grows with distance, 0 at no bias.
+fn lod_wanted(node_pos: vec3<f32>, depth: u32) -> bool {
+    let eye = u.camera_inverse * vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    let distance = length(node_pos - eye.xyz / eye.w);
+    let node_size = 2.0 / f32(1u << depth);
+    let threshold = u.dimensions.z * (exp2(u.distance_lod_bias * distance) - 1.0);
+    return node_size > distance * threshold;
+}
+
 // Returns leaf containing position
 fn find_voxel(pos: vec3<f32>, primary: bool) -> Voxel {
     var node_index = 0u;
@@ -156,7 +285,7 @@ fn find_voxel(pos: vec3<f32>, primary: bool) -> Voxel {
 
         // Increment counters
         let value = n.data[p];
-        if (primary && (value & 15u) < 15u && !u.pause_adaptive) {
+        if (primary && (value & 15u) < 15u && !u.pause_adaptive && lod_wanted(node_pos, depth)) {
             n.data[p] = value + 1u;
         }
 
@@ -179,6 +308,56 @@ fn in_bounds(v: vec3<f32>) -> bool {
     return (s.x * s.y * s.z) > 0.5; 
 }
 
+// True when all six face neighbours of the voxel are filled
+fn is_interior(voxel: Voxel) -> bool {
+    let voxel_size = 2.0 / f32(1u << voxel.depth);
+    for (var i = 0u; i < 6u; i = i + 1u) {
+        var offset = vec3<f32>(0.0);
+        offset[i % 3u] = select(-1.0, 1.0, i >= 3u) * voxel_size;
+
+        let neighbour_pos = voxel.pos + offset;
+        if (!in_bounds(neighbour_pos)) {
+            return false;
+        }
+        if (node(find_voxel(neighbour_pos, false).value) - VOXEL_OFFSET == 0u) {
+            return false;
+        }
+    }
+    return true;
+}
+
+// Slab of slab_thickness around the plane dot(slab_normal, p) = slab_center,
+// a thickness of 0 turns it off
+fn slab_enabled() -> bool {
+    return u.slab_thickness > 0.0;
+}
+
+// Distance range along the ray inside the slab, empty when x > y
+fn slab_entry(r: Ray) -> vec2<f32> {
+    let normal = normalize(u.slab_normal.xyz);
+    let d = dot(normal, r.pos) - u.slab_center;
+    let speed = dot(normal, r.dir);
+    let half = u.slab_thickness / 2.0;
+    if (abs(speed) < 0.000001) {
+        if (abs(d) <= half) {
+            return vec2<f32>(0.0, 1000.0);
+        }
+        return vec2<f32>(1.0, 0.0);
+    }
+
+    let t1 = (-half - d) / speed;
+    let t2 = (half - d) / speed;
+    return vec2<f32>(max(min(t1, t2), 0.0), max(t1, t2));
+}
+
+// True when any part of the voxel is inside the slab
+fn voxel_in_slab(voxel: Voxel) -> bool {
+    let normal = normalize(u.slab_normal.xyz);
+    let half_size = 1.0 / f32(1u << voxel.depth);
+    let extent = half_size * dot(abs(normal), vec3<f32>(1.0));
+    return abs(dot(normal, voxel.pos) - u.slab_center) <= u.slab_thickness / 2.0 + extent;
+}
+
 struct HitInfo {
     hit: bool;
     value: u32;
@@ -186,8 +365,15 @@ struct HitInfo {
     normal: vec3<f32>;
     steps: u32;
     depth: u32;
+    empty: vec3<f32>;
 };
 
+// Tint for an empty cell, cycling hue with depth so nested cells stand apart
+fn empty_colour(depth: u32) -> vec3<f32> {
+    let t = f32(depth) / 6.0;
+    return 0.5 + 0.5 * cos(6.28318 * (t + vec3<f32>(0.0, 0.33, 0.67)));
+}
+
 fn octree_ray(r: Ray, primary: bool) -> HitInfo {
     var pos = r.pos;
     let dir_mask = vec3<f32>(r.dir == vec3<f32>(0.0));
@@ -198,23 +384,43 @@ fn octree_ray(r: Ray, primary: bool) -> HitInfo {
         // Get position on surface of the octree
         dist = ray_box_dist(r, vec3<f32>(-1.0), vec3<f32>(1.0));
         if (dist == 0.0){
-            return HitInfo(false, 0u, vec3<f32>(0.0), vec3<f32>(0.0), 0u, 0u);
+            return HitInfo(false, 0u, vec3<f32>(0.0), vec3<f32>(0.0), 0u, 0u, vec3<f32>(0.0));
         }
 
         pos = r.pos + dir * dist;
     }
 
+    var normal = trunc(pos * 1.000001);
+
+    // Skip ahead to where the ray enters the slab
+    if (slab_enabled()) {
+        let range = slab_entry(Ray(pos, dir));
+        if (range.x > range.y) {
+            return HitInfo(false, 0u, vec3<f32>(0.0), vec3<f32>(0.0), 0u, 0u, vec3<f32>(0.0));
+        }
+        if (range.x > 0.0) {
+            let slab_normal = normalize(u.slab_normal.xyz);
+            normal = -slab_normal * sign(dot(slab_normal, dir));
+            pos = pos + dir * range.x + normal * -0.000002;
+            if (!in_bounds(pos)) {
+                return HitInfo(false, 0u, vec3<f32>(0.0), vec3<f32>(0.0), 0u, 0u, vec3<f32>(0.0));
+            }
+        }
+    }
+
     let r_sign = sign(dir);
 
     var voxel = Voxel(0u, vec3<f32>(0.0), 0u);
     var voxel_pos = pos;
     var steps = 0u;
-    var normal = trunc(pos * 1.000001);
+    var deepest = 0u;
+    var empty = vec3<f32>(0.0);
     loop {
         voxel = find_voxel(voxel_pos, primary);
         if (!u.pause_adaptive || !u.show_hits) {
             let tnipt = node(voxel.value) - VOXEL_OFFSET;
-            if (tnipt > 0u) {
+            let clipped = slab_enabled() && !voxel_in_slab(voxel);
+            if (tnipt > 0u && !clipped && !(u.surface_only && is_interior(voxel))) {
                 break;
             }
         } else {
@@ -224,6 +430,11 @@ fn octree_ray(r: Ray, primary: bool) -> HitInfo {
             }
         }
 
+        if (u.show_empty) {
+            empty = empty + empty_colour(voxel.depth) * 0.02;
+        }
+        deepest = max(deepest, voxel.depth);
+
         let voxel_size = 2.0 / f32(1u << voxel.depth);
         let t_max = (voxel.pos - pos + r_sign * voxel_size / 2.0) / dir;
 
@@ -235,57 +446,530 @@ fn octree_ray(r: Ray, primary: bool) -> HitInfo {
         voxel_pos = pos + dir * t_current - normal * 0.000002;
 
         if (!in_bounds(voxel_pos)) {
-            return HitInfo(false, 0x20202000u, vec3<f32>(0.0), vec3<f32>(0.0), steps, voxel.depth);
+            return HitInfo(false, 0x20202000u, vec3<f32>(0.0), vec3<f32>(0.0), steps, voxel.depth, empty);
         }
 
         steps = steps + 1u;
-        if (steps > 100u) {
-            return HitInfo(true, 0xFF000000u, voxel_pos, normal, steps, 100u);
+        // Same as octree.rs:trace_step_limit
+        if (steps > 100u + 64u * deepest) {
+            return HitInfo(true, 0xFF000000u, voxel_pos, normal, steps, 100u, empty);
         }
     }
 
-    return HitInfo(true, voxel.value, voxel_pos, normal, steps, voxel.depth);
+    return HitInfo(true, voxel.value, voxel_pos, normal, steps, voxel.depth, empty);
 }
 
-[[stage(fragment)]]
-fn fs_main(in: FSIn) -> [[location(0)]] vec4<f32> {
-    var output_colour = vec3<f32>(0.0, 0.0, 0.0);
-    let clip_space = get_clip_space(in.frag_pos, u.dimensions.xy);
-
+fn camera_ray(clip_space: vec2<f32>) -> Ray {
     let pos = u.camera_inverse * vec4<f32>(0.0, 0.0, 0.0, 1.0);
     let dir = u.camera_inverse * vec4<f32>(clip_space.x, clip_space.y, 1.0, 1.0);
     let pos = pos.xyz / pos.w;
     let dir = normalize(dir.xyz / dir.w - pos);
-    var ray = Ray(pos.xyz, dir.xyz);
+    return Ray(pos.xyz, dir.xyz);
+}
 
-    let hit = octree_ray(ray, true);
-    // output_colour = vec3<f32>(hit.pos);
+// Brightness and gamma for debug views so heatmaps stay readable
+fn debug_adjust(value: f32) -> vec3<f32> {
+    let value = clamp(value * u.debug_brightness, 0.0, 1.0);
+    return vec3<f32>(pow(value, 1.0 / u.debug_gamma));
+}
+
+// Tilts the face normal towards the edges within bevel_amount of a voxel edge,
+// measured as a fraction of the voxel size. Same as cpu_render.rs:bevel_normal
+fn bevel_normal(hit: HitInfo) -> vec3<f32> {
+    if (u.bevel_amount <= 0.0 || hit.depth >= 32u) {
+        return hit.normal;
+    }
+
+    let voxel_size = 2.0 / f32(1u << hit.depth);
+    let local = fract((hit.pos + 1.0) / voxel_size);
+
+    var normal = hit.normal;
+    for (var i = 0u; i < 3u; i = i + 1u) {
+        if (hit.normal[i] == 0.0) {
+            let edge = min(local[i], 1.0 - local[i]);
+            let t = max(1.0 - edge / u.bevel_amount, 0.0);
+            normal[i] = sign(local[i] - 0.5) * t;
+        }
+    }
+    return normalize(normal);
+}
+
+// Normal pointing away from the filled cells among the 26 around the hit voxel, the face
+// normal for isolated voxels. Same as cpu_render.rs:smooth_normal
+fn smooth_normal(hit: HitInfo) -> vec3<f32> {
+    if (hit.depth >= 32u) {
+        return hit.normal;
+    }
+
+    let voxel_size = 2.0 / f32(1u << hit.depth);
+    let centre = (floor((hit.pos + 1.0) / voxel_size) + 0.5) * voxel_size - 1.0;
+
+    var gradient = vec3<f32>(0.0);
+    for (var i = 0u; i < 27u; i = i + 1u) {
+        let offset = vec3<f32>(f32(i / 9u), f32(i / 3u % 3u), f32(i % 3u)) - 1.0;
+        let pos = centre + offset * voxel_size;
+        if (i != 13u && in_bounds(pos)) {
+            let voxel = find_voxel(pos, false);
+            if (node(voxel.value) > VOXEL_OFFSET) {
+                gradient = gradient + normalize(offset);
+            }
+        }
+    }
+
+    if (dot(-gradient, hit.normal) <= 0.0) {
+        return hit.normal;
+    }
+    return normalize(-gradient);
+}
+
+// Normal lighting uses, smooth or the bevelled face normal
+fn surface_normal(hit: HitInfo) -> vec3<f32> {
+    if (u.smooth_normals) {
+        return smooth_normal(hit);
+    }
+    return bevel_normal(hit);
+}
+
+// Environment in direction `dir`, `level` picks a blurrier mip
+fn environment(dir: vec3<f32>, level: f32) -> vec3<f32> {
+    let d = normalize(dir);
+    let uv = vec2<f32>(atan2(d.z, d.x) / 6.283185 + 0.5, acos(clamp(d.y, -1.0, 1.0)) / 3.141593);
+    return textureSampleLevel(environment_texture, surface_sampler, uv, level).xyz * u.environment_intensity;
+}
+
+// Cheap analytic daylight from sun_dir. Higher sky_turbidity washes the blue out and widens
+// the glow around the sun, and everything reddens and darkens as the sun sets. With `disc`
+// the sun itself is drawn, at least as big as the real one, bright enough to bloom
+fn procedural_sky(dir: vec3<f32>, disc: bool) -> vec3<f32> {
+    let d = normalize(dir);
+    let to_sun = -normalize(u.sun_dir.xyz);
+    let haze = clamp((u.sky_turbidity - 1.0) / 9.0, 0.0, 1.0);
+    let day = smoothStep(-0.1, 0.3, to_sun.y);
+
+    let zenith = mix(vec3<f32>(0.02, 0.03, 0.08), mix(vec3<f32>(0.2, 0.4, 0.85), vec3<f32>(0.5, 0.6, 0.75), haze), day);
+    let horizon = mix(vec3<f32>(0.6, 0.35, 0.2), mix(vec3<f32>(0.7, 0.8, 0.95), vec3<f32>(0.85, 0.85, 0.85), haze), day);
+    var colour = mix(horizon, zenith, sqrt(max(d.y, 0.0)));
+
+    let glow = pow(max(dot(d, to_sun), 0.0), mix(64.0, 8.0, haze)) * (0.3 + 0.7 * haze) * (0.2 + 0.8 * day);
+    colour = colour + vec3<f32>(1.0, 0.85, 0.6) * glow;
+
+    if (d.y < 0.0) {
+        return mix(colour, u.ground_colour.xyz * (0.2 + 0.8 * day), smoothStep(0.0, 0.05, -d.y));
+    }
+
+    if (disc && u.sun_enabled) {
+        let radius = max(u.sun_radius, 0.27) * 0.01745329;
+        let edge = smoothStep(cos(radius * 1.1), cos(radius), dot(d, to_sun));
+        let sun = mix(vec3<f32>(1.0, 0.5, 0.2), vec3<f32>(1.0, 0.95, 0.85), day) * 20.0;
+        colour = mix(colour, sun, edge);
+    }
+    return colour;
+}
+
+// Light arriving from `dir` where nothing is hit: the environment, the sky or the flat
+// background colour
+fn sky_light(dir: vec3<f32>, disc: bool) -> vec3<f32> {
+    if (u.environment) {
+        return environment(dir, 0.0);
+    }
+    if (u.sky) {
+        return procedural_sky(dir, disc);
+    }
+    return u.background.xyz;
+}
+
+// Colour of missed rays, a checkerboard of checker_size pixel cells when that isn't 0.
+// Same as cpu_render.rs:background_colour, which has no environment or sky
+fn background(pixel: vec2<f32>, dir: vec3<f32>) -> vec3<f32> {
+    if (u.environment || u.sky) {
+        return sky_light(dir, true);
+    }
+    if (u.checker_size > 0.0) {
+        let cell = vec2<i32>(floor(pixel / u.checker_size));
+        if (((cell.x + cell.y) & 1) == 1) {
+            return u.checker_colour.xyz;
+        }
+    }
+    return u.background.xyz;
+}
+
+// Brightness multiplier by face direction, top faces lighter and bottoms darker.
+// Same as cpu_render.rs:face_tint
+fn face_tint(normal: vec3<f32>) -> f32 {
+    let factor = 1.0 + 0.2 * normal.y - 0.1 * abs(normal.x) - 0.05 * abs(normal.z);
+    return mix(1.0, factor, u.face_tint_strength);
+}
+
+// Pixel offset in [-0.5, 0.5] of sample i out of count, spread over a spiral
+fn sample_offset(i: u32, count: u32) -> vec2<f32> {
+    let r = sqrt((f32(i) + 0.5) / f32(count)) * 0.5;
+    let theta = f32(i) * 2.399963;
+    return vec2<f32>(cos(theta), sin(theta)) * r;
+}
+
+// Samples for this pixel, edge_aa_samples on depth and normal discontinuities between
+// neighbouring pixels and 1 elsewhere. Same as cpu_render.rs:edge_samples
+fn edge_samples(hit: HitInfo, ray: Ray) -> u32 {
+    var depth = 1000.0;
+    if (hit.hit) {
+        depth = length(hit.pos - ray.pos);
+    }
+    let depth_edge = fwidth(depth) / depth;
+    let normal_edge = dot(fwidth(hit.normal), vec3<f32>(1.0));
+
+    if (u.edge_aa_samples > 1u && (depth_edge > 0.01 || normal_edge > 0.0)) {
+        return u.edge_aa_samples;
+    }
+    return 1u;
+}
+
+// Fraction of ao_rays rays over the hemisphere of the face that hit something within two
+// voxels of the hit. Same as cpu_render.rs:ambient_occlusion
+fn ambient_occlusion(hit: HitInfo) -> f32 {
+    if (u.ao_rays == 0u || hit.depth >= 32u) {
+        return 0.0;
+    }
+
+    let radius = 4.0 / f32(1u << hit.depth);
+    let origin = hit.pos + hit.normal * 0.0000025;
+    // Normals are axis aligned so swizzles give the tangents
+    let tangent = hit.normal.yzx;
+    let bitangent = hit.normal.zxy;
+
+    var occluded = 0u;
+    for (var i = 0u; i < u.ao_rays; i = i + 1u) {
+        // Even points on the disc lifted onto the hemisphere, so cosine weighted
+        let offset = sample_offset(i, u.ao_rays) * 2.0;
+        let up = sqrt(max(1.0 - dot(offset, offset), 0.0));
+        let dir = normalize(tangent * offset.x + bitangent * offset.y + hit.normal * up);
+
+        let ao_hit = octree_ray(Ray(origin, dir), false);
+        if (ao_hit.hit && distance(ao_hit.pos, origin) < radius) {
+            occluded = occluded + 1u;
+        }
+    }
+    return f32(occluded) / f32(u.ao_rays);
+}
+
+// Whether the shadow rays spread over the sun's disc, those frames are noisy so they're
+// averaged in the accumulation buffer. Same as render.rs:Uniforms::soft_shadows
+fn soft_shadows() -> bool {
+    return u.sun_enabled && u.shadows && u.sun_radius > 0.0 && u.shadow_samples > 1u;
+}
+
+// Fraction of the sun visible from the hit. With a sun_radius in degrees the shadow rays
+// spread over the sun's disc, turned per pixel and per accumulated sample so few samples
+// give noise that averages out instead of bands. Same as cpu_render.rs:sun_visibility
+fn sun_visibility(hit: HitInfo, sun_dir: vec3<f32>, pixel: vec2<f32>) -> f32 {
+    let origin = hit.pos + hit.normal * 0.0000025;
+    if (u.sun_radius <= 0.0 || u.shadow_samples <= 1u) {
+        return f32(!octree_ray(Ray(origin, -sun_dir), true).hit);
+    }
+
+    var side = cross(sun_dir, vec3<f32>(0.0, 1.0, 0.0));
+    if (length(side) < 0.001) {
+        side = cross(sun_dir, vec3<f32>(1.0, 0.0, 0.0));
+    }
+    side = normalize(side);
+    let up = cross(side, sun_dir);
+    let spread = tan(u.sun_radius * 0.01745329);
+    let angle = fract(
+        sin(dot(pixel, vec2<f32>(12.9898, 78.233))) * 43758.5453 + f32(u.path_samples) * 0.618034
+    ) * 6.283185;
+
+    var lit = 0u;
+    for (var i = 0u; i < u.shadow_samples; i = i + 1u) {
+        let offset = sample_offset(i, u.shadow_samples) * 2.0 * spread;
+        let offset = vec2<f32>(
+            offset.x * cos(angle) - offset.y * sin(angle),
+            offset.x * sin(angle) + offset.y * cos(angle),
+        );
+        let dir = normalize(-sun_dir + side * offset.x + up * offset.y);
+        if (!octree_ray(Ray(origin, dir), true).hit) {
+            lit = lit + 1u;
+        }
+    }
+    return f32(lit) / f32(u.shadow_samples);
+}
+
+// Diffuse light from the lights buffer with a hard shadow ray each. Point lights fall off
+// with the square of the distance
+fn lights_diffuse(hit: HitInfo) -> vec3<f32> {
+    let normal = surface_normal(hit);
+    let origin = hit.pos + hit.normal * 0.0000025;
+
+    var total = vec3<f32>(0.0);
+    for (var i = 0u; i < min(u.light_count, MAX_LIGHTS); i = i + 1u) {
+        let light = lights.data[i];
+        var to_light = -normalize(light.position.xyz);
+        var dist = 1000.0;
+        var attenuation = 1.0;
+        if (light.kind == LIGHT_POINT) {
+            let offset = light.position.xyz - hit.pos;
+            dist = length(offset);
+            to_light = offset / dist;
+            attenuation = 1.0 / max(dist * dist, 0.0001);
+        }
+
+        var diffuse = max(dot(normal, to_light), 0.0) * attenuation;
+        if (u.shadows && diffuse > 0.0) {
+            let shadow_hit = octree_ray(Ray(origin, to_light), true);
+            if (shadow_hit.hit && distance(shadow_hit.pos, origin) < dist) {
+                diffuse = 0.0;
+            }
+        }
+        total = total + diffuse * light.colour.xyz * light.colour.w;
+    }
+    return total;
+}
+
+// Material of the colour in a leaf, opaque and matte unless it's in the materials table
+fn hit_material(hit: HitInfo) -> ColourMaterial {
+    let colour = node(hit.value) - VOXEL_OFFSET;
+    for (var i = 0u; i < min(materials.count, 256u); i = i + 1u) {
+        if (materials.data[i].colour == colour) {
+            return materials.data[i];
+        }
+    }
+    return ColourMaterial(colour, 1.0, 0.0, 0.0);
+}
+
+fn hit_alpha(hit: HitInfo) -> f32 {
+    return hit_material(hit).alpha;
+}
+
+// Surface texture projected along each axis and blended by the normal
+fn triplanar(pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let uv = pos * u.texture_scale;
+    let weights = abs(normal) / (abs(normal.x) + abs(normal.y) + abs(normal.z));
+    let x = textureSampleLevel(surface_texture, surface_sampler, uv.zy, 0.0).xyz;
+    let y = textureSampleLevel(surface_texture, surface_sampler, uv.xz, 0.0).xyz;
+    let z = textureSampleLevel(surface_texture, surface_sampler, uv.xy, 0.0).xyz;
+    return x * weights.x + y * weights.y + z * weights.z;
+}
+
+// Palette colour of the hit leaf modulated by the surface texture
+fn hit_colour(hit: HitInfo) -> vec3<f32> {
+    let value = node(hit.value) - VOXEL_OFFSET;
+    return vec3<f32>(unpack_u8(value).yzw) / 255.0 * triplanar(hit.pos, hit.normal);
+}
+
+fn shade(hit: HitInfo, dir: vec3<f32>, pixel: vec2<f32>) -> vec3<f32> {
     if (u.show_steps) {
-        output_colour = vec3<f32>(f32(hit.steps) / 64.0);
-    } else {
-        if (hit.hit) {
-            if (u.show_hits) {
-                output_colour = vec3<f32>(f32(n.data[hit.value] & 15u) / 15.0);
-            } else {
-                let sun_dir = normalize(u.sun_dir.xyz);
-
-                let ambient = 0.3;
-                var diffuse = max(dot(hit.normal, -sun_dir), 0.0);
-
-                if (u.shadows) {
-                    let shadow_hit = octree_ray(Ray(hit.pos + hit.normal * 0.0000025, -sun_dir), true);
-                    if (shadow_hit.hit) {
-                        diffuse = 0.0;
-                    }
-                }
-
-                let value = node(hit.value) - VOXEL_OFFSET;
-                let colour = vec3<f32>(unpack_u8(value).yzw) / 255.0;
-                output_colour = (ambient + diffuse) * colour;
+        return debug_adjust(f32(hit.steps) / 64.0);
+    }
+
+    if (!hit.hit) {
+        return background(pixel, dir) + hit.empty;
+    }
+
+    if (u.show_hits) {
+        return debug_adjust(f32(n.data[hit.value] & 15u) / 15.0);
+    }
+
+    let sun_dir = normalize(u.sun_dir.xyz);
+
+    // A small mip of the environment stands in for the light from every direction
+    var ambient = vec3<f32>(0.3);
+    if (u.environment && u.environment_ambient) {
+        let level = f32(textureNumLevels(environment_texture)) - 3.0;
+        ambient = environment(surface_normal(hit), max(level, 0.0));
+    }
+    if (u.ao) {
+        ambient = ambient * (1.0 - u.ao_strength * ambient_occlusion(hit));
+    }
+    var diffuse = 0.0;
+
+    // With the sun off there's no directional light or shadow rays at all
+    if (u.sun_enabled) {
+        diffuse = max(dot(surface_normal(hit), -sun_dir), 0.0);
+
+        if (u.shadows && diffuse > 0.0) {
+            diffuse = diffuse * sun_visibility(hit, sun_dir, pixel);
+        }
+    }
+
+    let light = ambient + diffuse + lights_diffuse(hit);
+    let colour = hit_colour(hit);
+    return (light * face_tint(hit.normal) + hit_material(hit).emission) * colour + hit.empty;
+}
+
+// Shades a hit mixed with up to reflection_bounces reflections off metal voxels, each
+// surface weighted by how much the one before reflects
+fn shade_reflected(hit: HitInfo, dir: vec3<f32>, pixel: vec2<f32>) -> vec3<f32> {
+    if (!u.reflections || u.show_steps || u.show_hits) {
+        return shade(hit, dir, pixel);
+    }
+
+    var hit = hit;
+    var dir = dir;
+    var colour = vec3<f32>(0.0);
+    var weight = 1.0;
+    for (var bounce = 0u; bounce < u.reflection_bounces; bounce = bounce + 1u) {
+        if (!hit.hit) {
+            break;
+        }
+        let reflectivity = clamp(hit_material(hit).reflectivity, 0.0, 1.0);
+        if (reflectivity <= 0.0) {
+            break;
+        }
+
+        colour = colour + weight * (1.0 - reflectivity) * shade(hit, dir, pixel);
+        weight = weight * reflectivity;
+        dir = reflect(dir, hit.normal);
+        hit = octree_ray(Ray(hit.pos + hit.normal * 0.0000025, dir), false);
+    }
+    return colour + weight * shade(hit, dir, pixel);
+}
+
+// Just past where a ray going along `dir` leaves the voxel it hit
+fn voxel_exit(hit: HitInfo, dir: vec3<f32>) -> vec3<f32> {
+    let voxel_size = 2.0 / f32(1u << hit.depth);
+    let centre = (floor((hit.pos + 1.0) / voxel_size) + 0.5) * voxel_size - 1.0;
+    let t_max = (centre - hit.pos + sign(dir) * voxel_size / 2.0) / dir;
+    let t_exit = min(min(t_max.x, t_max.y), t_max.z);
+    return hit.pos + dir * (t_exit + 0.000002);
+}
+
+// Shades up to transparency_layers translucent voxels front to back starting at `hit`,
+// blending each under the ones in front of it, and finishes on the first opaque voxel
+fn shade_layers(hit: HitInfo, ray: Ray, pixel: vec2<f32>) -> vec3<f32> {
+    if (u.transparency_layers == 0u || !hit.hit || u.show_steps || u.show_hits) {
+        return shade_reflected(hit, ray.dir, pixel);
+    }
+
+    var hit = hit;
+    var colour = vec3<f32>(0.0);
+    var coverage = 0.0;
+    var r = ray;
+    for (var layer = 0u; layer <= u.transparency_layers; layer = layer + 1u) {
+        var alpha = 1.0;
+        if (hit.hit && layer < u.transparency_layers) {
+            alpha = hit_alpha(hit);
+        }
+
+        colour = colour + (1.0 - coverage) * alpha * shade_reflected(hit, r.dir, pixel);
+        coverage = coverage + (1.0 - coverage) * alpha;
+        if (alpha >= 1.0 || hit.depth >= 32u) {
+            break;
+        }
+
+        r = Ray(voxel_exit(hit, r.dir), r.dir);
+        hit = octree_ray(r, false);
+    }
+
+    return colour;
+}
+
+// https://www.reedbeta.com/blog/hash-functions-for-gpu-rendering/
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// Diffuse path traced colour, lit by the sun through shadow rays, emissive voxels and the
+// background where bounces escape. Leaf colours are the albedo
+fn path_trace(hit: HitInfo, dir: vec3<f32>, pixel: vec2<f32>) -> vec3<f32> {
+    if (!hit.hit) {
+        return background(pixel, dir);
+    }
+
+    let sun_dir = normalize(u.sun_dir.xyz);
+    var seed = pcg(u32(pixel.x) + pcg(u32(pixel.y) + pcg(u.path_samples)));
+    var hit = hit;
+    var dir = dir;
+    var throughput = vec3<f32>(1.0);
+    var radiance = vec3<f32>(0.0);
+    for (var bounce = 0u; bounce <= u.path_bounces; bounce = bounce + 1u) {
+        if (!hit.hit) {
+            // The sun is already sampled directly, so bounces don't see its disc
+            radiance = radiance + throughput * sky_light(dir, false);
+            break;
+        }
+
+        let colour = hit_colour(hit);
+        radiance = radiance + throughput * colour * hit_material(hit).emission;
+        throughput = throughput * colour;
+        let origin = hit.pos + hit.normal * 0.0000025;
+
+        if (u.sun_enabled) {
+            let sun = max(dot(hit.normal, -sun_dir), 0.0);
+            if (sun > 0.0 && !octree_ray(Ray(origin, -sun_dir), false).hit) {
+                radiance = radiance + throughput * sun;
             }
-        } else {
-            output_colour =  vec3<f32>(0.2);
         }
+
+        // Cosine weighted direction around the normal
+        seed = pcg(seed);
+        let r = sqrt(f32(seed) / 4294967296.0);
+        seed = pcg(seed);
+        let theta = f32(seed) / 4294967296.0 * 6.283185;
+        let tangent = hit.normal.yzx;
+        let bitangent = hit.normal.zxy;
+        dir = normalize(tangent * r * cos(theta) + bitangent * r * sin(theta) + hit.normal * sqrt(1.0 - r * r));
+        hit = octree_ray(Ray(origin, dir), false);
+    }
+    return radiance;
+}
+
+// Blends a shaded hit towards fog_colour by how far it is along the ray. Missed rays are
+// left alone so the sky stays visible. Same as cpu_render.rs:apply_fog
+fn fog(colour: vec3<f32>, hit: HitInfo, ray: Ray) -> vec3<f32> {
+    if (!u.fog || !hit.hit || u.show_steps || u.show_hits) {
+        return colour;
+    }
+    let amount = 1.0 - exp(-length(hit.pos - ray.pos) * u.fog_density);
+    return mix(colour, u.fog_colour.xyz, amount);
+}
+
+// Adds a sample to the pixel's sum in the accumulation buffer and returns the average
+fn accumulate(colour: vec3<f32>, pixel: vec2<f32>) -> vec3<f32> {
+    let index = u32(pixel.y) * u32(u.dimensions.x) + u32(pixel.x);
+    if (index >= arrayLength(&accum.data)) {
+        return colour;
+    }
+
+    var total = colour;
+    if (u.path_samples > 0u) {
+        total = total + accum.data[index].xyz;
+    }
+    accum.data[index] = vec4<f32>(total, 0.0);
+    return total / f32(u.path_samples + 1u);
+}
+
+[[stage(fragment)]]
+fn fs_main(in: FSIn) -> [[location(0)]] vec4<f32> {
+    let clip_space = get_clip_space(in.frag_pos, u.dimensions.xy);
+    let ray = camera_ray(clip_space);
+
+    let hit = octree_ray(ray, true);
+    if (u32(in.frag_pos.x) == u.pick_x && u32(in.frag_pos.y) == u.pick_y) {
+        pick.hit = u32(hit.hit);
+        pick.node = hit.value;
+    }
+    // output_colour = vec3<f32>(hit.pos);
+    if (u.path_trace) {
+        let colour = accumulate(path_trace(hit, ray.dir, in.frag_pos.xy), in.frag_pos.xy);
+        return vec4<f32>(max(fog(colour, hit, ray), vec3<f32>(0.0)), f32(hit.hit));
+    }
+
+    var output_colour = fog(shade_layers(hit, ray, in.frag_pos.xy), hit, ray);
+
+    // Only pixels on edges get extra samples
+    let samples = edge_samples(hit, ray);
+    for (var i = 1u; i < samples; i = i + 1u) {
+        let offset = sample_offset(i, samples);
+        let sample_pos = in.frag_pos + vec4<f32>(offset, 0.0, 0.0);
+        let sample_clip_space = get_clip_space(sample_pos, u.dimensions.xy);
+        let sample_ray = camera_ray(sample_clip_space);
+        let sample_hit = octree_ray(sample_ray, false);
+        output_colour = output_colour + fog(shade_layers(sample_hit, sample_ray, sample_pos.xy), sample_hit, sample_ray);
+    }
+    output_colour = output_colour / f32(samples);
+
+    if (soft_shadows()) {
+        output_colour = accumulate(output_colour, in.frag_pos.xy);
     }
 
     // let ahha = u.dimensions.x * u.dimensions.y;
@@ -301,5 +985,55 @@ fn fs_main(in: FSIn) -> [[location(0)]] vec4<f32> {
     // output_colour = vec3<f32>(unpack_u8(value).yzw);
     // output_colour = pos;
 
-    return vec4<f32>(pow(clamp(output_colour, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(f32(u.misc_bool) * -1.2 + 2.2)), 0.5);
+    // Unclamped, bloom.wgsl clamps and gamma corrects after adding the glow. Alpha is
+    // whether anything was hit, for screenshots
+    return vec4<f32>(max(output_colour, vec3<f32>(0.0)), f32(hit.hit));
+}
+
+// One pass of depth peeling, drawn once per layer up to transparency_layers + 1 with the
+// layer as the instance. Each pass traces from just behind the layer peeled before it,
+// blends what it finds under the colour so far and writes the running colour out, so the
+// last pass leaves the finished frame. The last layer counts as opaque
+[[stage(fragment)]]
+fn fs_peel(in: FSIn) -> [[location(0)]] vec4<f32> {
+    let index = u32(in.frag_pos.y) * u32(u.dimensions.x) + u32(in.frag_pos.x);
+    if (index >= arrayLength(&peels.data)) {
+        return vec4<f32>(0.0);
+    }
+
+    let clip_space = get_clip_space(in.frag_pos, u.dimensions.xy);
+    let ray = camera_ray(clip_space);
+
+    var peel = Peel(vec3<f32>(0.0), 0.0, 0.0, 0u, 0u);
+    if (in.layer > 0u) {
+        peel = peels.data[index];
+    }
+
+    if (peel.done == 0u) {
+        let hit = octree_ray(Ray(ray.pos + ray.dir * peel.start, ray.dir), in.layer == 0u);
+        if (in.layer == 0u) {
+            peel.hit = u32(hit.hit);
+            if (u32(in.frag_pos.x) == u.pick_x && u32(in.frag_pos.y) == u.pick_y) {
+                pick.hit = u32(hit.hit);
+                pick.node = hit.value;
+            }
+        }
+
+        var alpha = 1.0;
+        if (hit.hit && in.layer < u.transparency_layers && !u.show_steps && !u.show_hits) {
+            alpha = hit_alpha(hit);
+        }
+
+        let colour = fog(shade_reflected(hit, ray.dir, in.frag_pos.xy), hit, ray);
+        peel.colour = peel.colour + (1.0 - peel.coverage) * alpha * colour;
+        peel.coverage = peel.coverage + (1.0 - peel.coverage) * alpha;
+        if (alpha >= 1.0 || hit.depth >= 32u) {
+            peel.done = 1u;
+        } else {
+            peel.start = length(voxel_exit(hit, ray.dir) - ray.pos);
+        }
+    }
+
+    peels.data[index] = peel;
+    return vec4<f32>(max(peel.colour, vec3<f32>(0.0)), f32(peel.hit));
 }
@@ -2,19 +2,22 @@ use super::*;
 use dashmap::{DashMap, DashSet};
 use std::sync::Arc;
 
+// Records the world depth next to the chunk files
+const WORLD_FILE: &str = "world.txt";
+
 pub struct World {
     pub path: String,
     pub chunks: Arc<DashMap<u32, CpuOctree>>,
     pub loading: Arc<DashSet<u32>>,
+    // Streamed chunks form a 2^depth grid over the root cube
+    pub depth: u32,
+    // Position and radius chunks were last preloaded around
+    preloaded_at: Option<(Vector3<f32>, f32)>,
 }
 
 impl World {
     pub fn new(path: String) -> Self {
-        let mut world = Self {
-            path,
-            chunks: Arc::new(DashMap::new()),
-            loading: Arc::new(DashSet::new()),
-        };
+        let mut world = World::empty(path, 1);
 
         world.chunks.insert(
             1,
@@ -60,10 +63,22 @@ impl World {
         world
     }
 
+    /// World without any chunks loaded, streaming a `2^depth` grid of them from `path`
+    pub fn empty(path: String, depth: u32) -> Self {
+        Self {
+            path,
+            chunks: Arc::new(DashMap::new()),
+            loading: Arc::new(DashSet::new()),
+            depth,
+            preloaded_at: None,
+        }
+    }
+
     pub fn generate_world<S: AsRef<std::ffi::OsStr> + Sized>(
         path: S,
         procedual: &mut Procedural,
         gpu: &Gpu,
+        world_depth: u32,
     ) -> Result<(), String> {
         // Write chunk to file
         let path = std::path::Path::new(&path);
@@ -80,10 +95,10 @@ impl World {
 
         // let root = procedual.generate_chunk(gpu, Vector3::new(-1.0, -1.0, -1.0), 0);
         let mut root = CpuOctree::new(0);
-        let world_depth = 1;
+        std::fs::write(path.join(WORLD_FILE), format!("depth = {}\n", world_depth))
+            .map_err(|e| e.to_string())?;
 
         let world_size = 1 << world_depth;
-        let voxel_size = 2.0 / world_size as f32;
         let total_iterations = world_size * world_size * world_size;
 
         use indicatif::{ProgressBar, ProgressStyle};
@@ -102,10 +117,10 @@ impl World {
         for x in 0..world_size {
             for y in 0..world_size {
                 for z in 0..world_size {
-                    let pos = (Vector3::new(x as f32, y as f32, z as f32)) * voxel_size
-                        - Vector3::new(1.0, 1.0, 1.0);
+                    let cell = Vector3::new(x, y, z);
+                    let pos = World::chunk_origin(cell, world_depth);
 
-                    let index = CHUNK_OFFSET / 2 + i as u32;
+                    let index = World::chunk_id(cell, world_depth);
                     let chunk = procedual.generate_chunk(gpu, pos, world_depth);
                     if let Some(chunk) = chunk {
                         println!(
@@ -158,15 +173,27 @@ impl World {
 
     pub fn load_world<S: AsRef<std::ffi::OsStr> + Sized>(path: S) -> Result<Self, String> {
         let path = std::path::Path::new(&path);
-        let world = World::new(path.to_str().unwrap().to_string());
+        let mut world = World::new(path.to_str().unwrap().to_string());
         if !path.exists() {
             return Err("File doesn't exist!".to_string());
         }
 
         let file = std::fs::read(path.join("0.bin")).unwrap();
-        let root = unsafe { CpuOctree::from_bin(file) };
+        let root = CpuOctree::from_bin(&file);
         world.chunks.insert(0, root);
 
+        // Worlds from before the depth was saved are all depth 1
+        if let Ok(file) = std::fs::read_to_string(path.join(WORLD_FILE)) {
+            for line in file.lines() {
+                if let Some(("depth", value)) = line.split_once('=').map(|(k, v)| (k.trim(), v)) {
+                    world.depth = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid world depth '{}'", value.trim()))?;
+                }
+            }
+        }
+
         Ok(world)
     }
 
@@ -174,10 +201,10 @@ impl World {
         let path = self.path.clone() + "/" + &index.to_string() + ".bin";
         let mut file = std::fs::File::create(path).unwrap();
         let chunk = self.chunks.get(&index).unwrap();
-        let data = unsafe { chunk.bin() };
+        let data = chunk.bin();
 
         use std::io::Write;
-        file.write_all(data).unwrap();
+        file.write_all(&data).unwrap();
     }
 
     pub fn load_chunk(&mut self, index: u32) {
@@ -191,12 +218,97 @@ impl World {
         let path = self.path.clone() + "/" + &index.to_string() + ".bin";
         tokio::task::spawn(async move {
             let file = std::fs::read(path).unwrap();
-            let root = unsafe { CpuOctree::from_bin(file) };
+            let root = CpuOctree::from_bin(&file);
             chunks.insert(index, root);
             loading.remove(&index);
         });
     }
 
+    /// Id of the chunk at `cell` in the 2^world_depth grid, in the order generate_world makes them
+    pub fn chunk_id(cell: Vector3<u64>, world_depth: u32) -> u32 {
+        let size = 1u64 << world_depth;
+        CHUNK_OFFSET / 2 + (cell.x * size * size + cell.y * size + cell.z) as u32
+    }
+
+    /// Minimum corner of a chunk's cell in root space
+    pub fn chunk_origin(cell: Vector3<u64>, world_depth: u32) -> Vector3<f32> {
+        let voxel_size = 2.0 / (1u64 << world_depth) as f32;
+        cell.map(|c| c as f32) * voxel_size - Vector3::new(1.0, 1.0, 1.0)
+    }
+
+    /// Cells of the chunk grid whose cube is within `radius` of `pos`
+    pub fn chunks_in_radius(pos: Vector3<f32>, radius: f32, world_depth: u32) -> Vec<Vector3<u64>> {
+        let size = 1u64 << world_depth;
+        let voxel_size = 2.0 / size as f32;
+        // Only the cells under the bounding box of the sphere can be in range
+        let cell_range = |c: f32| {
+            let to_cell = |c: f32| {
+                ((c + 1.0) / voxel_size)
+                    .floor()
+                    .max(0.0)
+                    .min((size - 1) as f32)
+            };
+            to_cell(c - radius) as u64..=to_cell(c + radius) as u64
+        };
+
+        let mut cells = Vec::new();
+        for x in cell_range(pos.x) {
+            for y in cell_range(pos.y) {
+                for z in cell_range(pos.z) {
+                    let cell = Vector3::new(x, y, z);
+                    let min = World::chunk_origin(cell, world_depth);
+                    let max = min + Vector3::new(voxel_size, voxel_size, voxel_size);
+
+                    // Closest point of the cube to pos
+                    let closest = Vector3::new(
+                        pos.x.max(min.x).min(max.x),
+                        pos.y.max(min.y).min(max.y),
+                        pos.z.max(min.z).min(max.z),
+                    );
+                    if (closest - pos).magnitude() <= radius {
+                        cells.push(cell);
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    /// Starts loading saved chunks within `radius` of `pos` so they're ready before the
+    /// adaptive pass reaches them. Unloading is left to unsubdivision, which knows when
+    /// the gpu octree stops using a chunk.
+    pub fn preload_chunks(&mut self, pos: Vector3<f32>, radius: f32) {
+        for id in self.chunks_to_preload(pos, radius) {
+            self.load_chunk(id);
+        }
+    }
+
+    /// Saved chunks near `pos` that aren't loaded. Nothing until the camera has moved half a
+    /// chunk from the last preload, so chunks unsubdivision just dropped aren't reloaded
+    /// every frame. The radius is grown by the same half chunk to cover the gap
+    fn chunks_to_preload(&mut self, pos: Vector3<f32>, radius: f32) -> Vec<u32> {
+        if self.path.is_empty() {
+            return Vec::new();
+        }
+
+        let half_chunk = 1.0 / (1u64 << self.depth) as f32;
+        if let Some((last_pos, last_radius)) = self.preloaded_at {
+            if last_radius == radius && (pos - last_pos).magnitude() < half_chunk {
+                return Vec::new();
+            }
+        }
+        self.preloaded_at = Some((pos, radius));
+
+        World::chunks_in_radius(pos, radius + half_chunk, self.depth)
+            .into_iter()
+            .map(|cell| World::chunk_id(cell, self.depth))
+            .filter(|id| {
+                let path = self.path.clone() + "/" + &id.to_string() + ".bin";
+                !self.chunks.contains_key(id) && std::path::Path::new(&path).exists()
+            })
+            .collect()
+    }
+
     /// Returns (chunk, index, depth, pos)
     pub fn find_voxel(
         &self,
@@ -220,7 +332,7 @@ impl World {
             node_pos += Octree::pos_offset(child_index, depth);
 
             let tnipt = self.chunks.get(&chunk).unwrap().nodes[node_index + child_index].pointer;
-            if tnipt == CHUNK_OFFSET || depth == max_depth.unwrap_or(u32::MAX) {
+            if tnipt == CHUNK_OFFSET || depth == max_depth.unwrap_or(MAX_DEPTH).min(MAX_DEPTH) {
                 return (chunk, node_index + child_index, depth, node_pos);
             } else if tnipt > CHUNK_OFFSET {
                 chunk = tnipt - CHUNK_OFFSET;
@@ -335,3 +447,68 @@ impl World {
         println!("Mip success!");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_in_radius_matches_a_full_scan() {
+        let depth = 3;
+        let size = 1u64 << depth;
+        for (pos, radius) in [
+            (Vector3::new(0.1f32, -0.3, 0.7), 0.4f32),
+            (Vector3::new(-0.99, 0.99, 0.0), 0.1),
+            (Vector3::new(3.0, 0.0, 0.0), 1.0),
+            (Vector3::new(0.0, 0.0, 0.0), 5.0),
+        ] {
+            let mut expected = Vec::new();
+            for x in 0..size {
+                for y in 0..size {
+                    for z in 0..size {
+                        let cell = Vector3::new(x, y, z);
+                        let min = World::chunk_origin(cell, depth);
+                        let max = min + Vector3::new(0.25, 0.25, 0.25);
+                        let closest = Vector3::new(
+                            pos.x.max(min.x).min(max.x),
+                            pos.y.max(min.y).min(max.y),
+                            pos.z.max(min.z).min(max.z),
+                        );
+                        if (closest - pos).magnitude() <= radius {
+                            expected.push(cell);
+                        }
+                    }
+                }
+            }
+            assert_eq!(World::chunks_in_radius(pos, radius, depth), expected);
+        }
+    }
+
+    #[test]
+    fn preload_waits_for_the_camera_to_move() {
+        let dir = std::env::temp_dir().join(format!("preload_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let depth = 2;
+        for x in 0..4 {
+            let id = World::chunk_id(Vector3::new(x, 0, 0), depth);
+            std::fs::write(dir.join(format!("{}.bin", id)), []).unwrap();
+        }
+        let mut world = World::empty(dir.to_str().unwrap().to_string(), depth);
+
+        let pos = Vector3::new(-0.75, -0.75, -0.75);
+        let first = world.chunks_to_preload(pos, 0.1);
+        assert!(first.contains(&World::chunk_id(Vector3::new(0, 0, 0), depth)));
+        assert!(!first.contains(&World::chunk_id(Vector3::new(3, 0, 0), depth)));
+
+        // Standing still or shuffling within half a chunk doesn't look again
+        assert!(world.chunks_to_preload(pos, 0.1).is_empty());
+        let nudged = pos + Vector3::new(0.1, 0.0, 0.0);
+        assert!(world.chunks_to_preload(nudged, 0.1).is_empty());
+
+        let moved = Vector3::new(0.75, -0.75, -0.75);
+        let second = world.chunks_to_preload(moved, 0.1);
+        assert!(second.contains(&World::chunk_id(Vector3::new(3, 0, 0), depth)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}